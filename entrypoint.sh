@@ -8,7 +8,7 @@ ERR=1
 # create: create a new mnemonic, export it to a file under the name "import" and continue
 create_mnemonic() {
     echo "Creating mnemonic ..."
-    (echo ${PASSWORD} | tofnd ${ARGS} -m create) && mv $EXPORT_PATH $IMPORT_PATH && echo "... ok" && return $OK
+    tofnd ${ARGS} -m create && mv $EXPORT_PATH $IMPORT_PATH && echo "... ok" && return $OK
     return $ERR
 }
 
@@ -22,15 +22,7 @@ import_mnemonic() {
         return $ERR
     fi
 
-    # check if password exists
-    if [ -n "${NOPASSWORD}" ]; then \
-        echo "No password"
-        (cat $IMPORT_PATH | tofnd ${ARGS} -m import) || return $ERR
-    else
-        echo "With password"
-        # TODO: provide actual password here
-        ((echo $PASSWORD && cat $IMPORT_PATH) | tofnd ${ARGS} -m import) || return $ERR
-    fi
+    (cat $IMPORT_PATH | tofnd ${ARGS} -m import) || return $ERR
 
     echo "... ok"
     return $OK
@@ -39,14 +31,14 @@ import_mnemonic() {
 # export: export the mnemonic to $EXPORT_PATH, move it to $IMPORT_PATH and exit
 export_mnemonic() {
     echo "Exporting mnemonic ..."
-    echo ${PASSWORD} | tofnd ${ARGS} -m export && mv $EXPORT_PATH $IMPORT_PATH || return $ERR
+    tofnd ${ARGS} -m export && mv $EXPORT_PATH $IMPORT_PATH || return $ERR
     echo "... ok"
     return $OK
 }
 
-# Get password from env var
+# Get password from env var. tofnd reads it with `--password-source env:PASSWORD`
 EMPTY_STRING=""
-PASSWORD="${PASSWORD:-$EMPTY_STRING}"
+export PASSWORD="${PASSWORD:-$EMPTY_STRING}"
 
 # gather user's args
 ARGS=""
@@ -58,10 +50,14 @@ EXPORT_PATH=$TOFND_HOME/export
 
 echo "Using tofnd root:" $TOFND_HOME
 
-# add '--no-password' and '--unsafe' flags to args if enabled
-ARGS=${NOPASSWORD:+"${ARGS} --no-password"}
+# add '--no-password' flag to args if enabled, otherwise read the password from env var
+if [ -n "${NOPASSWORD}" ]; then \
+    ARGS="${ARGS} --no-password"
+else
+    ARGS="${ARGS} --password-source env:PASSWORD"
+fi
 # add '--unsafe' flag to args if enabled
-ARGS=${UNSAFE:+"${ARGS} --unsafe"}
+ARGS="${ARGS}${UNSAFE:+ --unsafe}"
 
 # check mnemonic arg
 if [ -n "${MNEMONIC_CMD}" ]; then \
@@ -104,5 +100,5 @@ if [ -n "${MNEMONIC_CMD}" ]; then \
 fi
 
 # execute tofnd daemon
-exec echo ${PASSWORD} | tofnd ${ARGS} "$@"; \
+exec tofnd ${ARGS} "$@"
 
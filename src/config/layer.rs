@@ -0,0 +1,263 @@
+//! A [ConfigLayer] holds the options provided by a single source: a config file,
+//! environment variables or command line arguments. Layers are combined with
+//! [ConfigLayer::merge], where the options of the later layer take precedence.
+//! Keys of config files are the long names of the command line arguments.
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::path::Path;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+pub(super) const TOFND_HOME_ENV_VAR: &str = "TOFND_HOME";
+pub(super) const TOFND_CONFIG_ENV_VAR: &str = "TOFND_CONFIG";
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(super) struct ConfigLayer {
+    pub(super) port: Option<u16>,
+    pub(super) listen: Option<String>,
+    pub(super) directory: Option<String>,
+    pub(super) mnemonic: Option<String>,
+    pub(super) no_password: Option<bool>,
+    pub(super) password_source: Option<String>,
+    pub(super) kdf: Option<String>,
+    pub(super) kek: Option<String>,
+    pub(super) storage: Option<String>,
+    pub(super) durability: Option<String>,
+    pub(super) stale_reservations: Option<String>,
+    pub(super) round_timeout: Option<u64>,
+    pub(super) compute_threads: Option<usize>,
+    pub(super) max_concurrent_keygens: Option<usize>,
+    pub(super) legacy_multisig_keys: Option<bool>,
+    #[serde(rename = "unsafe")]
+    pub(super) unsafe_primes: Option<bool>,
+    pub(super) log_filter: Option<String>,
+    pub(super) tls_cert: Option<String>,
+    pub(super) tls_key: Option<String>,
+    pub(super) tls_client_ca: Option<String>,
+    pub(super) tls_allowed_subject: Option<Vec<String>>,
+    // one-off commands can only be run from the command line
+    #[serde(skip)]
+    pub(super) change_password: Option<bool>,
+    #[serde(skip)]
+    pub(super) new_password_source: Option<String>,
+    #[serde(skip)]
+    pub(super) change_kek: Option<String>,
+    #[serde(skip)]
+    pub(super) rotate_data_key: Option<bool>,
+    #[serde(skip)]
+    pub(super) backup: Option<String>,
+    #[serde(skip)]
+    pub(super) restore: Option<String>,
+    #[serde(skip)]
+    pub(super) backup_passphrase_source: Option<String>,
+    #[serde(skip)]
+    pub(super) migrate: Option<bool>,
+    #[serde(skip)]
+    pub(super) list_reservations: Option<bool>,
+    #[serde(skip)]
+    pub(super) release_reservation: Option<String>,
+    #[serde(skip)]
+    pub(super) db_check: Option<bool>,
+}
+
+impl ConfigLayer {
+    /// Read a layer from a TOML (`.toml`) or YAML (`.yaml`, `.yml`) file.
+    pub(super) fn from_file(path: &Path) -> TofndResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("could not read config file {}: {}", path.display(), err))?;
+
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let layer: Self = match extension {
+            Some("toml") => toml::from_str(&content)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+            _ => {
+                return Err(anyhow!(
+                    "unsupported config file {}: expected a .toml, .yaml or .yml extension",
+                    path.display()
+                ))
+            }
+        };
+
+        Ok(layer)
+    }
+
+    /// Read a layer from `TOFND_*` environment variables.
+    pub(super) fn from_env() -> TofndResult<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Read a layer from `TOFND_*` variables, retrieved by `var`.
+    /// Allowed client subjects can only be set by the config file or the command line,
+    /// because subjects contain commas.
+    pub(super) fn from_vars<F>(var: F) -> TofndResult<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let bool_var = |name: &str| -> TofndResult<Option<bool>> {
+            var(name)
+                .map(|value| match value.as_str() {
+                    "true" | "1" => Ok(true),
+                    "false" | "0" => Ok(false),
+                    _ => Err(anyhow!("invalid boolean value [{}] for {}", value, name)),
+                })
+                .transpose()
+        };
+
+        Ok(Self {
+            port: var("TOFND_PORT").map(|port| port.parse()).transpose()?,
+            listen: var("TOFND_LISTEN"),
+            directory: var(TOFND_HOME_ENV_VAR),
+            mnemonic: var("TOFND_MNEMONIC"),
+            no_password: bool_var("TOFND_NO_PASSWORD")?,
+            password_source: var("TOFND_PASSWORD_SOURCE"),
+            kdf: var("TOFND_KDF"),
+            kek: var("TOFND_KEK"),
+            storage: var("TOFND_STORAGE"),
+            durability: var("TOFND_DURABILITY"),
+            stale_reservations: var("TOFND_STALE_RESERVATIONS"),
+            round_timeout: var("TOFND_ROUND_TIMEOUT")
+                .map(|secs| secs.parse())
+                .transpose()?,
+            compute_threads: var("TOFND_COMPUTE_THREADS")
+                .map(|threads| threads.parse())
+                .transpose()?,
+            max_concurrent_keygens: var("TOFND_MAX_CONCURRENT_KEYGENS")
+                .map(|keygens| keygens.parse())
+                .transpose()?,
+            legacy_multisig_keys: bool_var("TOFND_LEGACY_MULTISIG_KEYS")?,
+            unsafe_primes: bool_var("TOFND_UNSAFE")?,
+            log_filter: var("TOFND_LOG_FILTER"),
+            tls_cert: var("TOFND_TLS_CERT"),
+            tls_key: var("TOFND_TLS_KEY"),
+            tls_client_ca: var("TOFND_TLS_CLIENT_CA"),
+            tls_allowed_subject: None,
+            change_password: None,
+            new_password_source: None,
+            change_kek: None,
+            rotate_data_key: None,
+            backup: None,
+            restore: None,
+            backup_passphrase_source: None,
+            migrate: None,
+            list_reservations: None,
+            release_reservation: None,
+            db_check: None,
+        })
+    }
+
+    /// Read a layer from the command line arguments that were explicitly provided.
+    pub(super) fn from_matches(matches: &ArgMatches) -> TofndResult<Self> {
+        let value = |name: &str| matches.value_of(name).map(String::from);
+        // flags can only enable an option; absence leaves the option to other layers
+        let flag = |name: &str| matches.is_present(name).then(|| true);
+
+        Ok(Self {
+            port: matches
+                .value_of("port")
+                .map(|port| port.parse())
+                .transpose()?,
+            listen: value("listen"),
+            directory: value("directory"),
+            mnemonic: value("mnemonic"),
+            no_password: flag("no-password"),
+            password_source: value("password-source"),
+            kdf: value("kdf"),
+            kek: value("kek"),
+            storage: value("storage"),
+            durability: value("durability"),
+            stale_reservations: value("stale-reservations"),
+            round_timeout: matches
+                .value_of("round-timeout")
+                .map(|secs| secs.parse())
+                .transpose()?,
+            compute_threads: matches
+                .value_of("compute-threads")
+                .map(|threads| threads.parse())
+                .transpose()?,
+            max_concurrent_keygens: matches
+                .value_of("max-concurrent-keygens")
+                .map(|keygens| keygens.parse())
+                .transpose()?,
+            legacy_multisig_keys: flag("legacy-multisig-keys"),
+            unsafe_primes: flag("unsafe"),
+            log_filter: value("log-filter"),
+            tls_cert: value("tls-cert"),
+            tls_key: value("tls-key"),
+            tls_client_ca: value("tls-client-ca"),
+            tls_allowed_subject: matches
+                .values_of("tls-allowed-subject")
+                .map(|subjects| subjects.map(String::from).collect()),
+            change_password: flag("change-password"),
+            new_password_source: value("new-password-source"),
+            change_kek: value("change-kek"),
+            rotate_data_key: flag("rotate-data-key"),
+            backup: value("backup"),
+            restore: value("restore"),
+            backup_passphrase_source: value("backup-passphrase-source"),
+            migrate: flag("migrate"),
+            list_reservations: flag("list-reservations"),
+            release_reservation: value("release-reservation"),
+            db_check: matches
+                .subcommand_matches("db")
+                .and_then(|db| db.subcommand_matches("check"))
+                .map(|_| true),
+        })
+    }
+
+    /// Merge two layers. Options set in `other` take precedence.
+    /// Options that select the same setting in different ways are overridden together, so that
+    /// e.g. a `port` in `other` is not shadowed by a `listen` of `self`.
+    pub(super) fn merge(self, other: Self) -> Self {
+        let (port, listen) = match (other.port, other.listen) {
+            (None, None) => (self.port, self.listen),
+            (port, listen) => (port, listen),
+        };
+        // disabling no-password alone leaves the password source of `self`
+        let (no_password, password_source) = match (other.no_password, other.password_source) {
+            (None, None) => (self.no_password, self.password_source),
+            (Some(false), None) => (Some(false), self.password_source),
+            (no_password, password_source) => (no_password, password_source),
+        };
+
+        Self {
+            port,
+            listen,
+            directory: other.directory.or(self.directory),
+            mnemonic: other.mnemonic.or(self.mnemonic),
+            no_password,
+            password_source,
+            kdf: other.kdf.or(self.kdf),
+            kek: other.kek.or(self.kek),
+            storage: other.storage.or(self.storage),
+            durability: other.durability.or(self.durability),
+            stale_reservations: other.stale_reservations.or(self.stale_reservations),
+            round_timeout: other.round_timeout.or(self.round_timeout),
+            compute_threads: other.compute_threads.or(self.compute_threads),
+            max_concurrent_keygens: other.max_concurrent_keygens.or(self.max_concurrent_keygens),
+            legacy_multisig_keys: other.legacy_multisig_keys.or(self.legacy_multisig_keys),
+            unsafe_primes: other.unsafe_primes.or(self.unsafe_primes),
+            log_filter: other.log_filter.or(self.log_filter),
+            tls_cert: other.tls_cert.or(self.tls_cert),
+            tls_key: other.tls_key.or(self.tls_key),
+            tls_client_ca: other.tls_client_ca.or(self.tls_client_ca),
+            tls_allowed_subject: other.tls_allowed_subject.or(self.tls_allowed_subject),
+            change_password: other.change_password.or(self.change_password),
+            new_password_source: other.new_password_source.or(self.new_password_source),
+            change_kek: other.change_kek.or(self.change_kek),
+            rotate_data_key: other.rotate_data_key.or(self.rotate_data_key),
+            backup: other.backup.or(self.backup),
+            restore: other.restore.or(self.restore),
+            backup_passphrase_source: other
+                .backup_passphrase_source
+                .or(self.backup_passphrase_source),
+            migrate: other.migrate.or(self.migrate),
+            list_reservations: other.list_reservations.or(self.list_reservations),
+            release_reservation: other.release_reservation.or(self.release_reservation),
+            db_check: other.db_check.or(self.db_check),
+        }
+    }
+}
@@ -1,15 +1,30 @@
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
 
 // error handling
-use crate::{encrypted_sled::PasswordMethod, mnemonic::Cmd, TofndResult};
+use crate::{
+    addr,
+    encrypted_sled::{KdfParams, KekMethod, PasswordMethod},
+    kv_manager::{BackupCmd, Durability, ReservationCmd, StaleReservations, StorageBackend},
+    listen::ListenAddr,
+    mnemonic::Cmd,
+    tls::TlsConfig,
+    TofndResult,
+};
 use anyhow::anyhow;
+use std::{fmt, path::PathBuf, time::Duration};
+
+mod layer;
+use layer::{ConfigLayer, TOFND_CONFIG_ENV_VAR};
 
 // TODO: move these into constants.rs
 const DEFAULT_PATH_ROOT: &str = ".tofnd";
-const TOFND_HOME_ENV_VAR: &str = "TOFND_HOME";
 const DEFAULT_MNEMONIC_CMD: &str = "existing";
 const DEFAULT_PORT: u16 = 50051;
+const DEFAULT_LOG_FILTER: &str = "tofnd=debug,tofn=debug";
+const DEFAULT_ROUND_TIMEOUT_SECS: u64 = 600;
 const AVAILABLE_MNEMONIC_CMDS: [&str; 4] = ["existing", "create", "import", "export"];
+const AVAILABLE_STORAGE_BACKENDS: [&str; 3] = ["sled", "memory", "file"];
+const AVAILABLE_STALE_RESERVATIONS: [&str; 2] = ["release", "report"];
 
 #[cfg(feature = "malicious")]
 mod malicious;
@@ -19,41 +34,219 @@ use malicious::*;
 // TODO: move to types.rs
 #[derive(Clone, Debug)]
 pub struct Config {
-    pub port: u16,
+    pub listen_addr: ListenAddr,
+    pub tls: Option<TlsConfig>,
     pub safe_keygen: bool,
     pub mnemonic_cmd: Cmd,
     pub tofnd_path: String,
     pub password_method: PasswordMethod,
+    /// key derivation function of a new kvstore, or of the new password on `--change-password`.
+    /// Existing kvstores keep the kdf they were created with.
+    pub kdf: Option<KdfParams>,
+    /// how the kek that wraps the data key of the kvstore is retrieved
+    pub kek: KekMethod,
+    /// storage backend of the kvstore
+    pub storage: StorageBackend,
+    /// when writes of the kvstore are flushed to disk
+    pub durability: Durability,
+    /// if set, re-wrap the data key of the kvstore with the password retrieved by this method and exit
+    pub change_password: Option<PasswordMethod>,
+    /// if set, re-wrap the data key of the kvstore with the kek retrieved by this method and exit
+    pub change_kek: Option<KekMethod>,
+    /// if set, `change_password` and `change_kek` also replace the data key by a new random data key
+    /// and re-encrypt all records under it
+    pub rotate_data_key: bool,
+    /// if set, back up or restore the kvstore and exit
+    pub backup_cmd: Option<BackupCmd>,
+    /// if set, report the values of the kvstore that would be upgraded to the current format
+    /// and exit without changing the kvstore
+    pub migrate_dry_run: bool,
+    /// if set, list or release key reservations and exit
+    pub reservation_cmd: Option<ReservationCmd>,
+    /// if set, check the integrity of the kvstore and exit
+    pub db_check: bool,
+    /// what to do with key reservations that are left behind by a previous run
+    pub stale_reservations: StaleReservations,
+    /// how long a share waits for the messages of a round of keygen or sign before the parties
+    /// that did not send them are reported with missing messages. `None` waits forever.
+    pub round_timeout: Option<Duration>,
+    /// number of threads of the pool that executes the rounds of keygen and sign
+    pub compute_threads: usize,
+    /// maximum number of keygens that are executed at the same time. Further keygens are queued
+    /// until a running keygen ends. `None` executes all keygens at once.
+    pub max_concurrent_keygens: Option<usize>,
+    /// if set, multisig key presence reports keys without a record in the kvstore as present, and
+    /// multisig sign stores the missing record. Keys generated before multisig keygen stored
+    /// records have none, so they are only usable with this option.
+    pub legacy_multisig_keys: bool,
+    /// filter directives of the tracing subscriber, e.g. `tofnd=debug,tofn=debug`
+    pub log_filter: String,
     #[cfg(feature = "malicious")]
     pub behaviours: Behaviours,
 }
 impl Default for Config {
     fn default() -> Self {
         Config {
-            port: DEFAULT_PORT,
+            listen_addr: ListenAddr::Tcp(addr(DEFAULT_PORT)),
+            tls: None,
             safe_keygen: true,
             mnemonic_cmd: Cmd::Existing,
             tofnd_path: DEFAULT_PATH_ROOT.to_string(),
             password_method: PasswordMethod::Prompt,
+            kdf: None,
+            kek: KekMethod::default(),
+            storage: StorageBackend::default(),
+            durability: Durability::default(),
+            change_password: None,
+            change_kek: None,
+            rotate_data_key: false,
+            backup_cmd: None,
+            migrate_dry_run: false,
+            reservation_cmd: None,
+            db_check: false,
+            stale_reservations: StaleReservations::default(),
+            round_timeout: Some(Duration::from_secs(DEFAULT_ROUND_TIMEOUT_SECS)),
+            compute_threads: num_cpus::get(),
+            max_concurrent_keygens: None,
+            legacy_multisig_keys: false,
+            log_filter: DEFAULT_LOG_FILTER.to_string(),
             #[cfg(feature = "malicious")]
             behaviours: Behaviours::default(),
         }
     }
 }
 
-pub fn parse_args() -> TofndResult<Config> {
-    // need to use let to avoid dropping temporary value
-    let port = &DEFAULT_PORT.to_string();
+/// Prints the effective config. Secrets are redacted.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "listen: {}, directory: {}, storage: {}, durability: {}, mnemonic: {:?}, kek: {}, password method: {}, safe keygen: {}, log filter: {}",
+            self.listen_addr,
+            self.tofnd_path,
+            self.storage,
+            self.durability,
+            self.mnemonic_cmd,
+            self.kek,
+            self.password_method,
+            self.safe_keygen,
+            self.log_filter,
+        )?;
+        if let Some(kdf) = &self.kdf {
+            write!(f, ", kdf: {}", kdf)?;
+        }
+        if let Some(new_password_method) = &self.change_password {
+            write!(f, ", new password method: {}", new_password_method)?;
+        }
+        if let Some(new_kek) = &self.change_kek {
+            write!(f, ", new kek: {}", new_kek)?;
+        }
+        if self.rotate_data_key {
+            write!(f, ", rotate data key")?;
+        }
+        if let Some(backup_cmd) = &self.backup_cmd {
+            write!(f, ", {}", backup_cmd)?;
+        }
+        if self.migrate_dry_run {
+            write!(f, ", migrate dry-run")?;
+        }
+        if let Some(reservation_cmd) = &self.reservation_cmd {
+            write!(f, ", {}", reservation_cmd)?;
+        }
+        if self.db_check {
+            write!(f, ", db check")?;
+        }
+        write!(f, ", stale reservations: {}", self.stale_reservations)?;
+        match &self.round_timeout {
+            Some(timeout) => write!(f, ", round timeout: {}s", timeout.as_secs())?,
+            None => write!(f, ", round timeout: disabled")?,
+        }
+        write!(f, ", compute threads: {}", self.compute_threads)?;
+        match &self.max_concurrent_keygens {
+            Some(max) => write!(f, ", max concurrent keygens: {}", max)?,
+            None => write!(f, ", max concurrent keygens: unlimited")?,
+        }
+        if self.legacy_multisig_keys {
+            write!(f, ", legacy multisig keys")?;
+        }
+        match &self.tls {
+            Some(tls) => write!(
+                f,
+                ", tls: {{cert: {}, key: <redacted>, client ca: {}, allowed subjects: {:?}}}",
+                tls.cert.display(),
+                tls.client_ca.display(),
+                tls.allowed_subjects,
+            ),
+            None => write!(f, ", tls: disabled"),
+        }
+    }
+}
 
+/// Parse the config from a config file, `TOFND_*` environment variables and command line arguments.
+/// Options from environment variables override those of the config file, and command line arguments
+/// override both.
+pub fn parse_args() -> TofndResult<Config> {
     let app = App::new("tofnd")
         .about("A threshold signature scheme daemon")
         .version(crate_version!())
+        .arg(
+            Arg::with_name("config")
+                .help(
+                    "Path of a TOML (.toml) or YAML (.yaml, .yml) config file. Keys are the long names of the command line options. Can also be set with TOFND_CONFIG.",
+                )
+                .long("config")
+                .short("c")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("port")
+                .help("Port to listen on. (default: 50051)")
                 .long("port")
                 .short("p")
                 .required(false)
-                .default_value(port),
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .help(
+                    "Address to listen on: tcp://<host>:<port> or unix://<path>. Takes precedence over --port. Unix sockets are only accessible by the owner of the process.",
+                )
+                .long("listen")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .help("PEM encoded certificate of the server. Enables mutual TLS.")
+                .long("tls-cert")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .help("PEM encoded private key of the server.")
+                .long("tls-key")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .help("PEM encoded CA bundle used to verify client certificates.")
+                .long("tls-client-ca")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-allowed-subject")
+                .help(
+                    "Subject of a client certificate that is allowed to connect, e.g. 'CN=axelar-core'. Can be used multiple times. (default: all clients with a valid certificate are allowed)",
+                )
+                .long("tls-allowed-subject")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             // TODO: change to something like `--unsafe-primes`
@@ -76,21 +269,208 @@ pub fn parse_args() -> TofndResult<Config> {
                 .takes_value(false)
                 .display_order(0),
         )
+        .arg(
+            Arg::with_name("change-password")
+                .help(
+                    "Re-wrap the data key of the kv store with a new password and exit. The new password is read from --new-password-source.",
+                )
+                .long("change-password")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("new-password-source")
+                .help(
+                    "Where to read the new password from when using --change-password: prompt, file:<path>, env:<variable> or fd:<number>. (default: prompt)",
+                )
+                .long("new-password-source")
+                .required(false)
+                .takes_value(true)
+                .requires("change-password"),
+        )
+        .arg(
+            Arg::with_name("change-kek")
+                .help(
+                    "Re-wrap the data key of the kv store with a new kek and exit: key-file:<path> or command:<path>. Use --change-password to wrap it with a new password.",
+                )
+                .long("change-kek")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with("change-password"),
+        )
+        .arg(
+            Arg::with_name("rotate-data-key")
+                .help(
+                    "With --change-password or --change-kek, also replace the data key of the kv store by a new random key and re-encrypt all records under it, so that the old password or kek and copies of the old wrapped data key can no longer decrypt them.",
+                )
+                .long("rotate-data-key")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("backup")
+                .help(
+                    "Write all records of the kv store to a new encrypted backup file at this path and exit.",
+                )
+                .long("backup")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with_all(&["restore", "change-password", "change-kek"]),
+        )
+        .arg(
+            Arg::with_name("restore")
+                .help(
+                    "Merge all records of an encrypted backup file into the kv store and exit.",
+                )
+                .long("restore")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with_all(&["change-password", "change-kek"]),
+        )
+        .arg(
+            Arg::with_name("migrate")
+                .help(
+                    "Report the values of the kv store that would be upgraded to the current format and exit without changing the kv store. Values are upgraded automatically when tofnd starts.",
+                )
+                .long("migrate")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with_all(&["backup", "restore", "change-password", "change-kek"]),
+        )
+        .arg(
+            Arg::with_name("list-reservations")
+                .help("List the reserved keys of the kv store and the sessions that reserved them, and exit.")
+                .long("list-reservations")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with_all(&["backup", "restore", "change-password", "change-kek", "migrate"]),
+        )
+        .arg(
+            Arg::with_name("release-reservation")
+                .help("Release the reservation of a key of the kv store, so that its keygen can be retried, and exit.")
+                .long("release-reservation")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with_all(&["list-reservations", "backup", "restore", "change-password", "change-kek", "migrate"]),
+        )
+        .arg(
+            Arg::with_name("stale-reservations")
+                .help("What to do at startup with key reservations that were left behind by a previous run, e.g. after a crash during keygen. (default: release)")
+                .long("stale-reservations")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&AVAILABLE_STALE_RESERVATIONS),
+        )
+        .arg(
+            Arg::with_name("round-timeout")
+                .help("Seconds to wait for the messages of a round of keygen or sign. When the timeout expires, the parties whose messages are missing are reported as faulty. 0 waits forever. (default: 600)")
+                .long("round-timeout")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compute-threads")
+                .help("Number of threads that execute the rounds of keygen and sign. (default: number of cpus)")
+                .long("compute-threads")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-concurrent-keygens")
+                .help("Maximum number of keygens that are executed at the same time. Further keygens are queued until a running keygen ends. 0 executes all keygens at once. (default: 0)")
+                .long("max-concurrent-keygens")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("legacy-multisig-keys")
+                .help("Report multisig keys without a record in the kv store as present, and store their record on sign. Needed for multisig keys that were generated before tofnd stored multisig key records. (default: deactivated)")
+                .long("legacy-multisig-keys")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("backup-passphrase-source")
+                .help(
+                    "Where to read the passphrase of the backup file from when using --backup or --restore: prompt, file:<path>, env:<variable> or fd:<number>. (default: prompt)",
+                )
+                .long("backup-passphrase-source")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("password-source")
+                .help(
+                    "Where to read the password from: prompt, file:<path>, env:<variable> or fd:<number>. (default: prompt)",
+                )
+                .long("password-source")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kdf")
+                .help(
+                    "Key derivation function of the password for a new kv store or with --change-password: scrypt[:<log_n>:<r>:<p>] or argon2id[:<m_cost>:<t_cost>:<p_cost>]. Existing kv stores keep their kdf. (default: scrypt:15:8:1)",
+                )
+                .long("kdf")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kek")
+                .help(
+                    "Key-encryption key that wraps the data key of the kv store: password, key-file:<path> or command:<path>. A key file holds a hex-encoded 32-byte key; a command wraps and unwraps data keys, e.g. with a KMS or an HSM. (default: password)",
+                )
+                .long("kek")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .help("Storage backend of the kv store. (default: sled)")
+                .long("storage")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&AVAILABLE_STORAGE_BACKENDS),
+        )
+        .arg(
+            Arg::with_name("durability")
+                .help("When writes of the kv store are flushed to disk: every-write, periodic[:<ms>] or backend. Keygen results are always flushed before they are reported. (default: every-write)")
+                .long("durability")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-filter")
+                .help("Filter directives for logs. (default: tofnd=debug,tofn=debug)")
+                .long("log-filter")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("mnemonic")
+                .help("(default: existing)")
                 .long("mnemonic")
                 .short("m")
                 .required(false)
-                .default_value(DEFAULT_MNEMONIC_CMD)
+                .takes_value(true)
                 .possible_values(&AVAILABLE_MNEMONIC_CMDS),
         )
         .arg(
             Arg::with_name("directory")
+                .help("Root directory of tofnd. Can also be set with TOFND_HOME. (default: .tofnd)")
                 .long("directory")
                 .short("d")
                 .required(false)
-                .env(TOFND_HOME_ENV_VAR)
-                .default_value(DEFAULT_PATH_ROOT),
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Maintenance of the kv store")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("check").about(
+                    "Verify the kek and read every record of the kv store, report dangling reservations and corrupt records, and exit.",
+                )),
         );
 
     #[cfg(feature = "malicious")]
@@ -110,32 +490,245 @@ pub fn parse_args() -> TofndResult<Config> {
 
     let matches = app.get_matches();
 
-    let port = matches
-        .value_of("port")
-        .ok_or_else(|| anyhow!("port value"))?
-        .parse::<u16>()?;
-    let safe_keygen = !matches.is_present("unsafe");
-    let mnemonic_cmd = matches
-        .value_of("mnemonic")
-        .ok_or_else(|| anyhow!("cmd value"))?
-        .to_string();
-    let mnemonic_cmd = Cmd::from_string(&mnemonic_cmd)?;
-    let tofnd_path = matches
-        .value_of("directory")
-        .ok_or_else(|| anyhow!("directory value"))?
-        .to_string();
-    let password_method = match matches.is_present("no-password") {
-        true => PasswordMethod::NoPassword,
-        false => PasswordMethod::Prompt,
+    let config_path = matches
+        .value_of("config")
+        .map(String::from)
+        .or_else(|| std::env::var(TOFND_CONFIG_ENV_VAR).ok());
+    let file_layer = match config_path {
+        Some(path) => ConfigLayer::from_file(path.as_ref())?,
+        None => ConfigLayer::default(),
     };
 
-    Ok(Config {
-        port,
-        safe_keygen,
-        mnemonic_cmd,
-        tofnd_path,
-        password_method,
-        #[cfg(feature = "malicious")]
-        behaviours,
-    })
+    let layer = file_layer
+        .merge(ConfigLayer::from_env()?)
+        .merge(ConfigLayer::from_matches(&matches)?);
+
+    let cfg = Config::from_layer(layer)?;
+    #[cfg(feature = "malicious")]
+    let cfg = Config { behaviours, ..cfg };
+
+    Ok(cfg)
+}
+
+impl Config {
+    /// Resolve the options of a merged [ConfigLayer]. Missing options take their default value.
+    fn from_layer(layer: ConfigLayer) -> TofndResult<Self> {
+        let listen_addr = match (layer.listen, layer.port) {
+            (Some(listen), _) => listen.parse::<ListenAddr>()?,
+            (None, Some(port)) => ListenAddr::Tcp(addr(port)),
+            (None, None) => ListenAddr::Tcp(addr(DEFAULT_PORT)),
+        };
+
+        let tls = match (layer.tls_cert, layer.tls_key, layer.tls_client_ca) {
+            (Some(cert), Some(key), Some(client_ca)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: PathBuf::from(client_ca),
+                allowed_subjects: layer.tls_allowed_subject.unwrap_or_default(),
+            }),
+            (None, None, None) if layer.tls_allowed_subject.is_none() => None,
+            _ => {
+                return Err(anyhow!(
+                    "tls-cert, tls-key and tls-client-ca must be provided together, and tls-allowed-subject requires them"
+                ))
+            }
+        };
+
+        let mnemonic_cmd =
+            Cmd::from_string(layer.mnemonic.as_deref().unwrap_or(DEFAULT_MNEMONIC_CMD))?;
+
+        let password_method = match (layer.no_password.unwrap_or(false), layer.password_source) {
+            (true, None) => PasswordMethod::NoPassword,
+            (true, Some(_)) => {
+                return Err(anyhow!(
+                    "no-password and password-source cannot be used together"
+                ))
+            }
+            (false, Some(source)) => source.parse::<PasswordMethod>()?,
+            (false, None) => PasswordMethod::Prompt,
+        };
+
+        let change_password = match layer.change_password.unwrap_or(false) {
+            true => Some(
+                layer
+                    .new_password_source
+                    .map(|source| source.parse::<PasswordMethod>())
+                    .transpose()?
+                    .unwrap_or(PasswordMethod::Prompt),
+            ),
+            false => None,
+        };
+        let change_kek = layer
+            .change_kek
+            .map(|kek| kek.parse::<KekMethod>())
+            .transpose()?;
+        match (&change_kek, &change_password) {
+            (Some(KekMethod::Password), _) => {
+                return Err(anyhow!(
+                    "use change-password to wrap the data key with a new password"
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "change-password and change-kek cannot be used together"
+                ))
+            }
+            _ => {}
+        }
+        // both commands re-wrap the data key of the kvstore
+        let changes_kek = change_password.is_some() || change_kek.is_some();
+        let rotate_data_key = layer.rotate_data_key.unwrap_or(false);
+        if rotate_data_key && !changes_kek {
+            return Err(anyhow!(
+                "rotate-data-key can only be used together with change-password or change-kek"
+            ));
+        }
+
+        let backup_passphrase_method = layer
+            .backup_passphrase_source
+            .map(|source| source.parse::<PasswordMethod>())
+            .transpose()?
+            .unwrap_or(PasswordMethod::Prompt);
+        let backup_cmd = match (layer.backup, layer.restore) {
+            (Some(path), None) => Some(BackupCmd::Backup {
+                path: PathBuf::from(path),
+                passphrase_method: backup_passphrase_method,
+            }),
+            (None, Some(path)) => Some(BackupCmd::Restore {
+                path: PathBuf::from(path),
+                passphrase_method: backup_passphrase_method,
+            }),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("backup and restore cannot be used together"))
+            }
+        };
+        if backup_cmd.is_some() && changes_kek {
+            return Err(anyhow!(
+                "change-password and change-kek cannot be used together with backup or restore"
+            ));
+        }
+        let storage = layer
+            .storage
+            .map(|storage| storage.parse::<StorageBackend>())
+            .transpose()?
+            .unwrap_or_default();
+        if changes_kek && storage != StorageBackend::EncryptedSled {
+            return Err(anyhow!(
+                "change-password and change-kek are only supported by the sled storage backend, not by the {} backend; back up the kv store and restore it into a new kv store instead",
+                storage
+            ));
+        }
+        let kek = layer
+            .kek
+            .map(|kek| kek.parse::<KekMethod>())
+            .transpose()?
+            .unwrap_or_default();
+        if kek != KekMethod::Password && storage != StorageBackend::EncryptedSled {
+            return Err(anyhow!(
+                "keks other than password are only supported by the sled storage backend"
+            ));
+        }
+
+        let durability = layer
+            .durability
+            .map(|durability| durability.parse::<Durability>())
+            .transpose()?
+            .unwrap_or_default();
+
+        let migrate_dry_run = layer.migrate.unwrap_or(false);
+        if migrate_dry_run && (backup_cmd.is_some() || changes_kek) {
+            return Err(anyhow!(
+                "migrate cannot be used together with change-password, change-kek, backup or restore"
+            ));
+        }
+
+        let reservation_cmd = match (
+            layer.list_reservations.unwrap_or(false),
+            layer.release_reservation,
+        ) {
+            (false, None) => None,
+            (true, None) => Some(ReservationCmd::List),
+            (false, Some(key)) => Some(ReservationCmd::Release { key }),
+            (true, Some(_)) => {
+                return Err(anyhow!(
+                    "list-reservations and release-reservation cannot be used together"
+                ))
+            }
+        };
+        if reservation_cmd.is_some() && (backup_cmd.is_some() || changes_kek || migrate_dry_run) {
+            return Err(anyhow!(
+                "list-reservations and release-reservation cannot be used together with change-password, change-kek, backup, restore or migrate"
+            ));
+        }
+        let db_check = layer.db_check.unwrap_or(false);
+        if db_check
+            && (backup_cmd.is_some() || changes_kek || migrate_dry_run || reservation_cmd.is_some())
+        {
+            return Err(anyhow!(
+                "db check cannot be used together with change-password, change-kek, backup, restore, migrate, list-reservations or release-reservation"
+            ));
+        }
+        if db_check && storage != StorageBackend::EncryptedSled {
+            return Err(anyhow!(
+                "db check is only supported by the sled storage backend, not by the {} backend",
+                storage
+            ));
+        }
+
+        let stale_reservations = layer
+            .stale_reservations
+            .map(|policy| policy.parse::<StaleReservations>())
+            .transpose()?
+            .unwrap_or_default();
+
+        let round_timeout = match layer.round_timeout.unwrap_or(DEFAULT_ROUND_TIMEOUT_SECS) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let compute_threads = layer.compute_threads.unwrap_or_else(num_cpus::get);
+        if compute_threads == 0 {
+            return Err(anyhow!("compute threads must be at least 1"));
+        }
+        let max_concurrent_keygens = match layer.max_concurrent_keygens.unwrap_or(0) {
+            0 => None,
+            max => Some(max),
+        };
+
+        Ok(Config {
+            listen_addr,
+            tls,
+            safe_keygen: !layer.unsafe_primes.unwrap_or(false),
+            mnemonic_cmd,
+            tofnd_path: layer
+                .directory
+                .unwrap_or_else(|| DEFAULT_PATH_ROOT.to_string()),
+            password_method,
+            kdf: layer.kdf.map(|kdf| kdf.parse::<KdfParams>()).transpose()?,
+            kek,
+            storage,
+            durability,
+            change_password,
+            change_kek,
+            rotate_data_key,
+            backup_cmd,
+            migrate_dry_run,
+            reservation_cmd,
+            db_check,
+            stale_reservations,
+            round_timeout,
+            compute_threads,
+            max_concurrent_keygens,
+            legacy_multisig_keys: layer.legacy_multisig_keys.unwrap_or(false),
+            log_filter: layer
+                .log_filter
+                .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string()),
+            #[cfg(feature = "malicious")]
+            behaviours: Behaviours::default(),
+        })
+    }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,590 @@
+//! [ConfigLayer] and [Config] tests
+
+use super::{layer::ConfigLayer, Config, DEFAULT_LOG_FILTER, DEFAULT_PATH_ROOT};
+use crate::{
+    encrypted_sled::{KekMethod, PasswordMethod},
+    kv_manager::{BackupCmd, Durability, ReservationCmd, StaleReservations, StorageBackend},
+    listen::ListenAddr,
+    TofndResult,
+};
+
+use std::{collections::HashMap, time::Duration};
+use testdir::testdir;
+
+fn try_vars(pairs: &[(&str, &str)]) -> TofndResult<ConfigLayer> {
+    let vars: HashMap<String, String> = pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    ConfigLayer::from_vars(|name| vars.get(name).cloned())
+}
+
+fn vars(pairs: &[(&str, &str)]) -> ConfigLayer {
+    try_vars(pairs).unwrap()
+}
+
+#[test]
+fn defaults() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+
+    assert_eq!(cfg.listen_addr.to_string(), "tcp://0.0.0.0:50051");
+    assert_eq!(cfg.tofnd_path, DEFAULT_PATH_ROOT);
+    assert_eq!(cfg.log_filter, DEFAULT_LOG_FILTER);
+    assert!(cfg.safe_keygen);
+    assert!(cfg.tls.is_none());
+    assert!(matches!(cfg.password_method, PasswordMethod::Prompt));
+}
+
+#[test]
+fn toml_file() {
+    let path = testdir!().join("config.toml");
+    std::fs::write(
+        &path,
+        r#"
+port = 1234
+directory = "/var/lib/tofnd"
+no-password = true
+unsafe = true
+log-filter = "tofnd=info"
+tls-allowed-subject = ["CN=axelar-core"]
+"#,
+    )
+    .unwrap();
+
+    let layer = ConfigLayer::from_file(&path).unwrap();
+    assert_eq!(layer.port, Some(1234));
+    assert_eq!(layer.directory.as_deref(), Some("/var/lib/tofnd"));
+    assert_eq!(layer.no_password, Some(true));
+    assert_eq!(layer.unsafe_primes, Some(true));
+    assert_eq!(layer.log_filter.as_deref(), Some("tofnd=info"));
+    assert_eq!(
+        layer.tls_allowed_subject,
+        Some(vec!["CN=axelar-core".to_string()])
+    );
+}
+
+#[test]
+fn yaml_file() {
+    let path = testdir!().join("config.yaml");
+    std::fs::write(
+        &path,
+        "listen: unix:///run/tofnd.sock\nmnemonic: create\nno-password: false\n",
+    )
+    .unwrap();
+
+    let layer = ConfigLayer::from_file(&path).unwrap();
+    assert_eq!(
+        layer,
+        ConfigLayer {
+            listen: Some("unix:///run/tofnd.sock".to_string()),
+            mnemonic: Some("create".to_string()),
+            no_password: Some(false),
+            ..ConfigLayer::default()
+        }
+    );
+}
+
+#[test]
+fn invalid_files() {
+    let dir = testdir!();
+
+    // unknown keys are rejected so that typos do not go unnoticed
+    let path = dir.join("typo.toml");
+    std::fs::write(&path, "prot = 1234\n").unwrap();
+    assert!(ConfigLayer::from_file(&path).is_err());
+
+    // unsupported extension
+    let path = dir.join("config.json");
+    std::fs::write(&path, "{}").unwrap();
+    assert!(ConfigLayer::from_file(&path).is_err());
+
+    // missing file
+    assert!(ConfigLayer::from_file(&dir.join("missing.toml")).is_err());
+}
+
+#[test]
+fn env_vars() {
+    let layer = vars(&[
+        ("TOFND_HOME", "/home/tofnd"),
+        ("TOFND_PORT", "1234"),
+        ("TOFND_UNSAFE", "1"),
+        ("TOFND_NO_PASSWORD", "false"),
+    ]);
+    assert_eq!(
+        layer,
+        ConfigLayer {
+            directory: Some("/home/tofnd".to_string()),
+            port: Some(1234),
+            unsafe_primes: Some(true),
+            no_password: Some(false),
+            ..ConfigLayer::default()
+        }
+    );
+
+    assert!(try_vars(&[("TOFND_UNSAFE", "yes")]).is_err());
+    assert!(try_vars(&[("TOFND_PORT", "not a port")]).is_err());
+}
+
+#[test]
+fn precedence() {
+    let file = ConfigLayer {
+        port: Some(1),
+        directory: Some("file".to_string()),
+        log_filter: Some("file".to_string()),
+        no_password: Some(true),
+        ..ConfigLayer::default()
+    };
+    let env = vars(&[("TOFND_PORT", "2"), ("TOFND_HOME", "env")]);
+    let cli = ConfigLayer {
+        port: Some(3),
+        ..ConfigLayer::default()
+    };
+
+    let cfg = Config::from_layer(file.merge(env).merge(cli)).unwrap();
+    assert_eq!(cfg.listen_addr.to_string(), "tcp://0.0.0.0:3");
+    assert_eq!(cfg.tofnd_path, "env");
+    assert_eq!(cfg.log_filter, "file");
+    assert!(matches!(cfg.password_method, PasswordMethod::NoPassword));
+}
+
+#[test]
+fn listen_overrides_port() {
+    let layer = ConfigLayer {
+        port: Some(1234),
+        listen: Some("unix:///tmp/tofnd.sock".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(layer).unwrap();
+    assert!(matches!(cfg.listen_addr, ListenAddr::Unix(_)));
+}
+
+#[test]
+fn port_overrides_listen_of_lower_layer() {
+    let file = ConfigLayer {
+        listen: Some("unix:///tmp/tofnd.sock".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cli = ConfigLayer {
+        port: Some(1234),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(file.clone().merge(cli.clone())).unwrap();
+    assert_eq!(cfg.listen_addr.to_string(), "tcp://0.0.0.0:1234");
+
+    // and the other way around
+    let cfg = Config::from_layer(cli.merge(file)).unwrap();
+    assert!(matches!(cfg.listen_addr, ListenAddr::Unix(_)));
+}
+
+#[test]
+fn incomplete_tls() {
+    let tls = ConfigLayer {
+        tls_cert: Some("cert.pem".to_string()),
+        tls_key: Some("key.pem".to_string()),
+        tls_client_ca: Some("ca.pem".to_string()),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(tls.clone()).unwrap().tls.is_some());
+
+    let no_key = ConfigLayer {
+        tls_key: None,
+        ..tls.clone()
+    };
+    assert!(Config::from_layer(no_key).is_err());
+
+    let subjects_only = ConfigLayer {
+        tls_allowed_subject: Some(vec!["CN=axelar-core".to_string()]),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(subjects_only).is_err());
+}
+
+#[test]
+fn password_source() {
+    let layer = ConfigLayer {
+        password_source: Some("env:PASSWORD".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(layer.clone()).unwrap();
+    assert!(matches!(cfg.password_method, PasswordMethod::EnvVar(name) if name == "PASSWORD"));
+
+    // skipping the password and reading it are contradictory
+    let no_password = ConfigLayer {
+        no_password: Some(true),
+        ..layer
+    };
+    assert!(Config::from_layer(no_password).is_err());
+
+    let invalid = ConfigLayer {
+        password_source: Some("stdin".to_string()),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(invalid).is_err());
+}
+
+#[test]
+fn password_source_overrides_no_password_of_lower_layer() {
+    let file = ConfigLayer {
+        no_password: Some(true),
+        ..ConfigLayer::default()
+    };
+    let cli = ConfigLayer {
+        password_source: Some("env:PASSWORD".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(file.clone().merge(cli.clone())).unwrap();
+    assert!(matches!(cfg.password_method, PasswordMethod::EnvVar(name) if name == "PASSWORD"));
+    let env = vars(&[("TOFND_PASSWORD_SOURCE", "env:PASSWORD")]);
+    let cfg = Config::from_layer(file.clone().merge(env)).unwrap();
+    assert!(matches!(cfg.password_method, PasswordMethod::EnvVar(_)));
+
+    // and the other way around
+    let cfg = Config::from_layer(cli.clone().merge(file)).unwrap();
+    assert!(matches!(cfg.password_method, PasswordMethod::NoPassword));
+
+    // disabling no-password keeps the password source of lower layers
+    let env = vars(&[("TOFND_NO_PASSWORD", "false")]);
+    let cfg = Config::from_layer(cli.merge(env)).unwrap();
+    assert!(matches!(cfg.password_method, PasswordMethod::EnvVar(_)));
+}
+
+#[test]
+fn change_password() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert!(cfg.change_password.is_none());
+
+    let layer = ConfigLayer {
+        change_password: Some(true),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(layer.clone()).unwrap();
+    assert!(matches!(cfg.change_password, Some(PasswordMethod::Prompt)));
+
+    let layer = ConfigLayer {
+        new_password_source: Some("fd:3".to_string()),
+        ..layer
+    };
+    let cfg = Config::from_layer(layer).unwrap();
+    assert!(matches!(cfg.change_password, Some(PasswordMethod::Fd(3))));
+
+    // one-off commands can't be set in config files
+    let path = testdir!().join("config.toml");
+    std::fs::write(&path, "change-password = true\n").unwrap();
+    assert!(ConfigLayer::from_file(&path).is_err());
+}
+
+#[test]
+fn rotate_data_key() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert!(!cfg.rotate_data_key);
+
+    let layer = ConfigLayer {
+        change_password: Some(true),
+        rotate_data_key: Some(true),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(layer).unwrap();
+    assert!(cfg.rotate_data_key);
+
+    // the data key is only rotated along with a new password or kek
+    let layer = ConfigLayer {
+        rotate_data_key: Some(true),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(layer).is_err());
+}
+
+#[test]
+fn backup_cmd() {
+    let layer = ConfigLayer {
+        backup: Some("tofnd.backup".to_string()),
+        backup_passphrase_source: Some("env:BACKUP_PASSPHRASE".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(layer.clone()).unwrap();
+    assert!(matches!(
+        cfg.backup_cmd,
+        Some(BackupCmd::Backup {
+            passphrase_method: PasswordMethod::EnvVar(_),
+            ..
+        })
+    ));
+
+    let restore = ConfigLayer {
+        backup: None,
+        restore: Some("tofnd.backup".to_string()),
+        ..layer.clone()
+    };
+    let cfg = Config::from_layer(restore).unwrap();
+    assert!(matches!(cfg.backup_cmd, Some(BackupCmd::Restore { .. })));
+
+    // only one command at a time
+    let both = ConfigLayer {
+        restore: Some("tofnd.backup".to_string()),
+        ..layer.clone()
+    };
+    assert!(Config::from_layer(both).is_err());
+    let with_migrate = ConfigLayer {
+        migrate: Some(true),
+        ..layer.clone()
+    };
+    assert!(Config::from_layer(with_migrate).is_err());
+    let with_change_password = ConfigLayer {
+        change_password: Some(true),
+        ..layer
+    };
+    assert!(Config::from_layer(with_change_password).is_err());
+}
+
+#[test]
+fn storage_backend() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.storage, StorageBackend::EncryptedSled);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_STORAGE", "file")])).unwrap();
+    assert_eq!(cfg.storage, StorageBackend::File);
+
+    let cli = ConfigLayer {
+        storage: Some("memory".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(vars(&[("TOFND_STORAGE", "file")]).merge(cli)).unwrap();
+    assert_eq!(cfg.storage, StorageBackend::Memory);
+
+    assert!(Config::from_layer(vars(&[("TOFND_STORAGE", "rocksdb")])).is_err());
+
+    // only sled kvstores can be re-encrypted
+    let change_password = ConfigLayer {
+        storage: Some("file".to_string()),
+        change_password: Some(true),
+        ..ConfigLayer::default()
+    };
+    let err = Config::from_layer(change_password.clone()).unwrap_err();
+    assert!(
+        err.to_string().contains("not by the file backend"),
+        "{}",
+        err
+    );
+    let rotate_data_key = ConfigLayer {
+        rotate_data_key: Some(true),
+        ..change_password
+    };
+    assert!(Config::from_layer(rotate_data_key).is_err());
+    let change_kek = ConfigLayer {
+        storage: Some("file".to_string()),
+        change_kek: Some("key-file:/run/secrets/kek".to_string()),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(change_kek).is_err());
+}
+
+#[test]
+fn durability() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.durability, Durability::EveryWrite);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_DURABILITY", "periodic:100")])).unwrap();
+    assert_eq!(
+        cfg.durability,
+        Durability::Periodic(std::time::Duration::from_millis(100))
+    );
+
+    let cli = ConfigLayer {
+        durability: Some("backend".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(vars(&[("TOFND_DURABILITY", "every-write")]).merge(cli)).unwrap();
+    assert_eq!(cfg.durability, Durability::Backend);
+
+    assert!(Config::from_layer(vars(&[("TOFND_DURABILITY", "never")])).is_err());
+}
+
+#[test]
+fn round_timeout() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.round_timeout, Some(Duration::from_secs(600)));
+
+    let cfg = Config::from_layer(vars(&[("TOFND_ROUND_TIMEOUT", "30")])).unwrap();
+    assert_eq!(cfg.round_timeout, Some(Duration::from_secs(30)));
+
+    // 0 disables the timeout
+    let cli = ConfigLayer {
+        round_timeout: Some(0),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(vars(&[("TOFND_ROUND_TIMEOUT", "30")]).merge(cli)).unwrap();
+    assert_eq!(cfg.round_timeout, None);
+
+    assert!(try_vars(&[("TOFND_ROUND_TIMEOUT", "10s")]).is_err());
+}
+
+#[test]
+fn compute_threads() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert!(cfg.compute_threads > 0);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_COMPUTE_THREADS", "4")])).unwrap();
+    assert_eq!(cfg.compute_threads, 4);
+
+    assert!(Config::from_layer(vars(&[("TOFND_COMPUTE_THREADS", "0")])).is_err());
+    assert!(try_vars(&[("TOFND_COMPUTE_THREADS", "all")]).is_err());
+}
+
+#[test]
+fn max_concurrent_keygens() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.max_concurrent_keygens, None);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_MAX_CONCURRENT_KEYGENS", "2")])).unwrap();
+    assert_eq!(cfg.max_concurrent_keygens, Some(2));
+
+    // 0 removes the limit
+    let cli = ConfigLayer {
+        max_concurrent_keygens: Some(0),
+        ..ConfigLayer::default()
+    };
+    let cfg =
+        Config::from_layer(vars(&[("TOFND_MAX_CONCURRENT_KEYGENS", "2")]).merge(cli)).unwrap();
+    assert_eq!(cfg.max_concurrent_keygens, None);
+}
+
+#[test]
+fn legacy_multisig_keys() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert!(!cfg.legacy_multisig_keys);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_LEGACY_MULTISIG_KEYS", "true")])).unwrap();
+    assert!(cfg.legacy_multisig_keys);
+}
+
+#[test]
+fn kek() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.kek, KekMethod::Password);
+    assert!(cfg.change_kek.is_none());
+
+    let cfg = Config::from_layer(vars(&[("TOFND_KEK", "key-file:/run/secrets/kek")])).unwrap();
+    assert_eq!(cfg.kek, KekMethod::KeyFile("/run/secrets/kek".into()));
+    assert!(Config::from_layer(vars(&[("TOFND_KEK", "kms")])).is_err());
+
+    // keks other than password are only supported by sled kvstores
+    let file_storage = vars(&[
+        ("TOFND_KEK", "command:/usr/local/bin/kms-kek"),
+        ("TOFND_STORAGE", "file"),
+    ]);
+    assert!(Config::from_layer(file_storage).is_err());
+
+    let change_kek = ConfigLayer {
+        change_kek: Some("command:/usr/local/bin/kms-kek".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(change_kek.clone()).unwrap();
+    assert_eq!(
+        cfg.change_kek,
+        Some(KekMethod::Command("/usr/local/bin/kms-kek".into()))
+    );
+
+    // new passwords are set with change-password
+    let to_password = ConfigLayer {
+        change_kek: Some("password".to_string()),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(to_password).is_err());
+    let with_change_password = ConfigLayer {
+        change_password: Some(true),
+        ..change_kek.clone()
+    };
+    assert!(Config::from_layer(with_change_password).is_err());
+    let with_backup = ConfigLayer {
+        backup: Some("tofnd.backup".to_string()),
+        ..change_kek
+    };
+    assert!(Config::from_layer(with_backup).is_err());
+}
+
+#[test]
+fn db_check() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert!(!cfg.db_check);
+
+    let check = ConfigLayer {
+        db_check: Some(true),
+        ..ConfigLayer::default()
+    };
+    assert!(Config::from_layer(check.clone()).unwrap().db_check);
+
+    // db check is a one-off command
+    let with_migrate = ConfigLayer {
+        migrate: Some(true),
+        ..check.clone()
+    };
+    assert!(Config::from_layer(with_migrate).is_err());
+    // only sled kvstores can be checked
+    let with_file_storage = ConfigLayer {
+        storage: Some("file".to_string()),
+        ..check
+    };
+    let err = Config::from_layer(with_file_storage).unwrap_err();
+    assert!(
+        err.to_string().contains("not by the file backend"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn reservations() {
+    let cfg = Config::from_layer(ConfigLayer::default()).unwrap();
+    assert_eq!(cfg.reservation_cmd, None);
+    assert_eq!(cfg.stale_reservations, StaleReservations::Release);
+
+    let cfg = Config::from_layer(vars(&[("TOFND_STALE_RESERVATIONS", "report")])).unwrap();
+    assert_eq!(cfg.stale_reservations, StaleReservations::Report);
+    assert!(Config::from_layer(vars(&[("TOFND_STALE_RESERVATIONS", "delete")])).is_err());
+
+    let list = ConfigLayer {
+        list_reservations: Some(true),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(list.clone()).unwrap();
+    assert_eq!(cfg.reservation_cmd, Some(ReservationCmd::List));
+
+    let release = ConfigLayer {
+        release_reservation: Some("key_uid".to_string()),
+        ..ConfigLayer::default()
+    };
+    let cfg = Config::from_layer(release.clone()).unwrap();
+    assert_eq!(
+        cfg.reservation_cmd,
+        Some(ReservationCmd::Release {
+            key: "key_uid".to_string()
+        })
+    );
+
+    // only one command at a time
+    let both = ConfigLayer {
+        list_reservations: Some(true),
+        ..release
+    };
+    assert!(Config::from_layer(both).is_err());
+    let with_migrate = ConfigLayer {
+        migrate: Some(true),
+        ..list
+    };
+    assert!(Config::from_layer(with_migrate).is_err());
+}
+
+#[test]
+fn display_redacts_secrets() {
+    let layer = ConfigLayer {
+        tls_cert: Some("cert.pem".to_string()),
+        tls_key: Some("secret-key.pem".to_string()),
+        tls_client_ca: Some("ca.pem".to_string()),
+        ..ConfigLayer::default()
+    };
+    let printed = Config::from_layer(layer).unwrap().to_string();
+
+    assert!(printed.contains("cert.pem"));
+    assert!(printed.contains("<redacted>"));
+    assert!(!printed.contains("secret-key.pem"));
+}
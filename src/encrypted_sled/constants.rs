@@ -2,4 +2,23 @@
 pub(super) const PASSWORD_VERIFICATION_KEY: &str = "verification_key";
 pub(super) const PASSWORD_VERIFICATION_VALUE: &str = "verification_value";
 pub(super) const PASSWORD_SALT_KEY: &[u8] = b"password_salt_key";
+pub(super) const PASSWORD_KDF_PARAMS_KEY: &[u8] = b"password_kdf_params_key";
+/// the data key of the db, wrapped by a kek. Dbs without a wrapped data key use the key
+/// derived from the password and the password salt as data key.
+pub(super) const DATA_KEY_KEY: &[u8] = b"wrapped_data_key";
 pub(super) const UNSAFE_PASSWORD: &str = "tofnd_unsafe_password";
+/// maximum length in bytes of a password that is read from a file descriptor
+pub(super) const MAX_FD_PASSWORD_LEN: usize = 1024;
+pub(super) const ARCHIVE_TREE_NAME: &str = "archive";
+/// tree of the records that could not be decrypted when all records were re-encrypted
+pub(super) const SKIPPED_TREE_NAME: &str = "skipped";
+pub(super) const RECORD_FORMAT_VERSION_KEY: &[u8] = b"record_format_version";
+/// records are stored under a keyed hash of their key, which is encrypted along with the value.
+/// since version 1, records are bound to their sled key and the format version as associated data
+pub(super) const RECORD_FORMAT_VERSION: u8 = 2;
+/// first record format with hashed keys
+pub(super) const HASHED_KEYS_RECORD_FORMAT_VERSION: u8 = 2;
+/// records without associated data
+pub(crate) const LEGACY_RECORD_FORMAT_VERSION: u8 = 0;
+/// info used to derive the record key hashing key from the cipher key
+pub(super) const KEY_HASHING_KEY_INFO: &[u8] = b"tofnd record key hashing key";
@@ -0,0 +1,163 @@
+//! Password-based key derivation functions and their parameters.
+//! The [KdfParams] of a db are chosen when the db is created and stored unencrypted
+//! next to the password salt, so that the db can always be opened with the same parameters.
+
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use tofn::sdk::api::{deserialize, serialize};
+
+use super::{
+    password::{Password, PasswordSalt},
+    result::{
+        EncryptedDbError::{self, *},
+        EncryptedDbResult,
+    },
+};
+
+// default scrypt params, equal to `scrypt::Params::default()`
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// default argon2id params: 19 MiB of memory, 2 iterations, 1 degree of parallelism
+const ARGON2ID_M_COST: u32 = 19 * 1024;
+const ARGON2ID_T_COST: u32 = 2;
+const ARGON2ID_P_COST: u32 = 1;
+
+/// A key derivation function and its parameters
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KdfParams {
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+    /// `m_cost` is the memory size in KiB
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+/// Dbs created before [KdfParams] were stored use the default scrypt params.
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::Scrypt {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Returns an error if the params are not accepted by the kdf
+    fn validate(&self) -> EncryptedDbResult<()> {
+        match self {
+            Self::Scrypt { log_n, r, p } => {
+                scrypt::Params::new(*log_n, *r, *p)?;
+            }
+            Self::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                argon2::Params::new(*m_cost, *t_cost, *p_cost, None)
+                    .map_err(|err| PasswordArgon2Error(err.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive a [chacha20poly1305::Key] from `password` and `salt`
+    pub(crate) fn derive_key(
+        &self,
+        password: Password,
+        salt: PasswordSalt,
+    ) -> EncryptedDbResult<chacha20poly1305::Key> {
+        let mut output = chacha20poly1305::Key::default();
+
+        match self {
+            Self::Scrypt { log_n, r, p } => scrypt::scrypt(
+                password.as_ref(),
+                salt.as_ref(),
+                &scrypt::Params::new(*log_n, *r, *p)?,
+                output.as_mut_slice(),
+            )?,
+            Self::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                let params = argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(output.len()))
+                    .map_err(|err| PasswordArgon2Error(err.to_string()))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_ref(), salt.as_ref(), output.as_mut_slice())
+                    .map_err(|err| PasswordArgon2Error(err.to_string()))?
+            }
+        }
+
+        Ok(output)
+    }
+
+    pub(crate) fn to_bytes(&self) -> EncryptedDbResult<Vec<u8>> {
+        serialize(self).map_err(|_| Serialization)
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> EncryptedDbResult<Self> {
+        deserialize(bytes).ok_or(MalformedKdfParams)
+    }
+}
+
+/// Parses `scrypt[:<log_n>:<r>:<p>]` or `argon2id[:<m_cost>:<t_cost>:<p_cost>]`.
+/// Omitted parameters take their default value.
+impl FromStr for KdfParams {
+    type Err = EncryptedDbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidKdfParams(s.to_string());
+
+        let mut parts = s.split(':');
+        let kdf = parts.next().ok_or_else(invalid)?;
+        let params = parts
+            .map(|param| param.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let kdf_params = match (kdf, params.as_slice()) {
+            ("scrypt", []) => Self::default(),
+            ("scrypt", [log_n, r, p]) => Self::Scrypt {
+                log_n: u8::try_from(*log_n).map_err(|_| invalid())?,
+                r: *r,
+                p: *p,
+            },
+            ("argon2id", []) => Self::Argon2id {
+                m_cost: ARGON2ID_M_COST,
+                t_cost: ARGON2ID_T_COST,
+                p_cost: ARGON2ID_P_COST,
+            },
+            ("argon2id", [m_cost, t_cost, p_cost]) => Self::Argon2id {
+                m_cost: *m_cost,
+                t_cost: *t_cost,
+                p_cost: *p_cost,
+            },
+            _ => return Err(invalid()),
+        };
+        kdf_params.validate()?;
+        Ok(kdf_params)
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scrypt { log_n, r, p } => write!(f, "scrypt:{}:{}:{}", log_n, r, p),
+            Self::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => write!(f, "argon2id:{}:{}:{}", m_cost, t_cost, p_cost),
+        }
+    }
+}
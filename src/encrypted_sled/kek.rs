@@ -0,0 +1,327 @@
+//! Key-encryption keys (keks) wrap the random data key of a [super::Db].
+//! Records are encrypted under the data key, which is stored next to them wrapped by a kek, so
+//! changing the kek only re-wraps the data key. A kek is provided by a [KekMethod]:
+//! - `password`: the kek is derived from the password with the [KdfParams] and salt of the db.
+//! - `key-file:<path>`: the kek is a 32-byte hex-encoded key read from a file.
+//! - `command:<path>`: the data key is wrapped and unwrapped by an external command, e.g. a bridge
+//!   to a KMS or an HSM, so that the kek never leaves the secrets infrastructure.
+//!
+//! Command protocol: tofnd runs the command with a single argument, `wrap` or `unwrap`, and
+//! writes the hex-encoded data key or wrapped data key as one line to its stdin. The command
+//! writes the hex-encoded result as one line to its stdout and exits with status 0; any other
+//! status is an error. Wrapped data keys are opaque to tofnd, so a command can include
+//! e.g. the id and version of the KMS key it used.
+
+use std::{
+    fmt,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tofn::sdk::api::{deserialize, serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::{
+    kdf::KdfParams,
+    password::{trim_newline, Password, PasswordMethod, PasswordSalt},
+    result::{
+        EncryptedDbError::{self, *},
+        EncryptedDbResult,
+    },
+};
+
+/// associated data of data keys wrapped by a cipher
+const DATA_KEY_ASSOCIATED_DATA: &[u8] = b"tofnd data key";
+/// name of password keks
+pub(super) const PASSWORD_KEK: &str = "password";
+
+/// Specifies how the [Kek] of a db is retrieved
+#[derive(Clone, Debug, PartialEq)]
+pub enum KekMethod {
+    Password,
+    KeyFile(PathBuf),
+    Command(PathBuf),
+}
+
+impl Default for KekMethod {
+    fn default() -> Self {
+        Self::Password
+    }
+}
+
+impl KekMethod {
+    /// Retrieve the kek. The password of a password kek is retrieved with `password_method`.
+    pub fn execute(&self, password_method: &PasswordMethod) -> EncryptedDbResult<Kek> {
+        Ok(match self {
+            Self::Password => Kek::Password(password_method.execute()?),
+            Self::KeyFile(path) => Kek::KeyFile(KekKey::read(path)?),
+            Self::Command(program) => Kek::Command(program.clone()),
+        })
+    }
+}
+
+/// Parses `password`, `key-file:<path>` or `command:<path>`
+impl FromStr for KekMethod {
+    type Err = EncryptedDbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidKekMethod(s.to_string());
+        if s == "password" {
+            return Ok(Self::Password);
+        }
+        let mut parts = s.splitn(2, ':');
+        let (method, value) = match (parts.next(), parts.next()) {
+            (Some(method), Some(value)) => (method, value),
+            _ => return Err(invalid()),
+        };
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        Ok(match method {
+            "key-file" => Self::KeyFile(PathBuf::from(value)),
+            "command" => Self::Command(PathBuf::from(value)),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+impl fmt::Display for KekMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Password => write!(f, "password"),
+            Self::KeyFile(path) => write!(f, "key-file:{}", path.display()),
+            Self::Command(program) => write!(f, "command:{}", program.display()),
+        }
+    }
+}
+
+/// A 32-byte kek
+#[derive(Zeroize)]
+#[zeroize(drop)]
+pub struct KekKey([u8; 32]);
+
+impl KekKey {
+    /// Read a hex-encoded key from the file at `path`. A trailing newline is ignored.
+    fn read(path: &std::path::Path) -> EncryptedDbResult<Self> {
+        let malformed = |reason: &str| KeyFile(format!("{}: {}", path.display(), reason));
+        let mut hex = std::fs::read(path).map_err(|err| malformed(&err.to_string()))?;
+        trim_newline(&mut hex);
+        let bytes = from_hex(&hex);
+        hex.zeroize();
+        let mut bytes = bytes.ok_or_else(|| malformed("key is not hex-encoded"))?;
+        let mut key = Self([0; 32]);
+        if bytes.len() != key.0.len() {
+            bytes.zeroize();
+            return Err(malformed("key is not 32 bytes long"));
+        }
+        key.0.copy_from_slice(&bytes);
+        bytes.zeroize();
+        Ok(key)
+    }
+}
+
+/// The kek of a db
+pub enum Kek {
+    Password(Password),
+    KeyFile(KekKey),
+    Command(PathBuf),
+}
+
+impl From<Password> for Kek {
+    fn from(password: Password) -> Self {
+        Self::Password(password)
+    }
+}
+
+impl Kek {
+    /// Name of the kind of the kek, stored along with the wrapped data key
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Password(_) => PASSWORD_KEK,
+            Self::KeyFile(_) => "key-file",
+            Self::Command(_) => "command",
+        }
+    }
+
+    /// Returns the [KeyWrapper] of the kek. Password keks are derived from `salt` with `kdf`.
+    pub(super) fn wrapper(
+        &self,
+        salt: PasswordSalt,
+        kdf: &KdfParams,
+    ) -> EncryptedDbResult<Box<dyn KeyWrapper>> {
+        Ok(match self {
+            Self::Password(password) => {
+                let mut key = kdf.derive_key(password.clone(), salt)?;
+                let wrapper = CipherWrapper(XChaCha20Poly1305::new(&key));
+                key.zeroize();
+                Box::new(wrapper)
+            }
+            Self::KeyFile(key) => Box::new(CipherWrapper(XChaCha20Poly1305::new(Key::from_slice(
+                &key.0,
+            )))),
+            Self::Command(program) => Box::new(CommandWrapper(program.clone())),
+        })
+    }
+}
+
+/// Wraps and unwraps data keys with a kek
+pub(super) trait KeyWrapper {
+    fn wrap(&self, data_key: &Key) -> EncryptedDbResult<Vec<u8>>;
+    /// Returns [WrongKek] if `wrapped` was not wrapped by this kek
+    fn unwrap(&self, wrapped: &[u8]) -> EncryptedDbResult<Key>;
+}
+
+/// Wraps data keys with [XChaCha20Poly1305]. Wrapped keys are the nonce followed by the
+/// encrypted data key.
+struct CipherWrapper(XChaCha20Poly1305);
+
+impl KeyWrapper for CipherWrapper {
+    fn wrap(&self, data_key: &Key) -> EncryptedDbResult<Vec<u8>> {
+        let mut nonce = XNonce::default();
+        rand::thread_rng().fill_bytes(nonce.as_mut_slice());
+        let mut encrypted = data_key.to_vec();
+        self.0
+            .encrypt_in_place(&nonce, DATA_KEY_ASSOCIATED_DATA, &mut encrypted)
+            .map_err(|e| Encryption(e.to_string()))?;
+        Ok([nonce.as_slice(), &encrypted].concat())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> EncryptedDbResult<Key> {
+        if wrapped.len() < XNonce::default().len() {
+            return Err(Deserialization);
+        }
+        let (nonce, encrypted) = wrapped.split_at(XNonce::default().len());
+        let mut data_key = encrypted.to_vec();
+        self.0
+            .decrypt_in_place(
+                XNonce::from_slice(nonce),
+                DATA_KEY_ASSOCIATED_DATA,
+                &mut data_key,
+            )
+            .map_err(|_| WrongKek)?;
+        key_from_vec(data_key)
+    }
+}
+
+/// Wraps data keys with an external command that implements the command protocol
+struct CommandWrapper(PathBuf);
+
+impl CommandWrapper {
+    /// Runs the command with `op`, writing `input` to its stdin and returning its output
+    fn run(&self, op: &str, input: &[u8]) -> EncryptedDbResult<Vec<u8>> {
+        let err = |reason: String| KekCommand(format!("{} {}: {}", self.0.display(), op, reason));
+
+        let mut child = Command::new(&self.0)
+            .arg(op)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| err(e.to_string()))?;
+
+        // the line is allocated once, so that no copies of the data key are left behind
+        let mut line = Zeroizing::new(String::with_capacity(2 * input.len() + 1));
+        push_hex(&mut line, input);
+        line.push('\n');
+        // stdin is closed at the end of the statement, so that the command sees the end of its input
+        let written = child
+            .stdin
+            .take()
+            .expect("stdin of the kek command is piped")
+            .write_all(line.as_bytes());
+        drop(line);
+
+        let output = child.wait_with_output().map_err(|e| err(e.to_string()))?;
+        written.map_err(|e| err(e.to_string()))?;
+        let mut stdout = output.stdout;
+        if !output.status.success() {
+            stdout.zeroize();
+            return Err(err(output.status.to_string()));
+        }
+
+        trim_newline(&mut stdout);
+        let res = from_hex(&stdout).ok_or_else(|| err("output is not hex-encoded".to_string()));
+        stdout.zeroize();
+        res
+    }
+}
+
+impl KeyWrapper for CommandWrapper {
+    fn wrap(&self, data_key: &Key) -> EncryptedDbResult<Vec<u8>> {
+        self.run("wrap", data_key)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> EncryptedDbResult<Key> {
+        key_from_vec(self.run("unwrap", wrapped)?)
+    }
+}
+
+/// The data key of a db, wrapped by a kek of kind `kek`
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct WrappedDataKey {
+    pub(super) kek: String,
+    pub(super) wrapped: Vec<u8>,
+}
+
+impl WrappedDataKey {
+    pub(super) fn to_bytes(&self) -> EncryptedDbResult<Vec<u8>> {
+        serialize(&self).map_err(|_| Serialization)
+    }
+
+    pub(super) fn from_bytes(bytes: &[u8]) -> EncryptedDbResult<Self> {
+        deserialize(bytes).ok_or(Deserialization)
+    }
+}
+
+/// Convert `bytes` into a [Key]; `bytes` are zeroized
+fn key_from_vec(mut bytes: Vec<u8>) -> EncryptedDbResult<Key> {
+    let res = match bytes.len() {
+        32 => Ok(*Key::from_slice(&bytes)),
+        _ => Err(WrongKek),
+    };
+    bytes.zeroize();
+    res
+}
+
+/// Hex-encode `bytes`
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    push_hex(&mut hex, bytes);
+    hex
+}
+
+/// Append the hex encoding of `bytes` to `hex` without intermediate allocations, so that no
+/// copies of secret `bytes` are left behind if `hex` has enough capacity
+fn push_hex(hex: &mut String, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for byte in bytes {
+        hex.push(DIGITS[usize::from(byte >> 4)] as char);
+        hex.push(DIGITS[usize::from(byte & 0x0f)] as char);
+    }
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    // allocated once, since the result may be a key
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for pair in hex.chunks(2) {
+        let byte = std::str::from_utf8(pair)
+            .ok()
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+        match byte {
+            Some(byte) => bytes.push(byte),
+            None => {
+                bytes.zeroize();
+                return None;
+            }
+        }
+    }
+    Some(bytes)
+}
@@ -1,66 +1,232 @@
-//! Wrap [sled] with [chacha20poly1305] encryption. An [XChaCha20Entropy] is
+//! Wrap [sled] with [chacha20poly1305] encryption. A random data key is
 //! used as [XChaCha20Poly1305] cipher key to create an [EncryptedDb].
+//! The data key is stored wrapped by a [Kek], see [super::kek].
 //! A new random [XChaCha20Nonce] is created every time a new value needs to be
 //! inserted, forming a [EncryptedRecord]:<encrypted value, nonce>. The nonce is later
 //! used to decrypt and retrieve the originally inserted value.
+//! Records that are no longer in use can be moved to a separate archive tree,
+//! where they are kept encrypted under the same cipher.
+//! The kek can be changed with [EncryptedDb::change_kek], which re-wraps the data key
+//! without re-encrypting the records, or with [EncryptedDb::rotate_data_key], which also
+//! replaces the data key and re-encrypts all records under the new one.
+//! Password keks are derived with the [KdfParams] that were chosen when the db was created.
+//! Dbs created before data keys were wrapped use the key derived from the password as data key;
+//! when such a db is upgraded, its data key is rotated, so that the old password and salt no longer
+//! decrypt its records.
+//! Each record is bound to its key and to the record format version by authenticating them
+//! as associated data, so records cannot be swapped between keys without detection.
+//! Record keys are not stored in plaintext: each record is stored under a keyed hash of its key,
+//! and the original key is encrypted along with the value so that records can still be iterated.
+//! The hashing key is derived from the cipher key, so it also changes with the password.
+//! Opening a db never rewrites it: dbs with records of an older format are read in that format
+//! until they are upgraded with [EncryptedDb::upgrade]. Records that cannot be decrypted during
+//! an upgrade are moved aside to a separate tree instead of failing the upgrade.
 
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 use chacha20poly1305::aead::{AeadInPlace, NewAead};
 use chacha20poly1305::{self, XChaCha20Poly1305};
+use hmac::{Hmac, Mac, NewMac};
 use rand::RngCore;
+use sha2::Sha256;
 
+use sled::transaction::{ConflictableTransactionResult, Transactional};
 use sled::IVec;
 use zeroize::Zeroize;
 
 use super::constants::*;
+use super::kdf::KdfParams;
+use super::kek::{Kek, WrappedDataKey, PASSWORD_KEK};
 use super::password::{Password, PasswordSalt};
 use super::record::EncryptedRecord;
-use super::result::{EncryptedDbError::*, EncryptedDbResult};
+use super::result::{
+    EncryptedDbError::{self, *},
+    EncryptedDbResult,
+};
+
+/// Keyed hash used to derive the sled keys of records from their original keys.
+type KeyHasher = Hmac<Sha256>;
+
+/// A record as read by [EncryptedDb::check_records]: its sled key, along with its original key
+/// and decrypted value or the reason it cannot be decrypted.
+pub type CheckedRecord = (IVec, EncryptedDbResult<(IVec, IVec)>);
+
+/// A record that could not be decrypted while all records were re-encrypted, e.g. by
+/// [EncryptedDb::upgrade]. The record is moved to the skipped tree under the same sled key.
+#[derive(Debug)]
+pub struct SkippedRecord {
+    pub sled_key: IVec,
+    /// whether the record was in the archive tree
+    pub archived: bool,
+    pub reason: EncryptedDbError,
+}
+
+/// A batch of writes that is applied atomically by [EncryptedDb::apply_batch].
+/// Writes are applied in the order they were added.
+#[derive(Debug, Default)]
+pub struct Batch {
+    writes: Vec<(Vec<u8>, Option<IVec>)>,
+}
+
+impl Batch {
+    /// Set the value of `key` to `value`
+    pub fn insert<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        self.writes
+            .push((key.as_ref().to_vec(), Some(value.into())));
+    }
+
+    /// Remove `key`
+    pub fn remove<K>(&mut self, key: K)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.writes.push((key.as_ref().to_vec(), None));
+    }
+}
 
 /// A [sled] kv store with [XChaCha20Poly1305] value encryption.
 pub struct EncryptedDb {
     kv: sled::Db,
+    archive: sled::Tree,
+    data_key: chacha20poly1305::Key,
     cipher: XChaCha20Poly1305,
+    key_hasher: KeyHasher,
+    kdf: KdfParams,
+    /// name of the kind of kek that wraps the data key
+    kek: &'static str,
+    format_version: u8,
+    /// whether the data key is derived from the password, as in dbs created before data keys were wrapped
+    legacy_data_key: bool,
+}
+
+impl Drop for EncryptedDb {
+    fn drop(&mut self) {
+        self.data_key.zeroize();
+    }
 }
 
 impl EncryptedDb {
     /// create a new [EncryptedDb] that wraps sled::open(db_name).
-    /// Retrieves [XChaCha20Entropy] from a password-based-key-derivation-function and
-    /// verifies that the password is valid.
-    /// See [crate::password] for more info on pdkdf.
-    pub fn open<P>(db_name: P, password: Password) -> EncryptedDbResult<Self>
+    /// Unwraps the data key of the db with `kek`, or wraps a new random data key for a new db,
+    /// and verifies that the kek is valid. Password keks are derived with a
+    /// password-based-key-derivation-function; see [KdfParams].
+    pub fn open<P, K>(db_name: P, kek: K) -> EncryptedDbResult<Self>
+    where
+        P: AsRef<std::path::Path>,
+        K: Into<Kek>,
+    {
+        Self::open_with_kdf(db_name, kek, &KdfParams::default())
+    }
+
+    /// Same as [EncryptedDb::open], but a new db is created with `kdf`.
+    /// Existing dbs are opened with the [KdfParams] they were created with.
+    pub fn open_with_kdf<P, K>(db_name: P, kek: K, kdf: &KdfParams) -> EncryptedDbResult<Self>
+    where
+        P: AsRef<std::path::Path>,
+        K: Into<Kek>,
+    {
+        Self::open_with_kek(db_name, &kek.into(), kdf)
+    }
+
+    /// Same as [EncryptedDb::open_with_kdf], but borrows `kek`, e.g. to open the db again with the same kek.
+    pub fn open_with_kek<P>(db_name: P, kek: &Kek, kdf: &KdfParams) -> EncryptedDbResult<Self>
     where
         P: AsRef<std::path::Path>,
     {
         let kv = sled::open(db_name).map_err(CorruptedKv)?;
+        let archive = kv.open_tree(ARCHIVE_TREE_NAME).map_err(CorruptedKv)?;
 
-        let password_salt: PasswordSalt = if kv.was_recovered() {
-            // existing kv: get the existing password salt
-            kv.get(PASSWORD_SALT_KEY)?
+        let (data_key, kdf, format_version, legacy_data_key) = if kv.was_recovered() {
+            // existing kv: get the existing password salt, kdf params and record format.
+            // dbs without stored kdf params were created with the default params,
+            // and dbs without a stored record format use the legacy format
+            let password_salt: PasswordSalt = kv
+                .get(PASSWORD_SALT_KEY)?
                 .ok_or(MissingPasswordSalt)?
-                .try_into()?
+                .try_into()?;
+            let kdf = match kv.get(PASSWORD_KDF_PARAMS_KEY)? {
+                Some(bytes) => KdfParams::from_bytes(&bytes)?,
+                None => KdfParams::default(),
+            };
+            let format_version = match kv.get(RECORD_FORMAT_VERSION_KEY)? {
+                Some(bytes) if bytes.len() == 1 => bytes[0],
+                Some(_) => return Err(Deserialization),
+                None => LEGACY_RECORD_FORMAT_VERSION,
+            };
+            if format_version > RECORD_FORMAT_VERSION {
+                return Err(UnsupportedRecordFormat(format_version));
+            }
+            match kv.get(DATA_KEY_KEY)? {
+                Some(bytes) => {
+                    let wrapped = WrappedDataKey::from_bytes(&bytes)?;
+                    if wrapped.kek != kek.name() {
+                        return Err(KekMismatch {
+                            stored: wrapped.kek,
+                            given: kek.name().to_string(),
+                        });
+                    }
+                    let data_key = kek
+                        .wrapper(password_salt, &kdf)?
+                        .unwrap(&wrapped.wrapped)
+                        .map_err(|err| match err {
+                            WrongKek => Self::wrong_kek(kek),
+                            err => err,
+                        })?;
+                    (data_key, kdf, format_version, false)
+                }
+                // dbs without a wrapped data key use the key derived from the password as data key
+                None => {
+                    let password = match kek {
+                        Kek::Password(password) => password.clone(),
+                        _ => {
+                            return Err(KekMismatch {
+                                stored: PASSWORD_KEK.to_string(),
+                                given: kek.name().to_string(),
+                            })
+                        }
+                    };
+                    let data_key = kdf.derive_key(password, password_salt)?;
+                    (data_key, kdf, format_version, true)
+                }
+            }
         } else {
-            // new kv: choose a new password salt and store it
-            let mut password_salt = [0u8; 32];
-            rand::thread_rng().fill_bytes(&mut password_salt);
+            // new kv: choose a random data key and store it wrapped by the kek, along with
+            // a new password salt, the kdf params and the record format
+            let password_salt = Self::generate_salt();
+            let data_key = Self::generate_data_key();
+            let wrapped = Self::wrap_data_key(kek, password_salt, kdf, &data_key)?;
+            kv.insert(PASSWORD_KDF_PARAMS_KEY, kdf.to_bytes()?)?;
+            kv.insert(RECORD_FORMAT_VERSION_KEY, &[RECORD_FORMAT_VERSION])?;
+            kv.insert(DATA_KEY_KEY, wrapped)?;
             kv.insert(PASSWORD_SALT_KEY, &password_salt)?;
-            password_salt.into()
+            (data_key, kdf.clone(), RECORD_FORMAT_VERSION, false)
         };
 
-        // zeroize key since we are no longer using it after creating cipher
-        let mut key = Self::chacha20poly1305_kdf(password, password_salt)?;
-        let cipher = XChaCha20Poly1305::new(&key);
-        key.zeroize();
+        let (cipher, key_hasher) = Self::derive_keys(&data_key)?;
 
-        let encrypted_db = EncryptedDb { kv, cipher };
+        let encrypted_db = EncryptedDb {
+            kv,
+            archive,
+            data_key,
+            cipher,
+            key_hasher,
+            kdf,
+            kek: kek.name(),
+            format_version,
+            legacy_data_key,
+        };
 
-        // verify that [password] is correct
+        // verify that [kek] is correct
         if encrypted_db.kv.was_recovered() {
-            // existing kv: can we decrypt the verification value?
+            // existing kv: can we find and decrypt the verification value? With a wrong kek,
+            // the record is looked up under another hashed key, so a missing record is a wrong kek too
             encrypted_db
-                .get(PASSWORD_VERIFICATION_KEY)
-                .map_err(|_| WrongPassword)?;
+                .verify_password()
+                .map_err(|_| Self::wrong_kek(kek))?;
         } else {
             // new kv: encrypt the verification value
             encrypted_db.insert(PASSWORD_VERIFICATION_KEY, PASSWORD_VERIFICATION_VALUE)?;
@@ -69,21 +235,94 @@ impl EncryptedDb {
         Ok(encrypted_db)
     }
 
-    fn chacha20poly1305_kdf(
-        password: Password,
-        salt: PasswordSalt,
-    ) -> EncryptedDbResult<chacha20poly1305::Key> {
-        let mut output = chacha20poly1305::Key::default();
-
-        // default params: log_n = 15, r = 8, p = 1
-        scrypt::scrypt(
-            password.as_ref(),
-            salt.as_ref(),
-            &scrypt::Params::default(),
-            output.as_mut_slice(),
+    /// Returns `true` if the records of the db are in an older record format, or if its data key
+    /// is derived from the password; see [EncryptedDb::upgrade].
+    pub fn needs_upgrade(&self) -> bool {
+        self.legacy_data_key || self.format_version < RECORD_FORMAT_VERSION
+    }
+
+    /// Returns `true` if the data key of the db is derived from the password instead of being wrapped by a kek.
+    pub fn has_legacy_data_key(&self) -> bool {
+        self.legacy_data_key
+    }
+
+    /// Re-encrypt all records of a db with an older record format, so that they are bound
+    /// to their keys and stored under hashed keys. Records are rewritten in a single transaction,
+    /// so a crash leaves the db in the older format and the upgrade can be repeated.
+    /// The password-derived data key of a legacy db is replaced by a new random data key that is
+    /// wrapped by `kek`, which must be the kek the db was opened with; see [EncryptedDb::rotate_data_key].
+    /// Records that cannot be decrypted are moved to the skipped tree and returned, so that
+    /// a single corrupted record does not make the whole db unusable.
+    pub fn upgrade(&mut self, kek: &Kek) -> EncryptedDbResult<Vec<SkippedRecord>> {
+        if self.legacy_data_key {
+            return self.rotate_data_key_with(kek, None);
+        }
+        if !self.needs_upgrade() {
+            return Ok(vec![]);
+        }
+        let skipped = self.rewrite_records(
+            &self.cipher,
+            &self.key_hasher,
+            RECORD_FORMAT_VERSION,
+            vec![],
         )?;
+        self.format_version = RECORD_FORMAT_VERSION;
+        Ok(skipped)
+    }
+
+    /// Derive a [XChaCha20Poly1305] cipher and a [KeyHasher] from `data_key`.
+    /// The hashing key is derived from the data key with a keyed hash.
+    fn derive_keys(
+        data_key: &chacha20poly1305::Key,
+    ) -> EncryptedDbResult<(XChaCha20Poly1305, KeyHasher)> {
+        let cipher = XChaCha20Poly1305::new(data_key);
 
-        Ok(output)
+        let mut mac = KeyHasher::new_from_slice(data_key).map_err(|e| Encryption(e.to_string()))?;
+        mac.update(KEY_HASHING_KEY_INFO);
+        let mut hashing_key = mac.finalize().into_bytes();
+        let key_hasher =
+            KeyHasher::new_from_slice(&hashing_key).map_err(|e| Encryption(e.to_string()))?;
+
+        // zeroize the hashing key since we are no longer using it after creating the hasher
+        hashing_key.zeroize();
+        Ok((cipher, key_hasher))
+    }
+
+    /// Wrap `data_key` with `kek`, deriving password keks from `salt` with `kdf`.
+    /// Returns the bytes of the [WrappedDataKey].
+    fn wrap_data_key(
+        kek: &Kek,
+        salt: [u8; 32],
+        kdf: &KdfParams,
+        data_key: &chacha20poly1305::Key,
+    ) -> EncryptedDbResult<Vec<u8>> {
+        WrappedDataKey {
+            kek: kek.name().to_string(),
+            wrapped: kek.wrapper(salt.into(), kdf)?.wrap(data_key)?,
+        }
+        .to_bytes()
+    }
+
+    /// The error of a wrong `kek`; wrong password keks are reported as [WrongPassword]
+    fn wrong_kek(kek: &Kek) -> super::result::EncryptedDbError {
+        match kek {
+            Kek::Password(_) => WrongPassword,
+            _ => WrongKek,
+        }
+    }
+
+    /// get a new random data key using [rand::thread_rng]
+    fn generate_data_key() -> chacha20poly1305::Key {
+        let mut data_key = chacha20poly1305::Key::default();
+        rand::thread_rng().fill_bytes(data_key.as_mut_slice());
+        data_key
+    }
+
+    /// get a new random password salt using [rand::thread_rng]
+    fn generate_salt() -> [u8; 32] {
+        let mut password_salt = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut password_salt);
+        password_salt
     }
 
     /// get a new random nonce to use for value encryption using [rand::thread_rng]
@@ -93,43 +332,130 @@ impl EncryptedDb {
         bytes
     }
 
-    /// create a new [EncryptedRecord] containing an encrypted value and a newly derived random nonce
-    fn encrypt<V>(&self, value: V) -> EncryptedDbResult<EncryptedRecord>
+    /// Returns the sled key under which the record of `key` is stored in `format_version`.
+    /// Records of formats that predate key hashing are stored under their plaintext key.
+    fn sled_key_with(key_hasher: &KeyHasher, format_version: u8, key: &[u8]) -> IVec {
+        if format_version < HASHED_KEYS_RECORD_FORMAT_VERSION {
+            return key.into();
+        }
+        let mut mac = key_hasher.clone();
+        mac.update(key);
+        mac.finalize().into_bytes().as_slice().into()
+    }
+
+    /// Returns the sled key under which the record of `key` is stored
+    pub(super) fn sled_key(&self, key: &[u8]) -> IVec {
+        Self::sled_key_with(&self.key_hasher, self.format_version, key)
+    }
+
+    /// Returns the associated data of the record stored at `sled_key`: the record format version
+    /// followed by `sled_key`. Records of the legacy format have no associated data.
+    fn associated_data(format_version: u8, sled_key: &[u8]) -> Vec<u8> {
+        match format_version {
+            LEGACY_RECORD_FORMAT_VERSION => vec![],
+            _ => [&[format_version][..], sled_key].concat(),
+        }
+    }
+
+    /// create a new [EncryptedRecord] for `key` containing an encrypted value and a newly derived random nonce.
+    /// Returns the sled key of the record along with the record.
+    fn encrypt<V>(&self, key: &[u8], value: V) -> EncryptedDbResult<(IVec, EncryptedRecord)>
+    where
+        V: Into<IVec>,
+    {
+        Self::encrypt_with(
+            &self.cipher,
+            &self.key_hasher,
+            self.format_version,
+            key,
+            value,
+        )
+    }
+
+    /// create a new [EncryptedRecord] using `cipher`, `key_hasher` and `format_version` instead of those of the db.
+    /// In formats with hashed keys, the encrypted plaintext is the length of `key` as u32 LE, `key` and `value`.
+    fn encrypt_with<V>(
+        cipher: &XChaCha20Poly1305,
+        key_hasher: &KeyHasher,
+        format_version: u8,
+        key: &[u8],
+        value: V,
+    ) -> EncryptedDbResult<(IVec, EncryptedRecord)>
     where
         V: Into<IVec>,
     {
         let nonce = Self::generate_nonce();
+        let sled_key = Self::sled_key_with(key_hasher, format_version, key);
 
-        let mut value = value.into().to_vec();
+        let value = value.into();
+        let mut plaintext = if format_version < HASHED_KEYS_RECORD_FORMAT_VERSION {
+            value.to_vec()
+        } else {
+            let key_len = u32::try_from(key.len()).map_err(|_| Serialization)?;
+            [&key_len.to_le_bytes()[..], key, &value].concat()
+        };
 
         // encrypt value
-        self.cipher
-            .encrypt_in_place(&nonce, b"", &mut value)
+        cipher
+            .encrypt_in_place(
+                &nonce,
+                &Self::associated_data(format_version, &sled_key),
+                &mut plaintext,
+            )
             .map_err(|e| Encryption(e.to_string()))?;
 
         // return record
-        Ok(EncryptedRecord::new(value, nonce))
+        Ok((sled_key, EncryptedRecord::new(plaintext, nonce)))
     }
 
-    /// derive a decrypted value from a [EncryptedRecord] containing an encrypted value and a random nonce
-    fn decrypt_record_value(&self, record: EncryptedRecord) -> EncryptedDbResult<IVec> {
-        let (mut value, nonce) = record.into();
+    /// derive the original key and the decrypted value from a [EncryptedRecord] stored at `sled_key`
+    fn decrypt_record(
+        &self,
+        sled_key: &[u8],
+        record: EncryptedRecord,
+    ) -> EncryptedDbResult<(IVec, IVec)> {
+        let (mut plaintext, nonce) = record.into();
 
         // decrypt value
         self.cipher
-            .decrypt_in_place(&nonce, b"", &mut value)
+            .decrypt_in_place(
+                &nonce,
+                &Self::associated_data(self.format_version, sled_key),
+                &mut plaintext,
+            )
             .map_err(|e| Decryption(e.to_string()))?;
 
-        // return decrypted value
-        Ok(value.into())
+        if self.format_version < HASHED_KEYS_RECORD_FORMAT_VERSION {
+            return Ok((sled_key.into(), plaintext.into()));
+        }
+
+        // split the plaintext into the original key and the value
+        if plaintext.len() < 4 {
+            return Err(Deserialization);
+        }
+        let (key_len, rest) = plaintext.split_at(4);
+        let key_len = u32::from_le_bytes(key_len.try_into().map_err(|_| Deserialization)?) as usize;
+        if rest.len() < key_len {
+            return Err(Deserialization);
+        }
+        let (key, value) = rest.split_at(key_len);
+        let res = (key.into(), value.into());
+        plaintext.zeroize();
+
+        // return original key and decrypted value
+        Ok(res)
     }
 
-    /// derive a decrypted value from [EncryptedRecord] bytes
-    fn decrypt(&self, record_bytes: Option<IVec>) -> EncryptedDbResult<Option<IVec>> {
+    /// derive a decrypted value from [EncryptedRecord] bytes stored at `sled_key`
+    fn decrypt(
+        &self,
+        sled_key: &[u8],
+        record_bytes: Option<IVec>,
+    ) -> EncryptedDbResult<Option<IVec>> {
         let res = match record_bytes {
             Some(record_bytes) => {
                 let record = EncryptedRecord::from_bytes(&record_bytes)?;
-                let decrypted_value_bytes = self.decrypt_record_value(record)?;
+                let (_, decrypted_value_bytes) = self.decrypt_record(sled_key, record)?;
                 Some(decrypted_value_bytes)
             }
             None => None,
@@ -143,9 +469,9 @@ impl EncryptedDb {
         K: AsRef<[u8]>,
         V: Into<IVec>,
     {
-        let record = self.encrypt(value)?;
-        let prev_record_bytes_opt = self.kv.insert(&key, record.to_bytes()?)?;
-        self.decrypt(prev_record_bytes_opt)
+        let (sled_key, record) = self.encrypt(key.as_ref(), value)?;
+        let prev_record_bytes_opt = self.kv.insert(&sled_key, record.to_bytes()?)?;
+        self.decrypt(&sled_key, prev_record_bytes_opt)
     }
 
     /// Retrieve and decrypt a value from the `Tree` if it exists.
@@ -153,8 +479,9 @@ impl EncryptedDb {
     where
         K: AsRef<[u8]>,
     {
-        let bytes_opt = self.kv.get(&key)?;
-        self.decrypt(bytes_opt)
+        let sled_key = self.sled_key(key.as_ref());
+        let bytes_opt = self.kv.get(&sled_key)?;
+        self.decrypt(&sled_key, bytes_opt)
     }
 
     /// Returns `true` if the `Tree` contains a value for the specified key.
@@ -162,7 +489,7 @@ impl EncryptedDb {
     where
         K: AsRef<[u8]>,
     {
-        Ok(self.kv.contains_key(&key)?)
+        Ok(self.kv.contains_key(self.sled_key(key.as_ref()))?)
     }
 
     /// Delete a value, decrypting and returning the old value if it existed.
@@ -170,8 +497,378 @@ impl EncryptedDb {
     where
         K: AsRef<[u8]>,
     {
-        let prev_val = self.kv.remove(&key)?;
-        self.decrypt(prev_val)
+        let sled_key = self.sled_key(key.as_ref());
+        let prev_val = self.kv.remove(&sled_key)?;
+        self.decrypt(&sled_key, prev_val)
+    }
+
+    /// Atomically remove `key` and store an encrypted `value` under `archive_key` in the archive tree.
+    /// Returns `false` and leaves both trees untouched if `key` does not exist.
+    pub fn archive<K, A, V>(&self, key: K, archive_key: A, value: V) -> EncryptedDbResult<bool>
+    where
+        K: AsRef<[u8]>,
+        A: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        // encrypt outside of the transaction because the closure may be retried
+        let sled_key = self.sled_key(key.as_ref());
+        let (archive_sled_key, record) = self.encrypt(archive_key.as_ref(), value)?;
+        let record_bytes = record.to_bytes()?;
+
+        let archived = (&*self.kv, &self.archive).transaction(
+            |(kv, archive)| -> ConflictableTransactionResult<bool, sled::Error> {
+                if kv.remove(&sled_key)?.is_none() {
+                    return Ok(false);
+                }
+                archive.insert(&archive_sled_key, record_bytes.clone())?;
+                Ok(true)
+            },
+        )?;
+
+        Ok(archived)
+    }
+
+    /// Retrieve and decrypt a value from the archive tree if it exists.
+    pub fn get_archived<K>(&self, archive_key: K) -> EncryptedDbResult<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let sled_key = self.sled_key(archive_key.as_ref());
+        let bytes_opt = self.archive.get(&sled_key)?;
+        self.decrypt(&sled_key, bytes_opt)
+    }
+
+    /// Atomically apply all writes of `batch` in a single transaction.
+    pub fn apply_batch(&self, batch: Batch) -> EncryptedDbResult<()> {
+        // encrypt outside of the transaction because the closure may be retried
+        let writes = batch
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => {
+                    let (sled_key, record) = self.encrypt(&key, value)?;
+                    Ok((sled_key, Some(record.to_bytes()?)))
+                }
+                None => Ok((self.sled_key(&key), None)),
+            })
+            .collect::<EncryptedDbResult<Vec<_>>>()?;
+
+        self.kv
+            .transaction(|kv| -> ConflictableTransactionResult<(), sled::Error> {
+                for (sled_key, record_bytes) in &writes {
+                    match record_bytes {
+                        Some(record_bytes) => kv.insert(sled_key, record_bytes.clone())?,
+                        None => kv.remove(sled_key)?,
+                    };
+                }
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+
+    /// Change the kek of the db. The data key is wrapped by `new_kek`; password keks are derived
+    /// from a new salt, with `new_kdf` if provided; otherwise, the current [KdfParams] are kept.
+    /// Records are not re-encrypted, since they are encrypted under the data key.
+    /// The wrapped data key, the salt and the kdf params are written in a single transaction, so that
+    /// a crash leaves the data key wrapped either by the old kek or by the new one.
+    pub fn change_kek(
+        &mut self,
+        new_kek: Kek,
+        new_kdf: Option<&KdfParams>,
+    ) -> EncryptedDbResult<()> {
+        let new_kdf = new_kdf.unwrap_or(&self.kdf).clone();
+        let new_kdf_bytes = IVec::from(new_kdf.to_bytes()?);
+        let new_salt = Self::generate_salt();
+        let wrapped = IVec::from(Self::wrap_data_key(
+            &new_kek,
+            new_salt,
+            &new_kdf,
+            &self.data_key,
+        )?);
+        let new_salt = IVec::from(&new_salt);
+
+        self.kv
+            .transaction(|kv| -> ConflictableTransactionResult<(), sled::Error> {
+                kv.insert(PASSWORD_SALT_KEY, new_salt.clone())?;
+                kv.insert(PASSWORD_KDF_PARAMS_KEY, new_kdf_bytes.clone())?;
+                kv.insert(DATA_KEY_KEY, wrapped.clone())?;
+                Ok(())
+            })?;
+        self.kv.flush()?;
+
+        self.kdf = new_kdf;
+        self.kek = new_kek.name();
+        Ok(())
+    }
+
+    /// Change the kek of the db like [EncryptedDb::change_kek], and replace the data key by a new
+    /// random data key. All records of both trees are re-encrypted under the new data key in the
+    /// current record format, and written along with the new wrapped data key, salt and kdf params
+    /// in a single transaction, so that a crash leaves the db either unchanged or fully rotated.
+    /// Copies of the db, e.g. in old backups of the disk, are not affected.
+    /// Records that cannot be decrypted are moved to the skipped tree and returned.
+    pub fn rotate_data_key(
+        &mut self,
+        new_kek: Kek,
+        new_kdf: Option<&KdfParams>,
+    ) -> EncryptedDbResult<Vec<SkippedRecord>> {
+        self.rotate_data_key_with(&new_kek, new_kdf)
+    }
+
+    /// Same as [EncryptedDb::rotate_data_key], but borrows `new_kek`.
+    fn rotate_data_key_with(
+        &mut self,
+        new_kek: &Kek,
+        new_kdf: Option<&KdfParams>,
+    ) -> EncryptedDbResult<Vec<SkippedRecord>> {
+        let new_kdf = new_kdf.unwrap_or(&self.kdf).clone();
+        let mut new_data_key = Self::generate_data_key();
+
+        let rotated = self.reencrypt_all(new_kek, &new_kdf, &new_data_key);
+        let (new_cipher, new_key_hasher, skipped) = match rotated {
+            Ok(rotated) => rotated,
+            Err(err) => {
+                new_data_key.zeroize();
+                return Err(err);
+            }
+        };
+
+        self.data_key.zeroize();
+        self.data_key = new_data_key;
+        self.cipher = new_cipher;
+        self.key_hasher = new_key_hasher;
+        self.format_version = RECORD_FORMAT_VERSION;
+        self.kdf = new_kdf;
+        self.kek = new_kek.name();
+        self.legacy_data_key = false;
+        Ok(skipped)
+    }
+
+    /// Re-encrypt all records under `new_data_key` and store it wrapped by `new_kek`;
+    /// see [EncryptedDb::rotate_data_key]. Returns the cipher and key hasher of `new_data_key`,
+    /// and the records that could not be decrypted.
+    fn reencrypt_all(
+        &self,
+        new_kek: &Kek,
+        new_kdf: &KdfParams,
+        new_data_key: &chacha20poly1305::Key,
+    ) -> EncryptedDbResult<(XChaCha20Poly1305, KeyHasher, Vec<SkippedRecord>)> {
+        let (new_cipher, new_key_hasher) = Self::derive_keys(new_data_key)?;
+        let new_salt = Self::generate_salt();
+        let wrapped = Self::wrap_data_key(new_kek, new_salt, new_kdf, new_data_key)?;
+        let skipped = self.rewrite_records(
+            &new_cipher,
+            &new_key_hasher,
+            RECORD_FORMAT_VERSION,
+            vec![
+                (PASSWORD_SALT_KEY, IVec::from(&new_salt)),
+                (PASSWORD_KDF_PARAMS_KEY, IVec::from(new_kdf.to_bytes()?)),
+                (DATA_KEY_KEY, IVec::from(wrapped)),
+            ],
+        )?;
+        Ok((new_cipher, new_key_hasher, skipped))
+    }
+
+    /// Change the kek of the db to a password kek of `new_password`; see [EncryptedDb::change_kek].
+    pub fn change_password(
+        &mut self,
+        new_password: Password,
+        new_kdf: Option<&KdfParams>,
+    ) -> EncryptedDbResult<()> {
+        self.change_kek(Kek::Password(new_password), new_kdf)
+    }
+
+    /// Re-encrypt all records of both trees with `new_cipher` and `new_key_hasher` in `new_format_version`,
+    /// and write them along with the unencrypted `internal_records` and the record format version
+    /// in a single transaction. Records whose sled key changes are removed from their old sled key.
+    /// Records that cannot be decrypted are moved to the skipped tree and returned.
+    fn rewrite_records(
+        &self,
+        new_cipher: &XChaCha20Poly1305,
+        new_key_hasher: &KeyHasher,
+        new_format_version: u8,
+        internal_records: Vec<(&'static [u8], IVec)>,
+    ) -> EncryptedDbResult<Vec<SkippedRecord>> {
+        // re-encrypt outside of the transaction because the closure may be retried
+        let mut skipped = vec![];
+        let kv_records = self.reencrypt_tree(
+            &self.kv,
+            false,
+            new_cipher,
+            new_key_hasher,
+            new_format_version,
+            &mut skipped,
+        )?;
+        let archive_records = self.reencrypt_tree(
+            &self.archive,
+            true,
+            new_cipher,
+            new_key_hasher,
+            new_format_version,
+            &mut skipped,
+        )?;
+        let skipped_tree = self.kv.open_tree(SKIPPED_TREE_NAME)?;
+
+        (&*self.kv, &self.archive, &skipped_tree).transaction(
+            |(kv, archive, skipped_tree)| -> ConflictableTransactionResult<(), sled::Error> {
+                for (record, record_bytes) in &skipped {
+                    match record.archived {
+                        true => archive.remove(&record.sled_key)?,
+                        false => kv.remove(&record.sled_key)?,
+                    };
+                    skipped_tree.insert(Self::skipped_key(record), record_bytes.clone())?;
+                }
+                for (old_sled_key, _, _) in &kv_records {
+                    kv.remove(old_sled_key)?;
+                }
+                for (_, sled_key, record_bytes) in &kv_records {
+                    kv.insert(sled_key, record_bytes.clone())?;
+                }
+                for (old_sled_key, _, _) in &archive_records {
+                    archive.remove(old_sled_key)?;
+                }
+                for (_, sled_key, record_bytes) in &archive_records {
+                    archive.insert(sled_key, record_bytes.clone())?;
+                }
+                for (key, value) in &internal_records {
+                    kv.insert(*key, value.clone())?;
+                }
+                kv.insert(RECORD_FORMAT_VERSION_KEY, &[new_format_version])?;
+                Ok(())
+            },
+        )?;
+        self.kv.flush()?;
+
+        Ok(skipped.into_iter().map(|(record, _)| record).collect())
+    }
+
+    /// Decrypt all records of `tree` and encrypt them again with `new_cipher` and `new_key_hasher`
+    /// in `new_format_version`. Returns the old sled key, the new sled key and the new record bytes.
+    /// Records that cannot be decrypted are added to `skipped` along with their bytes instead;
+    /// errors of sled are returned.
+    fn reencrypt_tree(
+        &self,
+        tree: &sled::Tree,
+        archived: bool,
+        new_cipher: &XChaCha20Poly1305,
+        new_key_hasher: &KeyHasher,
+        new_format_version: u8,
+        skipped: &mut Vec<(SkippedRecord, IVec)>,
+    ) -> EncryptedDbResult<Vec<(IVec, IVec, IVec)>> {
+        let mut records = vec![];
+        for res in tree.iter() {
+            let (old_sled_key, record_bytes) = res?;
+            if Self::is_unencrypted_key(&old_sled_key) {
+                continue;
+            }
+            let decrypted = EncryptedRecord::from_bytes(&record_bytes)
+                .and_then(|record| self.decrypt_record(&old_sled_key, record));
+            let (key, value) = match decrypted {
+                Ok(decrypted) => decrypted,
+                Err(reason) => {
+                    let record = SkippedRecord {
+                        sled_key: old_sled_key,
+                        archived,
+                        reason,
+                    };
+                    skipped.push((record, record_bytes));
+                    continue;
+                }
+            };
+            let (sled_key, record) =
+                Self::encrypt_with(new_cipher, new_key_hasher, new_format_version, &key, value)?;
+            records.push((old_sled_key, sled_key, record.to_bytes()?.into()));
+        }
+        Ok(records)
+    }
+
+    /// Returns the key of `record` in the skipped tree: its sled key, prefixed by the tree it was in.
+    fn skipped_key(record: &SkippedRecord) -> Vec<u8> {
+        let tree: &[u8] = if record.archived {
+            ARCHIVE_TREE_NAME.as_bytes()
+        } else {
+            b"kv"
+        };
+        [tree, b"/", &record.sled_key].concat()
+    }
+
+    /// Iterate over all user records, decrypting their original keys and values.
+    /// Records are returned in the order of their sled keys, which is not the order of
+    /// their original keys. Internal records used for password verification are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = EncryptedDbResult<(IVec, IVec)>> + '_ {
+        self.kv
+            .iter()
+            .filter(|res| match res {
+                Ok((sled_key, _)) => !Self::is_unencrypted_key(sled_key),
+                Err(_) => true,
+            })
+            .map(move |res| {
+                let (sled_key, record_bytes) = res?;
+                let record = EncryptedRecord::from_bytes(&record_bytes)?;
+                self.decrypt_record(&sled_key, record)
+            })
+            .filter(|res| match res {
+                Ok((key, _)) => key != PASSWORD_VERIFICATION_KEY.as_bytes(),
+                Err(_) => true,
+            })
+    }
+
+    /// Iterate over all records of the kv tree, or of the archive tree if `archived` is set,
+    /// without stopping at records that cannot be decrypted. Internal records are skipped.
+    /// Only errors of sled are returned as errors.
+    pub fn check_records(
+        &self,
+        archived: bool,
+    ) -> impl Iterator<Item = EncryptedDbResult<CheckedRecord>> + '_ {
+        let tree: &sled::Tree = if archived { &self.archive } else { &self.kv };
+        tree.iter()
+            .filter(|res| match res {
+                Ok((sled_key, _)) => !Self::is_unencrypted_key(sled_key),
+                Err(_) => true,
+            })
+            .map(move |res| {
+                let (sled_key, record_bytes) = res?;
+                let record = EncryptedRecord::from_bytes(&record_bytes)
+                    .and_then(|record| self.decrypt_record(&sled_key, record));
+                Ok((sled_key, record))
+            })
+            .filter(|res| match res {
+                Ok((_, Ok((key, _)))) => key != PASSWORD_VERIFICATION_KEY.as_bytes(),
+                _ => true,
+            })
+    }
+
+    /// Verify that the password verification record holds the expected value.
+    /// Returns [WrongPassword] if it cannot be decrypted, and [CorruptedPasswordVerification]
+    /// if it is missing or holds another value.
+    pub fn verify_password(&self) -> EncryptedDbResult<()> {
+        let value = self
+            .get(PASSWORD_VERIFICATION_KEY)
+            .map_err(|_| WrongPassword)?;
+        match value {
+            Some(value) if value == PASSWORD_VERIFICATION_VALUE.as_bytes() => Ok(()),
+            _ => Err(CorruptedPasswordVerification),
+        }
+    }
+
+    /// Returns `true` if `sled_key` holds an unencrypted internal value.
+    /// Unencrypted internal values are always stored under their plaintext key.
+    fn is_unencrypted_key(sled_key: &IVec) -> bool {
+        sled_key == PASSWORD_SALT_KEY
+            || sled_key == PASSWORD_KDF_PARAMS_KEY
+            || sled_key == DATA_KEY_KEY
+            || sled_key == RECORD_FORMAT_VERSION_KEY
+    }
+
+    /// Returns the [KdfParams] used to derive password keks of the db.
+    pub fn kdf(&self) -> &KdfParams {
+        &self.kdf
+    }
+
+    /// Returns the name of the kind of kek that wraps the data key of the db.
+    pub fn kek(&self) -> &'static str {
+        self.kek
     }
 
     /// Returns true if the database was recovered from a previous process.
@@ -179,8 +876,45 @@ impl EncryptedDb {
         self.kv.was_recovered()
     }
 
-    #[cfg(test)]
+    /// Synchronously flushes all dirty records to disk. Returns the number of bytes flushed.
     pub fn flush(&self) -> EncryptedDbResult<usize> {
         Ok(self.kv.flush()?)
     }
+
+    /// Rewrite all records in an older record format to test migrations.
+    #[cfg(test)]
+    pub(crate) fn downgrade_to_format(&mut self, format_version: u8) -> EncryptedDbResult<()> {
+        self.rewrite_records(&self.cipher, &self.key_hasher, format_version, vec![])?;
+        if format_version == LEGACY_RECORD_FORMAT_VERSION {
+            self.kv.remove(RECORD_FORMAT_VERSION_KEY)?;
+            self.kv.flush()?;
+        }
+        self.format_version = format_version;
+        Ok(())
+    }
+
+    /// Re-encrypt all records under the key derived from `password` and a new salt, and remove
+    /// the wrapped data key, as in dbs created before data keys were wrapped.
+    #[cfg(test)]
+    pub(crate) fn downgrade_to_password_key(
+        &mut self,
+        password: Password,
+    ) -> EncryptedDbResult<()> {
+        let salt = Self::generate_salt();
+        let data_key = self.kdf.derive_key(password, salt.into())?;
+        let (cipher, key_hasher) = Self::derive_keys(&data_key)?;
+        self.rewrite_records(
+            &cipher,
+            &key_hasher,
+            self.format_version,
+            vec![(PASSWORD_SALT_KEY, IVec::from(&salt))],
+        )?;
+        self.kv.remove(DATA_KEY_KEY)?;
+        self.kv.flush()?;
+        self.data_key = data_key;
+        self.cipher = cipher;
+        self.key_hasher = key_hasher;
+        self.legacy_data_key = true;
+        Ok(())
+    }
 }
@@ -1,15 +1,20 @@
 //! Wrap a layer of encryption around [sled]. We use [chacha20poly1305] to encrypt/decrypt values.
 //! Specifically, use [chacha20poly1305::XChaCha20] because the nonces are generated randomly.
-//! To create an new [Db], an [Entropy] needs to be provided.
+//! To create an new [Db], a [Kek] needs to be provided, which wraps the random data key of the db.
 
 mod constants;
+mod kdf;
+mod kek;
 mod kv;
 mod password;
 mod record;
 mod result;
 
 // match the API of sled
-pub use kv::EncryptedDb as Db;
+pub use kdf::KdfParams;
+pub(crate) use kek::to_hex;
+pub use kek::{Kek, KekMethod};
+pub use kv::{Batch, EncryptedDb as Db, SkippedRecord};
 pub use password::{Password, PasswordMethod, PasswordSalt};
 pub use result::EncryptedDbError as Error;
 pub use result::EncryptedDbResult as Result;
@@ -17,5 +22,7 @@ pub use result::EncryptedDbResult as Result;
 #[cfg(test)]
 mod tests;
 
+#[cfg(test)]
+pub(crate) use constants::LEGACY_RECORD_FORMAT_VERSION;
 #[cfg(test)]
 pub use tests::get_test_password;
@@ -1,10 +1,22 @@
 //! Handles the generation of an [Entropy] from user's password using [scrypt] pbkdf.
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    fs::File,
+    io::Read,
+    mem::ManuallyDrop,
+    os::unix::io::{FromRawFd, RawFd},
+    path::PathBuf,
+    str::FromStr,
+};
 
-use super::{constants::UNSAFE_PASSWORD, result::EncryptedDbResult};
+use super::{
+    constants::{MAX_FD_PASSWORD_LEN, UNSAFE_PASSWORD},
+    result::{EncryptedDbError, EncryptedDbResult},
+};
 
 use sled::IVec;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
 /// Safely store strings
 // TODO use https://docs.rs/secrecy ?
@@ -47,20 +59,143 @@ use rpassword::read_password;
 pub enum PasswordMethod {
     NoPassword,
     Prompt,
+    /// read the content of a file. A trailing newline is ignored.
+    File(PathBuf),
+    /// read an environment variable. The variable is removed from the environment
+    /// so that it is not inherited by child processes.
+    EnvVar(String),
+    /// read the first line from an open file descriptor, e.g. a pipe
+    Fd(RawFd),
 }
 impl PasswordMethod {
     /// Execute the password method to retrieve a password
     pub fn execute(&self) -> EncryptedDbResult<Password> {
+        self.execute_with_prompt("Please type your tofnd password:")
+    }
+
+    /// Execute the password method to retrieve a password. `prompt` is printed before
+    /// reading from [PasswordMethod::Prompt].
+    pub fn execute_with_prompt(&self, prompt: &str) -> EncryptedDbResult<Password> {
         Ok(match self {
             Self::NoPassword => Password(UNSAFE_PASSWORD.to_string()),
             Self::Prompt => {
-                println!("Please type your tofnd password:");
+                println!("{}", prompt);
                 Password(read_password()?)
             }
+            Self::File(path) => {
+                let mut bytes = std::fs::read(path)?;
+                trim_newline(&mut bytes);
+                password_from_bytes(bytes)?
+            }
+            Self::EnvVar(name) => {
+                let value = std::env::var(name);
+                std::env::remove_var(name);
+                match value {
+                    Ok(value) => Password(value),
+                    Err(std::env::VarError::NotPresent) => {
+                        return Err(EncryptedDbError::MissingPasswordEnvVar(name.clone()))
+                    }
+                    Err(std::env::VarError::NotUnicode(_)) => {
+                        return Err(EncryptedDbError::MalformedPassword)
+                    }
+                }
+            }
+            Self::Fd(fd) => password_from_bytes(read_line_from_fd(*fd)?)?,
+        })
+    }
+}
+
+/// Parses `prompt`, `file:<path>`, `env:<variable>` or `fd:<number>`.
+/// Skipping the password is only possible with `--no-password`.
+impl FromStr for PasswordMethod {
+    type Err = EncryptedDbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EncryptedDbError::InvalidPasswordMethod(s.to_string());
+        if s == "prompt" {
+            return Ok(Self::Prompt);
+        }
+        let mut parts = s.splitn(2, ':');
+        let (method, value) = match (parts.next(), parts.next()) {
+            (Some(method), Some(value)) => (method, value),
+            _ => return Err(invalid()),
+        };
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        Ok(match method {
+            "file" => Self::File(PathBuf::from(value)),
+            "env" => Self::EnvVar(value.to_string()),
+            "fd" => Self::Fd(value.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
         })
     }
 }
 
+impl fmt::Display for PasswordMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoPassword => write!(f, "none"),
+            Self::Prompt => write!(f, "prompt"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::EnvVar(name) => write!(f, "env:{}", name),
+            Self::Fd(fd) => write!(f, "fd:{}", fd),
+        }
+    }
+}
+
+/// Zeroize and remove a trailing `\n` or `\r\n`
+pub(super) fn trim_newline(bytes: &mut Vec<u8>) {
+    for end in &[b'\n', b'\r'] {
+        if bytes.last() == Some(end) {
+            let len = bytes.len() - 1;
+            bytes[len] = 0;
+            bytes.truncate(len);
+        }
+    }
+}
+
+/// Convert `bytes` into a [Password] without copying; `bytes` are zeroized on failure
+fn password_from_bytes(bytes: Vec<u8>) -> EncryptedDbResult<Password> {
+    String::from_utf8(bytes).map(Password).map_err(|err| {
+        err.into_bytes().zeroize();
+        EncryptedDbError::MalformedPassword
+    })
+}
+
+/// Read from `fd` one byte at a time until a newline, so that nothing after the
+/// password is consumed. Standard streams are left open; other descriptors are closed.
+/// Passwords longer than [MAX_FD_PASSWORD_LEN] are rejected.
+fn read_line_from_fd(fd: RawFd) -> EncryptedDbResult<Vec<u8>> {
+    // safety: the caller of tofnd hands over `fd` for reading the password
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+
+    // the buffer is never reallocated, so no copies of the password are left behind
+    let mut bytes = Zeroizing::new(Vec::with_capacity(MAX_FD_PASSWORD_LEN));
+    let mut byte = Zeroizing::new([0u8; 1]);
+    let res = loop {
+        match file.read(&mut *byte) {
+            Ok(0) => break Ok(()),
+            Ok(_) if byte[0] == b'\n' => break Ok(()),
+            Ok(_) if bytes.len() == MAX_FD_PASSWORD_LEN => {
+                break Err(EncryptedDbError::PasswordTooLong(MAX_FD_PASSWORD_LEN))
+            }
+            Ok(_) => bytes.push(byte[0]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err.into()),
+        }
+    };
+
+    if fd > 2 {
+        drop(ManuallyDrop::into_inner(file));
+    }
+
+    res?;
+    trim_newline(&mut bytes);
+    // move the buffer out without copying it
+    Ok(std::mem::take(&mut *bytes))
+}
+
 #[cfg(test)]
 impl From<&str> for Password {
     fn from(value: &str) -> Self {
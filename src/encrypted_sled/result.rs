@@ -6,12 +6,34 @@ pub enum EncryptedDbError {
     CorruptedKv(sled::Error),
     #[error("Password read error: {0}")]
     PasswordRead(#[from] std::io::Error), // rpassword::read_password() Error
+    #[error("Password environment variable {0} is not set")]
+    MissingPasswordEnvVar(String),
+    #[error("Malformed password: password is not valid UTF-8")]
+    MalformedPassword,
+    #[error("Password is longer than {0} bytes")]
+    PasswordTooLong(usize),
+    #[error("Invalid password method [{0}]: expected prompt, file:<path>, env:<variable> or fd:<number>")]
+    InvalidPasswordMethod(String),
+    #[error("Invalid kek method [{0}]: expected password, key-file:<path> or command:<path>")]
+    InvalidKekMethod(String),
+    #[error("Key file error: {0}")]
+    KeyFile(String),
+    #[error("Kek command error: {0}")]
+    KekCommand(String),
     #[error("Password scrypt params error: {0}")]
     PasswordScryptParams(#[from] scrypt::errors::InvalidParams),
     #[error("Password scrypt error: {0}")]
     PasswordScryptError(#[from] scrypt::errors::InvalidOutputLen),
+    #[error("Password argon2 error: {0}")]
+    PasswordArgon2Error(String),
+    #[error("Invalid kdf params [{0}]: expected scrypt[:<log_n>:<r>:<p>] or argon2id[:<m_cost>:<t_cost>:<p_cost>]")]
+    InvalidKdfParams(String),
+    #[error("Malformed kdf params: failed to deserialize the stored kdf params")]
+    MalformedKdfParams,
     #[error("Sled error: {0}")]
     SledError(#[from] sled::Error),
+    #[error("Sled transaction error: {0}")]
+    SledTransactionError(#[from] sled::transaction::TransactionError),
     #[error("Serialization error: failed to serialize the encrypted record")]
     Serialization,
     #[error("Deserialization error: failed to deserialize encrypted record bytes")]
@@ -20,8 +42,16 @@ pub enum EncryptedDbError {
     Encryption(String),
     #[error("ChaCha20 decryption error: {0}")]
     Decryption(String),
+    #[error("Unsupported record format version {0}: the db was written by a newer tofnd")]
+    UnsupportedRecordFormat(u8),
     #[error("Wrong password")]
     WrongPassword,
+    #[error("Wrong kek: the data key cannot be unwrapped")]
+    WrongKek,
+    #[error("The data key of the db is wrapped by a {stored} kek, not by a {given} kek")]
+    KekMismatch { stored: String, given: String },
+    #[error("Password verification record is missing or corrupted")]
+    CorruptedPasswordVerification,
     #[error("Missing password salt")]
     MissingPasswordSalt,
     #[error("Malformed password salt: {0}")]
@@ -1,4 +1,11 @@
-use super::{kv::EncryptedDb, Password};
+use super::{
+    constants::{
+        ARCHIVE_TREE_NAME, DATA_KEY_KEY, LEGACY_RECORD_FORMAT_VERSION, PASSWORD_KDF_PARAMS_KEY,
+        PASSWORD_SALT_KEY, RECORD_FORMAT_VERSION, RECORD_FORMAT_VERSION_KEY, SKIPPED_TREE_NAME,
+    },
+    kv::{Batch, EncryptedDb},
+    KdfParams, Kek, KekMethod, Password, PasswordMethod,
+};
 use testdir::testdir;
 
 #[test]
@@ -85,6 +92,721 @@ fn test_large_input() {
     assert_eq!(res, Some(sled::IVec::from(large_value)));
 }
 
+#[test]
+fn test_iter() {
+    let db_path = testdir!("iter");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+
+    db.insert("key1", "value1").unwrap();
+    db.insert("key2", "value2").unwrap();
+
+    // iter returns decrypted keys and values and skips internal password records
+    let mut res = db.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    res.sort();
+    assert_eq!(
+        res,
+        vec![
+            (sled::IVec::from("key1"), sled::IVec::from("value1")),
+            (sled::IVec::from("key2"), sled::IVec::from("value2")),
+        ]
+    );
+}
+
+#[test]
+fn test_archive() {
+    let db_path = testdir!("archive");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+
+    db.insert("key", "value").unwrap();
+
+    // archive <key> -> removes <key> and stores the archived value
+    let res = db.archive("key", "archive_key", "archived value").unwrap();
+    assert!(res);
+    assert!(!db.contains_key("key").unwrap());
+    let res = db.get_archived("archive_key").unwrap();
+    assert_eq!(res, Some(sled::IVec::from("archived value")));
+
+    // archived values are not visible to iter
+    assert_eq!(db.iter().count(), 0);
+
+    // archive <key> again -> returns false because key does not exist
+    let res = db.archive("key", "archive_key2", "archived value").unwrap();
+    assert!(!res);
+    assert!(db.get_archived("archive_key2").unwrap().is_none());
+
+    // archived values persist across reopening the db
+    drop(db);
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    let res = db.get_archived("archive_key").unwrap();
+    assert_eq!(res, Some(sled::IVec::from("archived value")));
+}
+
+#[test]
+fn test_apply_batch() {
+    let db_path = testdir!("apply_batch");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+
+    db.insert("removed", "value").unwrap();
+    db.insert("overwritten", "value").unwrap();
+
+    let mut batch = Batch::default();
+    batch.insert("new", "new value");
+    batch.insert("overwritten", "new value");
+    batch.remove("removed");
+    db.apply_batch(batch).unwrap();
+
+    let mut res = db.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    res.sort();
+    assert_eq!(
+        res,
+        vec![
+            (sled::IVec::from("new"), sled::IVec::from("new value")),
+            (
+                sled::IVec::from("overwritten"),
+                sled::IVec::from("new value")
+            ),
+        ]
+    );
+
+    // batch writes persist across reopening the db
+    drop(db);
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.get("new").unwrap(), Some(sled::IVec::from("new value")));
+}
+
+#[test]
+fn test_change_password() {
+    let db_path = testdir!("change_password");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    db.insert("archived", "value").unwrap();
+    db.archive("archived", "archive_key", "archived value")
+        .unwrap();
+
+    db.change_password(Password::from("new password"), None)
+        .unwrap();
+
+    // the open db keeps working with the new cipher
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    drop(db);
+
+    // the old password is rejected
+    assert!(matches!(
+        EncryptedDb::open(&db_path, get_test_password()),
+        Err(super::Error::WrongPassword)
+    ));
+
+    // all records are available with the new password
+    let db = EncryptedDb::open(&db_path, Password::from("new password")).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert_eq!(
+        db.get_archived("archive_key").unwrap(),
+        Some(sled::IVec::from("archived value"))
+    );
+    assert_eq!(db.iter().count(), 1);
+}
+
+#[test]
+fn test_swapped_record() {
+    let db_path = testdir!("swapped_record");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key_uid_1", "share 1").unwrap();
+    db.insert("key_uid_2", "share 2").unwrap();
+    let sled_key_1 = db.sled_key(b"key_uid_1");
+    let sled_key_2 = db.sled_key(b"key_uid_2");
+    drop(db);
+
+    // swap the encrypted records of the two keys on disk
+    let kv = sled::open(&db_path).unwrap();
+    let record_1 = kv.get(&sled_key_1).unwrap().unwrap();
+    let record_2 = kv.get(&sled_key_2).unwrap().unwrap();
+    kv.insert(&sled_key_1, record_2).unwrap();
+    kv.insert(&sled_key_2, record_1).unwrap();
+    drop(kv);
+
+    // the swapped records fail to decrypt
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert!(matches!(
+        db.get("key_uid_1"),
+        Err(super::Error::Decryption(_))
+    ));
+    assert!(matches!(
+        db.get("key_uid_2"),
+        Err(super::Error::Decryption(_))
+    ));
+}
+
+#[test]
+fn test_check_records() {
+    let db_path = testdir!("check_records");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key_uid_1", "share 1").unwrap();
+    db.insert("key_uid_2", "share 2").unwrap();
+    db.insert("key_uid_3", "share 3").unwrap();
+    assert!(db.archive("key_uid_3", "key_uid_3/1", "share 3").unwrap());
+    let sled_key_2 = db.sled_key(b"key_uid_2");
+    drop(db);
+
+    // corrupt the encrypted record of the second key on disk
+    let kv = sled::open(&db_path).unwrap();
+    let mut record = kv.get(&sled_key_2).unwrap().unwrap().to_vec();
+    let last = record.len() - 1;
+    record[last] ^= 1;
+    kv.insert(&sled_key_2, record).unwrap();
+    drop(kv);
+
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.verify_password().unwrap();
+
+    // all records are returned, and the corrupted one is reported with its sled key
+    let records = db
+        .check_records(false)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(records.len(), 2);
+    for (sled_key, record) in records {
+        if sled_key == sled_key_2 {
+            assert!(record.is_err());
+        } else {
+            assert_eq!(
+                record.unwrap(),
+                (sled::IVec::from("key_uid_1"), sled::IVec::from("share 1"))
+            );
+        }
+    }
+
+    let archived = db
+        .check_records(true)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(archived.len(), 1);
+    assert_eq!(
+        archived[0].1.as_ref().unwrap(),
+        &(sled::IVec::from("key_uid_3/1"), sled::IVec::from("share 3"))
+    );
+}
+
+#[test]
+fn test_migrate_legacy_format() {
+    let db_path = testdir!("migrate_legacy_format");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    db.insert("archived", "value").unwrap();
+    db.archive("archived", "archive_key", "archived value")
+        .unwrap();
+    db.downgrade_to_format(LEGACY_RECORD_FORMAT_VERSION)
+        .unwrap();
+    drop(db);
+
+    // legacy records are not bound to their keys
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("key").unwrap().is_some());
+    assert!(kv.get(RECORD_FORMAT_VERSION_KEY).unwrap().is_none());
+    drop(kv);
+
+    // opening a legacy db reads its records in the legacy format without rewriting them
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert!(db.needs_upgrade());
+    drop(db);
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("key").unwrap().is_some());
+    drop(kv);
+
+    // upgrading a legacy db migrates all records
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert!(db.upgrade(&get_test_password().into()).unwrap().is_empty());
+    assert!(!db.needs_upgrade());
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert_eq!(
+        db.get_archived("archive_key").unwrap(),
+        Some(sled::IVec::from("archived value"))
+    );
+    drop(db);
+
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("key").unwrap().is_none());
+    assert_eq!(
+        kv.get(RECORD_FORMAT_VERSION_KEY).unwrap(),
+        Some(sled::IVec::from(&[RECORD_FORMAT_VERSION]))
+    );
+    drop(kv);
+
+    // migrated dbs keep opening
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert_eq!(db.iter().count(), 1);
+}
+
+#[test]
+fn test_upgrade_skips_corrupted_record() {
+    let db_path = testdir!("upgrade_skips_corrupted_record");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    db.insert("corrupted", "value").unwrap();
+    db.downgrade_to_format(LEGACY_RECORD_FORMAT_VERSION)
+        .unwrap();
+    drop(db);
+
+    // legacy records are stored under their plaintext keys
+    let kv = sled::open(&db_path).unwrap();
+    kv.insert("corrupted", &b"not a record"[..]).unwrap();
+    kv.flush().unwrap();
+    drop(kv);
+
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    let skipped = db.upgrade(&get_test_password().into()).unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].sled_key, sled::IVec::from("corrupted"));
+    assert!(!skipped[0].archived);
+
+    // the other records are migrated, and the corrupted one no longer breaks iteration
+    assert!(!db.needs_upgrade());
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    let records = db.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(records.len(), 1);
+    drop(db);
+
+    // the corrupted record is kept aside as it was
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("corrupted").unwrap().is_none());
+    let skipped_tree = kv.open_tree(SKIPPED_TREE_NAME).unwrap();
+    assert_eq!(
+        skipped_tree.get("kv/corrupted").unwrap(),
+        Some(sled::IVec::from("not a record"))
+    );
+}
+
+#[test]
+fn test_hidden_keys() {
+    let db_path = testdir!("hidden_keys");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key_uid", "share").unwrap();
+    db.insert("archived_key_uid", "share").unwrap();
+    db.archive("archived_key_uid", "archived_key_uid/1", "archived share")
+        .unwrap();
+    db.change_password(Password::from("new password"), None)
+        .unwrap();
+    drop(db);
+
+    // no key is stored in plaintext, neither in the kv nor in the archive tree
+    let kv = sled::open(&db_path).unwrap();
+    let archive = kv.open_tree(ARCHIVE_TREE_NAME).unwrap();
+    let contains_plaintext = |tree: &sled::Tree, s: &[u8]| {
+        tree.iter().any(|res| {
+            let (key, value) = res.unwrap();
+            key.windows(s.len()).any(|w| w == s) || value.windows(s.len()).any(|w| w == s)
+        })
+    };
+    for &s in [&b"key_uid"[..], &b"verification_key"[..]].iter() {
+        assert!(!contains_plaintext(&kv, s));
+        assert!(!contains_plaintext(&archive, s));
+    }
+    drop(archive);
+    drop(kv);
+
+    // keys keep working transparently
+    let db = EncryptedDb::open(&db_path, Password::from("new password")).unwrap();
+    assert!(db.contains_key("key_uid").unwrap());
+    assert_eq!(db.get("key_uid").unwrap(), Some(sled::IVec::from("share")));
+    assert_eq!(
+        db.get_archived("archived_key_uid/1").unwrap(),
+        Some(sled::IVec::from("archived share"))
+    );
+    let res = db.iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        res,
+        vec![(sled::IVec::from("key_uid"), sled::IVec::from("share"))]
+    );
+    assert_eq!(
+        db.remove("key_uid").unwrap(),
+        Some(sled::IVec::from("share"))
+    );
+    assert!(!db.contains_key("key_uid").unwrap());
+}
+
+#[test]
+fn test_migrate_plaintext_keys() {
+    let db_path = testdir!("migrate_plaintext_keys");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key_uid", "share").unwrap();
+    db.insert("archived_key_uid", "share").unwrap();
+    db.archive("archived_key_uid", "archived_key_uid/1", "archived share")
+        .unwrap();
+    // format 1 binds records to their plaintext keys
+    db.downgrade_to_format(1).unwrap();
+    drop(db);
+
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("key_uid").unwrap().is_some());
+    drop(kv);
+
+    // upgrading the db moves all records to hashed keys
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert!(db.upgrade(&get_test_password().into()).unwrap().is_empty());
+    assert_eq(db.get("key_uid").unwrap(), Some(sled::IVec::from("share")));
+    assert_eq!(
+        db.get_archived("archived_key_uid/1").unwrap(),
+        Some(sled::IVec::from("archived share"))
+    );
+    assert_eq!(db.iter().count(), 1);
+    drop(db);
+
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get("key_uid").unwrap().is_none());
+    assert!(kv.get("verification_key").unwrap().is_none());
+    assert_eq!(
+        kv.get(RECORD_FORMAT_VERSION_KEY).unwrap(),
+        Some(sled::IVec::from(&[RECORD_FORMAT_VERSION]))
+    );
+}
+
+#[test]
+fn test_kdf_params() {
+    let cheap_scrypt = KdfParams::Scrypt {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+    let cheap_argon2id = KdfParams::Argon2id {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    // new dbs store their kdf params and keep them on reopening
+    for &kdf in [&cheap_scrypt, &cheap_argon2id].iter() {
+        let db_path = testdir!().join(kdf.to_string());
+        let db = EncryptedDb::open_with_kdf(&db_path, get_test_password(), kdf).unwrap();
+        db.insert("key", "value").unwrap();
+        drop(db);
+
+        let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+        assert_eq!(db.kdf(), kdf);
+        assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+        assert_eq!(db.iter().count(), 1);
+    }
+
+    // dbs without stored kdf params were created with the default params
+    let db_path = testdir!().join("legacy");
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    drop(db);
+    let kv = sled::open(&db_path).unwrap();
+    kv.remove(PASSWORD_KDF_PARAMS_KEY).unwrap();
+    drop(kv);
+    let db = EncryptedDb::open_with_kdf(&db_path, get_test_password(), &cheap_scrypt).unwrap();
+    assert_eq!(db.kdf(), &KdfParams::default());
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+
+    // the kdf can be changed along with the password
+    let mut db = db;
+    db.change_password(Password::from("new password"), Some(&cheap_argon2id))
+        .unwrap();
+    drop(db);
+    let db = EncryptedDb::open(&db_path, Password::from("new password")).unwrap();
+    assert_eq!(db.kdf(), &cheap_argon2id);
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+}
+
+#[test]
+fn test_kdf_params_from_str() {
+    let parse = |s: &str| s.parse::<KdfParams>();
+
+    assert_eq!(parse("scrypt").unwrap(), KdfParams::default());
+    assert_eq!(parse("scrypt:15:8:1").unwrap(), KdfParams::default());
+    assert_eq!(
+        parse("argon2id:65536:3:4").unwrap(),
+        KdfParams::Argon2id {
+            m_cost: 65536,
+            t_cost: 3,
+            p_cost: 4
+        }
+    );
+    assert_eq!(parse("argon2id").unwrap().to_string(), "argon2id:19456:2:1");
+
+    for invalid in [
+        "",
+        "pbkdf2",
+        "scrypt:15",
+        "scrypt:300:8:1",
+        "scrypt:0:8:1",
+        "argon2id:1:1:1",
+    ]
+    .iter()
+    {
+        assert!(parse(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_password_methods() {
+    let dir = testdir!("password_methods");
+
+    // file: a trailing newline is ignored
+    let path = dir.join("password");
+    std::fs::write(&path, "file password\r\n").unwrap();
+    let password = PasswordMethod::File(path).execute().unwrap();
+    assert_eq!(password.as_ref(), b"file password");
+    assert!(PasswordMethod::File(dir.join("missing")).execute().is_err());
+
+    // env var: the variable is removed after reading it
+    let var = "TOFND_TEST_PASSWORD_METHODS";
+    std::env::set_var(var, "env password");
+    let method = PasswordMethod::EnvVar(var.to_string());
+    assert_eq!(method.execute().unwrap().as_ref(), b"env password");
+    assert!(std::env::var(var).is_err());
+    assert!(method.execute().is_err());
+
+    // fd: only the first line is read
+    use std::{io::Write, os::unix::io::IntoRawFd};
+    let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+    writer.write_all(b"fd password\nmnemonic\n").unwrap();
+    drop(writer);
+    let password = PasswordMethod::Fd(reader.into_raw_fd()).execute().unwrap();
+    assert_eq!(password.as_ref(), b"fd password");
+
+    // fd: overlong passwords are rejected
+    let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+    writer.write_all(&vec![b'a'; 2000]).unwrap();
+    drop(writer);
+    assert!(matches!(
+        PasswordMethod::Fd(reader.into_raw_fd()).execute(),
+        Err(super::result::EncryptedDbError::PasswordTooLong(_))
+    ));
+}
+
+#[test]
+fn test_password_method_from_str() {
+    let parse = |s: &str| s.parse::<PasswordMethod>().map(|method| method.to_string());
+
+    assert_eq!(parse("prompt").unwrap(), "prompt");
+    assert_eq!(
+        parse("file:/run/secrets/tofnd").unwrap(),
+        "file:/run/secrets/tofnd"
+    );
+    assert_eq!(parse("env:PASSWORD").unwrap(), "env:PASSWORD");
+    assert_eq!(parse("fd:3").unwrap(), "fd:3");
+
+    for invalid in ["", "none", "file:", "env", "fd:three", "pass:word"].iter() {
+        assert!(parse(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_key_file_kek() {
+    let dir = testdir!("key_file_kek");
+    let db_path = dir.join("db");
+    let key_file = |name: &str, byte: u8| {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("{:02x}", byte).repeat(32) + "\n").unwrap();
+        KekMethod::KeyFile(path)
+            .execute(&PasswordMethod::NoPassword)
+            .unwrap()
+    };
+
+    let db = EncryptedDb::open(&db_path, key_file("kek", 1)).unwrap();
+    assert_eq!(db.kek(), "key-file");
+    db.insert("key", "value").unwrap();
+    drop(db);
+
+    // the data key is only unwrapped by the same kek
+    assert!(matches!(
+        EncryptedDb::open(&db_path, key_file("other_kek", 2)),
+        Err(super::Error::WrongKek)
+    ));
+    assert!(matches!(
+        EncryptedDb::open(&db_path, get_test_password()),
+        Err(super::Error::KekMismatch { .. })
+    ));
+
+    // changing the kek keeps the records
+    let mut db = EncryptedDb::open(&db_path, key_file("kek", 1)).unwrap();
+    db.change_kek(get_test_password().into(), None).unwrap();
+    drop(db);
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.kek(), "password");
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+
+    // malformed key files are rejected
+    for (name, content) in [("short", "2a2a"), ("not_hex", "zz".repeat(32).as_str())].iter() {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        assert!(KekMethod::KeyFile(path)
+            .execute(&PasswordMethod::NoPassword)
+            .is_err());
+    }
+}
+
+#[test]
+fn test_command_kek() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = testdir!("command_kek");
+    let db_path = dir.join("db");
+    // a command that "wraps" data keys by prefixing them with a key id
+    let command = |name: &str, script: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\nread line\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+        path
+    };
+    let kms = command(
+        "kms",
+        "case \"$1\" in\n  wrap) echo \"00$line\" ;;\n  unwrap) echo \"${line#00}\" ;;\n  *) exit 1 ;;\nesac",
+    );
+    let failing = command("failing", "exit 1");
+    let other = command("other", &format!("echo {}", "2a".repeat(32)));
+
+    let db = EncryptedDb::open(&db_path, Kek::Command(kms.clone())).unwrap();
+    assert_eq!(db.kek(), "command");
+    db.insert("key", "value").unwrap();
+    drop(db);
+
+    let db = EncryptedDb::open(&db_path, Kek::Command(kms)).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    drop(db);
+
+    // failing commands and commands that return another data key are errors
+    assert!(matches!(
+        EncryptedDb::open(&db_path, Kek::Command(failing)),
+        Err(super::Error::KekCommand(_))
+    ));
+    assert!(matches!(
+        EncryptedDb::open(&db_path, Kek::Command(other)),
+        Err(super::Error::WrongKek)
+    ));
+}
+
+#[test]
+fn test_rotate_legacy_data_key() {
+    let db_path = testdir!("rotate_legacy_data_key");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    db.downgrade_to_password_key(get_test_password()).unwrap();
+    drop(db);
+    let kv = sled::open(&db_path).unwrap();
+    let legacy_salt = kv.get(PASSWORD_SALT_KEY).unwrap().unwrap();
+    kv.insert("corrupted", &b"not a record"[..]).unwrap();
+    drop(kv);
+
+    // legacy dbs can only be opened with their password
+    assert!(matches!(
+        EncryptedDb::open(&db_path, Password::from("wrong password")),
+        Err(super::Error::WrongPassword)
+    ));
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get(DATA_KEY_KEY).unwrap().is_none());
+    drop(kv);
+
+    // opening a legacy db does not change its data key
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert!(db.has_legacy_data_key());
+    drop(db);
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get(DATA_KEY_KEY).unwrap().is_none());
+    drop(kv);
+
+    // upgrading a legacy db replaces its data key by a wrapped random data key,
+    // moving aside the records that cannot be decrypted
+    let kek = Kek::Password(get_test_password());
+    let mut db = EncryptedDb::open_with_kek(&db_path, &kek, &KdfParams::default()).unwrap();
+    let skipped = db.upgrade(&kek).unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].sled_key, sled::IVec::from("corrupted"));
+    assert!(!db.needs_upgrade());
+    drop(db);
+    let kv = sled::open(&db_path).unwrap();
+    assert!(kv.get(DATA_KEY_KEY).unwrap().is_some());
+    assert!(kv.get("corrupted").unwrap().is_none());
+    drop(kv);
+
+    let db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert_eq!(db.iter().count(), 1);
+    drop(db);
+
+    // the password-derived data key was replaced: with the legacy salt, the password no longer
+    // decrypts the records
+    let kv = sled::open(&db_path).unwrap();
+    kv.insert(PASSWORD_SALT_KEY, legacy_salt).unwrap();
+    kv.remove(DATA_KEY_KEY).unwrap();
+    drop(kv);
+    assert!(matches!(
+        EncryptedDb::open(&db_path, get_test_password()),
+        Err(super::Error::WrongPassword)
+    ));
+}
+
+#[test]
+fn test_rotate_data_key() {
+    let db_path = testdir!("rotate_data_key");
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    db.insert("key", "value").unwrap();
+    db.insert("archived", "value").unwrap();
+    db.archive("archived", "archive_key", "archived value")
+        .unwrap();
+    drop(db);
+
+    // the internal records that unwrap the data key with the old password
+    let kv = sled::open(&db_path).unwrap();
+    let old_records: Vec<_> = [PASSWORD_SALT_KEY, PASSWORD_KDF_PARAMS_KEY, DATA_KEY_KEY]
+        .iter()
+        .map(|key| (*key, kv.get(key).unwrap().unwrap()))
+        .collect();
+    drop(kv);
+
+    let mut db = EncryptedDb::open(&db_path, get_test_password()).unwrap();
+    let skipped = db
+        .rotate_data_key(Password::from("new password").into(), None)
+        .unwrap();
+    assert!(skipped.is_empty());
+    // the open db keeps working with the new data key
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    drop(db);
+
+    // all records are available with the new password
+    let db = EncryptedDb::open(&db_path, Password::from("new password")).unwrap();
+    assert_eq!(db.get("key").unwrap(), Some(sled::IVec::from("value")));
+    assert_eq!(
+        db.get_archived("archive_key").unwrap(),
+        Some(sled::IVec::from("archived value"))
+    );
+    assert_eq!(db.iter().count(), 1);
+    drop(db);
+
+    // the old wrapped data key no longer decrypts the records
+    let kv = sled::open(&db_path).unwrap();
+    for (key, value) in old_records {
+        kv.insert(key, value).unwrap();
+    }
+    drop(kv);
+    assert!(matches!(
+        EncryptedDb::open(&db_path, get_test_password()),
+        Err(super::Error::WrongPassword)
+    ));
+}
+
+#[test]
+fn test_kek_method_from_str() {
+    let parse = |s: &str| s.parse::<KekMethod>().map(|method| method.to_string());
+
+    assert_eq!(parse("password").unwrap(), "password");
+    assert_eq!(
+        parse("key-file:/run/secrets/kek").unwrap(),
+        "key-file:/run/secrets/kek"
+    );
+    assert_eq!(
+        parse("command:/usr/local/bin/kms-kek").unwrap(),
+        "command:/usr/local/bin/kms-kek"
+    );
+
+    for invalid in ["", "prompt", "key-file:", "command", "kms:key"].iter() {
+        assert!(parse(invalid).is_err(), "{}", invalid);
+    }
+}
+
 pub fn get_test_password() -> Password {
     crate::encrypted_sled::PasswordMethod::NoPassword
         .execute()
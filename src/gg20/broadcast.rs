@@ -1,16 +1,30 @@
 //! This module handles the routing of incoming traffic.
 //! Receives and validates messages until the connection is closed by the client.
 //! The incoming messages come from the gRPC stream and are forwarded to shares' internal channels.
+//!
+//! The router ignores messages from parties that are not part of the session, so that they are not
+//! attributed to any share. The payload of a message is not inspected: its tofn header, i.e. the share
+//! that sent it and the share a p2p is addressed to, is checked by tofn when the message is delivered.
+//!
+//! Every accepted message is forwarded to every share, p2p messages included: a round of tofn expects the
+//! p2p messages between all pairs of shares, so that faults can be attributed, e.g. when a share
+//! complains about the p2p it received from another. Shares receive the same allocation of a message,
+//! so a party with many shares does not copy each message once per share.
 
 // tonic cruft
 use super::proto;
 use futures_util::StreamExt;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tonic::Status;
 
 // logging
 use tracing::{debug, info, span, warn, Level, Span};
 
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
 /// Results of routing
 #[derive(Debug, PartialEq)]
 enum RoutingStatus {
@@ -21,10 +35,11 @@ enum RoutingStatus {
 
 /// Receives incoming from a gRPC stream and broadcasts them to internal channels;
 /// Loops until client closes the socket, or a message containing [proto::message_in::Data::Abort] is received  
-/// Empty and unknown messages are ignored
+/// Empty and unknown messages, and messages from unknown parties are ignored
 pub(super) async fn broadcast_messages(
     in_grpc_stream: &mut tonic::Streaming<proto::MessageIn>,
-    mut out_internal_channels: Vec<mpsc::UnboundedSender<Option<proto::TrafficIn>>>,
+    out_internal_channels: Vec<mpsc::UnboundedSender<Option<Arc<proto::TrafficIn>>>>,
+    parties: KnownParties,
     span: Span,
 ) {
     // loop until `stop` is received
@@ -39,11 +54,48 @@ pub(super) async fn broadcast_messages(
             RoutingStatus::Skip => continue,
         };
 
-        // send the message to all channels
-        for out_channel in &mut out_internal_channels {
-            let _ = out_channel.send(Some(traffic.clone()));
+        // check the sender of the message
+        if let Err(err) = parties.check(&traffic) {
+            let route_span = span!(parent: &span, Level::INFO, "routing");
+            let _start = route_span.enter();
+            warn!("ignore incoming msg: {}", err);
+            continue;
+        }
+
+        route(traffic, &out_internal_channels);
+    }
+}
+
+/// send the message to all channels
+fn route(
+    traffic: proto::TrafficIn,
+    out_internal_channels: &[mpsc::UnboundedSender<Option<Arc<proto::TrafficIn>>>],
+) {
+    let traffic = Arc::new(traffic);
+    for out_channel in out_internal_channels {
+        let _ = out_channel.send(Some(traffic.clone()));
+    }
+}
+
+/// The parties of a session. Used to check the sender of incoming messages.
+pub(super) struct KnownParties {
+    party_uids: Vec<String>,
+}
+
+impl KnownParties {
+    pub(super) fn new(party_uids: &[String]) -> Self {
+        Self {
+            party_uids: party_uids.to_vec(),
         }
     }
+
+    /// check that `traffic` was sent by a party of the session
+    fn check(&self, traffic: &proto::TrafficIn) -> TofndResult<()> {
+        if !self.party_uids.contains(&traffic.from_party_uid) {
+            return Err(anyhow!("unknown sender party {}", traffic.from_party_uid));
+        }
+        Ok(())
+    }
 }
 
 /// gets a gPRC [proto::MessageIn] and checks the type
@@ -115,6 +167,15 @@ fn open_message(msg: Option<Result<proto::MessageIn, Status>>, span: Span) -> Ro
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryInto;
+    use tofn::{
+        collections::TypedUsize,
+        gg20::keygen::{
+            create_party_keypair_and_zksetup_unsafe, new_keygen, KeygenPartyShareCounts,
+            KeygenProtocol, SecretRecoveryKey,
+        },
+        sdk::api::{BytesVec, Protocol},
+    };
 
     struct TestCase {
         message_in: proto::MessageIn,
@@ -173,4 +234,245 @@ mod tests {
         let result = open_message(None, span);
         assert_eq!(result, RoutingStatus::Stop);
     }
+
+    fn traffic(from_party: usize, is_broadcast: bool) -> proto::TrafficIn {
+        proto::TrafficIn {
+            from_party_uid: format!("party-{}", from_party),
+            is_broadcast,
+            payload: vec![42; 8],
+        }
+    }
+
+    #[test]
+    fn test_check_sender() {
+        let party_uids: Vec<_> = (0..3).map(|i| format!("party-{}", i)).collect();
+        let parties = KnownParties::new(&party_uids);
+
+        assert!(parties.check(&traffic(1, true)).is_ok());
+        assert!(parties.check(&traffic(2, false)).is_ok());
+        // unknown sender
+        assert!(parties.check(&traffic(3, true)).is_err());
+    }
+
+    #[test]
+    fn test_route_to_all_shares() {
+        let (senders, mut receivers): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| mpsc::unbounded_channel()).unzip();
+
+        let p2p = traffic(0, false);
+        route(p2p.clone(), &senders);
+
+        let received: Vec<_> = receivers
+            .iter_mut()
+            .map(|receiver| receiver.try_recv().unwrap().unwrap())
+            .collect();
+        assert_eq!(*received[0], p2p);
+        // all shares get the same allocation of the message
+        assert!(received
+            .iter()
+            .all(|traffic| Arc::ptr_eq(traffic, &received[0])));
+    }
+
+    /// the messages that all shares of round 2 of keygen send, as they reach the router
+    fn round_2_traffic(
+        keygens: &[KeygenProtocol],
+        party_uids: &[String],
+        party_share_counts: &[usize],
+    ) -> Vec<proto::TrafficIn> {
+        let mut msgs = vec![];
+        for (share, keygen) in keygens.iter().enumerate() {
+            let round = match keygen {
+                Protocol::NotDone(round) => round,
+                Protocol::Done(_) => panic!("keygen done in round 2"),
+            };
+            let from_party_uid = party_uids[party_of(share, party_share_counts)].clone();
+            msgs.push(proto::TrafficIn {
+                from_party_uid: from_party_uid.clone(),
+                is_broadcast: true,
+                payload: round.bcast_out().unwrap().clone(),
+            });
+            for (_, p2p) in round.p2ps_out().unwrap().iter() {
+                msgs.push(proto::TrafficIn {
+                    from_party_uid: from_party_uid.clone(),
+                    is_broadcast: false,
+                    payload: p2p.clone(),
+                });
+            }
+        }
+        msgs
+    }
+
+    /// the party that holds `share`
+    fn party_of(share: usize, party_share_counts: &[usize]) -> usize {
+        let mut next_party_share = 0;
+        for (party, share_count) in party_share_counts.iter().enumerate() {
+            next_party_share += share_count;
+            if share < next_party_share {
+                return party;
+            }
+        }
+        panic!("share {} does not exist", share);
+    }
+
+    /// deliver `msgs` through the router to the round of each of our shares in `keygens`
+    fn deliver(
+        msgs: Vec<proto::TrafficIn>,
+        keygens: &mut [KeygenProtocol],
+        party_uids: &[String],
+        check: bool,
+    ) {
+        let parties = KnownParties::new(party_uids);
+        let (senders, mut receivers): (Vec<_>, Vec<_>) = (0..keygens.len())
+            .map(|_| mpsc::unbounded_channel())
+            .unzip();
+        for msg in msgs {
+            if check {
+                parties.check(&msg).unwrap();
+            }
+            route(msg, &senders);
+            for (keygen, receiver) in keygens.iter_mut().zip(receivers.iter_mut()) {
+                let traffic = receiver.try_recv().unwrap().unwrap();
+                let from = party_uids
+                    .iter()
+                    .position(|uid| uid == &traffic.from_party_uid)
+                    .unwrap();
+                match keygen {
+                    Protocol::NotDone(round) => round
+                        .msg_in(TypedUsize::from_usize(from), &traffic.payload)
+                        .unwrap(),
+                    Protocol::Done(_) => panic!("keygen done in round 2"),
+                }
+            }
+        }
+    }
+
+    /// The router forwards the messages that tofn emits unchanged, so tofn can check and use them.
+    /// The router does not parse the tofn wire format, so it does not depend on its layout.
+    #[test]
+    fn test_route_tofn_messages() {
+        let party_share_counts = [1, 2, 1];
+        let party_uids: Vec<_> = (0..3).map(|i| format!("party-{}", i)).collect();
+        let (keygens, round_1_bcasts) = keygen_round_2(&party_share_counts, 1, &[0, 1, 2], None);
+        let msgs = round_2_traffic(&keygens, &party_uids, &party_share_counts);
+
+        // the shares of party-1 receive all bcasts and all p2ps of round 2
+        let (mut keygens, _) = keygen_round_2(&party_share_counts, 1, &[1], Some(&round_1_bcasts));
+        deliver(msgs, &mut keygens, &party_uids, true);
+        for keygen in &keygens {
+            match keygen {
+                Protocol::NotDone(round) => assert!(!round.expecting_more_msgs_this_round()),
+                Protocol::Done(_) => panic!("keygen done in round 2"),
+            }
+        }
+    }
+
+    /// create the keygens of all shares of the parties, and execute their first round, which only has bcasts
+    fn keygen_round_2(
+        party_share_counts: &[usize],
+        threshold: usize,
+        parties: &[usize],
+        round_1_bcasts: Option<&[(usize, BytesVec)]>,
+    ) -> (Vec<KeygenProtocol>, Vec<(usize, BytesVec)>) {
+        let secret_recovery_key: SecretRecoveryKey = (&[42u8; 64][..]).try_into().unwrap();
+        let mut keygens = vec![];
+        for party in parties {
+            let party_id = TypedUsize::from_usize(*party);
+            let party_keygen_data =
+                create_party_keypair_and_zksetup_unsafe(party_id, &secret_recovery_key, b"bench")
+                    .unwrap();
+            for subindex in 0..party_share_counts[*party] {
+                let keygen = new_keygen(
+                    KeygenPartyShareCounts::from_vec(party_share_counts.to_vec()).unwrap(),
+                    threshold,
+                    party_id,
+                    subindex,
+                    &party_keygen_data,
+                    #[cfg(feature = "malicious")]
+                    tofn::gg20::keygen::malicious::Behaviour::Honest,
+                )
+                .unwrap();
+                keygens.push((*party, keygen));
+            }
+        }
+
+        let own_bcasts: Vec<_> = keygens
+            .iter()
+            .map(|(party, keygen)| match keygen {
+                Protocol::NotDone(round) => (*party, round.bcast_out().unwrap().clone()),
+                Protocol::Done(_) => panic!("keygen done in round 1"),
+            })
+            .collect();
+        let bcasts = round_1_bcasts.unwrap_or(&own_bcasts);
+
+        let keygens = keygens
+            .into_iter()
+            .map(|(_, keygen)| match keygen {
+                Protocol::NotDone(mut round) => {
+                    for (from, bcast) in bcasts {
+                        round.msg_in(TypedUsize::from_usize(*from), bcast).unwrap();
+                    }
+                    round.execute_next_round().unwrap()
+                }
+                Protocol::Done(_) => panic!("keygen done in round 1"),
+            })
+            .collect();
+        (keygens, own_bcasts)
+    }
+
+    /// Handling of the messages of the second round of keygen, which has a bcast and p2ps, at high share counts.
+    /// Our party holds a quarter of all shares. Each message goes through the router, and each of our shares
+    /// deserializes it in tofn, as in [super::super::protocol::execute_protocol]. The round is handled once
+    /// without the sender check of the router, and once with the check.
+    /// Run with
+    ///     cargo test --release route_high_share_counts -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn route_high_share_counts() {
+        let total_share_counts = [16, 32, 64];
+
+        for total_share_count in total_share_counts.iter() {
+            let party_share_counts = vec![total_share_count / 4; 4];
+            let threshold = total_share_count / 2;
+            let party_uids: Vec<_> = (0..4).map(|i| format!("party-{}", i)).collect();
+
+            // the messages of round 2 of all shares
+            let (keygens, round_1_bcasts) =
+                keygen_round_2(&party_share_counts, threshold, &[0, 1, 2, 3], None);
+            let msgs = round_2_traffic(&keygens, &party_uids, &party_share_counts);
+            let msg_count = msgs.len();
+
+            for check in [false, true].iter() {
+                let (mut keygens, _) =
+                    keygen_round_2(&party_share_counts, threshold, &[0], Some(&round_1_bcasts));
+                let msgs = msgs.clone();
+
+                let start = std::time::Instant::now();
+                deliver(msgs, &mut keygens, &party_uids, *check);
+                let elapsed = start.elapsed();
+
+                for keygen in &keygens {
+                    match keygen {
+                        Protocol::NotDone(round) => {
+                            assert!(!round.expecting_more_msgs_this_round())
+                        }
+                        Protocol::Done(_) => panic!("keygen done in round 2"),
+                    }
+                }
+
+                println!(
+                    "{} {} msgs to {} of {} shares: total {:?}, {:.2} msgs/s",
+                    if *check {
+                        "checked and handled"
+                    } else {
+                        "handled"
+                    },
+                    msg_count,
+                    keygens.len(),
+                    total_share_count,
+                    elapsed,
+                    msg_count as f64 / elapsed.as_secs_f64(),
+                );
+            }
+        }
+    }
 }
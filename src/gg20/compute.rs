@@ -0,0 +1,145 @@
+//! A pool of threads that executes the CPU-heavy work of keygen and sign.
+//! Generating safe primes and creating and verifying zk proofs take seconds of CPU, so this work
+//! must not run on the async runtime, where it would stall the gRPC server and all other sessions.
+//! Sessions send the work to the [ComputePool] as jobs and await their results.
+
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// logging
+use tracing::error;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Handle of the compute pool. The threads of the pool exit when the handle is dropped.
+pub(super) struct ComputePool {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+impl ComputePool {
+    /// Spawn a pool of `threads` threads
+    pub(super) fn new(threads: usize) -> TofndResult<Self> {
+        let (sender, receiver) = mpsc::unbounded_channel::<Job>();
+        // idle threads take turns waiting for the next job
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("compute-{}", i))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(mut receiver) => receiver.blocking_recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        // a panicking job only loses its own result
+                        Some(job) => {
+                            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                error!("compute job panicked");
+                            }
+                        }
+                        None => return,
+                    }
+                })
+                .map_err(|err| anyhow!("could not spawn compute thread: {}", err))?;
+        }
+
+        Ok(Self { sender })
+    }
+
+    /// Execute `f` on the pool and return its result.
+    /// Jobs that are still queued when their caller goes away, e.g. because its session was
+    /// aborted, are skipped.
+    pub(super) async fn run<F, T>(&self, f: F) -> TofndResult<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.sender
+            .send(Box::new(move || {
+                if !result_sender.is_closed() {
+                    let _ = result_sender.send(f());
+                }
+            }))
+            .map_err(|_| anyhow!("compute pool is closed"))?;
+        result_receiver
+            .await
+            .map_err(|_| anyhow!("compute job failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn run_returns_result() {
+        let pool = ComputePool::new(2).unwrap();
+        assert_eq!(pool.run(|| 6 * 7).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn jobs_run_off_the_runtime() {
+        let pool = ComputePool::new(1).unwrap();
+        let name = pool
+            .run(|| thread::current().name().map(String::from))
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("compute-0"));
+    }
+
+    #[tokio::test]
+    async fn threads_run_jobs_concurrently() {
+        let threads = 4;
+        let pool = Arc::new(ComputePool::new(threads).unwrap());
+
+        // each job waits until all jobs have started, which only ends if they run at the same time
+        let started = Arc::new(AtomicUsize::new(0));
+        let jobs: Vec<_> = (0..threads)
+            .map(|_| {
+                let pool = pool.clone();
+                let started = started.clone();
+                tokio::spawn(async move {
+                    pool.run(move || {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        while started.load(Ordering::SeqCst) < threads {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                    })
+                    .await
+                })
+            })
+            .collect();
+        for job in jobs {
+            tokio::time::timeout(Duration::from_secs(5), job)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_job_does_not_kill_the_pool() {
+        let pool = ComputePool::new(1).unwrap();
+        assert!(pool
+            .run(|| -> u32 { panic!("job panicked") })
+            .await
+            .is_err());
+        assert_eq!(pool.run(|| 1).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,71 @@
+//! This module handles the delete_key and archive_key gRPCs.
+//! Archiving moves the [PartyInfo] of a key out of the kv-store and into the archive, along with a timestamp and a reason.
+//! Deleting permanently wipes the [PartyInfo] of a key, and requires the client to confirm the key uid.
+
+use super::{proto, service::Gg20Service, types::PartyInfo};
+use crate::kv_manager::holds;
+
+// logging
+use tracing::info;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+impl Gg20Service {
+    pub(super) async fn handle_delete_key(
+        &self,
+        request: proto::DeleteKeyRequest,
+    ) -> TofndResult<proto::delete_key_response::Response> {
+        // deletion is irreversible; make sure the client means it
+        if request.confirm_key_uid != request.key_uid {
+            return Err(anyhow!(
+                "confirmation [{}] does not match key uid [{}]",
+                request.confirm_key_uid,
+                request.key_uid
+            ));
+        }
+
+        // only keys that hold a PartyInfo are deleted, so that records that do not
+        // belong to gg20 (e.g. the mnemonic) cannot be removed
+        if !self
+            .kv_manager
+            .kv()
+            .delete(&request.key_uid, holds::<PartyInfo>)
+            .await?
+        {
+            info!(
+                "Did not find key {} in kv store during delete",
+                request.key_uid
+            );
+            return Ok(proto::delete_key_response::Response::Absent);
+        }
+        info!("Deleted key {}", request.key_uid);
+
+        Ok(proto::delete_key_response::Response::Success)
+    }
+
+    pub(super) async fn handle_archive_key(
+        &self,
+        request: proto::ArchiveKeyRequest,
+    ) -> TofndResult<proto::archive_key_response::Response> {
+        let archive_key = match self
+            .kv_manager
+            .kv()
+            .archive(&request.key_uid, request.reason, holds::<PartyInfo>)
+            .await?
+        {
+            Some(archive_key) => archive_key,
+            None => {
+                info!(
+                    "Did not find key {} in kv store during archive",
+                    request.key_uid
+                );
+                return Ok(proto::archive_key_response::Response::Absent);
+            }
+        };
+        info!("Archived key {} as {}", request.key_uid, archive_key);
+
+        Ok(proto::archive_key_response::Response::Success)
+    }
+}
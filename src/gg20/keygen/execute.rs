@@ -8,7 +8,8 @@ use super::{
     Gg20Service, ProtocolCommunication,
 };
 
-use crate::gg20::protocol;
+use crate::gg20::{protocol, registry::SessionProgress};
+use std::sync::Arc;
 use tofn::{
     gg20::keygen::{new_keygen, KeygenProtocol},
     sdk::api::TofnResult,
@@ -45,10 +46,11 @@ impl Gg20Service {
     pub(super) async fn execute_keygen(
         &self,
         chans: ProtocolCommunication<
-            Option<proto::TrafficIn>,
+            Option<Arc<proto::TrafficIn>>,
             Result<proto::MessageOut, tonic::Status>,
         >,
         ctx: &Context,
+        progress: Option<SessionProgress>,
         execute_span: Span,
     ) -> TofndKeygenOutput {
         // try to create keygen with context
@@ -64,6 +66,9 @@ impl Gg20Service {
             chans,
             &ctx.uids,
             &ctx.share_counts,
+            self.cfg.round_timeout,
+            &self.compute_pool,
+            progress.as_ref(),
             execute_span.clone(),
         )
         .await;
@@ -17,7 +17,7 @@ use super::{
     types::{KeygenInitSanitized, MAX_PARTY_SHARE_COUNT, MAX_TOTAL_SHARE_COUNT},
     Gg20Service,
 };
-use crate::kv_manager::KeyReservation;
+use crate::{kv_manager::KeyReservation, multisig::MULTISIG_KEY_PREFIX};
 
 impl Gg20Service {
     /// Receives a message from the stream and tries to handle keygen init operations.
@@ -71,11 +71,11 @@ impl Gg20Service {
         let keygen_init = Self::keygen_sanitize_args(keygen_init)
             .map_err(|err| anyhow!("failed to sanitize KeygenInit: {}", err))?;
 
-        // reserve key
+        // reserve key on behalf of the session, which is registered under the key uid
         let key_uid_reservation = self
             .kv_manager
             .kv()
-            .reserve_key(keygen_init.new_key_uid.clone())
+            .reserve_key(keygen_init.new_key_uid.clone(), &keygen_init.new_key_uid)
             .await
             .map_err(|err| anyhow!("failed to reseve key: {}", err))?;
 
@@ -99,6 +99,15 @@ impl Gg20Service {
     pub(crate) fn keygen_sanitize_args(
         args: proto::KeygenInit,
     ) -> TofndResult<KeygenInitSanitized> {
+        // multisig keys are stored under this prefix in the same kv store
+        if args.new_key_uid.starts_with(MULTISIG_KEY_PREFIX) {
+            return Err(anyhow!(
+                "key uid [{}] starts with reserved prefix [{}]",
+                args.new_key_uid,
+                MULTISIG_KEY_PREFIX,
+            ));
+        }
+
         // convert `u32`s to `usize`s
         use std::convert::TryFrom;
         let my_index = usize::try_from(args.my_party_index)?;
@@ -347,5 +356,14 @@ mod tests {
             threshold: 1,
         };
         assert!(Gg20Service::keygen_sanitize_args(raw_keygen_init).is_err());
+
+        let raw_keygen_init = proto::KeygenInit {
+            new_key_uid: format!("{}test_uid", MULTISIG_KEY_PREFIX), // key uid of the multisig namespace
+            party_uids: vec!["party_1".to_owned(), "party_2".to_owned()],
+            party_share_counts: vec![1, 1],
+            my_party_index: 0,
+            threshold: 1,
+        };
+        assert!(Gg20Service::keygen_sanitize_args(raw_keygen_init).is_err());
     }
 }
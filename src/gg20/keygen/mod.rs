@@ -15,7 +15,11 @@
 //! All relevant helper structs and types are defined in [self::types]
 
 use super::{
-    broadcast::broadcast_messages, proto, service::Gg20Service, types::ProtocolCommunication,
+    broadcast::{broadcast_messages, KnownParties},
+    proto::{self, session_info::Kind},
+    service::Gg20Service,
+    session::{client_disconnected, KeygenSlot, SessionTasks},
+    types::ProtocolCommunication,
 };
 
 use tonic::Status;
@@ -49,12 +53,15 @@ impl Gg20Service {
         &self,
         mut stream_in: tonic::Streaming<proto::MessageIn>,
         mut stream_out_sender: mpsc::UnboundedSender<Result<proto::MessageOut, Status>>,
+        mut slot: KeygenSlot,
         keygen_span: Span,
     ) -> TofndResult<()> {
         // 1. Receive KeygenInit, open message, sanitize arguments -> init mod
+        //    Register the session and wait for a keygen slot if the keygen is queued -> registry and session mods
         // 2. Spawn N keygen threads to execute the protocol in parallel; one of each of our shares -> execute mod
         // 3. Spawn 1 router thread to route messages from client to the respective keygen thread -> routing mod
         // 4. Wait for all keygen threads to finish and aggregate all responses -> result mod
+        // All threads are aborted when this function returns -> session mod
 
         // 1.
         // get KeygenInit message from stream, sanitize arguments and reserve key
@@ -62,6 +69,33 @@ impl Gg20Service {
             .handle_keygen_init(&mut stream_in, keygen_span.clone())
             .await?;
 
+        // the session is listed and can be cancelled until this function returns
+        let registration = self.sessions.register(
+            Kind::Keygen,
+            &keygen_init.new_key_uid,
+            &keygen_init.party_uids,
+            slot.is_queued(),
+        );
+
+        // the slot is held until this function returns
+        if slot.is_queued() {
+            info!("Keygen queued until a running keygen ends");
+            let res = tokio::select! {
+                res = slot.wait() => res,
+                _ = client_disconnected(&stream_out_sender) => Err(anyhow!("client disconnected while keygen was queued")),
+                _ = registration.cancelled() => Err(anyhow!("keygen was cancelled while queued")),
+            };
+            if let Err(err) = res {
+                self.kv_manager
+                    .kv()
+                    .unreserve_key(key_uid_reservation)
+                    .await;
+                return Err(err);
+            }
+            registration.set_running();
+            info!("Keygen left the queue");
+        }
+
         // 2.
         // find my share count to allocate channel vectors
         let my_share_count = keygen_init.my_shares_count();
@@ -75,24 +109,32 @@ impl Gg20Service {
         // create in and out channels for each share, and spawn as many threads
         let mut keygen_senders = Vec::with_capacity(my_share_count);
         let mut aggregator_receivers = Vec::with_capacity(my_share_count);
+        // threads of this session; the ones still running are aborted when the session ends
+        let mut tasks = SessionTasks::new(registration.guard());
 
-        // computation of (party_keypair, party_zksetup) is intensive so we compute them here once
+        // computation of (party_keypair, party_zksetup) is intensive so we compute them here once, on the compute pool
         let secret_recovery_key = self.kv_manager.seed().await?;
-        let session_nonce = keygen_init.new_key_uid.as_bytes();
+        let session_nonce = keygen_init.new_key_uid.as_bytes().to_vec();
 
         info!("Generating keypair for party {} ...", keygen_init.my_index);
 
         let party_id = TypedUsize::<KeygenPartyId>::from_usize(keygen_init.my_index);
 
-        let party_keygen_data = match self.cfg.safe_keygen {
-            true => create_party_keypair_and_zksetup(party_id, &secret_recovery_key, session_nonce),
-            false => create_party_keypair_and_zksetup_unsafe(
-                party_id,
-                &secret_recovery_key,
-                session_nonce,
-            ),
-        }
-        .map_err(|_| anyhow!("Party keypair generation failed"))?;
+        let safe_keygen = self.cfg.safe_keygen;
+        let party_keygen_data = self
+            .compute_pool
+            .run(move || match safe_keygen {
+                true => {
+                    create_party_keypair_and_zksetup(party_id, &secret_recovery_key, &session_nonce)
+                }
+                false => create_party_keypair_and_zksetup_unsafe(
+                    party_id,
+                    &secret_recovery_key,
+                    &session_nonce,
+                ),
+            })
+            .await?
+            .map_err(|_| anyhow!("Party keypair generation failed"))?;
 
         info!(
             "Finished generating keypair for party {}",
@@ -118,6 +160,8 @@ impl Gg20Service {
             );
             // clone gg20 service because tokio thread takes ownership
             let gg20 = self.clone();
+            // all shares receive the same messages, so the first share records the progress of the session
+            let progress = (my_tofnd_subindex == 0).then(|| registration.progress());
 
             // set up log state
             let log_info = ctx.log_info();
@@ -125,9 +169,11 @@ impl Gg20Service {
             let execute_span = span!(parent: &keygen_span, Level::DEBUG, "execute", state);
 
             // spawn keygen thread and continue immediately
-            tokio::spawn(async move {
+            tasks.spawn(async move {
                 // wait for keygen's result inside thread
-                let secret_key_share = gg20.execute_keygen(chans, &ctx, execute_span).await;
+                let secret_key_share = gg20
+                    .execute_keygen(chans, &ctx, progress, execute_span)
+                    .await;
                 // send result to aggregator
                 let _ = aggregator_sender.send(secret_key_share);
             });
@@ -135,14 +181,21 @@ impl Gg20Service {
 
         // 3.
         // spin up broadcaster thread and return immediately
-        tokio::spawn(async move {
-            broadcast_messages(&mut stream_in, keygen_senders, keygen_span).await;
+        let parties = KnownParties::new(&keygen_init.party_uids);
+        tasks.spawn(async move {
+            broadcast_messages(&mut stream_in, keygen_senders, parties, keygen_span).await;
         });
 
         // 4.
-        // wait for all keygen threads to end, aggregate their responses, and store data in KV store
+        // wait for all keygen threads to end, unless the client disconnects or the keygen is cancelled
+        let keygen_outputs = tokio::select! {
+            keygen_outputs = Self::aggregate_keygen_outputs(aggregator_receivers) => keygen_outputs,
+            _ = client_disconnected(&stream_out_sender) => Err(anyhow!("client disconnected")),
+            _ = registration.cancelled() => Err(anyhow!("keygen was cancelled")),
+        };
+        // aggregate their responses, and store data in KV store
         self.aggregate_results(
-            aggregator_receivers,
+            keygen_outputs,
             &mut stream_out_sender,
             key_uid_reservation,
             keygen_init,
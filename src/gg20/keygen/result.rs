@@ -14,10 +14,7 @@ use super::{
 use crate::{gg20::types::PartyInfo, kv_manager::KeyReservation};
 
 // tonic cruft
-use tokio::sync::{
-    mpsc,
-    oneshot::{self, Receiver},
-};
+use tokio::sync::{mpsc, oneshot::Receiver};
 use tonic::Status;
 
 // error handling
@@ -28,17 +25,21 @@ use std::convert::TryInto;
 
 impl Gg20Service {
     /// aggregate results from all keygen threads, create a record and insert it in the KvStore
+    /// if the keygen threads did not produce outputs, or the protocol found crimes, the key is unreserved
     pub(super) async fn aggregate_results(
         &self,
-        aggregator_receivers: Vec<oneshot::Receiver<TofndKeygenOutput>>,
+        keygen_outputs: TofndResult<Vec<TofnKeygenOutput>>,
         stream_out_sender: &mut mpsc::UnboundedSender<Result<proto::MessageOut, Status>>,
         key_uid_reservation: KeyReservation,
         keygen_init: KeygenInitSanitized,
     ) -> TofndResult<()> {
-        // wait all keygen threads and aggregate results
+        // try to process keygen outputs
+        let processed_outputs = keygen_outputs.and_then(|keygen_outputs| {
+            Self::process_keygen_outputs(&keygen_init, keygen_outputs, stream_out_sender)
+        });
         // can't use `map_err` because of `.await` func :(
-        let keygen_outputs = match Self::aggregate_keygen_outputs(aggregator_receivers).await {
-            Ok(keygen_outputs) => keygen_outputs,
+        let (pub_key, group_recover_info, secret_key_shares) = match processed_outputs {
+            Ok(processed_outputs) => processed_outputs,
             Err(err) => {
                 self.kv_manager
                     .kv()
@@ -51,10 +52,6 @@ impl Gg20Service {
             }
         };
 
-        // try to process keygen outputs
-        let (pub_key, group_recover_info, secret_key_shares) =
-            Self::process_keygen_outputs(&keygen_init, keygen_outputs, stream_out_sender)?;
-
         // try to retrieve private recovery info from all shares
         let private_recover_info =
             Self::get_private_recovery_data(&secret_key_shares).map_err(|err| anyhow!(err))?;
@@ -74,6 +71,13 @@ impl Gg20Service {
             .await
             .map_err(|err| anyhow!(err))?;
 
+        // report success only after the data is on disk, whatever the durability policy
+        self.kv_manager
+            .kv()
+            .flush()
+            .await
+            .map_err(|err| anyhow!(err))?;
+
         // try to send result
         Ok(
             stream_out_sender.send(Ok(proto::MessageOut::new_keygen_result(
@@ -185,7 +189,7 @@ impl Gg20Service {
     }
 
     /// wait all keygen threads and get keygen outputs
-    async fn aggregate_keygen_outputs(
+    pub(super) async fn aggregate_keygen_outputs(
         aggregator_receivers: Vec<Receiver<TofndKeygenOutput>>,
     ) -> TofndResult<Vec<TofnKeygenOutput>> {
         let mut keygen_outputs = Vec::with_capacity(aggregator_receivers.len());
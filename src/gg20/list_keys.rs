@@ -0,0 +1,61 @@
+//! This module handles the list_keys gRPC.
+//! Iterates the kv-store and returns the public metadata of every stored [PartyInfo].
+
+use super::{proto, service::Gg20Service, types::PartyInfo};
+use crate::{mnemonic::MNEMONIC_KEY, multisig::MULTISIG_KEY_PREFIX};
+
+use std::convert::{TryFrom, TryInto};
+
+// logging
+use tracing::warn;
+
+// error handling
+use crate::TofndResult;
+
+impl Gg20Service {
+    pub(super) async fn handle_list_keys(&self) -> TofndResult<Vec<proto::KeyInfo>> {
+        let records = self.kv_manager.kv().get_all().await?;
+
+        let mut keys = Vec::with_capacity(records.len());
+        for (key_uid, value) in records {
+            // the mnemonic and multisig keys are stored in the same kv-store, but they are not gg20 keys
+            if key_uid == MNEMONIC_KEY || key_uid.starts_with(MULTISIG_KEY_PREFIX) {
+                continue;
+            }
+
+            let party_info: PartyInfo = match value.try_into() {
+                Ok(party_info) => party_info,
+                Err(err) => {
+                    warn!("Skipping key {} while listing keys: {}", key_uid, err);
+                    continue;
+                }
+            };
+
+            match party_info.key_info(key_uid.clone()) {
+                Ok(key_info) => keys.push(key_info),
+                Err(err) => warn!("Skipping key {} while listing keys: {}", key_uid, err),
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+impl PartyInfo {
+    /// get the public metadata of a stored key
+    fn key_info(&self, key_uid: String) -> TofndResult<proto::KeyInfo> {
+        Ok(proto::KeyInfo {
+            key_uid,
+            threshold: u32::try_from(self.common.threshold())?,
+            party_uids: self.tofnd.party_uids.clone(),
+            party_share_counts: self
+                .tofnd
+                .share_counts
+                .iter()
+                .map(|count| u32::try_from(*count))
+                .collect::<Result<Vec<u32>, _>>()?,
+            my_party_index: u32::try_from(self.tofnd.index)?,
+            pub_key: self.common.encoded_pubkey(),
+        })
+    }
+}
@@ -1,6 +1,7 @@
 //! [proto::gg20_server::Gg20] gRPC server API
 //! Available gRPCs are:
 //!     [recover] - Recovers private data of a party provided a mnemonic.
+//!     [key_presence] - Checks if a key exists in the kv-store.
 //!     [keygen] - Starts keygen.
 //!     [sign] - Starts sing.
 
@@ -8,7 +9,7 @@
 use super::proto;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tonic::{Request, Response, Status};
+use tonic::{metadata::MetadataValue, Request, Response, Status};
 pub mod proto_helpers;
 
 // logging
@@ -16,15 +17,26 @@ use tracing::{error, info, span, Level};
 
 // gRPC
 mod broadcast;
+mod compute;
+mod delete_key;
 mod key_presence;
 mod keygen;
+mod list_keys;
 mod protocol;
 mod recover;
+mod registry;
 pub mod service;
+mod session;
+mod sessions;
 mod sign;
 pub mod types;
 use types::*;
 
+/// response header of the keygen gRPC that tells whether the keygen is queued
+const SESSION_STATUS_HEADER: &str = "x-tofnd-session-status";
+const KEYGEN_QUEUED: &str = "queued";
+const KEYGEN_RUNNING: &str = "running";
+
 #[tonic::async_trait]
 impl proto::gg20_server::Gg20 for service::Gg20Service {
     type KeygenStream = UnboundedReceiverStream<Result<proto::MessageOut, tonic::Status>>;
@@ -79,6 +91,8 @@ impl proto::gg20_server::Gg20 for service::Gg20Service {
     }
 
     /// Keygen streaming gRPC. See [keygen].
+    /// The `x-tofnd-session-status` header of the response is `queued` if the keygen waits for a
+    /// running keygen to end, and `running` otherwise.
     async fn keygen(
         &self,
         request: Request<tonic::Streaming<proto::MessageIn>>,
@@ -92,9 +106,19 @@ impl proto::gg20_server::Gg20 for service::Gg20Service {
         let s = span.clone();
         let gg20 = self.clone();
 
+        // take a keygen slot now, so that the client learns whether the keygen is queued
+        let slot = self.keygen_slots.take();
+        let status = match slot.is_queued() {
+            true => KEYGEN_QUEUED,
+            false => KEYGEN_RUNNING,
+        };
+
         tokio::spawn(async move {
             // can't return an error from a spawned thread
-            if let Err(e) = gg20.handle_keygen(stream_in, msg_sender.clone(), s).await {
+            if let Err(e) = gg20
+                .handle_keygen(stream_in, msg_sender.clone(), slot, s)
+                .await
+            {
                 error!("keygen failure: {:?}", e.to_string());
                 // we can't handle errors in tokio threads. Log error if we are unable to send the status code to client.
                 if let Err(e) = msg_sender.send(Err(Status::invalid_argument(e.to_string()))) {
@@ -102,7 +126,12 @@ impl proto::gg20_server::Gg20 for service::Gg20Service {
                 }
             }
         });
-        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+
+        let mut response = Response::new(UnboundedReceiverStream::new(rx));
+        response
+            .metadata_mut()
+            .insert(SESSION_STATUS_HEADER, MetadataValue::from_static(status));
+        Ok(response)
     }
 
     /// Sign sreaming gRPC. See [sign].
@@ -132,3 +161,111 @@ impl proto::gg20_server::Gg20 for service::Gg20Service {
         Ok(Response::new(UnboundedReceiverStream::new(rx)))
     }
 }
+
+/// [proto::gg20_admin_server::Gg20Admin] gRPC server API
+/// Available gRPCs are:
+///     [list_keys] - Lists the metadata of all keys in the kv-store.
+///     [delete_key] - Archives or permanently deletes a key from the kv-store.
+///     [sessions] - Lists or cancels the running keygen and sign sessions.
+#[tonic::async_trait]
+impl proto::gg20_admin_server::Gg20Admin for service::Gg20Service {
+    /// ListKeys unary gRPC. See [list_keys].
+    async fn list_keys(
+        &self,
+        request: tonic::Request<proto::ListKeysRequest>,
+    ) -> Result<Response<proto::ListKeysResponse>, Status> {
+        let _ = request.into_inner();
+
+        let keys = match self.handle_list_keys().await {
+            Ok(keys) => {
+                info!("Listed {} keys successfully!", keys.len());
+                keys
+            }
+            Err(err) => {
+                error!("Unable to list keys: {}", err);
+                return Err(Status::internal(err.to_string()));
+            }
+        };
+
+        Ok(Response::new(proto::ListKeysResponse { keys }))
+    }
+
+    /// DeleteKey unary gRPC. See [delete_key].
+    async fn delete_key(
+        &self,
+        request: tonic::Request<proto::DeleteKeyRequest>,
+    ) -> Result<Response<proto::DeleteKeyResponse>, Status> {
+        let request = request.into_inner();
+
+        let response = match self.handle_delete_key(request).await {
+            Ok(res) => {
+                info!("Key deletion completed successfully!");
+                res
+            }
+            Err(err) => {
+                error!("Unable to complete key deletion: {}", err);
+                proto::delete_key_response::Response::Fail
+            }
+        };
+
+        Ok(Response::new(proto::DeleteKeyResponse {
+            response: response as i32,
+        }))
+    }
+
+    /// ArchiveKey unary gRPC. See [delete_key].
+    async fn archive_key(
+        &self,
+        request: tonic::Request<proto::ArchiveKeyRequest>,
+    ) -> Result<Response<proto::ArchiveKeyResponse>, Status> {
+        let request = request.into_inner();
+
+        let response = match self.handle_archive_key(request).await {
+            Ok(res) => {
+                info!("Key archival completed successfully!");
+                res
+            }
+            Err(err) => {
+                error!("Unable to complete key archival: {}", err);
+                proto::archive_key_response::Response::Fail
+            }
+        };
+
+        Ok(Response::new(proto::ArchiveKeyResponse {
+            response: response as i32,
+        }))
+    }
+
+    /// ListSessions unary gRPC. See [sessions].
+    async fn list_sessions(
+        &self,
+        request: tonic::Request<proto::ListSessionsRequest>,
+    ) -> Result<Response<proto::ListSessionsResponse>, Status> {
+        let _ = request.into_inner();
+
+        let sessions = self.handle_list_sessions().await;
+        info!("Listed {} sessions successfully!", sessions.len());
+
+        Ok(Response::new(proto::ListSessionsResponse { sessions }))
+    }
+
+    /// CancelSession unary gRPC. See [sessions].
+    async fn cancel_session(
+        &self,
+        request: tonic::Request<proto::CancelSessionRequest>,
+    ) -> Result<Response<proto::CancelSessionResponse>, Status> {
+        let request = request.into_inner();
+
+        let response = match self.handle_cancel_session(request).await {
+            Ok(res) => res,
+            Err(err) => {
+                error!("Unable to cancel session: {}", err);
+                proto::cancel_session_response::Response::Fail
+            }
+        };
+
+        Ok(Response::new(proto::CancelSessionResponse {
+            response: response as i32,
+        }))
+    }
+}
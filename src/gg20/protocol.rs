@@ -1,13 +1,15 @@
 //! Abstract functionality used by keygen, sign, etc.
 
+use std::sync::Arc;
 use tofn::{
     collections::TypedUsize,
     sdk::api::{Protocol, ProtocolOutput, Round},
 };
 
 // tonic cruft
-use super::{proto, ProtocolCommunication};
+use super::{compute::ComputePool, proto, registry::SessionProgress, ProtocolCommunication};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout_at, Duration, Instant};
 
 // logging
 use tracing::{debug, error, span, warn, Level, Span};
@@ -17,35 +19,52 @@ use crate::TofndResult;
 use anyhow::anyhow;
 
 /// execute gg20 protocol
+/// Rounds are executed and incoming messages are processed on the `compute_pool`, since they are CPU-heavy.
+/// If the messages of a round do not arrive within `round_timeout`, the round is executed without
+/// them and tofn reports the parties that did not send them with [tofn::sdk::api::Fault::MissingMessage].
+/// The current round and the received messages are recorded in `progress`, if given.
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn execute_protocol<F, K, P, const MAX_MSG_IN_LEN: usize>(
     mut party: Protocol<F, K, P, MAX_MSG_IN_LEN>,
     mut chans: ProtocolCommunication<
-        Option<proto::TrafficIn>,
+        Option<Arc<proto::TrafficIn>>,
         Result<proto::MessageOut, tonic::Status>,
     >,
     party_uids: &[String],
     party_share_counts: &[usize],
+    round_timeout: Option<Duration>,
+    compute_pool: &ComputePool,
+    progress: Option<&SessionProgress>,
     span: Span,
 ) -> TofndResult<ProtocolOutput<F, P>>
 where
     K: Clone,
+    Protocol<F, K, P, MAX_MSG_IN_LEN>: Send + 'static,
+    Round<F, K, P, MAX_MSG_IN_LEN>: Send + 'static,
 {
     // set up counters for logging
     let total_num_of_shares = party_share_counts.iter().fold(0, |acc, s| acc + *s);
     let total_round_p2p_msgs = total_num_of_shares * (total_num_of_shares - 1); // total number of messages is n(n-1)
 
     let mut round_count = 0;
-    while let Protocol::NotDone(mut round) = party {
+    while let Protocol::NotDone(round) = party {
         round_count += 1;
+        if let Some(progress) = progress {
+            progress.round_started(round_count);
+        }
 
         // handle outgoing traffic
         handle_outgoing(&chans.sender, &round, party_uids, round_count, span.clone())?;
 
         // collect incoming traffic
-        handle_incoming(
+        let round = handle_incoming(
             &mut chans.receiver,
-            &mut round,
+            round,
             party_uids,
+            party_share_counts,
+            round_timeout,
+            compute_pool,
+            progress,
             total_round_p2p_msgs,
             total_num_of_shares,
             round_count,
@@ -54,8 +73,9 @@ where
         .await?;
 
         // check if everything was ok this round
-        party = round
-            .execute_next_round()
+        party = compute_pool
+            .run(move || round.execute_next_round())
+            .await?
             .map_err(|_| anyhow!("Error in tofn::execute_next_round"))?;
     }
 
@@ -111,22 +131,50 @@ fn handle_outgoing<F, K, P, const MAX_MSG_IN_LEN: usize>(
     Ok(())
 }
 
+/// Collect the messages of a round. Returns the round once it has all messages, or when the round times out.
+#[allow(clippy::too_many_arguments)]
 async fn handle_incoming<F, K, P, const MAX_MSG_IN_LEN: usize>(
-    receiver: &mut UnboundedReceiver<Option<proto::TrafficIn>>,
-    round: &mut Round<F, K, P, MAX_MSG_IN_LEN>,
+    receiver: &mut UnboundedReceiver<Option<Arc<proto::TrafficIn>>>,
+    mut round: Round<F, K, P, MAX_MSG_IN_LEN>,
     party_uids: &[String],
+    party_share_counts: &[usize],
+    round_timeout: Option<Duration>,
+    compute_pool: &ComputePool,
+    progress: Option<&SessionProgress>,
     total_round_p2p_msgs: usize,
     total_num_of_shares: usize,
     round_count: usize,
     span: Span,
-) -> TofndResult<()> {
+) -> TofndResult<Round<F, K, P, MAX_MSG_IN_LEN>>
+where
+    Round<F, K, P, MAX_MSG_IN_LEN>: Send + 'static,
+{
     let mut p2p_msg_count = 0;
     let mut bcast_msg_count = 0;
+    // messages received from each party in this round
+    let mut party_msg_counts = vec![0; party_uids.len()];
+    let deadline = round_timeout.map(|timeout| Instant::now() + timeout);
 
     // loop until no more messages are needed for this round
     while round.expecting_more_msgs_this_round() {
         // get internal message from broadcaster
-        let traffic = receiver.recv().await.ok_or(format!(
+        let traffic = match deadline {
+            Some(deadline) => match timeout_at(deadline, receiver.recv()).await {
+                Ok(traffic) => traffic,
+                Err(_) => {
+                    // tofn reports the parties whose messages are missing when the round is executed
+                    let missing =
+                        missing_parties(&round, party_uids, party_share_counts, &party_msg_counts);
+                    warn!(
+                        "round {} timed out: missing messages from parties {:?}",
+                        round_count, missing
+                    );
+                    break;
+                }
+            },
+            None => receiver.recv().await,
+        };
+        let traffic = traffic.ok_or(format!(
             "{}: stream closed by client before protocol has completed",
             round_count
         ));
@@ -148,41 +196,77 @@ async fn handle_incoming<F, K, P, const MAX_MSG_IN_LEN: usize>(
             }
         };
 
-        // We have to spawn a new span it in each loop because `async` calls don't work well with tracing
-        // See details on how we need to make spans curve around `.await`s here:
-        // https://docs.rs/tracing/0.1.25/tracing/span/index.html#entering-a-span
-        let recv_span = span!(parent: &span, Level::DEBUG, "incoming", round = round_count);
-        let _start = recv_span.enter();
+        let from = {
+            // We have to spawn a new span it in each loop because `async` calls don't work well with tracing
+            // See details on how we need to make spans curve around `.await`s here:
+            // https://docs.rs/tracing/0.1.25/tracing/span/index.html#entering-a-span
+            let recv_span = span!(parent: &span, Level::DEBUG, "incoming", round = round_count);
+            let _start = recv_span.enter();
 
-        // log incoming message
-        if traffic.is_broadcast {
-            bcast_msg_count += 1;
-            debug!(
-                "got incoming bcast message {}/{}",
-                bcast_msg_count, total_num_of_shares
-            );
-        } else {
-            p2p_msg_count += 1;
-            debug!(
-                "got incoming p2p message {}/{}",
-                p2p_msg_count, total_round_p2p_msgs
-            );
-        }
+            // log incoming message
+            if traffic.is_broadcast {
+                bcast_msg_count += 1;
+                debug!(
+                    "got incoming bcast message {}/{}",
+                    bcast_msg_count, total_num_of_shares
+                );
+            } else {
+                p2p_msg_count += 1;
+                debug!(
+                    "got incoming p2p message {}/{}",
+                    p2p_msg_count, total_round_p2p_msgs
+                );
+            }
+
+            // get sender's party index
+            let from = party_uids
+                .iter()
+                .position(|uid| uid == &traffic.from_party_uid)
+                .ok_or_else(|| anyhow!("from uid does not exist in party uids"))?;
+            party_msg_counts[from] += 1;
+            if let Some(progress) = progress {
+                progress.msg_received(round_count);
+            }
+            from
+        };
 
-        // get sender's party index
-        let from = party_uids
-            .iter()
-            .position(|uid| uid == &traffic.from_party_uid)
-            .ok_or_else(|| anyhow!("from uid does not exist in party uids"))?;
-
-        // try to set a message
-        if round
-            .msg_in(TypedUsize::from_usize(from), &traffic.payload)
-            .is_err()
-        {
+        // try to set a message; the round is moved to the compute pool and back
+        let (next_round, res) = compute_pool
+            .run(move || {
+                let res = round.msg_in(TypedUsize::from_usize(from), &traffic.payload);
+                (round, res)
+            })
+            .await?;
+        round = next_round;
+        if res.is_err() {
             return Err(anyhow!("error calling tofn::msg_in with [from: {}]", from));
         };
     }
 
-    Ok(())
+    Ok(round)
+}
+
+/// Returns the uids of the parties that sent fewer messages than expected in this round.
+/// Each share of a party sends a bcast and a p2p to every other share if the round has them.
+fn missing_parties<F, K, P, const MAX_MSG_IN_LEN: usize>(
+    round: &Round<F, K, P, MAX_MSG_IN_LEN>,
+    party_uids: &[String],
+    party_share_counts: &[usize],
+    party_msg_counts: &[usize],
+) -> Vec<String> {
+    let total_num_of_shares: usize = party_share_counts.iter().sum();
+    let mut msgs_per_share = 0;
+    if round.bcast_out().is_some() {
+        msgs_per_share += 1;
+    }
+    if round.p2ps_out().is_some() {
+        msgs_per_share += total_num_of_shares - 1;
+    }
+
+    party_uids
+        .iter()
+        .zip(party_share_counts.iter().zip(party_msg_counts.iter()))
+        .filter(|(_, (share_count, msg_count))| **msg_count < *share_count * msgs_per_share)
+        .map(|(uid, _)| uid.clone())
+        .collect()
 }
@@ -147,11 +147,14 @@ impl Gg20Service {
         keygen_init_sanitized: KeygenInitSanitized,
         secret_key_shares: Vec<SecretKeyShare>,
     ) -> TofndResult<()> {
-        // try to make a reservation
+        // try to make a reservation on behalf of the recovered keygen session
         let reservation = self
             .kv_manager
             .kv()
-            .reserve_key(keygen_init_sanitized.new_key_uid)
+            .reserve_key(
+                keygen_init_sanitized.new_key_uid.clone(),
+                &keygen_init_sanitized.new_key_uid,
+            )
             .await
             .map_err(|err| anyhow!("failed to complete reservation: {}", err))?;
         // acquire kv-data
@@ -162,11 +165,17 @@ impl Gg20Service {
             keygen_init_sanitized.my_index,
         );
         // try writing the data to the kv-store
+        self.kv_manager
+            .kv()
+            .put(reservation, kv_data.try_into()?)
+            .await
+            .map_err(|err| anyhow!("failed to update kv store: {}", err))?;
+        // recovery succeeds only after the data is on disk
         Ok(self
             .kv_manager
             .kv()
-            .put(reservation, kv_data.try_into()?)
+            .flush()
             .await
-            .map_err(|err| anyhow!("failed to update kv store: {}", err))?)
+            .map_err(|err| anyhow!("failed to flush kv store: {}", err))?)
     }
 }
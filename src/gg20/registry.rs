@@ -0,0 +1,315 @@
+//! Registry of the running keygen and sign sessions.
+//! A session registers itself once it has received its init message, under its `new_key_uid` or
+//! `new_sig_uid`, and stays registered until it ends and all of its tasks have ended. The registry
+//! records the state of each session, i.e. whether it is queued, its current round and the messages
+//! it received in each round, and allows to cancel a session. See [super::sessions] for the gRPCs that expose it.
+
+use super::proto::{self, session_info::Kind, session_info::State};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+
+/// Progress of a session
+#[derive(Debug, Default)]
+struct Progress {
+    queued: bool,
+    /// current round; 0 before the first round starts
+    round: usize,
+    /// messages received in each round; the first element is the first round
+    round_msg_counts: Vec<usize>,
+}
+
+/// A registered session
+struct Session {
+    kind: Kind,
+    uid: String,
+    party_uids: Vec<String>,
+    start_time: SystemTime,
+    progress: Mutex<Progress>,
+    cancel: watch::Sender<bool>,
+}
+
+impl Session {
+    fn progress(&self) -> std::sync::MutexGuard<'_, Progress> {
+        // progress is only used for reporting, so it is still usable if an updater panicked
+        self.progress
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn info(&self) -> proto::SessionInfo {
+        let progress = self.progress();
+        let state = match progress.queued {
+            true => State::Queued,
+            false => State::Running,
+        };
+        proto::SessionInfo {
+            kind: self.kind as i32,
+            session_uid: self.uid.clone(),
+            state: state as i32,
+            round: to_u32(progress.round),
+            round_msg_counts: progress
+                .round_msg_counts
+                .iter()
+                .map(|c| to_u32(*c))
+                .collect(),
+            start_time: self
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            party_uids: self.party_uids.clone(),
+        }
+    }
+}
+
+fn to_u32(count: usize) -> u32 {
+    u32::try_from(count).unwrap_or(u32::MAX)
+}
+
+/// The running sessions of a [super::service::Gg20Service]
+#[derive(Clone, Default)]
+pub(super) struct SessionRegistry {
+    // sessions are stored under an id of their own, because clients can reuse a session uid
+    sessions: Arc<Mutex<HashMap<u64, Arc<Session>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SessionRegistry {
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<Session>>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Register a session. The session is removed from the registry when the returned
+    /// [Registration] and all of its [SessionGuard]s are dropped.
+    pub(super) fn register(
+        &self,
+        kind: Kind,
+        uid: &str,
+        party_uids: &[String],
+        queued: bool,
+    ) -> Registration {
+        let (cancel, cancelled) = watch::channel(false);
+        let session = Arc::new(Session {
+            kind,
+            uid: uid.to_string(),
+            party_uids: party_uids.to_vec(),
+            start_time: SystemTime::now(),
+            progress: Mutex::new(Progress {
+                queued,
+                ..Progress::default()
+            }),
+            cancel,
+        });
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions().insert(id, session.clone());
+
+        Registration {
+            listing: Arc::new(Listing {
+                registry: self.clone(),
+                id,
+            }),
+            session,
+            cancelled,
+        }
+    }
+
+    /// The info of all running sessions, oldest first
+    pub(super) fn list(&self) -> Vec<proto::SessionInfo> {
+        // ids are assigned in the order of registration
+        let mut sessions: Vec<_> = self
+            .sessions()
+            .iter()
+            .map(|(id, session)| (*id, session.clone()))
+            .collect();
+        sessions.sort_by_key(|(id, _)| *id);
+        sessions.iter().map(|(_, session)| session.info()).collect()
+    }
+
+    /// Cancel all running sessions with `uid`. Returns the number of cancelled sessions.
+    pub(super) fn cancel(&self, uid: &str) -> usize {
+        let sessions = self.sessions();
+        let matching = sessions.values().filter(|session| session.uid == uid);
+        let mut count = 0;
+        for session in matching {
+            // the session may have ended in the meantime
+            let _ = session.cancel.send(true);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Removes a session from the registry when it is dropped
+struct Listing {
+    registry: SessionRegistry,
+    id: u64,
+}
+
+impl Drop for Listing {
+    fn drop(&mut self) {
+        self.registry.sessions().remove(&self.id);
+    }
+}
+
+/// The registration of a session
+pub(super) struct Registration {
+    listing: Arc<Listing>,
+    session: Arc<Session>,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl Registration {
+    /// Keeps the session listed after the registration is dropped, e.g. until the tasks of the session end
+    pub(super) fn guard(&self) -> SessionGuard {
+        SessionGuard {
+            _listing: self.listing.clone(),
+        }
+    }
+
+    /// The session left the queue
+    pub(super) fn set_running(&self) {
+        self.session.progress().queued = false;
+    }
+
+    /// Handle to record the progress of the protocol
+    pub(super) fn progress(&self) -> SessionProgress {
+        SessionProgress(self.session.clone())
+    }
+
+    /// Resolves when the session is cancelled with [SessionRegistry::cancel]
+    pub(super) async fn cancelled(&self) {
+        let mut cancelled = self.cancelled.clone();
+        while !*cancelled.borrow() {
+            // the sender lives as long as the registration
+            if cancelled.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Keeps a session listed in the registry while it is held
+#[derive(Clone)]
+pub(super) struct SessionGuard {
+    _listing: Arc<Listing>,
+}
+
+/// Records the progress of a session's protocol
+#[derive(Clone)]
+pub(super) struct SessionProgress(Arc<Session>);
+
+impl SessionProgress {
+    /// `round` started; rounds are counted from 1
+    pub(super) fn round_started(&self, round: usize) {
+        let mut progress = self.0.progress();
+        progress.round = round;
+        if progress.round_msg_counts.len() < round {
+            progress.round_msg_counts.resize(round, 0);
+        }
+    }
+
+    /// A message of `round` was received
+    pub(super) fn msg_received(&self, round: usize) {
+        let mut progress = self.0.progress();
+        if progress.round_msg_counts.len() < round {
+            progress.round_msg_counts.resize(round, 0);
+        }
+        progress.round_msg_counts[round - 1] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn party_uids() -> Vec<String> {
+        vec!["alice".to_string(), "bob".to_string()]
+    }
+
+    #[test]
+    fn registration_lifetime() {
+        let registry = SessionRegistry::default();
+        let keygen = registry.register(Kind::Keygen, "key", &party_uids(), false);
+        let sign = registry.register(Kind::Sign, "sig", &party_uids(), false);
+
+        let sessions = registry.list();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].session_uid, "key");
+        assert_eq!(sessions[0].kind, Kind::Keygen as i32);
+        assert_eq!(sessions[0].party_uids, party_uids());
+        assert_eq!(sessions[1].session_uid, "sig");
+        assert_eq!(sessions[1].kind, Kind::Sign as i32);
+
+        drop(keygen);
+        let sessions = registry.list();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_uid, "sig");
+
+        drop(sign);
+        assert!(registry.list().is_empty());
+
+        // a guard keeps the session listed after its registration is dropped
+        let keygen = registry.register(Kind::Keygen, "key", &party_uids(), false);
+        let guard = keygen.guard();
+        drop(keygen);
+        assert_eq!(registry.list().len(), 1);
+        drop(guard);
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn progress() {
+        let registry = SessionRegistry::default();
+        let registration = registry.register(Kind::Keygen, "key", &party_uids(), true);
+        let info = &registry.list()[0];
+        assert_eq!(info.state, State::Queued as i32);
+        assert_eq!(info.round, 0);
+        assert!(info.round_msg_counts.is_empty());
+
+        registration.set_running();
+        let progress = registration.progress();
+        progress.round_started(1);
+        progress.msg_received(1);
+        progress.msg_received(1);
+        progress.round_started(2);
+        progress.msg_received(2);
+
+        let info = &registry.list()[0];
+        assert_eq!(info.state, State::Running as i32);
+        assert_eq!(info.round, 2);
+        assert_eq!(info.round_msg_counts, vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let registry = SessionRegistry::default();
+        let first = registry.register(Kind::Sign, "sig", &party_uids(), false);
+        let second = registry.register(Kind::Sign, "sig", &party_uids(), false);
+        let other = registry.register(Kind::Sign, "other-sig", &party_uids(), false);
+
+        assert_eq!(registry.cancel("absent"), 0);
+        assert_eq!(registry.cancel("sig"), 2);
+
+        let wait = Duration::from_secs(5);
+        timeout(wait, first.cancelled()).await.unwrap();
+        timeout(wait, second.cancelled()).await.unwrap();
+        // a cancelled session stays cancelled
+        timeout(wait, first.cancelled()).await.unwrap();
+        assert!(timeout(Duration::from_millis(100), other.cancelled())
+            .await
+            .is_err());
+    }
+}
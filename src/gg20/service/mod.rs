@@ -1,8 +1,10 @@
 //! This mod includes the service implementation derived from
 
-use super::proto;
+use super::{compute::ComputePool, proto, registry::SessionRegistry, session::KeygenSlots};
 use crate::config::Config;
 use crate::kv_manager::KvManager;
+use crate::TofndResult;
+use std::sync::Arc;
 
 #[cfg(feature = "malicious")]
 pub mod malicious;
@@ -12,9 +14,23 @@ pub mod malicious;
 pub struct Gg20Service {
     pub(super) kv_manager: KvManager,
     pub(super) cfg: Config,
+    pub(super) compute_pool: Arc<ComputePool>,
+    pub(super) keygen_slots: KeygenSlots,
+    pub(super) sessions: SessionRegistry,
 }
 
-/// create a new Gg20 gRPC server
-pub fn new_service(cfg: Config, kv_manager: KvManager) -> impl proto::gg20_server::Gg20 {
-    Gg20Service { kv_manager, cfg }
+/// create a new Gg20 gRPC server; the service also serves the [proto::gg20_admin_server::Gg20Admin] gRPCs
+pub fn new_service(
+    cfg: Config,
+    kv_manager: KvManager,
+) -> TofndResult<impl proto::gg20_server::Gg20 + proto::gg20_admin_server::Gg20Admin + Clone> {
+    let compute_pool = Arc::new(ComputePool::new(cfg.compute_threads)?);
+    let keygen_slots = KeygenSlots::new(cfg.max_concurrent_keygens);
+    Ok(Gg20Service {
+        kv_manager,
+        cfg,
+        compute_pool,
+        keygen_slots,
+        sessions: SessionRegistry::default(),
+    })
 }
@@ -0,0 +1,233 @@
+//! Tasks of a keygen or sign session.
+//! Each share of a session is executed in its own task, and a broadcaster task routes the messages
+//! of the client to the shares. All tasks are owned by the [SessionTasks] of the session, which
+//! aborts the tasks that are still running when the session ends: after the result is sent, when a
+//! share fails, or when the client disconnects. The session stays listed in the
+//! [super::registry::SessionRegistry] until all of its tasks have ended.
+//!
+//! The number of keygens that are executed at the same time can be limited with [KeygenSlots].
+//! A keygen that finds all slots taken is queued until a running keygen ends.
+
+use super::{proto, registry::SessionGuard};
+use std::{future::Future, sync::Arc};
+use tokio::{
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tonic::Status;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+/// The tasks spawned by a session. Dropping it aborts all tasks that are still running.
+pub(super) struct SessionTasks {
+    handles: Vec<JoinHandle<()>>,
+    guard: SessionGuard,
+}
+
+impl SessionTasks {
+    /// Tasks of the session of `guard`; each task holds the guard until it ends
+    pub(super) fn new(guard: SessionGuard) -> Self {
+        Self {
+            handles: vec![],
+            guard,
+        }
+    }
+
+    /// Spawn a task of the session
+    pub(super) fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = self.guard.clone();
+        self.handles.push(tokio::spawn(async move {
+            // dropped when the task ends or is aborted
+            let _guard = guard;
+            task.await
+        }));
+    }
+}
+
+impl Drop for SessionTasks {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            handle.abort();
+        }
+    }
+}
+
+/// Limits the number of keygens that are executed at the same time. Queued keygens get a slot in
+/// the order in which they were queued.
+#[derive(Clone)]
+pub(super) struct KeygenSlots {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl KeygenSlots {
+    /// `max` slots; `None` executes all keygens at once
+    pub(super) fn new(max: Option<usize>) -> Self {
+        Self {
+            semaphore: max.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    /// Take a free slot, or a place in the queue if all slots are taken
+    pub(super) fn take(&self) -> KeygenSlot {
+        let semaphore = match &self.semaphore {
+            Some(semaphore) => semaphore.clone(),
+            None => return KeygenSlot::default(),
+        };
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => KeygenSlot {
+                permit: Some(permit),
+                queue: None,
+            },
+            Err(_) => KeygenSlot {
+                permit: None,
+                queue: Some(semaphore),
+            },
+        }
+    }
+}
+
+/// The slot of a keygen. The slot is freed when it is dropped.
+#[derive(Default)]
+pub(super) struct KeygenSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    queue: Option<Arc<Semaphore>>,
+}
+
+impl KeygenSlot {
+    /// The keygen waits for a running keygen to end
+    pub(super) fn is_queued(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Wait until the keygen gets a slot. Returns immediately if it is not queued.
+    pub(super) async fn wait(&mut self) -> TofndResult<()> {
+        if let Some(queue) = &self.queue {
+            let permit = queue
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| anyhow!("keygen queue is closed"))?;
+            self.permit = Some(permit);
+            self.queue = None;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves when the client has dropped the stream of the session, i.e. it will not receive the result
+pub(super) async fn client_disconnected(
+    stream_out_sender: &mpsc::UnboundedSender<Result<proto::MessageOut, Status>>,
+) {
+    stream_out_sender.closed().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gg20::{proto::session_info::Kind, registry::SessionRegistry};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn session_tasks(registry: &SessionRegistry) -> SessionTasks {
+        let registration = registry.register(Kind::Sign, "sig", &[], false);
+        SessionTasks::new(registration.guard())
+    }
+
+    #[tokio::test]
+    async fn drop_aborts_running_tasks() {
+        // each task holds a sender, so the channel closes once no task is alive
+        let (alive, mut all_ended) = mpsc::unbounded_channel::<()>();
+        let (share_sender, mut share_receiver) = mpsc::unbounded_channel::<()>();
+
+        let registry = SessionRegistry::default();
+        let mut tasks = session_tasks(&registry);
+        for _ in 0..3 {
+            let alive = alive.clone();
+            tasks.spawn(async move {
+                let _alive = alive;
+                std::future::pending::<()>().await;
+            });
+        }
+        // a task that waits on a channel that is never closed, like a share waiting for messages
+        let alive_share = alive.clone();
+        tasks.spawn(async move {
+            let _alive = alive_share;
+            let _ = share_receiver.recv().await;
+        });
+        drop(alive);
+
+        // the session is listed until its tasks have ended
+        assert_eq!(registry.list().len(), 1);
+        drop(tasks);
+        assert_eq!(timeout(TIMEOUT, all_ended.recv()).await.unwrap(), None);
+        // the share was aborted although its channel is still open
+        assert!(share_sender.send(()).is_err());
+        timeout(TIMEOUT, async {
+            while !registry.list().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_not_affected() {
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+
+        let registry = SessionRegistry::default();
+        let mut tasks = session_tasks(&registry);
+        tasks.spawn(async move {
+            let _ = result_sender.send(42);
+        });
+
+        assert_eq!(timeout(TIMEOUT, result_receiver).await.unwrap(), Ok(42));
+        drop(tasks);
+    }
+
+    #[tokio::test]
+    async fn keygen_slots() {
+        let slots = KeygenSlots::new(Some(1));
+
+        let first = slots.take();
+        assert!(!first.is_queued());
+        let mut second = slots.take();
+        assert!(second.is_queued());
+
+        // the queued keygen gets the slot once the running keygen ends
+        assert!(timeout(Duration::from_millis(100), second.wait())
+            .await
+            .is_err());
+        drop(first);
+        timeout(TIMEOUT, second.wait()).await.unwrap().unwrap();
+        assert!(!second.is_queued());
+        assert!(slots.take().is_queued());
+    }
+
+    #[tokio::test]
+    async fn unlimited_keygen_slots() {
+        let slots = KeygenSlots::new(None);
+        let mut taken: Vec<_> = (0..100).map(|_| slots.take()).collect();
+        assert!(taken.iter().all(|slot| !slot.is_queued()));
+        taken[0].wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_disconnect() {
+        let (stream_out_sender, stream_out_receiver) = mpsc::unbounded_channel();
+
+        let disconnected = tokio::spawn(async move {
+            client_disconnected(&stream_out_sender).await;
+        });
+        drop(stream_out_receiver);
+
+        timeout(TIMEOUT, disconnected).await.unwrap().unwrap();
+    }
+}
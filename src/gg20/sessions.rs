@@ -0,0 +1,40 @@
+//! This module handles the list_sessions and cancel_session gRPCs.
+//! Both gRPCs operate on the [super::registry::SessionRegistry] of the running keygen and sign sessions.
+
+use super::{proto, service::Gg20Service};
+
+// logging
+use tracing::info;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+impl Gg20Service {
+    pub(super) async fn handle_list_sessions(&self) -> Vec<proto::SessionInfo> {
+        self.sessions.list()
+    }
+
+    /// Cancels all running sessions with the requested uid. A cancelled session aborts its tasks
+    /// and returns an error to its client; the key of a cancelled keygen is unreserved.
+    pub(super) async fn handle_cancel_session(
+        &self,
+        request: proto::CancelSessionRequest,
+    ) -> TofndResult<proto::cancel_session_response::Response> {
+        if request.session_uid.is_empty() {
+            return Err(anyhow!("session uid is empty"));
+        }
+
+        let cancelled = self.sessions.cancel(&request.session_uid);
+        if cancelled == 0 {
+            info!("No running session [{}] to cancel", request.session_uid);
+            return Ok(proto::cancel_session_response::Response::Absent);
+        }
+
+        info!(
+            "Cancelled {} running sessions [{}]",
+            cancelled, request.session_uid
+        );
+        Ok(proto::cancel_session_response::Response::Success)
+    }
+}
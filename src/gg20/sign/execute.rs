@@ -7,7 +7,8 @@ use super::{
     types::{Context, TofndSignOutput},
     Gg20Service, ProtocolCommunication,
 };
-use crate::gg20::protocol;
+use crate::gg20::{protocol, registry::SessionProgress};
+use std::sync::Arc;
 use tofn::gg20::sign::new_sign;
 
 // logging
@@ -22,10 +23,11 @@ impl Gg20Service {
     pub(super) async fn execute_sign(
         &self,
         chans: ProtocolCommunication<
-            Option<proto::TrafficIn>,
+            Option<Arc<proto::TrafficIn>>,
             Result<proto::MessageOut, tonic::Status>,
         >,
         ctx: &Context,
+        progress: Option<SessionProgress>,
         execute_span: Span,
     ) -> TofndSignOutput {
         // try to create sign with context
@@ -46,6 +48,9 @@ impl Gg20Service {
             // &ctx.sign_init.participant_uids,
             &ctx.sign_uids(),
             &ctx.sign_share_counts,
+            self.cfg.round_timeout,
+            &self.compute_pool,
+            progress.as_ref(),
             execute_span.clone(),
         )
         .await;
@@ -14,7 +14,13 @@
 //!
 //! All relevant helper structs and types are defined in [self::types]
 
-use super::{broadcast::broadcast_messages, proto, service::Gg20Service, ProtocolCommunication};
+use super::{
+    broadcast::{broadcast_messages, KnownParties},
+    proto::{self, session_info::Kind},
+    service::Gg20Service,
+    session::{client_disconnected, SessionTasks},
+    ProtocolCommunication,
+};
 
 // tonic cruft
 use tokio::sync::{mpsc, oneshot};
@@ -42,10 +48,11 @@ impl Gg20Service {
         mut stream_out_sender: mpsc::UnboundedSender<Result<proto::MessageOut, Status>>,
         sign_span: Span,
     ) -> TofndResult<()> {
-        // 1. Receive SignInit, open message, sanitize arguments -> init mod
+        // 1. Receive SignInit, open message, sanitize arguments and register the session -> init and registry mods
         // 2. Spawn N sign threads to execute the protocol in parallel; one of each of our shares -> execute mod
         // 3. Spawn 1 router thread to route messages from client to the respective sign thread -> routing mod
         // 4. Wait for all sign threads to finish and aggregate all responses -> result mod
+        // All threads are aborted when this function returns -> session mod
 
         // 1.
         // get SignInit message from stream and sanitize arguments
@@ -54,6 +61,14 @@ impl Gg20Service {
             .handle_sign_init(&mut stream_in, &mut stream_out, sign_span.clone())
             .await?;
 
+        // the session is listed and can be cancelled until this function returns
+        let registration = self.sessions.register(
+            Kind::Sign,
+            &sign_init.new_sig_uid,
+            &sign_init.participant_uids,
+            false,
+        );
+
         // 2.
         // find my share count to allocate channel vectors
         let my_share_count = party_info.shares.len();
@@ -67,6 +82,8 @@ impl Gg20Service {
         // create in and out channels for each share, and spawn as many threads
         let mut sign_senders = Vec::with_capacity(my_share_count);
         let mut aggregator_receivers = Vec::with_capacity(my_share_count);
+        // threads of this session; the ones still running are aborted when the session ends
+        let mut tasks = SessionTasks::new(registration.guard());
 
         for my_tofnd_subindex in 0..my_share_count {
             // channels for communication between router (sender) and protocol threads (receivers)
@@ -82,6 +99,8 @@ impl Gg20Service {
             let ctx = Context::new(sign_init.clone(), party_info.clone(), my_tofnd_subindex)?;
             // clone gg20 service because tokio thread takes ownership
             let gg20 = self.clone();
+            // all shares receive the same messages, so the first share records the progress of the session
+            let progress = (my_tofnd_subindex == 0).then(|| registration.progress());
 
             // set up log state
             let log_info = ctx.log_info();
@@ -89,9 +108,11 @@ impl Gg20Service {
             let execute_span = span!(parent: &sign_span, Level::INFO, "execute", state);
 
             // spawn sign threads
-            tokio::spawn(async move {
+            tasks.spawn(async move {
                 // get result of sign
-                let signature = gg20.execute_sign(chans, &ctx, execute_span.clone()).await;
+                let signature = gg20
+                    .execute_sign(chans, &ctx, progress, execute_span.clone())
+                    .await;
                 // send result to aggregator
                 let _ = aggregator_sender.send(signature);
             });
@@ -99,19 +120,23 @@ impl Gg20Service {
 
         // 3.
         // spin up broadcaster thread and return immediately
+        let parties = KnownParties::new(&sign_init.participant_uids);
         let span = sign_span.clone();
-        tokio::spawn(async move {
-            broadcast_messages(&mut stream_in, sign_senders, span).await;
+        tasks.spawn(async move {
+            broadcast_messages(&mut stream_in, sign_senders, parties, span).await;
         });
 
         // 4.
-        // wait for all sign threads to end, get responses, and return signature
-        Self::handle_results(
-            aggregator_receivers,
-            &mut stream_out_sender,
-            &sign_init.participant_uids,
-        )
-        .await?;
+        // wait for all sign threads to end, get responses, and return signature, unless the client disconnects or the sign is cancelled
+        tokio::select! {
+            res = Self::handle_results(
+                aggregator_receivers,
+                &mut stream_out_sender,
+                &sign_init.participant_uids,
+            ) => res?,
+            _ = client_disconnected(&stream_out) => return Err(anyhow!("client disconnected")),
+            _ = registration.cancelled() => return Err(anyhow!("sign was cancelled")),
+        }
 
         Ok(())
     }
@@ -98,4 +98,89 @@ impl PartyInfo {
             self.tofnd.party_uids,
         );
     }
+
+    /// Run a gg20 keygen of a single party that holds all `share_count` shares, and return its PartyInfo.
+    /// Used as a fixture of the values stored by keygen.
+    #[cfg(test)]
+    pub(crate) fn from_test_keygen(share_count: usize) -> Self {
+        use std::convert::TryInto;
+        use tofn::{
+            collections::TypedUsize,
+            gg20::keygen::{
+                create_party_keypair_and_zksetup_unsafe, new_keygen, KeygenPartyShareCounts,
+                SecretRecoveryKey,
+            },
+            sdk::api::Protocol,
+        };
+
+        let party_id = TypedUsize::from_usize(0);
+        let secret_recovery_key: SecretRecoveryKey = (&[42u8; 64][..]).try_into().unwrap();
+        let party_keygen_data =
+            create_party_keypair_and_zksetup_unsafe(party_id, &secret_recovery_key, b"fixture")
+                .unwrap();
+        let mut keygens: Vec<_> = (0..share_count)
+            .map(|subindex| {
+                new_keygen(
+                    KeygenPartyShareCounts::from_vec(vec![share_count]).unwrap(),
+                    share_count - 1,
+                    party_id,
+                    subindex,
+                    &party_keygen_data,
+                    #[cfg(feature = "malicious")]
+                    tofn::gg20::keygen::malicious::Behaviour::Honest,
+                )
+                .unwrap()
+            })
+            .collect();
+
+        // all shares execute the same rounds; deliver all messages of a round to all shares, as the router does
+        while keygens
+            .iter()
+            .any(|keygen| matches!(keygen, Protocol::NotDone(_)))
+        {
+            let rounds: Vec<_> = keygens
+                .into_iter()
+                .map(|keygen| match keygen {
+                    Protocol::NotDone(round) => round,
+                    Protocol::Done(_) => panic!("shares completed keygen in different rounds"),
+                })
+                .collect();
+
+            let mut msgs = vec![];
+            for round in rounds.iter() {
+                if let Some(bcast) = round.bcast_out() {
+                    msgs.push(bcast.clone());
+                }
+                if let Some(p2ps) = round.p2ps_out() {
+                    for (_, p2p) in p2ps.iter() {
+                        msgs.push(p2p.clone());
+                    }
+                }
+            }
+
+            keygens = rounds
+                .into_iter()
+                .map(|mut round| {
+                    for msg in msgs.iter() {
+                        round.msg_in(party_id, msg).unwrap();
+                    }
+                    round.execute_next_round().unwrap()
+                })
+                .collect();
+        }
+
+        let secret_key_shares = keygens
+            .into_iter()
+            .map(|keygen| match keygen {
+                Protocol::Done(Ok(secret_key_share)) => secret_key_share,
+                _ => panic!("keygen failed"),
+            })
+            .collect();
+        Self::get_party_info(
+            secret_key_shares,
+            vec!["party_uid".to_string()],
+            vec![share_count],
+            0,
+        )
+    }
 }
@@ -0,0 +1,352 @@
+//! Encrypted backup and restore of all records of the kv store, e.g. the mnemonic entropy,
+//! gg20 [crate::gg20::types::PartyInfo]s and multisig key records.
+//!
+//! A backup file consists of a header followed by the encrypted records:
+//!     magic (8 bytes) | version (2 bytes, LE) | salt (32 bytes) | nonce (24 bytes) | ciphertext
+//! The records are encrypted with [XChaCha20Poly1305] under a key derived from a
+//! user-supplied passphrase with [scrypt]. The header is authenticated as associated data,
+//! so any modification of the file is detected on restore.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use tofn::sdk::api::{deserialize, serialize};
+use zeroize::Zeroize;
+
+use crate::encrypted_sled::{Password, PasswordMethod};
+
+use super::{
+    error::{
+        InnerKvError::*,
+        InnerKvResult,
+        KvError::{BackupErr, RestoreErr},
+        KvResult,
+    },
+    migration::migrate_value,
+    types::WriteOp,
+    value::KvManager,
+};
+
+// logging
+use tracing::info;
+
+const BACKUP_MAGIC: &[u8; 8] = b"TOFNDBAK";
+const BACKUP_VERSION: u16 = 1;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = BACKUP_MAGIC.len() + 2 + SALT_LEN + NONCE_LEN;
+
+/// A backup command and the method to retrieve the passphrase of the backup file
+#[derive(Clone, Debug)]
+pub enum BackupCmd {
+    /// write all records to a new backup file at `path`
+    Backup {
+        path: PathBuf,
+        passphrase_method: PasswordMethod,
+    },
+    /// merge all records of the backup file at `path` into the kv store
+    Restore {
+        path: PathBuf,
+        passphrase_method: PasswordMethod,
+    },
+}
+
+impl BackupCmd {
+    pub fn passphrase_method(&self) -> &PasswordMethod {
+        match self {
+            Self::Backup {
+                passphrase_method, ..
+            } => passphrase_method,
+            Self::Restore {
+                passphrase_method, ..
+            } => passphrase_method,
+        }
+    }
+}
+
+impl fmt::Display for BackupCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backup {
+                path,
+                passphrase_method,
+            } => write!(
+                f,
+                "backup to {} (passphrase method: {})",
+                path.display(),
+                passphrase_method
+            ),
+            Self::Restore {
+                path,
+                passphrase_method,
+            } => write!(
+                f,
+                "restore from {} (passphrase method: {})",
+                path.display(),
+                passphrase_method
+            ),
+        }
+    }
+}
+
+/// implement backup functions for KvManager
+impl KvManager {
+    /// Execute a [BackupCmd] with the passphrase of the backup file
+    pub async fn handle_backup_cmd(&self, cmd: &BackupCmd, passphrase: Password) -> KvResult<()> {
+        match cmd {
+            BackupCmd::Backup { path, .. } => {
+                let count = self.backup(path, passphrase).await?;
+                info!("Backed up {} records to {}", count, path.display());
+            }
+            BackupCmd::Restore { path, .. } => {
+                let count = self.restore(path, passphrase).await?;
+                info!("Restored {} records from {}", count, path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all records of the kv store to a new backup file at `path`, encrypted under `passphrase`.
+    /// Returns the number of records, or [BackupErr] if `path` already exists.
+    pub async fn backup(&self, path: &Path, passphrase: Password) -> KvResult<usize> {
+        let records = self.kv().get_all().await?;
+        let count = records.len();
+        write_backup(path, passphrase, records).map_err(BackupErr)?;
+        Ok(count)
+    }
+
+    /// Merge all records of the backup file at `path` into the kv store. Records are upgraded to
+    /// the current version, and records that already exist with the same value are skipped,
+    /// so an interrupted restore can be repeated. Missing records are written in a single batch.
+    /// Returns the number of restored records, or [RestoreErr] if the backup is corrupted, the
+    /// passphrase is wrong, or a record already exists with a different value.
+    pub async fn restore(&self, path: &Path, passphrase: Password) -> KvResult<usize> {
+        let records = read_backup(path, passphrase).map_err(RestoreErr)?;
+
+        let mut ops = vec![];
+        for (key, value) in records {
+            // backups may hold values of older versions
+            let value = match migrate_value(&key, &value).map_err(RestoreErr)? {
+                Some((_, upgraded)) => upgraded,
+                None => value,
+            };
+            if self.kv().exists(&key).await? {
+                if self.kv().get(&key).await? != value {
+                    return Err(RestoreErr(LogicalErr(format!(
+                        "key <{}> already exists with a different value",
+                        key
+                    ))));
+                }
+                continue;
+            }
+            ops.push(WriteOp::Insert { key, value });
+        }
+
+        // restore all missing records at once, so that a failed restore leaves the kv store untouched
+        let count = ops.len();
+        self.kv().batch(ops).await?;
+        Ok(count)
+    }
+}
+
+/// Derive the cipher of a backup file from `passphrase` and `salt`
+fn backup_cipher(passphrase: Password, salt: &[u8]) -> InnerKvResult<XChaCha20Poly1305> {
+    let mut key = chacha20poly1305::Key::default();
+    scrypt::scrypt(
+        passphrase.as_ref(),
+        salt,
+        &scrypt::Params::default(),
+        key.as_mut_slice(),
+    )
+    .map_err(|err| EncryptionErr(err.to_string()))?;
+
+    // zeroize key since we are no longer using it after creating cipher
+    let cipher = XChaCha20Poly1305::new(&key);
+    key.zeroize();
+    Ok(cipher)
+}
+
+/// Encrypt `records` and write them to a new file at `path`, readable only by the owner.
+fn write_backup(
+    path: &Path,
+    passphrase: Password,
+    mut records: Vec<(String, Vec<u8>)>,
+) -> InnerKvResult<()> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut nonce = XNonce::default();
+    rand::thread_rng().fill_bytes(nonce.as_mut_slice());
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(BACKUP_MAGIC);
+    header.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let payload = serialize(&records);
+    records.iter_mut().for_each(|(_, value)| value.zeroize());
+    let mut payload = payload.map_err(|_| SerializationErr)?;
+
+    backup_cipher(passphrase, &salt)?
+        .encrypt_in_place(&nonce, &header, &mut payload)
+        .map_err(|err| EncryptionErr(err.to_string()))?;
+
+    // never overwrite an existing file; it might be the only other backup
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&header)?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Read and decrypt the records of the backup file at `path`
+fn read_backup(path: &Path, passphrase: Password) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+    let bytes = std::fs::read(path)?;
+
+    if bytes.len() < HEADER_LEN || &bytes[..BACKUP_MAGIC.len()] != BACKUP_MAGIC {
+        return Err(BackupFormatErr("not a tofnd backup file".to_string()));
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+    let (version, rest) = header[BACKUP_MAGIC.len()..].split_at(2);
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version != BACKUP_VERSION {
+        return Err(BackupFormatErr(format!(
+            "unsupported backup version {}",
+            version
+        )));
+    }
+    let (salt, nonce) = rest.split_at(SALT_LEN);
+
+    let mut payload = ciphertext.to_vec();
+    backup_cipher(passphrase, salt)?
+        .decrypt_in_place(XNonce::from_slice(nonce), header, &mut payload)
+        .map_err(|_| BackupIntegrityErr)?;
+
+    let records = deserialize(&payload).ok_or(DeserializationErr);
+    payload.zeroize();
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypted_sled::get_test_password;
+    use testdir::testdir;
+
+    fn test_records() -> Vec<(String, Vec<u8>)> {
+        vec![
+            ("mnemonic".to_string(), vec![1, 2, 3]),
+            ("key_uid".to_string(), vec![4, 5, 6]),
+        ]
+    }
+
+    #[test]
+    fn backup_file() {
+        let dir = testdir!();
+        let path = dir.join("backup");
+
+        write_backup(&path, "passphrase".into(), test_records()).unwrap();
+        assert_eq!(
+            read_backup(&path, "passphrase".into()).unwrap(),
+            test_records()
+        );
+
+        // existing files are not overwritten
+        assert!(write_backup(&path, "passphrase".into(), test_records()).is_err());
+
+        // wrong passphrase
+        assert!(matches!(
+            read_backup(&path, "wrong".into()),
+            Err(BackupIntegrityErr)
+        ));
+
+        // tampered header and ciphertext
+        let bytes = std::fs::read(&path).unwrap();
+        for i in [BACKUP_MAGIC.len() + 2, bytes.len() - 1].iter() {
+            let mut tampered = bytes.clone();
+            tampered[*i] ^= 1;
+            let tampered_path = dir.join(format!("tampered_{}", i));
+            std::fs::write(&tampered_path, tampered).unwrap();
+            assert!(matches!(
+                read_backup(&tampered_path, "passphrase".into()),
+                Err(BackupIntegrityErr)
+            ));
+        }
+
+        // unknown version
+        let mut unknown_version = bytes.clone();
+        unknown_version[BACKUP_MAGIC.len()] = 2;
+        let unknown_version_path = dir.join("unknown_version");
+        std::fs::write(&unknown_version_path, unknown_version).unwrap();
+        assert!(matches!(
+            read_backup(&unknown_version_path, "passphrase".into()),
+            Err(BackupFormatErr(_))
+        ));
+
+        // truncated file
+        let truncated_path = dir.join("truncated");
+        std::fs::write(&truncated_path, &bytes[..HEADER_LEN - 1]).unwrap();
+        assert!(matches!(
+            read_backup(&truncated_path, "passphrase".into()),
+            Err(BackupFormatErr(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let dir = testdir!();
+        let path = dir.join("backup");
+
+        let source =
+            KvManager::new(dir.join("source").to_str().unwrap(), get_test_password()).unwrap();
+        for (key, value) in test_records() {
+            let reservation = source.kv().reserve_key(key, "test").await.unwrap();
+            source.kv().put(reservation, value).await.unwrap();
+        }
+        assert_eq!(source.backup(&path, "passphrase".into()).await.unwrap(), 2);
+
+        // restore into a fresh kv store
+        let target =
+            KvManager::new(dir.join("target").to_str().unwrap(), get_test_password()).unwrap();
+        assert_eq!(target.restore(&path, "passphrase".into()).await.unwrap(), 2);
+        let mut restored = target.kv().get_all().await.unwrap();
+        restored.sort();
+        let mut expected = test_records();
+        expected.sort();
+        assert_eq!(restored, expected);
+
+        // restoring again skips identical records
+        assert_eq!(target.restore(&path, "passphrase".into()).await.unwrap(), 0);
+
+        // conflicting records are rejected
+        let conflicting =
+            KvManager::new(dir.join("conflict").to_str().unwrap(), get_test_password()).unwrap();
+        let reservation = conflicting
+            .kv()
+            .reserve_key("key_uid".to_string(), "test")
+            .await
+            .unwrap();
+        conflicting.kv().put(reservation, vec![0]).await.unwrap();
+        assert!(conflicting
+            .restore(&path, "passphrase".into())
+            .await
+            .is_err());
+        // a failed restore doesn't write any record
+        assert_eq!(conflicting.kv().get_all().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! Integrity check of the kv store, run with `tofnd db check`.
+//!
+//! A corrupted record is otherwise only noticed when a session fails to read it, e.g. when a sign
+//! fails with a deserialization error. The check verifies the kek, and reads every record and
+//! every archived record of the [encrypted_sled::Db] as the type that is expected for its key.
+//! Reservations of sessions that never completed are reported too.
+
+use std::{convert::TryFrom, fmt, path::Path};
+
+use tofn::sdk::api::deserialize;
+
+use crate::{
+    encrypted_sled::{self, to_hex, Kek},
+    gg20::types::{Entropy, PartyInfo},
+    multisig::types::MultisigKeyInfo,
+};
+
+use super::{
+    error::{
+        InnerKvError::{LogicalErr, SledErr},
+        InnerKvResult,
+        KvError::CheckErr,
+        KvResult,
+    },
+    kv::kv_path,
+    migration::ValueKind,
+    types::{ArchivedRecord, Reservation, ReservationRecord},
+    value::{KvManager, KvValue},
+};
+
+// logging
+use tracing::{error, info, warn};
+
+/// A record of the kv store that cannot be read
+#[derive(Debug)]
+pub struct CorruptRecord {
+    /// the key of the record, or its hex-encoded sled key if the record cannot be decrypted
+    pub key: String,
+    /// whether the record is in the archive
+    pub archived: bool,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.archived {
+            write!(f, "archived ")?;
+        }
+        write!(f, "key <{}>: {}", self.key, self.reason)
+    }
+}
+
+/// The findings of [KvManager::check]
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// number of keys that hold a valid value
+    pub values: usize,
+    /// number of valid archived records
+    pub archived: usize,
+    /// reservations of sessions that never completed
+    pub reservations: Vec<Reservation>,
+    /// records that cannot be decrypted or deserialized
+    pub corrupt: Vec<CorruptRecord>,
+}
+
+impl CheckReport {
+    /// Logs all findings
+    pub fn log(&self) {
+        for reservation in &self.reservations {
+            warn!("Dangling reservation: {}", reservation);
+        }
+        for record in &self.corrupt {
+            error!("Corrupt record: {}", record);
+        }
+        info!("{}", self);
+    }
+
+    fn push_corrupt(&mut self, key: String, archived: bool, reason: String) {
+        self.corrupt.push(CorruptRecord {
+            key,
+            archived,
+            reason,
+        });
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checked kv store: {} values, {} archived records, {} dangling reservations, {} corrupt records",
+            self.values,
+            self.archived,
+            self.reservations.len(),
+            self.corrupt.len()
+        )
+    }
+}
+
+/// implement the integrity check for KvManager
+impl KvManager {
+    /// Checks the integrity of the sled kvstore under `root`.
+    /// Must be called before a [KvManager] is created for `root`. Kvstores of an older tofnd are
+    /// checked as they are on disk: they are neither migrated nor is their data key rotated.
+    /// Returns [super::error::KvError::InitErr] if `kek` is wrong, and [CheckErr] if the
+    /// kvstore cannot be read at all.
+    pub fn check(root: &str, kek: Kek) -> KvResult<CheckReport> {
+        let db_name = kv_path(root);
+        // don't create a new db when there is nothing to check
+        if !Path::new(&db_name).exists() {
+            return Err(CheckErr(LogicalErr(format!(
+                "kvstore [{}] not found",
+                db_name
+            ))));
+        }
+
+        let db = encrypted_sled::Db::open(&db_name, kek)?;
+        db.verify_password()?;
+
+        let mut report = CheckReport::default();
+        for record in db.check_records(false) {
+            let (sled_key, record) = record.map_err(|err| CheckErr(SledErr(err)))?;
+            let (key, value) = match record {
+                Ok((key, value)) => (key, value),
+                Err(err) => {
+                    report.push_corrupt(to_hex(&sled_key), false, err.to_string());
+                    continue;
+                }
+            };
+            let key = match String::from_utf8(key.to_vec()) {
+                Ok(key) => key,
+                Err(_) => {
+                    let reason = "key is not valid utf8".to_string();
+                    report.push_corrupt(to_hex(&sled_key), false, reason);
+                    continue;
+                }
+            };
+
+            if let Some(record) = ReservationRecord::from_bytes(&value) {
+                report.reservations.push(record.into_reservation(key));
+                continue;
+            }
+            match check_stored(&key, &value) {
+                Ok(()) => report.values += 1,
+                Err(err) => report.push_corrupt(key, false, err.to_string()),
+            }
+        }
+
+        for record in db.check_records(true) {
+            let (sled_key, record) = record.map_err(|err| CheckErr(SledErr(err)))?;
+            let (archive_key, record) = match record {
+                Ok((archive_key, record)) => {
+                    (String::from_utf8_lossy(&archive_key).into_owned(), record)
+                }
+                Err(err) => {
+                    report.push_corrupt(to_hex(&sled_key), true, err.to_string());
+                    continue;
+                }
+            };
+            match check_archived(&archive_key, &record) {
+                Ok(()) => report.archived += 1,
+                Err(err) => report.push_corrupt(archive_key, true, err.to_string()),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Checks that `bytes` is a [KvValue] as it is stored by [super::kv::Kv::put], holding a valid
+/// value of the kind expected for `key`
+fn check_stored(key: &str, bytes: &[u8]) -> InnerKvResult<()> {
+    let value: KvValue = deserialize(bytes)
+        .ok_or_else(|| LogicalErr("failed to deserialize the stored value".to_string()))?;
+    check_value(key, value)
+}
+
+/// Checks that `value` is a valid value of the kind expected for `key`
+fn check_value(key: &str, value: Vec<u8>) -> InnerKvResult<()> {
+    match ValueKind::of_key(key) {
+        ValueKind::PartyInfo => PartyInfo::try_from(value).map(|_| ()),
+        ValueKind::Entropy => Entropy::try_from(value).map(|_| ()),
+        ValueKind::MultisigKeyInfo => MultisigKeyInfo::try_from(value).map(|_| ()),
+    }
+}
+
+/// Checks that `bytes` is an [ArchivedRecord] of a valid value of the key it was archived from.
+/// Archive keys are `key`/`timestamp`.
+fn check_archived(archive_key: &str, bytes: &[u8]) -> InnerKvResult<()> {
+    let key = archive_key
+        .rsplitn(2, '/')
+        .nth(1)
+        .ok_or_else(|| LogicalErr("archive key has no timestamp".to_string()))?;
+    let record: ArchivedRecord = deserialize(bytes)
+        .ok_or_else(|| LogicalErr("failed to deserialize the archived record".to_string()))?;
+    check_stored(key, &record.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encrypted_sled::{get_test_password, Password},
+        kv_manager::{holds, tests::sled_contents},
+        mnemonic::MNEMONIC_KEY,
+        multisig::MULTISIG_KEY_PREFIX,
+    };
+    use std::{convert::TryInto, thread::sleep, time::Duration};
+    use testdir::testdir;
+    use tofn::sdk::api::serialize;
+
+    /// Opens the kvstore under `root` once the kv_manager that used it has released it
+    fn open_db(root: &str) -> encrypted_sled::Db {
+        // sled does not support to rapidly open/close databases, see tests/tofnd_party.rs
+        for _ in 0..50 {
+            match encrypted_sled::Db::open(kv_path(root), get_test_password()) {
+                Ok(db) => return db,
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        }
+        panic!("could not open kvstore [{}]", root);
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let dir = testdir!();
+        let root = dir.to_str().unwrap();
+
+        // write records the way the services do
+        let kv_manager = KvManager::new(root, get_test_password()).unwrap();
+        let kv = kv_manager.kv();
+        let entropy: Vec<u8> = Entropy(vec![42; 32]).try_into().unwrap();
+        let put_entropy = || async {
+            let reservation = kv
+                .reserve_key(MNEMONIC_KEY.to_string(), "tofnd process 1")
+                .await
+                .unwrap();
+            kv.put(reservation, entropy.clone()).await.unwrap();
+        };
+        // a valid value that is archived, and a valid value that replaces it
+        put_entropy().await;
+        kv.archive(MNEMONIC_KEY, "test".to_string(), holds::<Entropy>)
+            .await
+            .unwrap()
+            .unwrap();
+        put_entropy().await;
+        // a reservation of a session that never completed
+        let _reservation = kv
+            .reserve_key("key_uid".to_string(), "key_uid")
+            .await
+            .unwrap();
+        kv.flush().await.unwrap();
+        drop(kv_manager);
+
+        // a clean kvstore has no corrupt records; wait until the kv_manager released it
+        drop(open_db(root));
+        let report = KvManager::check(root, get_test_password().into()).unwrap();
+        assert_eq!(report.values, 1);
+        assert_eq!(report.archived, 1);
+        assert_eq!(report.reservations.len(), 1);
+        assert_eq!(report.reservations[0].key, "key_uid");
+        assert_eq!(report.reservations[0].session, "key_uid");
+        assert!(report.corrupt.is_empty(), "{:?}", report.corrupt);
+
+        let db = open_db(root);
+        // stored values that are not of the kind expected for their key
+        let stored = |value: Vec<u8>| serialize(&value).unwrap();
+        db.insert("other_key_uid", stored(vec![1, 2, 3])).unwrap();
+        db.insert(
+            format!("{}key_uid", MULTISIG_KEY_PREFIX),
+            stored(entropy.clone()),
+        )
+        .unwrap();
+        // a value that was not stored as a [KvValue]
+        db.insert("raw_key_uid", entropy).unwrap();
+        // a corrupt archived record
+        let archived = serialize(&ArchivedRecord {
+            value: stored(vec![1, 2, 3]),
+            archived_at: 1,
+            reason: "test".to_string(),
+        })
+        .unwrap();
+        db.insert("archived_key_uid", vec![]).unwrap();
+        assert!(db
+            .archive("archived_key_uid", "archived_key_uid/1", archived)
+            .unwrap());
+        drop(db);
+
+        let report = KvManager::check(root, get_test_password().into()).unwrap();
+        assert_eq!(report.values, 1);
+        assert_eq!(report.archived, 1);
+        assert_eq!(report.reservations.len(), 1);
+        let mut corrupt: Vec<_> = report
+            .corrupt
+            .iter()
+            .map(|record| (record.key.as_str(), record.archived))
+            .collect();
+        corrupt.sort_unstable();
+        assert_eq!(
+            corrupt,
+            vec![
+                ("archived_key_uid/1", true),
+                ("multisig/key_uid", false),
+                ("other_key_uid", false),
+                ("raw_key_uid", false)
+            ]
+        );
+
+        // a wrong password is detected
+        assert!(KvManager::check(root, Password::from("wrong").into()).is_err());
+        // missing kvstores are not created
+        assert!(KvManager::check(
+            dir.join("missing").to_str().unwrap(),
+            get_test_password().into()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn check_legacy_db() {
+        let dir = testdir!();
+        let root = dir.to_str().unwrap();
+        let db_name = kv_path(root);
+
+        // a kvstore of an older tofnd: legacy records under a password-derived data key
+        let mut db = encrypted_sled::Db::open(&db_name, get_test_password()).unwrap();
+        let stored = serialize(&vec![1u8, 2, 3]).unwrap();
+        db.insert("key_uid", stored).unwrap();
+        db.downgrade_to_format(encrypted_sled::LEGACY_RECORD_FORMAT_VERSION)
+            .unwrap();
+        db.downgrade_to_password_key(get_test_password()).unwrap();
+        drop(db);
+        // legacy records are stored under their plaintext keys
+        let raw = sled::open(&db_name).unwrap();
+        raw.insert("corrupted", &b"not a record"[..]).unwrap();
+        raw.flush().unwrap();
+        drop(raw);
+        let before = sled_contents(&db_name);
+
+        let report = KvManager::check(root, get_test_password().into()).unwrap();
+        let corrupt: Vec<_> = report
+            .corrupt
+            .iter()
+            .map(|record| (record.key.as_str(), record.archived))
+            .collect();
+        let corrupted_key = to_hex(b"corrupted");
+        assert!(
+            corrupt.contains(&(corrupted_key.as_str(), false)),
+            "{:?}",
+            report.corrupt
+        );
+
+        // the check neither migrated the kvstore nor rotated its data key
+        assert_eq!(sled_contents(&db_name), before);
+    }
+}
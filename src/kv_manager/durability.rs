@@ -0,0 +1,327 @@
+//! Durability policy of the kv store.
+//!
+//! Backends such as [crate::encrypted_sled::Db] buffer writes and persist them in the background,
+//! so a write can be lost if tofnd stops right after it returned. [Durability] decides when the
+//! kv actor calls [Storage::flush]. Regardless of the policy, pending writes are flushed when the
+//! actor stops and when [super::kv::Kv::flush] is called, e.g. before a keygen reports success.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use super::{
+    error::{InnerKvError, InnerKvResult},
+    storage::Storage,
+};
+
+/// Default flush interval of [Durability::Periodic]
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 500;
+
+/// When writes of the kv store are flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// flush after every write, before the write is acknowledged
+    EveryWrite,
+    /// flush pending writes periodically
+    Periodic(Duration),
+    /// leave flushing to the storage backend; sled flushes every 500ms in the background
+    Backend,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self::EveryWrite
+    }
+}
+
+/// Parses `every-write`, `periodic`, `periodic:<milliseconds>` or `backend`
+impl FromStr for Durability {
+    type Err = InnerKvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            InnerKvError::LogicalErr(format!(
+                "invalid durability policy [{}]: expected every-write, periodic[:<ms>] or backend",
+                s
+            ))
+        };
+        match s {
+            "every-write" => Ok(Self::EveryWrite),
+            "periodic" => Ok(Self::Periodic(Duration::from_millis(
+                DEFAULT_FLUSH_INTERVAL_MS,
+            ))),
+            "backend" => Ok(Self::Backend),
+            _ => {
+                let ms = s
+                    .strip_prefix("periodic:")
+                    .and_then(|ms| ms.parse::<u64>().ok())
+                    .filter(|ms| *ms > 0)
+                    .ok_or_else(invalid)?;
+                Ok(Self::Periodic(Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EveryWrite => write!(f, "every-write"),
+            Self::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Self::Backend => write!(f, "backend"),
+        }
+    }
+}
+
+/// Tracks the writes of the kv actor that are not yet flushed
+pub(super) struct Flusher {
+    durability: Durability,
+    dirty: bool,
+}
+
+impl Flusher {
+    pub(super) fn new(durability: Durability) -> Self {
+        Self {
+            durability,
+            dirty: false,
+        }
+    }
+
+    /// Returns the flush interval if writes are flushed periodically
+    pub(super) fn interval(&self) -> Option<Duration> {
+        match self.durability {
+            Durability::Periodic(interval) => Some(interval),
+            _ => None,
+        }
+    }
+
+    /// Records a successful write, and flushes it if every write must be durable
+    pub(super) fn wrote(&mut self, kv: &dyn Storage) -> InnerKvResult<()> {
+        self.dirty = true;
+        match self.durability {
+            Durability::EveryWrite => self.flush(kv),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes all writes that are not yet flushed
+    pub(super) fn flush(&mut self, kv: &dyn Storage) -> InnerKvResult<()> {
+        if self.dirty {
+            kv.flush()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encrypted_sled::{get_test_password, KdfParams},
+        gg20::types::PartyInfo,
+        kv_manager::{
+            error::KvResult,
+            kv::Kv,
+            storage::{BatchOp, MemoryStorage, StorageBackend},
+            KvManager, StaleReservations,
+        },
+    };
+    use std::{
+        collections::BTreeMap,
+        convert::TryInto,
+        sync::{Arc, Mutex},
+    };
+    use testdir::testdir;
+    use tokio::time::{sleep, timeout};
+
+    type Records = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    /// A [Storage] that only persists records on [Storage::flush].
+    /// Its durable records are what would survive if tofnd was killed.
+    struct CrashStorage {
+        live: MemoryStorage,
+        durable: Records,
+    }
+
+    impl Storage for CrashStorage {
+        fn get(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+            self.live.get(key)
+        }
+        fn contains_key(&self, key: &str) -> InnerKvResult<bool> {
+            self.live.contains_key(key)
+        }
+        fn insert(&self, key: &str, value: Vec<u8>) -> InnerKvResult<()> {
+            self.live.insert(key, value)
+        }
+        fn remove(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+            self.live.remove(key)
+        }
+        fn archive(&self, key: &str, archive_key: &str, value: Vec<u8>) -> InnerKvResult<bool> {
+            self.live.archive(key, archive_key, value)
+        }
+        fn get_archived(&self, archive_key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+            self.live.get_archived(archive_key)
+        }
+        fn get_all(&self) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+            self.live.get_all()
+        }
+        fn apply_batch(&self, batch: Vec<BatchOp>) -> InnerKvResult<()> {
+            self.live.apply_batch(batch)
+        }
+        fn flush(&self) -> InnerKvResult<()> {
+            *self.durable.lock().unwrap() = self.live.get_all()?.into_iter().collect();
+            Ok(())
+        }
+    }
+
+    /// Spawns a kv actor on a [CrashStorage] and returns its durable records
+    fn crash_kv(durability: Durability) -> (Kv<Vec<u8>>, Records) {
+        let durable = Records::default();
+        let storage = CrashStorage {
+            live: MemoryStorage::new(),
+            durable: durable.clone(),
+        };
+        let kv = Kv::with_storage(Box::new(storage), durability).unwrap();
+        (kv, durable)
+    }
+
+    /// Reserves `key` and puts `value`, like a keygen that stores its result
+    async fn reserve_and_put(kv: &Kv<Vec<u8>>, key: &str, value: Vec<u8>) {
+        let reservation = kv.reserve_key(key.to_string(), "test").await.unwrap();
+        kv.put(reservation, value).await.unwrap();
+    }
+
+    // The actor only flushes before it responds to a command, or when the policy requires it,
+    // so reading the durable records right after a command is equivalent to killing tofnd at
+    // that point.
+
+    #[tokio::test]
+    async fn flush_after_put() {
+        let (kv, durable) = crash_kv(Durability::Backend);
+
+        // without a flush, a put can be lost
+        reserve_and_put(&kv, "lost", vec![1]).await;
+        assert!(durable.lock().unwrap().get("lost").is_none());
+
+        // keygen flushes before it reports success
+        reserve_and_put(&kv, "key_uid", vec![2]).await;
+        kv.flush().await.unwrap();
+        let durable = durable.lock().unwrap();
+        assert_eq!(durable.get("key_uid"), Some(&vec![2]));
+        assert!(durable.contains_key("lost"));
+    }
+
+    #[tokio::test]
+    async fn every_write() {
+        let (kv, durable) = crash_kv(Durability::EveryWrite);
+
+        reserve_and_put(&kv, "key_uid", vec![1]).await;
+        assert_eq!(durable.lock().unwrap().get("key_uid"), Some(&vec![1]));
+
+        assert!(kv.delete("key_uid", |_| true).await.unwrap());
+        assert!(durable.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn periodic() {
+        let (kv, durable) = crash_kv(Durability::Periodic(Duration::from_millis(10)));
+
+        reserve_and_put(&kv, "key_uid", vec![1]).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(durable.lock().unwrap().get("key_uid"), Some(&vec![1]));
+    }
+
+    /// Retries `open` until the kv manager that used the kv store has released it
+    async fn reopen<T>(open: impl Fn() -> KvResult<T>) -> T {
+        // sled does not support to rapidly open/close databases, see tests/tofnd_party.rs
+        for _ in 0..50 {
+            match open() {
+                Ok(res) => return res,
+                Err(_) => sleep(Duration::from_millis(100)).await,
+            }
+        }
+        panic!("could not reopen kvstore");
+    }
+
+    // Unlike the tests above, the actor is dropped while keygens put their results into an
+    // on-disk kv store, and the kv store is reopened from disk afterwards.
+    #[tokio::test]
+    async fn drop_actor_during_keygen_put() {
+        let dir = testdir!();
+        let root = dir.to_str().unwrap();
+        let value: Vec<u8> = PartyInfo::from_test_keygen(1).try_into().unwrap();
+
+        // writes are only flushed when the actor stops
+        let kv_manager = KvManager::with_backend(
+            root,
+            get_test_password().into(),
+            &KdfParams::default(),
+            &StorageBackend::default(),
+            Durability::Backend,
+        )
+        .unwrap();
+
+        // a keygen that completed before the actor is dropped
+        reserve_and_put(kv_manager.kv(), "completed", value.clone()).await;
+        kv_manager.kv().flush().await.unwrap();
+
+        // keygens that send their put and are dropped before the put is acknowledged
+        let in_flight: Vec<String> = (0..16).map(|i| format!("in_flight_{}", i)).collect();
+        for key in &in_flight {
+            let reservation = kv_manager.kv().reserve_key(key.clone(), key).await.unwrap();
+            let put = kv_manager.kv().put(reservation, value.clone());
+            // polls the put once, which queues its command
+            let _ = timeout(Duration::from_secs(0), put).await;
+        }
+        drop(kv_manager);
+
+        // each key of the reopened kv store holds either the complete value or its reservation
+        let kv_manager = reopen(|| KvManager::new(root, get_test_password())).await;
+        assert_eq!(kv_manager.kv().get("completed").await.unwrap(), value);
+        let reserved: Vec<String> = kv_manager
+            .kv()
+            .reservations()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|reservation| reservation.key)
+            .collect();
+        for key in in_flight.iter().filter(|key| !reserved.contains(key)) {
+            assert_eq!(kv_manager.kv().get(key).await.unwrap(), value);
+        }
+
+        // keygens of the keys that were left reserved can be retried
+        let kv_manager = kv_manager
+            .sweep_reservations(StaleReservations::Release)
+            .await
+            .unwrap();
+        for key in &reserved {
+            reserve_and_put(kv_manager.kv(), key, value.clone()).await;
+        }
+        drop(kv_manager);
+
+        let report = reopen(|| KvManager::check(root, get_test_password().into())).await;
+        assert_eq!(report.values, in_flight.len() + 1);
+        assert!(report.reservations.is_empty());
+        assert!(report.corrupt.is_empty());
+    }
+
+    #[test]
+    fn parse_policy() {
+        for policy in [
+            Durability::EveryWrite,
+            Durability::Periodic(Duration::from_millis(100)),
+            Durability::Backend,
+        ]
+        .iter()
+        {
+            assert_eq!(&policy.to_string().parse::<Durability>().unwrap(), policy);
+        }
+        assert_eq!(
+            "periodic".parse::<Durability>().unwrap(),
+            Durability::Periodic(Duration::from_millis(DEFAULT_FLUSH_INTERVAL_MS))
+        );
+        for invalid in ["never", "periodic:", "periodic:0", "periodic:fast"].iter() {
+            assert!(invalid.parse::<Durability>().is_err());
+        }
+    }
+}
@@ -20,6 +20,8 @@ pub enum KvError {
     RecvErr(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("Send Error: {0}")] // errors sending to "actor pattern"'s channels
     SendErr(String),
+    #[error("Spawn Error: {0}")] // errors starting the thread of the "actor pattern"
+    SpawnErr(std::io::Error),
     #[error("Reserve Error: {0}")]
     ReserveErr(InnerKvError),
     #[error("Put Error: {0}")]
@@ -28,6 +30,36 @@ pub enum KvError {
     GetErr(InnerKvError),
     #[error("Exits Error: {0}")]
     ExistsErr(InnerKvError),
+    #[error("Get All Error: {0}")]
+    GetAllErr(InnerKvError),
+    #[error("Archive Error: {0}")]
+    ArchiveErr(InnerKvError),
+    #[error("Delete Error: {0}")]
+    DeleteErr(InnerKvError),
+    #[error("Change Kek Error: {0}")]
+    ChangeKekErr(InnerKvError),
+    #[error("Backup Error: {0}")]
+    BackupErr(InnerKvError),
+    #[error("Restore Error: {0}")]
+    RestoreErr(InnerKvError),
+    #[error("Update Error: {0}")]
+    UpdateErr(InnerKvError),
+    #[error("Migrate Error: {0}")]
+    MigrateErr(InnerKvError),
+    #[error("Storage Error: {0}")]
+    StorageErr(InnerKvError),
+    #[error("Batch Error: {0}")]
+    BatchErr(InnerKvError),
+    #[error("Scan Error: {0}")]
+    ScanErr(InnerKvError),
+    #[error("Reservations Error: {0}")]
+    ReservationsErr(InnerKvError),
+    #[error("Release Error: {0}")]
+    ReleaseErr(InnerKvError),
+    #[error("Flush Error: {0}")]
+    FlushErr(InnerKvError),
+    #[error("Check Error: {0}")]
+    CheckErr(InnerKvError),
 }
 pub type KvResult<Success> = Result<Success, KvError>;
 
@@ -42,5 +74,17 @@ pub enum InnerKvError {
     SerializationErr,
     #[error("Deserialization Error: failed to deserialize kvstore bytes")]
     DeserializationErr,
+    #[error("IO Error: {0}")]
+    IoErr(#[from] std::io::Error),
+    #[error("Encryption Error: {0}")]
+    EncryptionErr(String),
+    #[error("Backup Format Error: {0}")]
+    BackupFormatErr(String),
+    #[error("Backup Integrity Error: the backup is corrupted or the passphrase is wrong")]
+    BackupIntegrityErr,
+    #[error("Value Version Error: {0}")]
+    ValueVersionErr(String),
+    #[error("Storage Format Error: {0}")]
+    StorageFormatErr(String),
 }
-pub(super) type InnerKvResult<Success> = Result<Success, InnerKvError>;
+pub type InnerKvResult<Success> = Result<Success, InnerKvError>;
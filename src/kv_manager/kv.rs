@@ -1,26 +1,45 @@
 //! Public API for kvstore operations
 //! Errors are mapped to [super::error::KvError]
 
-use crate::encrypted_sled::{self, Password};
+use crate::encrypted_sled::{self, KdfParams, Kek};
 
 use super::{
-    error::{KvError::*, KvResult},
-    sled_bindings::{handle_exists, handle_get, handle_put, handle_reserve},
+    durability::{Durability, Flusher},
+    error::{
+        InnerKvError::{LogicalErr, SledErr},
+        InnerKvResult,
+        KvError::*,
+        KvResult,
+    },
+    sled_bindings::{
+        handle_archive, handle_batch, handle_delete, handle_exists, handle_get, handle_get_all,
+        handle_put, handle_range, handle_release, handle_reservations, handle_reserve,
+        handle_scan_prefix, handle_update,
+    },
+    storage::Storage,
     types::{
         Command::{self, *},
-        KeyReservation, DEFAULT_KV_NAME, DEFAULT_KV_PATH,
+        KeyReservation, Reservation, ValueCheck, WriteOp, DEFAULT_KV_NAME, DEFAULT_KV_PATH,
+        DEFAULT_KV_QUEUE_LEN,
     },
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt::Debug, path::PathBuf};
-use tokio::sync::{mpsc, oneshot};
+use std::{
+    fmt::Debug,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Interval,
+};
 
 // logging
 use tracing::{info, warn};
 
 #[derive(Clone)]
 pub struct Kv<V> {
-    sender: mpsc::UnboundedSender<Command<V>>,
+    sender: mpsc::Sender<Command<V>>,
 }
 
 // database functionality using the "actor" pattern (Kv is the "handle"): https://ryhl.io/blog/actors-with-tokio/
@@ -29,44 +48,45 @@ impl<V: 'static> Kv<V>
 where
     V: Debug + Send + Sync + Serialize + DeserializeOwned,
 {
-    /// Creates a new kv service. Returns [InitErr] on failure.
-    /// the path of the kvstore is `root_path` + "/kvstore/" + `kv_name`
-    pub fn new(root_path: &str, password: Password) -> KvResult<Self> {
-        let kv_path = PathBuf::from(root_path)
-            .join(DEFAULT_KV_PATH)
-            .join(DEFAULT_KV_NAME);
-        // use to_string_lossy() instead of to_str() to avoid handling Option<&str>
-        let kv_path = kv_path.to_string_lossy().to_string();
-        Self::with_db_name(kv_path, password)
-    }
-
-    /// Creates a kvstore at `full_db_name` and spawns a new kv_manager. Returns [InitErr] on failure.
-    /// `full_db_name` is the name of the path of the kvstrore + its name
-    /// Example: ~/tofnd/kvstore/database_1
-    pub fn with_db_name(full_db_name: String, password: Password) -> KvResult<Self> {
-        let (sender, rx) = mpsc::unbounded_channel();
-
-        // get kv store from db name before entering the kv_cmd_handler because
-        // it's more convenient to return an error from outside of a tokio::span
-        let kv = get_kv_store(&full_db_name, password)?;
-
-        tokio::spawn(kv_cmd_handler(rx, kv));
+    /// Spawns a new kv_manager on top of `storage` that flushes writes according to `durability`.
+    /// Storage operations block on disk IO and encryption, so the kv_manager runs on a dedicated
+    /// thread instead of the async runtime that drives the protocols. Commands are queued in a
+    /// bounded channel; when the queue is full, callers wait until the kv_manager catches up.
+    /// Returns [SpawnErr] on failure.
+    pub fn with_storage(storage: Box<dyn Storage>, durability: Durability) -> KvResult<Self> {
+        let (sender, rx) = mpsc::channel(DEFAULT_KV_QUEUE_LEN);
+
+        // a runtime of its own drives the command queue and the periodic flushes
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .map_err(SpawnErr)?;
+        std::thread::Builder::new()
+            .name("kv_manager".to_string())
+            .spawn(move || runtime.block_on(kv_cmd_handler(rx, storage, durability)))
+            .map_err(SpawnErr)?;
+
         Ok(Self { sender })
     }
 
-    /// Reserves a key in the kvstore with [super::types::DEFAULT_RESERV] value.
+    /// Reserves a key in the kvstore on behalf of `session`, recording the session and the time of the reservation.
     /// Returns [ReserveErr] or [SendErr] on failure.
-    pub async fn reserve_key(&self, key: String) -> KvResult<KeyReservation> {
+    pub async fn reserve_key(&self, key: String, session: &str) -> KvResult<KeyReservation> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.sender
-            .send(ReserveKey { key, resp: resp_tx })
+            .send(ReserveKey {
+                key,
+                session: session.to_string(),
+                resp: resp_tx,
+            })
+            .await
             .map_err(|err| SendErr(err.to_string()))?;
         resp_rx.await?.map_err(ReserveErr)
     }
 
     /// Unreserves an existing reservation
     pub async fn unreserve_key(&self, reservation: KeyReservation) {
-        let _ = self.sender.send(UnreserveKey { reservation });
+        let _ = self.sender.send(UnreserveKey { reservation }).await;
     }
 
     /// Puts a new value given a [super::types::KeyReservation]
@@ -79,6 +99,7 @@ where
                 value,
                 resp: resp_tx,
             })
+            .await
             .map_err(|e| SendErr(e.to_string()))?;
         resp_rx.await?.map_err(PutErr)
     }
@@ -92,6 +113,7 @@ where
                 key: key.to_string(),
                 resp: resp_tx,
             })
+            .await
             .map_err(|e| SendErr(e.to_string()))?;
         resp_rx.await?.map_err(GetErr)
     }
@@ -105,78 +127,382 @@ where
                 key: key.to_string(),
                 resp: resp_tx,
             })
+            .await
             .map_err(|e| SendErr(e.to_string()))?;
         resp_rx.await?.map_err(ExistsErr)
     }
+
+    /// Gets all keys that hold a value, along with their values
+    /// Returns [GetAllErr] or [SendErr] on failure.
+    pub async fn get_all(&self) -> KvResult<Vec<(String, V)>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(GetAll { resp: resp_tx })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(GetAllErr)
+    }
+
+    /// Moves the value of a key to the archive, recording the time and `reason` of archival.
+    /// The value is checked with `check` and archived in the same operation of the kv_manager.
+    /// Returns the key of the archived record, or `None` if the key does not exist.
+    /// Returns [ArchiveErr] or [SendErr] on failure.
+    pub async fn archive(
+        &self,
+        key: &str,
+        reason: String,
+        check: ValueCheck<V>,
+    ) -> KvResult<Option<String>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Archive {
+                key: key.to_string(),
+                reason,
+                check,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(ArchiveErr)
+    }
+
+    /// Permanently deletes the value of a key.
+    /// The value is checked with `check` and deleted in the same operation of the kv_manager.
+    /// Returns `false` if the key does not exist.
+    /// Returns [DeleteErr] or [SendErr] on failure.
+    pub async fn delete(&self, key: &str, check: ValueCheck<V>) -> KvResult<bool> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Delete {
+                key: key.to_string(),
+                check,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(DeleteErr)
+    }
+
+    /// Overwrites the value of a key that holds a value
+    /// Returns [UpdateErr] or [SendErr] on failure.
+    pub async fn update(&self, key: &str, value: V) -> KvResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Update {
+                key: key.to_string(),
+                value,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(UpdateErr)
+    }
+
+    /// Atomically applies all writes of `ops`: either all of them succeed, or none is applied.
+    /// Returns [BatchErr] or [SendErr] on failure.
+    pub async fn batch(&self, ops: Vec<WriteOp<V>>) -> KvResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Batch { ops, resp: resp_tx })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(BatchErr)
+    }
+
+    /// Gets all keys within `range` that hold a value, along with their values, ordered by key
+    /// Returns [ScanErr] or [SendErr] on failure.
+    pub async fn range<R>(&self, range: R) -> KvResult<Vec<(String, V)>>
+    where
+        R: RangeBounds<String>,
+    {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Range {
+                start: to_owned_bound(range.start_bound()),
+                end: to_owned_bound(range.end_bound()),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(ScanErr)
+    }
+
+    /// Gets all keys that start with `prefix` and hold a value, along with their values, ordered by key
+    /// Returns [ScanErr] or [SendErr] on failure.
+    pub async fn scan_prefix(&self, prefix: &str) -> KvResult<Vec<(String, V)>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(ScanPrefix {
+                prefix: prefix.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(ScanErr)
+    }
+
+    /// Gets all keys that are reserved but don't hold a value yet, ordered by key
+    /// Returns [ReservationsErr] or [SendErr] on failure.
+    pub async fn reservations(&self) -> KvResult<Vec<Reservation>> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Reservations { resp: resp_tx })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(ReservationsErr)
+    }
+
+    /// Releases the reservation of a key, regardless of the session that owns it
+    /// Returns the released reservation, or [ReleaseErr] or [SendErr] on failure.
+    pub async fn release_reservation(&self, key: &str) -> KvResult<Reservation> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Release {
+                key: key.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(ReleaseErr)
+    }
+
+    /// Flushes all writes to disk, regardless of the [Durability] policy.
+    /// Sessions call this before they report that their result is stored.
+    /// Returns [FlushErr] or [SendErr] on failure.
+    pub async fn flush(&self) -> KvResult<()> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.sender
+            .send(Flush { resp: resp_tx })
+            .await
+            .map_err(|e| SendErr(e.to_string()))?;
+        resp_rx.await?.map_err(FlushErr)
+    }
 }
 
-/// Returns the db with name `db_name`, or creates a new if such DB does not exist
-/// Returns [sled::Error] on failure.
-/// Default path DB path is the executable's directory; The caller can specify a
-/// full path followed by the name of the DB
-/// Usage:
-///  let my_db = get_kv_store(&"my_current_dir_db")?;
-///  let my_db = get_kv_store(&"/tmp/my_tmp_bd")?;
-pub fn get_kv_store(
+/// Returns the path of the kvstore: `root_path` + "/kvstore/" + `kv_name`
+pub(super) fn kv_path(root_path: &str) -> String {
+    let kv_path = PathBuf::from(root_path)
+        .join(DEFAULT_KV_PATH)
+        .join(DEFAULT_KV_NAME);
+    // use to_string_lossy() instead of to_str() to avoid handling Option<&str>
+    kv_path.to_string_lossy().to_string()
+}
+
+/// Logs the records of db `db_name` that could not be decrypted while `action`, and were moved aside
+pub(super) fn log_skipped_records(
     db_name: &str,
-    password: Password,
-) -> encrypted_sled::Result<encrypted_sled::Db> {
-    // create/open DB
-    let kv = encrypted_sled::Db::open(db_name, password)?;
-
-    // log whether the DB was newly created or not
-    if kv.was_recovered() {
-        info!("kv_manager found existing db [{}]", db_name);
-    } else {
-        info!(
-            "kv_manager cannot open existing db [{}]. creating new db",
-            db_name
+    action: &str,
+    skipped: &[encrypted_sled::SkippedRecord],
+) {
+    for record in skipped {
+        warn!(
+            "kv_manager could not decrypt {} [{}] of db [{}] while {}: {}. The record was moved to the skipped tree",
+            if record.archived { "archived record" } else { "record" },
+            encrypted_sled::to_hex(&record.sled_key),
+            db_name,
+            action,
+            record.reason,
         );
     }
-    Ok(kv)
+}
+
+fn to_owned_bound(bound: Bound<&String>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Re-wraps the data key of the kvstore under `root_path` with `new_kek`, and derives a new
+/// password kek with `new_kdf` if provided. If `rotate_data_key` is set, the data key is replaced
+/// by a new random data key and all records are re-encrypted under it; the data key of a kvstore
+/// that uses the key derived from its password as data key is always replaced.
+/// Records that cannot be decrypted are moved aside and logged.
+/// Must not be called while a [Kv] of the same kvstore is running.
+/// Returns [InitErr] if `kek` is wrong and [ChangeKekErr] on failure.
+pub fn change_kek(
+    root_path: &str,
+    kek: Kek,
+    new_kek: Kek,
+    new_kdf: Option<&KdfParams>,
+    rotate_data_key: bool,
+) -> KvResult<()> {
+    let db_name = kv_path(root_path);
+    // don't create a new db when there is nothing to re-wrap
+    if !std::path::Path::new(&db_name).exists() {
+        return Err(ChangeKekErr(LogicalErr(format!(
+            "kvstore [{}] not found",
+            db_name
+        ))));
+    }
+
+    let mut kv = encrypted_sled::Db::open(&db_name, kek)?;
+    // a legacy data key is derived from the old password, so it must not be kept under the new kek
+    let rotate_data_key = rotate_data_key || kv.has_legacy_data_key();
+    let res = match rotate_data_key {
+        true => kv.rotate_data_key(new_kek, new_kdf),
+        false => kv.change_kek(new_kek, new_kdf).map(|_| vec![]),
+    };
+    let skipped = res.map_err(|err| ChangeKekErr(SledErr(err)))?;
+    log_skipped_records(&db_name, "rotating its data key", &skipped);
+    info!(
+        "kv_manager changed the kek of db [{}] to kek [{}] with kdf [{}]{}",
+        db_name,
+        kv.kek(),
+        kv.kdf(),
+        if rotate_data_key {
+            " and rotated its data key"
+        } else {
+            ""
+        }
+    );
+    Ok(())
 }
 
 // private handler function to process commands as per the "actor" pattern (see above)
 async fn kv_cmd_handler<V: 'static>(
-    mut rx: mpsc::UnboundedReceiver<Command<V>>,
-    kv: encrypted_sled::Db,
+    mut rx: mpsc::Receiver<Command<V>>,
+    kv: Box<dyn Storage>,
+    durability: Durability,
 ) where
     V: Serialize + DeserializeOwned,
 {
+    let mut flusher = Flusher::new(durability);
+    let mut flush_interval = flusher.interval().map(tokio::time::interval);
+
     // if resp.send() fails then log a warning and continue
     // see discussion https://github.com/axelarnetwork/tofnd/pull/15#discussion_r595426775
-    while let Some(cmd) = rx.recv().await {
+    loop {
+        let cmd = tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = tick(&mut flush_interval) => {
+                if let Err(err) = flusher.flush(&*kv) {
+                    warn!("kv_manager periodic flush failed: {}", err);
+                }
+                continue;
+            }
+        };
+
         // TODO better error handling and logging: we should log when `handle_*` fails
         // TODO refactor repeated code
         match cmd {
-            ReserveKey { key, resp } => {
-                if resp.send(handle_reserve(&kv, key)).is_err() {
+            ReserveKey { key, session, resp } => {
+                let res = handle_reserve(&*kv, key, session);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
                     warn!("receiver dropped");
                 }
             }
             UnreserveKey { reservation } => {
-                let _ = kv.remove(&reservation.key);
+                let _ = written(&mut flusher, &*kv, kv.remove(&reservation.key));
             }
             Put {
                 reservation,
                 value,
                 resp,
             } => {
-                if resp.send(handle_put(&kv, reservation, value)).is_err() {
+                let res = handle_put(&*kv, reservation, value);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
                     warn!("receiver dropped");
                 }
             }
             Get { key, resp } => {
-                if resp.send(handle_get(&kv, key)).is_err() {
+                if resp.send(handle_get(&*kv, key)).is_err() {
                     warn!("receiver dropped");
                 }
             }
             Exists { key, resp } => {
-                if resp.send(handle_exists(&kv, &key)).is_err() {
+                if resp.send(handle_exists(&*kv, &key)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            GetAll { resp } => {
+                if resp.send(handle_get_all(&*kv)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Archive {
+                key,
+                reason,
+                check,
+                resp,
+            } => {
+                let res = handle_archive(&*kv, key, reason, check);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Delete { key, check, resp } => {
+                let res = handle_delete(&*kv, key, check);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Update { key, value, resp } => {
+                let res = handle_update(&*kv, key, value);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Batch { ops, resp } => {
+                let res = handle_batch(&*kv, ops);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Range { start, end, resp } => {
+                if resp.send(handle_range(&*kv, start, end)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            ScanPrefix { prefix, resp } => {
+                if resp.send(handle_scan_prefix(&*kv, prefix)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Reservations { resp } => {
+                if resp.send(handle_reservations(&*kv)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Release { key, resp } => {
+                let res = handle_release(&*kv, key);
+                if resp.send(written(&mut flusher, &*kv, res)).is_err() {
+                    warn!("receiver dropped");
+                }
+            }
+            Flush { resp } => {
+                if resp.send(flusher.flush(&*kv)).is_err() {
                     warn!("receiver dropped");
                 }
             }
         }
     }
+
+    // all handles are dropped; don't leave writes behind
+    if let Err(err) = flusher.flush(&*kv) {
+        warn!("kv_manager final flush failed: {}", err);
+    }
     info!("kv_manager stop");
 }
+
+/// Records the write of `res` in `flusher` if it succeeded.
+/// Returns an error if the write must be flushed before it is acknowledged and flushing fails.
+fn written<T>(flusher: &mut Flusher, kv: &dyn Storage, res: InnerKvResult<T>) -> InnerKvResult<T> {
+    let value = res?;
+    flusher.wrote(kv)?;
+    Ok(value)
+}
+
+/// Waits for the next tick of `interval`, or forever if there is no interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
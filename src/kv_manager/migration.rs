@@ -0,0 +1,494 @@
+//! Versioned envelope of the values stored in the kv store, and the registry of migrations
+//! that upgrade values written by older versions of tofnd.
+//!
+//! Every [super::value::KvValue] is wrapped in an envelope:
+//!     magic (8 bytes) | kind (1 byte) | version (2 bytes, LE) | payload
+//! The payload is the serialized value in the layout of `version`. Values written before the
+//! envelope was introduced have no envelope; they are read as version [UNVERSIONED] of the kind
+//! expected by the caller. Unversioned values start with a length prefix or a field of the
+//! serialized struct, which never matches the magic.
+//!
+//! A change to the layout of a stored type must bump the current version of its [ValueKind]
+//! and add a [Migration] from the previous version to [MIGRATIONS]. Values are upgraded when
+//! they are read, and all values of the kv store are upgraded when tofnd starts.
+
+use std::fmt;
+
+use crate::{mnemonic::MNEMONIC_KEY, multisig::MULTISIG_KEY_PREFIX};
+
+use super::{
+    error::{InnerKvError::*, InnerKvResult, KvError::MigrateErr, KvResult},
+    types::WriteOp,
+    value::KvManager,
+};
+
+// logging
+use tracing::info;
+
+const ENVELOPE_MAGIC: &[u8; 8] = b"TOFNDKV\0";
+const HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 1 + 2;
+
+/// version of values written before the envelope was introduced
+pub(super) const UNVERSIONED: u16 = 0;
+
+/// The type of a value stored in the kv store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    /// [crate::gg20::types::PartyInfo] of a gg20 key
+    PartyInfo,
+    /// [crate::gg20::types::Entropy] of the mnemonic
+    Entropy,
+    /// [crate::multisig::types::MultisigKeyInfo] of a multisig key
+    MultisigKeyInfo,
+}
+
+impl ValueKind {
+    #[cfg(test)]
+    const ALL: [ValueKind; 3] = [
+        ValueKind::PartyInfo,
+        ValueKind::Entropy,
+        ValueKind::MultisigKeyInfo,
+    ];
+
+    /// Returns the version of the values of this kind written by this tofnd
+    pub(super) fn current_version(self) -> u16 {
+        match self {
+            Self::PartyInfo => 1,
+            Self::Entropy => 1,
+            Self::MultisigKeyInfo => 1,
+        }
+    }
+
+    /// Returns the kind of the value stored at `key`
+    pub(super) fn of_key(key: &str) -> Self {
+        if key == MNEMONIC_KEY {
+            Self::Entropy
+        } else if key.starts_with(MULTISIG_KEY_PREFIX) {
+            Self::MultisigKeyInfo
+        } else {
+            Self::PartyInfo
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::PartyInfo => 0,
+            Self::Entropy => 1,
+            Self::MultisigKeyInfo => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::PartyInfo),
+            1 => Some(Self::Entropy),
+            2 => Some(Self::MultisigKeyInfo),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Upgrades the payload of a value of `kind` from version `from` to version `from + 1`
+struct Migration {
+    kind: ValueKind,
+    from: u16,
+    description: &'static str,
+    upgrade: fn(Vec<u8>) -> InnerKvResult<Vec<u8>>,
+}
+
+/// Registry of all migrations
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        kind: ValueKind::PartyInfo,
+        from: UNVERSIONED,
+        description: "wrap unversioned value in a versioned envelope",
+        upgrade: unchanged,
+    },
+    Migration {
+        kind: ValueKind::Entropy,
+        from: UNVERSIONED,
+        description: "wrap unversioned value in a versioned envelope",
+        upgrade: unchanged,
+    },
+    Migration {
+        kind: ValueKind::MultisigKeyInfo,
+        from: UNVERSIONED,
+        description: "wrap unversioned value in a versioned envelope",
+        upgrade: unchanged,
+    },
+];
+
+/// Migration of a payload whose layout did not change
+fn unchanged(payload: Vec<u8>) -> InnerKvResult<Vec<u8>> {
+    Ok(payload)
+}
+
+/// Wrap the serialized `payload` of a value of `kind` in an envelope of the current version
+pub(super) fn seal(kind: ValueKind, payload: Vec<u8>) -> Vec<u8> {
+    seal_version(kind, kind.current_version(), payload)
+}
+
+fn seal_version(kind: ValueKind, version: u16, payload: Vec<u8>) -> Vec<u8> {
+    let mut value = Vec::with_capacity(HEADER_LEN + payload.len());
+    value.extend_from_slice(ENVELOPE_MAGIC);
+    value.push(kind.to_byte());
+    value.extend_from_slice(&version.to_le_bytes());
+    value.extend_from_slice(&payload);
+    value
+}
+
+/// Returns the version and the payload of the enveloped `value` of `kind`.
+/// Returns [ValueVersionErr] if `value` holds a value of another kind.
+fn open(kind: ValueKind, value: &[u8]) -> InnerKvResult<(u16, Vec<u8>)> {
+    if value.len() < HEADER_LEN || !value.starts_with(ENVELOPE_MAGIC) {
+        return Ok((UNVERSIONED, value.to_vec()));
+    }
+
+    let (header, payload) = value.split_at(HEADER_LEN);
+    let stored_kind = ValueKind::from_byte(header[ENVELOPE_MAGIC.len()]).ok_or_else(|| {
+        ValueVersionErr(format!(
+            "unknown value kind {}",
+            header[ENVELOPE_MAGIC.len()]
+        ))
+    })?;
+    if stored_kind != kind {
+        return Err(ValueVersionErr(format!(
+            "expected a {} value, found a {} value",
+            kind, stored_kind
+        )));
+    }
+    let version = u16::from_le_bytes([header[HEADER_LEN - 2], header[HEADER_LEN - 1]]);
+
+    Ok((version, payload.to_vec()))
+}
+
+/// Upgrade `payload` of a value of `kind` from `version` to the current version.
+/// Returns the descriptions of the applied migrations along with the upgraded payload.
+fn upgrade(
+    kind: ValueKind,
+    mut version: u16,
+    mut payload: Vec<u8>,
+) -> InnerKvResult<(Vec<u8>, Vec<&'static str>)> {
+    if version > kind.current_version() {
+        return Err(ValueVersionErr(format!(
+            "{} value of version {} was written by a newer tofnd; latest supported version is {}",
+            kind,
+            version,
+            kind.current_version()
+        )));
+    }
+
+    let mut steps = vec![];
+    while version < kind.current_version() {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.kind == kind && m.from == version)
+            .ok_or_else(|| {
+                ValueVersionErr(format!(
+                    "no migration of {} values from version {}",
+                    kind, version
+                ))
+            })?;
+        payload = (migration.upgrade)(payload)?;
+        steps.push(migration.description);
+        version += 1;
+    }
+
+    Ok((payload, steps))
+}
+
+/// Returns the serialized payload of the `value` of `kind`, upgraded to the current version
+pub(super) fn unseal(kind: ValueKind, value: &[u8]) -> InnerKvResult<Vec<u8>> {
+    let (version, payload) = open(kind, value)?;
+    let (payload, _) = upgrade(kind, version, payload)?;
+    Ok(payload)
+}
+
+/// A value of the kv store that is not in the current version, and the migrations that upgrade it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedMigration {
+    pub key: String,
+    pub kind: ValueKind,
+    pub from_version: u16,
+    pub to_version: u16,
+    pub steps: Vec<&'static str>,
+}
+
+impl fmt::Display for PlannedMigration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key <{}>: {} v{} -> v{} ({})",
+            self.key,
+            self.kind,
+            self.from_version,
+            self.to_version,
+            self.steps.join(", ")
+        )
+    }
+}
+
+/// Returns the planned migration and the upgraded value if the `value` stored at `key`
+/// is not in the current version
+pub(super) fn migrate_value(
+    key: &str,
+    value: &[u8],
+) -> InnerKvResult<Option<(PlannedMigration, Vec<u8>)>> {
+    let kind = ValueKind::of_key(key);
+    let (from_version, payload) = open(kind, value)?;
+    if from_version == kind.current_version() {
+        return Ok(None);
+    }
+
+    let (payload, steps) = upgrade(kind, from_version, payload)?;
+    let planned = PlannedMigration {
+        key: key.to_string(),
+        kind,
+        from_version,
+        to_version: kind.current_version(),
+        steps,
+    };
+    Ok(Some((planned, seal(kind, payload))))
+}
+
+/// implement migration functions for KvManager
+impl KvManager {
+    /// Returns the values of the kv store that are not in the current version,
+    /// without changing the kv store
+    pub async fn plan_migrations(&self) -> KvResult<Vec<PlannedMigration>> {
+        let mut planned = vec![];
+        for (key, value) in self.kv().get_all().await? {
+            if let Some((migration, _)) = migrate_value(&key, &value).map_err(MigrateErr)? {
+                planned.push(migration);
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Upgrade all values of the kv store to the current version.
+    /// All values are upgraded in a single batch, so an interrupted migration leaves the kv store
+    /// untouched and is repeated on the next start.
+    pub async fn migrate(self) -> KvResult<Self> {
+        let mut migrations = vec![];
+        let mut ops = vec![];
+        for (key, value) in self.kv().get_all().await? {
+            if let Some((migration, value)) = migrate_value(&key, &value).map_err(MigrateErr)? {
+                migrations.push(migration);
+                ops.push(WriteOp::Update { key, value });
+            }
+        }
+        if ops.is_empty() {
+            return Ok(self);
+        }
+
+        self.kv().batch(ops).await?;
+        for migration in &migrations {
+            info!("migrated {}", migration);
+        }
+        info!(
+            "migrated {} values to the current version",
+            migrations.len()
+        );
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::TryFrom, thread::sleep, time::Duration};
+    use testdir::testdir;
+    use tofn::sdk::api::serialize;
+
+    use crate::{
+        encrypted_sled::{self, get_test_password, LEGACY_RECORD_FORMAT_VERSION},
+        gg20::types::{Entropy, PartyInfo},
+        kv_manager::{kv::kv_path, tests::sled_contents},
+        multisig::types::MultisigKeyInfo,
+    };
+
+    // fixtures of values written by the current format and by tofnd versions without an envelope
+    const ENTROPY_V1: &[u8] = include_bytes!("fixtures/entropy_v1.bin");
+    const ENTROPY_UNVERSIONED: &[u8] = include_bytes!("fixtures/entropy_unversioned.bin");
+    const MULTISIG_KEY_INFO_V1: &[u8] = include_bytes!("fixtures/multisig_key_info_v1.bin");
+    const MULTISIG_KEY_INFO_UNVERSIONED: &[u8] =
+        include_bytes!("fixtures/multisig_key_info_unversioned.bin");
+
+    fn fixture_entropy() -> Entropy {
+        Entropy((0..32).collect())
+    }
+
+    /// Opens the kv store under `root` once the kv manager that used it has released it
+    fn open_kv_manager(root: &str) -> KvManager {
+        // sled does not support to rapidly open/close databases, see tests/tofnd_party.rs
+        for _ in 0..50 {
+            match KvManager::new(root, get_test_password()) {
+                Ok(kv_manager) => return kv_manager,
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        }
+        panic!("could not open kvstore [{}]", root);
+    }
+
+    #[test]
+    fn registry() {
+        // every kind can be upgraded from every older version
+        for &kind in ValueKind::ALL.iter() {
+            for version in UNVERSIONED..kind.current_version() {
+                let (_, steps) = upgrade(kind, version, vec![]).unwrap();
+                assert_eq!(steps.len(), usize::from(kind.current_version() - version));
+            }
+            assert!(ValueKind::from_byte(kind.to_byte()) == Some(kind));
+        }
+
+        // migrations are unique and don't start at the current version
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert!(m.from < m.kind.current_version());
+            assert!(MIGRATIONS[i + 1..]
+                .iter()
+                .all(|other| other.kind != m.kind || other.from != m.from));
+        }
+    }
+
+    #[test]
+    fn fixtures() {
+        // values written by the current format are read and written unchanged
+        assert_eq!(
+            Entropy::try_from(ENTROPY_V1.to_vec()).unwrap().0,
+            fixture_entropy().0
+        );
+        assert_eq!(Vec::<u8>::try_from(fixture_entropy()).unwrap(), ENTROPY_V1);
+        let multisig_key_info = MultisigKeyInfo::try_from(MULTISIG_KEY_INFO_V1.to_vec()).unwrap();
+        assert_eq!(
+            Vec::<u8>::try_from(multisig_key_info).unwrap(),
+            MULTISIG_KEY_INFO_V1
+        );
+
+        // current values don't need a migration
+        assert!(migrate_value(MNEMONIC_KEY, ENTROPY_V1).unwrap().is_none());
+        assert!(migrate_value("multisig/key_uid", MULTISIG_KEY_INFO_V1)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unversioned_fixtures() {
+        // unversioned values are read transparently
+        assert_eq!(
+            Entropy::try_from(ENTROPY_UNVERSIONED.to_vec()).unwrap().0,
+            fixture_entropy().0
+        );
+        assert_eq!(
+            MultisigKeyInfo::try_from(MULTISIG_KEY_INFO_UNVERSIONED.to_vec()).unwrap(),
+            MultisigKeyInfo::try_from(MULTISIG_KEY_INFO_V1.to_vec()).unwrap()
+        );
+
+        // unversioned values are migrated to the current format
+        let (planned, value) = migrate_value(MNEMONIC_KEY, ENTROPY_UNVERSIONED)
+            .unwrap()
+            .unwrap();
+        assert_eq!(planned.kind, ValueKind::Entropy);
+        assert_eq!(planned.from_version, UNVERSIONED);
+        assert_eq!(planned.to_version, ValueKind::Entropy.current_version());
+        assert_eq!(value, ENTROPY_V1);
+
+        let (planned, value) = migrate_value("multisig/key_uid", MULTISIG_KEY_INFO_UNVERSIONED)
+            .unwrap()
+            .unwrap();
+        assert_eq!(planned.kind, ValueKind::MultisigKeyInfo);
+        assert_eq!(value, MULTISIG_KEY_INFO_V1);
+    }
+
+    #[test]
+    fn unsupported_values() {
+        // values of a newer version are rejected
+        let payload = unseal(ValueKind::Entropy, ENTROPY_V1).unwrap();
+        let newer = seal_version(
+            ValueKind::Entropy,
+            ValueKind::Entropy.current_version() + 1,
+            payload,
+        );
+        assert!(matches!(Entropy::try_from(newer), Err(ValueVersionErr(_))));
+
+        // values of another kind are rejected
+        assert!(matches!(
+            MultisigKeyInfo::try_from(ENTROPY_V1.to_vec()),
+            Err(ValueVersionErr(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn migrate_party_info_from_keygen() {
+        // the PartyInfo of a gg20 keygen, as stored by tofnd versions without an envelope
+        let party_info = PartyInfo::from_test_keygen(2);
+        let unversioned = serialize(&party_info).unwrap();
+        let current = Vec::<u8>::try_from(party_info).unwrap();
+
+        let dir = testdir!();
+        let root = dir.to_str().unwrap();
+        let kv_manager = KvManager::new(root, get_test_password()).unwrap();
+        let reservation = kv_manager
+            .kv()
+            .reserve_key("key_uid".to_string(), "key_uid")
+            .await
+            .unwrap();
+        kv_manager
+            .kv()
+            .put(reservation, unversioned.clone())
+            .await
+            .unwrap();
+        drop(kv_manager);
+
+        // the reopened kv store plans the migration of the key, which is read transparently before it
+        let kv_manager = open_kv_manager(root);
+        let planned = kv_manager.plan_migrations().await.unwrap();
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].key, "key_uid");
+        assert_eq!(planned[0].kind, ValueKind::PartyInfo);
+        assert_eq!(planned[0].from_version, UNVERSIONED);
+        assert_eq!(
+            planned[0].to_version,
+            ValueKind::PartyInfo.current_version()
+        );
+        let stored = kv_manager.kv().get("key_uid").await.unwrap();
+        assert_eq!(
+            serialize(&PartyInfo::try_from(stored).unwrap()).unwrap(),
+            unversioned
+        );
+
+        // after the migration, the key is stored in the current format
+        let kv_manager = kv_manager.migrate().await.unwrap();
+        assert!(kv_manager.plan_migrations().await.unwrap().is_empty());
+        assert_eq!(kv_manager.kv().get("key_uid").await.unwrap(), current);
+    }
+
+    #[tokio::test]
+    async fn plan_migrations_of_legacy_db() {
+        // a kv store of an older tofnd: an unversioned value in the legacy record format,
+        // under a data key derived from the password
+        let dir = testdir!();
+        let root = dir.to_str().unwrap();
+        let db_name = kv_path(root);
+        let mut db = encrypted_sled::Db::open(&db_name, get_test_password()).unwrap();
+        db.insert(MNEMONIC_KEY, ENTROPY_UNVERSIONED).unwrap();
+        db.downgrade_to_format(LEGACY_RECORD_FORMAT_VERSION)
+            .unwrap();
+        db.downgrade_to_password_key(get_test_password()).unwrap();
+        drop(db);
+        let before = sled_contents(&db_name);
+
+        // the dry run plans the migration without migrating or rotating the kv store
+        let kv_manager = open_kv_manager(root);
+        let planned = kv_manager.plan_migrations().await.unwrap();
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].key, MNEMONIC_KEY);
+        drop(kv_manager);
+        assert_eq!(sled_contents(&db_name), before);
+    }
+}
@@ -1,22 +1,40 @@
-//! Key-Value Store service. We use [sled] for the underlying db implementation.
+//! Key-Value Store service. By default we use [sled] for the underlying db implementation;
+//! see [storage] for other backends.
 //! For every kvstore initialized, a daemon is spawned that serves basic
 //! database functionality using the "actor" pattern ([kv::Kv] is the "handle"): https://ryhl.io/blog/actors-with-tokio/
 //! See https://tokio.rs/tokio/tutorial/channels for tokio channels
 //! See [kv] module for the public API.
 
+/// encrypted backup and restore of the kv store
+mod backup;
+/// integrity check of the kv store
+mod check;
+/// durability policy of kv store writes
+mod durability;
 /// Custom error types for [kv] and [sled_bindings]
 pub mod error;
 /// public API of kv manager
 mod kv;
+/// versioned envelope of stored values and migrations of older versions
+mod migration;
+/// startup sweep and admin commands of key reservations
+mod reservation;
 /// sled bindings for basic kv operations
 mod sled_bindings;
+/// pluggable storage backends of the kv store
+mod storage;
 /// definition of kv_manager types and default paths
 mod types;
 /// wrapers for values stored by tofnd services
 mod value;
 
-pub use types::KeyReservation;
-pub use value::KvManager;
+pub use backup::BackupCmd;
+pub use check::{CheckReport, CorruptRecord};
+pub use durability::Durability;
+pub use reservation::{ReservationCmd, StaleReservations};
+pub use storage::{BatchOp, Storage, StorageBackend};
+pub use types::{KeyReservation, Reservation, ValueCheck, WriteOp};
+pub use value::{holds, KvManager};
 
 // tests for low-level operations
 #[cfg(test)]
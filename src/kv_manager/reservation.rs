@@ -0,0 +1,201 @@
+//! Handling of key reservations that outlived their session.
+//!
+//! A key is reserved before its value is computed, e.g. at the start of a keygen, and the
+//! reservation is replaced by the value when the session completes. If tofnd stops in between,
+//! the reservation is never removed, and every retry of the session fails because the key is
+//! already reserved. Only one tofnd process can open a kv store, so all reservations that exist
+//! at startup are orphaned; they are released or reported according to [StaleReservations].
+
+use std::{fmt, str::FromStr};
+
+use super::{
+    error::{InnerKvError, KvResult},
+    value::KvManager,
+};
+
+// logging
+use tracing::{info, warn};
+
+/// What to do with reservations that are found at startup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleReservations {
+    /// release stale reservations, so that their keys can be reserved again
+    Release,
+    /// only report stale reservations; they can be released with [ReservationCmd::Release]
+    Report,
+}
+
+impl Default for StaleReservations {
+    fn default() -> Self {
+        Self::Release
+    }
+}
+
+/// Parses `release` or `report`
+impl FromStr for StaleReservations {
+    type Err = InnerKvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "release" => Ok(Self::Release),
+            "report" => Ok(Self::Report),
+            _ => Err(InnerKvError::LogicalErr(format!(
+                "invalid stale reservations policy [{}]: expected release or report",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for StaleReservations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Release => write!(f, "release"),
+            Self::Report => write!(f, "report"),
+        }
+    }
+}
+
+/// An admin command on the reservations of the kv store
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReservationCmd {
+    /// list all reserved keys along with the session that reserved them
+    List,
+    /// release the reservation of a key
+    Release { key: String },
+}
+
+impl fmt::Display for ReservationCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::List => write!(f, "list reservations"),
+            Self::Release { key } => write!(f, "release reservation of key <{}>", key),
+        }
+    }
+}
+
+/// implement reservation functions for KvManager
+impl KvManager {
+    /// Execute a [ReservationCmd]
+    pub async fn handle_reservation_cmd(&self, cmd: &ReservationCmd) -> KvResult<()> {
+        match cmd {
+            ReservationCmd::List => {
+                let reservations = self.kv().reservations().await?;
+                for reservation in &reservations {
+                    info!("{}", reservation);
+                }
+                info!("Found {} reserved keys", reservations.len());
+            }
+            ReservationCmd::Release { key } => {
+                let reservation = self.kv().release_reservation(key).await?;
+                info!("Released {}", reservation);
+            }
+        }
+        Ok(())
+    }
+
+    /// Release or report the reservations that were left behind by a previous run of tofnd.
+    /// Must be called before any session can reserve keys.
+    pub async fn sweep_reservations(self, policy: StaleReservations) -> KvResult<Self> {
+        let reservations = self.kv().reservations().await?;
+        for reservation in &reservations {
+            match policy {
+                StaleReservations::Release => {
+                    self.kv().release_reservation(&reservation.key).await?;
+                    info!("Released stale reservation: {}", reservation);
+                }
+                StaleReservations::Report => {
+                    warn!("Found stale reservation: {}. Release it with --release-reservation to retry its session.", reservation);
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_manager::{durability::Durability, storage::MemoryStorage};
+
+    fn kv_manager() -> KvManager {
+        KvManager::with_storage(".", Box::new(MemoryStorage::new()), Durability::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sweep_releases() {
+        let kv_manager = kv_manager();
+        // a reservation of a session that never completed
+        let _ = kv_manager
+            .kv()
+            .reserve_key("key_uid".to_string(), "key_uid")
+            .await
+            .unwrap();
+        // keys that hold a value are kept
+        let reservation = kv_manager
+            .kv()
+            .reserve_key("mnemonic".to_string(), "tofnd process 1")
+            .await
+            .unwrap();
+        kv_manager.kv().put(reservation, vec![1]).await.unwrap();
+
+        let kv_manager = kv_manager
+            .sweep_reservations(StaleReservations::Release)
+            .await
+            .unwrap();
+        assert!(kv_manager.kv().reservations().await.unwrap().is_empty());
+        assert!(!kv_manager.kv().exists("key_uid").await.unwrap());
+        assert!(kv_manager.kv().exists("mnemonic").await.unwrap());
+
+        // the key can be reserved again
+        assert!(kv_manager
+            .kv()
+            .reserve_key("key_uid".to_string(), "key_uid")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn sweep_reports() {
+        let kv_manager = kv_manager();
+        let _ = kv_manager
+            .kv()
+            .reserve_key("key_uid".to_string(), "key_uid")
+            .await
+            .unwrap();
+
+        let kv_manager = kv_manager
+            .sweep_reservations(StaleReservations::Report)
+            .await
+            .unwrap();
+        let reservations = kv_manager.kv().reservations().await.unwrap();
+        assert_eq!(reservations.len(), 1);
+        assert_eq!(reservations[0].session, "key_uid");
+
+        // reported reservations can be released by the admin command
+        kv_manager
+            .handle_reservation_cmd(&ReservationCmd::Release {
+                key: "key_uid".to_string(),
+            })
+            .await
+            .unwrap();
+        assert!(kv_manager.kv().reservations().await.unwrap().is_empty());
+        assert!(kv_manager
+            .handle_reservation_cmd(&ReservationCmd::Release {
+                key: "key_uid".to_string(),
+            })
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parse_policy() {
+        for policy in [StaleReservations::Release, StaleReservations::Report].iter() {
+            assert_eq!(
+                &policy.to_string().parse::<StaleReservations>().unwrap(),
+                policy
+            );
+        }
+        assert!("delete".parse::<StaleReservations>().is_err());
+    }
+}
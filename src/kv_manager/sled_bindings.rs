@@ -1,18 +1,26 @@
-//! Bindings for [sled::Db] operations. Errors are mapped to [super::error::InnerKvError].
+//! Bindings for [Storage] operations. Errors are mapped to [super::error::InnerKvError].
 
 use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashSet,
+    ops::Bound,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tofn::sdk::api::{deserialize, serialize};
 
 use super::error::{InnerKvError::*, InnerKvResult};
-use super::types::{KeyReservation, DEFAULT_RESERV};
+use super::storage::{BatchOp, Storage};
+use super::types::{
+    is_reservation, ArchivedRecord, KeyReservation, Reservation, ReservationRecord, ValueCheck,
+    WriteOp,
+};
 
-use crate::encrypted_sled;
-
-/// Reserves a key. New's key value is [DEFAULT_RESERV].
+/// Reserves a key on behalf of `session`. New's key value is a [ReservationRecord].
 /// Returns [SledErr] of [LogicalErr] on failure.
 pub(super) fn handle_reserve(
-    kv: &encrypted_sled::Db,
+    kv: &dyn Storage,
     key: String,
+    session: String,
 ) -> InnerKvResult<KeyReservation> {
     // search key in kv store.
     // If reserve key already exists inside our database, return an error
@@ -23,8 +31,12 @@ pub(super) fn handle_reserve(
         )));
     }
 
-    // try to insert the new key with default value
-    kv.insert(&key, DEFAULT_RESERV)?;
+    // try to insert the new key with a record of the reservation
+    let record = ReservationRecord {
+        session,
+        created_at: now()?.as_secs(),
+    };
+    kv.insert(&key, record.to_bytes().ok_or(SerializationErr)?)?;
 
     // return key reservation
     Ok(KeyReservation { key })
@@ -33,18 +45,18 @@ pub(super) fn handle_reserve(
 /// Inserts a value to an existing key.
 /// Returns [SledErr] of [LogicalErr] on failure.
 pub(super) fn handle_put<V>(
-    kv: &encrypted_sled::Db,
+    kv: &dyn Storage,
     reservation: KeyReservation,
     value: V,
 ) -> InnerKvResult<()>
 where
     V: Serialize,
 {
-    // check if key holds the default reserve value. If not, send an error.
-    // Explanation of code ugliness: that's the standard way to compare a
-    // sled retrieved value with a local value:
-    // https://docs.rs/sled/0.34.6/sled/struct.Tree.html#examples-4
-    if kv.get(&reservation.key)? != Some(sled::IVec::from(DEFAULT_RESERV)) {
+    // check if key holds a reservation. If not, send an error.
+    if !kv
+        .get(&reservation.key)?
+        .map_or(false, |bytes| is_reservation(&bytes))
+    {
         return Err(LogicalErr(format!(
             "did not find reservation for key <{}> in kv store.",
             reservation.key
@@ -62,7 +74,7 @@ where
 
 /// Get the value of an existing key.
 /// Returns [SledErr] of [LogicalErr] on failure.
-pub(super) fn handle_get<V>(kv: &encrypted_sled::Db, key: String) -> InnerKvResult<V>
+pub(super) fn handle_get<V>(kv: &dyn Storage, key: String) -> InnerKvResult<V>
 where
     V: DeserializeOwned,
 {
@@ -80,7 +92,7 @@ where
 
 /// Checks if a key exists in the kvstore.
 /// Returns [SledErr] of [LogicalErr] on failure.
-pub(super) fn handle_exists(kv: &encrypted_sled::Db, key: &str) -> InnerKvResult<bool> {
+pub(super) fn handle_exists(kv: &dyn Storage, key: &str) -> InnerKvResult<bool> {
     kv.contains_key(key).map_err(|err| {
         LogicalErr(format!(
             "Could not perform 'contains_key' for key <{}> due to error: {}",
@@ -88,3 +100,280 @@ pub(super) fn handle_exists(kv: &encrypted_sled::Db, key: &str) -> InnerKvResult
         ))
     })
 }
+
+/// Get all keys that hold a value, along with their values. Reserved keys are skipped.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_get_all<V>(kv: &dyn Storage) -> InnerKvResult<Vec<(String, V)>>
+where
+    V: DeserializeOwned,
+{
+    deserialize_values(kv.get_all()?)
+}
+
+/// Get all keys within the range from `start` to `end` that hold a value, along with their values,
+/// ordered by key. Reserved keys are skipped.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_range<V>(
+    kv: &dyn Storage,
+    start: Bound<String>,
+    end: Bound<String>,
+) -> InnerKvResult<Vec<(String, V)>>
+where
+    V: DeserializeOwned,
+{
+    deserialize_values(kv.range((as_str(&start), as_str(&end)))?)
+}
+
+/// Get all keys that start with `prefix` and hold a value, along with their values, ordered by key.
+/// Reserved keys are skipped.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_scan_prefix<V>(
+    kv: &dyn Storage,
+    prefix: String,
+) -> InnerKvResult<Vec<(String, V)>>
+where
+    V: DeserializeOwned,
+{
+    let records = kv
+        .range((Bound::Included(prefix.as_str()), Bound::Unbounded))?
+        .into_iter()
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .collect();
+    deserialize_values(records)
+}
+
+fn as_str(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_str()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_str()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Deserialize the values of `records`, skipping keys that are reserved but don't hold a value yet
+fn deserialize_values<V>(records: Vec<(String, Vec<u8>)>) -> InnerKvResult<Vec<(String, V)>>
+where
+    V: DeserializeOwned,
+{
+    let mut values = vec![];
+    for (key, bytes) in records {
+        if is_reservation(&bytes) {
+            continue;
+        }
+
+        let value = deserialize(&bytes).ok_or(DeserializationErr)?;
+        values.push((key, value));
+    }
+
+    Ok(values)
+}
+
+/// Returns the serialized bytes stored at `key` if `key` holds a value.
+/// Returns [LogicalErr] if `key` does not exist or is only reserved.
+fn get_stored_bytes(kv: &dyn Storage, key: &str) -> InnerKvResult<Vec<u8>> {
+    match kv.get(key)? {
+        Some(bytes) if is_reservation(&bytes) => Err(LogicalErr(format!(
+            "key <{}> is reserved but does not have a value.",
+            key
+        ))),
+        Some(bytes) => Ok(bytes),
+        None => Err(LogicalErr(format!("key <{}> does not have a value.", key))),
+    }
+}
+
+/// Returns the serialized bytes stored at `key` if `key` holds a value that passes `check`,
+/// or `None` if `key` does not exist.
+/// Returns [LogicalErr] if `key` is only reserved or its value does not pass `check`.
+fn get_checked_bytes<V>(
+    kv: &dyn Storage,
+    key: &str,
+    check: ValueCheck<V>,
+) -> InnerKvResult<Option<Vec<u8>>>
+where
+    V: DeserializeOwned,
+{
+    if !kv.contains_key(key)? {
+        return Ok(None);
+    }
+    let bytes = get_stored_bytes(kv, key)?;
+
+    let value = deserialize(&bytes).ok_or(DeserializationErr)?;
+    if !check(value) {
+        return Err(LogicalErr(format!(
+            "key <{}> does not hold the expected value.",
+            key
+        )));
+    }
+    Ok(Some(bytes))
+}
+
+/// Returns the current time since unix epoch
+fn now() -> InnerKvResult<Duration> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| LogicalErr(format!("system time is before unix epoch: {}", err)))
+}
+
+/// Moves the value of `key` to the archive, along with the current time and `reason`, if the value passes `check`.
+/// The archived record is stored under `key`/`timestamp`, where `timestamp` is the time of archival in nanoseconds,
+/// so a key can be archived more than once. The timestamp is increased if the clock has not advanced since
+/// the previous archival of `key`.
+/// Returns the key of the archived record, or `None` if `key` does not exist.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_archive<V>(
+    kv: &dyn Storage,
+    key: String,
+    reason: String,
+    check: ValueCheck<V>,
+) -> InnerKvResult<Option<String>>
+where
+    V: DeserializeOwned,
+{
+    let bytes = match get_checked_bytes(kv, &key, check)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+
+    let now = now()?;
+    let mut timestamp = now.as_nanos();
+    let mut archive_key = format!("{}/{}", key, timestamp);
+    while kv.get_archived(&archive_key)?.is_some() {
+        timestamp += 1;
+        archive_key = format!("{}/{}", key, timestamp);
+    }
+
+    let record = ArchivedRecord {
+        value: bytes,
+        archived_at: now.as_secs(),
+        reason,
+    };
+    let record = serialize(&record).map_err(|_| SerializationErr)?;
+
+    if !kv.archive(&key, &archive_key, record)? {
+        return Err(LogicalErr(format!(
+            "key <{}> was removed before it could be archived.",
+            key
+        )));
+    }
+
+    Ok(Some(archive_key))
+}
+
+/// Permanently deletes the value of `key` if it passes `check`. Reserved keys cannot be deleted.
+/// Returns `false` if `key` does not exist.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_delete<V>(
+    kv: &dyn Storage,
+    key: String,
+    check: ValueCheck<V>,
+) -> InnerKvResult<bool>
+where
+    V: DeserializeOwned,
+{
+    if get_checked_bytes(kv, &key, check)?.is_none() {
+        return Ok(false);
+    }
+    kv.remove(&key)?;
+    Ok(true)
+}
+
+/// Overwrites the value of an existing key. Reserved keys cannot be updated.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_update<V>(kv: &dyn Storage, key: String, value: V) -> InnerKvResult<()>
+where
+    V: Serialize,
+{
+    let _ = get_stored_bytes(kv, &key)?;
+
+    // convert value into bytes
+    let bytes = serialize(&value).map_err(|_| SerializationErr)?;
+
+    kv.insert(&key, bytes)?;
+    Ok(())
+}
+
+/// Atomically applies all writes of `ops`. Each write is checked like its single-key counterpart,
+/// and nothing is written if any check fails. A key can be written at most once in a batch.
+/// Returns [SledErr] or [LogicalErr] on failure.
+pub(super) fn handle_batch<V>(kv: &dyn Storage, ops: Vec<WriteOp<V>>) -> InnerKvResult<()>
+where
+    V: Serialize,
+{
+    let mut keys = HashSet::with_capacity(ops.len());
+    let mut batch = Vec::with_capacity(ops.len());
+    for op in ops {
+        if !keys.insert(op.key().to_string()) {
+            return Err(LogicalErr(format!(
+                "key <{}> is written more than once in a batch.",
+                op.key()
+            )));
+        }
+
+        let op = match op {
+            WriteOp::Insert { key, value } => {
+                if kv.contains_key(&key)? {
+                    return Err(LogicalErr(format!("key <{}> already exists.", key)));
+                }
+                BatchOp::Insert {
+                    key,
+                    value: serialize(&value).map_err(|_| SerializationErr)?,
+                }
+            }
+            WriteOp::Put { reservation, value } => {
+                if !kv
+                    .get(&reservation.key)?
+                    .map_or(false, |bytes| is_reservation(&bytes))
+                {
+                    return Err(LogicalErr(format!(
+                        "did not find reservation for key <{}> in kv store.",
+                        reservation.key
+                    )));
+                }
+                BatchOp::Insert {
+                    key: reservation.key,
+                    value: serialize(&value).map_err(|_| SerializationErr)?,
+                }
+            }
+            WriteOp::Update { key, value } => {
+                let _ = get_stored_bytes(kv, &key)?;
+                BatchOp::Insert {
+                    key,
+                    value: serialize(&value).map_err(|_| SerializationErr)?,
+                }
+            }
+            WriteOp::Delete { key } => {
+                let _ = get_stored_bytes(kv, &key)?;
+                BatchOp::Remove { key }
+            }
+        };
+        batch.push(op);
+    }
+
+    kv.apply_batch(batch)
+}
+
+/// Get all keys that are reserved but don't hold a value yet, ordered by key.
+/// Returns [SledErr] on failure.
+pub(super) fn handle_reservations(kv: &dyn Storage) -> InnerKvResult<Vec<Reservation>> {
+    let mut reservations: Vec<_> = kv
+        .get_all()?
+        .into_iter()
+        .filter_map(|(key, bytes)| {
+            ReservationRecord::from_bytes(&bytes).map(|record| record.into_reservation(key))
+        })
+        .collect();
+    reservations.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(reservations)
+}
+
+/// Releases the reservation of `key`, so that `key` can be reserved again.
+/// Returns the released reservation, or [LogicalErr] if `key` is not reserved.
+pub(super) fn handle_release(kv: &dyn Storage, key: String) -> InnerKvResult<Reservation> {
+    let record = kv
+        .get(&key)?
+        .and_then(|bytes| ReservationRecord::from_bytes(&bytes))
+        .ok_or_else(|| LogicalErr(format!("key <{}> is not reserved.", key)))?;
+    kv.remove(&key)?;
+
+    Ok(record.into_reservation(key))
+}
@@ -0,0 +1,452 @@
+//! A [Storage] that appends every write as an encrypted entry to a single file, and keeps
+//! the records in memory. The entries of the file are replayed when it is opened.
+//!
+//! File format:
+//!     header: magic (8 bytes) | version (2 bytes, LE) | salt (32 bytes) | kdf params length (4 bytes, LE) | kdf params
+//!     entry:  length (4 bytes, LE) | nonce (24 bytes) | ciphertext
+//! Each entry is encrypted with [XChaCha20Poly1305] under a key derived from the password and the
+//! salt with the stored [KdfParams]. The header and the index of the entry are authenticated as
+//! associated data, so entries cannot be modified, reordered or moved to another file without
+//! detection. The first entry of a file verifies the password. A partially written last entry,
+//! e.g. after a crash, is discarded when the file is opened.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tofn::sdk::api::{deserialize, serialize};
+use zeroize::Zeroize;
+
+use crate::encrypted_sled::{self, KdfParams, Password};
+
+use super::{
+    super::error::{InnerKvError::*, InnerKvResult, KvError::StorageErr, KvResult},
+    BatchOp, Storage,
+};
+
+// logging
+use tracing::{info, warn};
+
+const FILE_MAGIC: &[u8; 8] = b"TOFNDLOG";
+const FILE_VERSION: u16 = 1;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const LENGTH_LEN: usize = 4;
+
+/// A write of the kv store, appended to the file
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Verification,
+    Insert {
+        key: String,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: String,
+    },
+    Archive {
+        key: String,
+        archive_key: String,
+        value: Vec<u8>,
+    },
+    Batch(Vec<BatchOp>),
+}
+
+/// Single-file append-only [Storage]
+pub struct FileStorage {
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    /// length of the file up to the last complete entry
+    len: u64,
+    header: Vec<u8>,
+    cipher: XChaCha20Poly1305,
+    next_index: u64,
+    kv: BTreeMap<String, Vec<u8>>,
+    archive: BTreeMap<String, Vec<u8>>,
+}
+
+impl FileStorage {
+    /// Opens the file at `path`, or creates a new file encrypted with a key derived from
+    /// `password` with `kdf`. Existing files are opened with the [KdfParams] they were created with.
+    /// Returns [crate::kv_manager::error::KvError::InitErr] if the password is wrong.
+    pub fn open<P>(path: P, password: Password, kdf: &KdfParams) -> KvResult<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let state = if path.exists() {
+            let state = State::open(path, password)?;
+            info!(
+                "kv_manager found existing file storage [{}] with {} records",
+                path.display(),
+                state.kv.len()
+            );
+            state
+        } else {
+            info!(
+                "kv_manager cannot open existing file storage [{}]. creating new file storage with kdf [{}]",
+                path.display(),
+                kdf
+            );
+            State::create(path, password, kdf)?
+        };
+
+        Ok(Self {
+            state: Mutex::new(state),
+        })
+    }
+
+    fn state(&self) -> InnerKvResult<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| LogicalErr("file storage lock is poisoned".to_string()))
+    }
+}
+
+impl State {
+    /// Create a new file at `path` that holds only a password verification entry
+    fn create(path: &Path, password: Password, kdf: &KdfParams) -> KvResult<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let kdf_bytes = kdf.to_bytes()?;
+
+        let mut header = Vec::with_capacity(8 + 2 + SALT_LEN + LENGTH_LEN + kdf_bytes.len());
+        header.extend_from_slice(FILE_MAGIC);
+        header.extend_from_slice(&FILE_VERSION.to_le_bytes());
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&(kdf_bytes.len() as u32).to_le_bytes());
+        header.extend_from_slice(&kdf_bytes);
+
+        let cipher = cipher(password, salt, kdf)?;
+
+        let create = || -> InnerKvResult<File> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // never overwrite an existing file
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?;
+            file.write_all(&header)?;
+            Ok(file)
+        };
+        let file = create().map_err(StorageErr)?;
+
+        let mut state = Self {
+            file,
+            len: header.len() as u64,
+            header,
+            cipher,
+            next_index: 0,
+            kv: BTreeMap::new(),
+            archive: BTreeMap::new(),
+        };
+        state.append(Entry::Verification).map_err(StorageErr)?;
+        Ok(state)
+    }
+
+    /// Open the existing file at `path` and replay its entries
+    fn open(path: &Path, password: Password) -> KvResult<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| StorageErr(err.into()))?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)
+            .map_err(|err| StorageErr(err.into()))?;
+
+        let (header, kdf) = parse_header(&bytes).map_err(StorageErr)?;
+        let salt: [u8; SALT_LEN] = header[10..10 + SALT_LEN]
+            .try_into()
+            .map_err(|_| StorageErr(StorageFormatErr("malformed salt".to_string())))?;
+        let cipher = cipher(password, salt, &kdf)?;
+
+        let mut state = Self {
+            file,
+            len: header.len() as u64,
+            header: header.to_vec(),
+            cipher,
+            next_index: 0,
+            kv: BTreeMap::new(),
+            archive: BTreeMap::new(),
+        };
+
+        let mut pos = header.len();
+        while pos < bytes.len() {
+            let entry_len = match bytes.get(pos..pos + LENGTH_LEN) {
+                Some(len) => u32::from_le_bytes(len.try_into().unwrap()) as usize,
+                None => break,
+            };
+            let entry_bytes = match bytes.get(pos + LENGTH_LEN..pos + LENGTH_LEN + entry_len) {
+                Some(entry_bytes) => entry_bytes,
+                None => break,
+            };
+
+            let entry = match state.decrypt(entry_bytes) {
+                Ok(entry) => entry,
+                // the first entry can only fail to decrypt under a wrong password
+                Err(_) if state.next_index == 0 => {
+                    return Err(encrypted_sled::Error::WrongPassword.into())
+                }
+                Err(err) => return Err(StorageErr(err)),
+            };
+            if (state.next_index == 0) != matches!(entry, Entry::Verification) {
+                return Err(StorageErr(StorageFormatErr(format!(
+                    "unexpected entry {}",
+                    state.next_index
+                ))));
+            }
+            state.apply(entry);
+
+            pos += LENGTH_LEN + entry_len;
+            state.len = pos as u64;
+            state.next_index += 1;
+        }
+
+        if state.next_index == 0 {
+            return Err(StorageErr(StorageFormatErr(
+                "missing password verification entry".to_string(),
+            )));
+        }
+        if pos < bytes.len() {
+            warn!(
+                "discarding a partially written entry of file storage [{}]",
+                path.display()
+            );
+            state
+                .file
+                .set_len(state.len)
+                .and_then(|_| state.file.sync_all())
+                .map_err(|err| StorageErr(err.into()))?;
+        }
+
+        Ok(state)
+    }
+
+    /// Returns the associated data of the entry at `index`
+    fn associated_data(&self, index: u64) -> Vec<u8> {
+        [&self.header[..], &index.to_le_bytes()].concat()
+    }
+
+    fn decrypt(&self, entry_bytes: &[u8]) -> InnerKvResult<Entry> {
+        if entry_bytes.len() < NONCE_LEN {
+            return Err(StorageFormatErr("entry is too short".to_string()));
+        }
+        let (nonce, ciphertext) = entry_bytes.split_at(NONCE_LEN);
+
+        let mut plaintext = ciphertext.to_vec();
+        self.cipher
+            .decrypt_in_place(
+                XNonce::from_slice(nonce),
+                &self.associated_data(self.next_index),
+                &mut plaintext,
+            )
+            .map_err(|_| {
+                StorageFormatErr(format!(
+                    "entry {} is corrupted or the password is wrong",
+                    self.next_index
+                ))
+            })?;
+
+        let entry = deserialize(&plaintext).ok_or(DeserializationErr);
+        plaintext.zeroize();
+        entry
+    }
+
+    /// Encrypt `entry`, append it to the file, and apply it to the records
+    fn append(&mut self, entry: Entry) -> InnerKvResult<()> {
+        let mut nonce = XNonce::default();
+        rand::thread_rng().fill_bytes(nonce.as_mut_slice());
+
+        let mut plaintext = serialize(&entry).map_err(|_| SerializationErr)?;
+        let res = self.cipher.encrypt_in_place(
+            &nonce,
+            &self.associated_data(self.next_index),
+            &mut plaintext,
+        );
+        if res.is_err() {
+            plaintext.zeroize();
+        }
+        res.map_err(|err| EncryptionErr(err.to_string()))?;
+
+        let entry_len = NONCE_LEN + plaintext.len();
+        let mut entry_bytes = Vec::with_capacity(LENGTH_LEN + entry_len);
+        entry_bytes.extend_from_slice(&(entry_len as u32).to_le_bytes());
+        entry_bytes.extend_from_slice(&nonce);
+        entry_bytes.extend_from_slice(&plaintext);
+
+        if let Err(err) = self
+            .file
+            .write_all(&entry_bytes)
+            .and_then(|_| self.file.sync_data())
+        {
+            // drop a partially written entry, so that later entries are not appended after it
+            let _ = self.file.set_len(self.len);
+            return Err(err.into());
+        }
+
+        self.len += entry_bytes.len() as u64;
+        self.next_index += 1;
+        self.apply(entry);
+        Ok(())
+    }
+
+    /// Apply `entry` to the records
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Verification => {}
+            Entry::Insert { key, value } => {
+                self.kv.insert(key, value);
+            }
+            Entry::Remove { key } => {
+                self.kv.remove(&key);
+            }
+            Entry::Archive {
+                key,
+                archive_key,
+                value,
+            } => {
+                self.kv.remove(&key);
+                self.archive.insert(archive_key, value);
+            }
+            Entry::Batch(batch) => {
+                for op in batch {
+                    match op {
+                        BatchOp::Insert { key, value } => {
+                            self.kv.insert(key, value);
+                        }
+                        BatchOp::Remove { key } => {
+                            self.kv.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the header of a file and the [KdfParams] stored in it
+fn parse_header(bytes: &[u8]) -> InnerKvResult<(&[u8], KdfParams)> {
+    let kdf_len_pos = FILE_MAGIC.len() + 2 + SALT_LEN;
+    if bytes.len() < kdf_len_pos + LENGTH_LEN || !bytes.starts_with(FILE_MAGIC) {
+        return Err(StorageFormatErr("not a tofnd storage file".to_string()));
+    }
+
+    let version = u16::from_le_bytes([bytes[FILE_MAGIC.len()], bytes[FILE_MAGIC.len() + 1]]);
+    if version != FILE_VERSION {
+        return Err(StorageFormatErr(format!(
+            "unsupported version {}: expected version {}",
+            version, FILE_VERSION
+        )));
+    }
+
+    let kdf_len = u32::from_le_bytes(
+        bytes[kdf_len_pos..kdf_len_pos + LENGTH_LEN]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    let header_len = kdf_len_pos + LENGTH_LEN + kdf_len;
+    let kdf_bytes = bytes
+        .get(kdf_len_pos + LENGTH_LEN..header_len)
+        .ok_or_else(|| StorageFormatErr("truncated header".to_string()))?;
+    let kdf = KdfParams::from_bytes(kdf_bytes)?;
+
+    Ok((&bytes[..header_len], kdf))
+}
+
+/// Derive the cipher of a file from `password` and `salt`
+fn cipher(
+    password: Password,
+    salt: [u8; SALT_LEN],
+    kdf: &KdfParams,
+) -> encrypted_sled::Result<XChaCha20Poly1305> {
+    // zeroize key since we are no longer using it after creating cipher
+    let mut key = kdf.derive_key(password, salt.into())?;
+    let cipher = XChaCha20Poly1305::new(&key);
+    key.zeroize();
+    Ok(cipher)
+}
+
+impl Storage for FileStorage {
+    fn get(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(self.state()?.kv.get(key).cloned())
+    }
+
+    fn contains_key(&self, key: &str) -> InnerKvResult<bool> {
+        Ok(self.state()?.kv.contains_key(key))
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>) -> InnerKvResult<()> {
+        self.state()?.append(Entry::Insert {
+            key: key.to_string(),
+            value,
+        })
+    }
+
+    fn remove(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        let mut state = self.state()?;
+        let value = match state.kv.get(key) {
+            Some(value) => value.clone(),
+            None => return Ok(None),
+        };
+        state.append(Entry::Remove {
+            key: key.to_string(),
+        })?;
+        Ok(Some(value))
+    }
+
+    fn archive(&self, key: &str, archive_key: &str, value: Vec<u8>) -> InnerKvResult<bool> {
+        let mut state = self.state()?;
+        if !state.kv.contains_key(key) {
+            return Ok(false);
+        }
+        state.append(Entry::Archive {
+            key: key.to_string(),
+            archive_key: archive_key.to_string(),
+            value,
+        })?;
+        Ok(true)
+    }
+
+    fn get_archived(&self, archive_key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(self.state()?.archive.get(archive_key).cloned())
+    }
+
+    fn get_all(&self) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+        Ok(self
+            .state()?
+            .kv
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn apply_batch(&self, batch: Vec<BatchOp>) -> InnerKvResult<()> {
+        // a batch is a single entry, so a partially written batch is discarded as a whole
+        self.state()?.append(Entry::Batch(batch))
+    }
+
+    /// Every entry is synced to disk when it is appended, so there is nothing to flush
+    fn flush(&self) -> InnerKvResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{
+    super::error::{InnerKvError::LogicalErr, InnerKvResult},
+    BatchOp, Storage,
+};
+
+/// A [Storage] that keeps all records in memory. Records are lost when the storage is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    records: Mutex<Records>,
+}
+
+#[derive(Debug, Default)]
+struct Records {
+    kv: BTreeMap<String, Vec<u8>>,
+    archive: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn records(&self) -> InnerKvResult<MutexGuard<'_, Records>> {
+        self.records
+            .lock()
+            .map_err(|_| LogicalErr("memory storage lock is poisoned".to_string()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(self.records()?.kv.get(key).cloned())
+    }
+
+    fn contains_key(&self, key: &str) -> InnerKvResult<bool> {
+        Ok(self.records()?.kv.contains_key(key))
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>) -> InnerKvResult<()> {
+        self.records()?.kv.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(self.records()?.kv.remove(key))
+    }
+
+    fn archive(&self, key: &str, archive_key: &str, value: Vec<u8>) -> InnerKvResult<bool> {
+        let mut records = self.records()?;
+        if records.kv.remove(key).is_none() {
+            return Ok(false);
+        }
+        records.archive.insert(archive_key.to_string(), value);
+        Ok(true)
+    }
+
+    fn get_archived(&self, archive_key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(self.records()?.archive.get(archive_key).cloned())
+    }
+
+    fn get_all(&self) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+        Ok(self
+            .records()?
+            .kv
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn apply_batch(&self, batch: Vec<BatchOp>) -> InnerKvResult<()> {
+        let mut records = self.records()?;
+        for op in batch {
+            match op {
+                BatchOp::Insert { key, value } => {
+                    records.kv.insert(key, value);
+                }
+                BatchOp::Remove { key } => {
+                    records.kv.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Records are not persisted, so there is nothing to flush
+    fn flush(&self) -> InnerKvResult<()> {
+        Ok(())
+    }
+}
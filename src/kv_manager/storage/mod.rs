@@ -0,0 +1,174 @@
+//! Storage backends of [super::kv::Kv]. The kv actor reads and writes records through the
+//! [Storage] trait, so that tofnd can run on top of different stores:
+//! * [StorageBackend::EncryptedSled]: an [crate::encrypted_sled::Db] (default)
+//! * [StorageBackend::Memory]: a [MemoryStorage] that keeps records in memory, for tests and ephemeral nodes
+//! * [StorageBackend::File]: a [FileStorage] that appends encrypted records to a single file
+//!
+//! Other stores can be plugged in by implementing [Storage] and passing it to
+//! [super::KvManager::with_storage].
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+    str::FromStr,
+};
+
+use crate::encrypted_sled::{KdfParams, Kek};
+
+use super::{
+    error::{InnerKvError, InnerKvResult, KvError, KvResult},
+    kv::kv_path,
+};
+
+/// single-file append-only storage
+mod file;
+/// in-memory storage
+mod memory;
+/// [Storage] implementation of [crate::encrypted_sled::Db]
+mod sled_storage;
+
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+
+/// A key-value store of the records of a [super::kv::Kv]. Records that are moved to the archive
+/// are kept apart from the live records and are not visible to [Storage::get_all].
+/// Implementations may buffer writes until [Storage::flush], and must keep values confidential
+/// at rest unless they don't persist records at all.
+pub trait Storage: Send {
+    /// Returns the value of `key` if it exists
+    fn get(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>>;
+
+    /// Returns `true` if `key` exists
+    fn contains_key(&self, key: &str) -> InnerKvResult<bool>;
+
+    /// Sets the value of `key`, overwriting its previous value
+    fn insert(&self, key: &str, value: Vec<u8>) -> InnerKvResult<()>;
+
+    /// Removes `key`, returning its value if it existed
+    fn remove(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>>;
+
+    /// Atomically removes `key` and stores `value` under `archive_key` in the archive.
+    /// Returns `false` and leaves the store untouched if `key` does not exist.
+    fn archive(&self, key: &str, archive_key: &str, value: Vec<u8>) -> InnerKvResult<bool>;
+
+    /// Returns the archived value of `archive_key` if it exists
+    fn get_archived(&self, archive_key: &str) -> InnerKvResult<Option<Vec<u8>>>;
+
+    /// Returns all live keys along with their values, in no particular order
+    fn get_all(&self) -> InnerKvResult<Vec<(String, Vec<u8>)>>;
+
+    /// Atomically applies all writes of `batch` in order: either all of them are applied,
+    /// or none of them is.
+    fn apply_batch(&self, batch: Vec<BatchOp>) -> InnerKvResult<()>;
+
+    /// Persists all writes that returned before this call, so that they survive a crash
+    fn flush(&self) -> InnerKvResult<()>;
+
+    /// Returns the live keys within `range` along with their values, ordered by key.
+    /// The default implementation filters [Storage::get_all], since backends such as
+    /// [crate::encrypted_sled::Db] don't store keys in order.
+    fn range(&self, range: (Bound<&str>, Bound<&str>)) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+        let mut records: Vec<_> = self
+            .get_all()?
+            .into_iter()
+            .filter(|(key, _)| RangeBounds::<str>::contains(&range, key.as_str()))
+            .collect();
+        records.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(records)
+    }
+}
+
+/// A write of a batch that is applied by [Storage::apply_batch]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of `key`, overwriting its previous value
+    Insert { key: String, value: Vec<u8> },
+    /// Removes `key` if it exists
+    Remove { key: String },
+}
+
+/// The built-in [Storage] backends
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageBackend {
+    EncryptedSled,
+    Memory,
+    File,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        Self::EncryptedSled
+    }
+}
+
+impl StorageBackend {
+    /// Opens the storage of the kvstore under `root_path`, or creates it if it does not exist.
+    /// The data key of new sled stores is wrapped by `kek`, and password keks are derived with `kdf`.
+    /// File stores are encrypted with a key derived from the password of a password `kek` with `kdf`.
+    pub fn open(&self, root_path: &str, kek: Kek, kdf: &KdfParams) -> KvResult<Box<dyn Storage>> {
+        let storage: Box<dyn Storage> = match self {
+            Self::EncryptedSled => Box::new(sled_storage::open(&kv_path(root_path), kek, kdf)?),
+            Self::Memory => Box::new(MemoryStorage::new()),
+            Self::File => {
+                let password = match kek {
+                    Kek::Password(password) => password,
+                    kek => {
+                        return Err(KvError::StorageErr(InnerKvError::LogicalErr(format!(
+                            "the file storage backend does not support {} keks",
+                            kek.name()
+                        ))))
+                    }
+                };
+                Box::new(FileStorage::open(
+                    format!("{}.log", kv_path(root_path)),
+                    password,
+                    kdf,
+                )?)
+            }
+        };
+        Ok(storage)
+    }
+
+    /// Upgrades the storage of the kvstore under `root_path` if it was written by an older tofnd,
+    /// e.g. re-encrypts the records of a sled store in the current record format.
+    /// Opening a storage never upgrades it, so that one-off commands leave the store untouched.
+    /// Records that cannot be decrypted are logged and moved aside instead of failing the upgrade.
+    /// Must not be called while the storage is open.
+    pub fn upgrade(&self, root_path: &str, kek: &Kek) -> KvResult<()> {
+        match self {
+            Self::EncryptedSled => sled_storage::upgrade(&kv_path(root_path), kek),
+            Self::Memory | Self::File => Ok(()),
+        }
+    }
+}
+
+/// Parses `sled`, `memory` or `file`
+impl FromStr for StorageBackend {
+    type Err = InnerKvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sled" => Ok(Self::EncryptedSled),
+            "memory" => Ok(Self::Memory),
+            "file" => Ok(Self::File),
+            _ => Err(InnerKvError::LogicalErr(format!(
+                "invalid storage backend [{}]: expected sled, memory or file",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EncryptedSled => write!(f, "sled"),
+            Self::Memory => write!(f, "memory"),
+            Self::File => write!(f, "file"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
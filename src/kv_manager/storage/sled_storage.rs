@@ -0,0 +1,124 @@
+use crate::encrypted_sled::{self, KdfParams, Kek};
+
+use super::{
+    super::error::{
+        InnerKvError::{LogicalErr, SledErr},
+        InnerKvResult,
+        KvError::MigrateErr,
+        KvResult,
+    },
+    BatchOp, Storage,
+};
+
+// logging
+use tracing::info;
+
+/// Returns the db with name `db_name`, or creates a new if such DB does not exist
+/// Returns [sled::Error] on failure.
+/// Default path DB path is the executable's directory; The caller can specify a
+/// full path followed by the name of the DB
+/// Usage:
+///  let my_db = open(&"my_current_dir_db", kek, &KdfParams::default())?;
+///  let my_db = open(&"/tmp/my_tmp_bd", kek, &KdfParams::default())?;
+pub(super) fn open(
+    db_name: &str,
+    kek: Kek,
+    kdf: &KdfParams,
+) -> encrypted_sled::Result<encrypted_sled::Db> {
+    // create/open DB
+    let kv = encrypted_sled::Db::open_with_kdf(db_name, kek, kdf)?;
+
+    // log whether the DB was newly created or not
+    if kv.was_recovered() {
+        info!(
+            "kv_manager found existing db [{}] with kek [{}] and kdf [{}]",
+            db_name,
+            kv.kek(),
+            kv.kdf()
+        );
+    } else {
+        info!(
+            "kv_manager cannot open existing db [{}]. creating new db with kek [{}] and kdf [{}]",
+            db_name,
+            kv.kek(),
+            kv.kdf()
+        );
+    }
+    Ok(kv)
+}
+
+/// Re-encrypts the records of the existing db with name `db_name` in the current record format,
+/// and replaces its data key if it is derived from the password, if the db was written by an older tofnd.
+/// Records that cannot be decrypted are moved aside and logged.
+pub(super) fn upgrade(db_name: &str, kek: &Kek) -> KvResult<()> {
+    // don't create a new db: it is created in the current format when it is opened
+    if !std::path::Path::new(db_name).exists() {
+        return Ok(());
+    }
+
+    let mut kv = encrypted_sled::Db::open_with_kek(db_name, kek, &KdfParams::default())?;
+    if !kv.needs_upgrade() {
+        return Ok(());
+    }
+    let skipped = kv.upgrade(kek).map_err(|err| MigrateErr(SledErr(err)))?;
+    log_skipped_records(db_name, "upgrading it", &skipped);
+    info!(
+        "kv_manager upgraded db [{}] to the current record format and data key, skipping {} records",
+        db_name,
+        skipped.len()
+    );
+    Ok(())
+}
+
+impl Storage for encrypted_sled::Db {
+    fn get(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(encrypted_sled::Db::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn contains_key(&self, key: &str) -> InnerKvResult<bool> {
+        Ok(encrypted_sled::Db::contains_key(self, key)?)
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>) -> InnerKvResult<()> {
+        encrypted_sled::Db::insert(self, key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(encrypted_sled::Db::remove(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn archive(&self, key: &str, archive_key: &str, value: Vec<u8>) -> InnerKvResult<bool> {
+        Ok(encrypted_sled::Db::archive(self, key, archive_key, value)?)
+    }
+
+    fn get_archived(&self, archive_key: &str) -> InnerKvResult<Option<Vec<u8>>> {
+        Ok(encrypted_sled::Db::get_archived(self, archive_key)?.map(|value| value.to_vec()))
+    }
+
+    fn get_all(&self) -> InnerKvResult<Vec<(String, Vec<u8>)>> {
+        self.iter()
+            .map(|record| -> InnerKvResult<(String, Vec<u8>)> {
+                let (key, value) = record?;
+                let key = String::from_utf8(key.to_vec())
+                    .map_err(|err| LogicalErr(format!("key is not valid utf8: {}", err)))?;
+                Ok((key, value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn apply_batch(&self, batch: Vec<BatchOp>) -> InnerKvResult<()> {
+        let mut sled_batch = encrypted_sled::Batch::default();
+        for op in batch {
+            match op {
+                BatchOp::Insert { key, value } => sled_batch.insert(key, value),
+                BatchOp::Remove { key } => sled_batch.remove(key),
+            }
+        }
+        Ok(encrypted_sled::Db::apply_batch(self, sled_batch)?)
+    }
+    fn flush(&self) -> InnerKvResult<()> {
+        encrypted_sled::Db::flush(self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! [Storage] tests, run against every backend
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use super::{BatchOp, FileStorage, MemoryStorage, Storage, StorageBackend};
+use crate::{
+    encrypted_sled::{get_test_password, KdfParams, Password},
+    kv_manager::error::KvError,
+};
+use std::ops::Bound;
+
+use testdir::testdir;
+
+// cheap kdf to keep the tests fast
+fn test_kdf() -> KdfParams {
+    KdfParams::Scrypt {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    }
+}
+
+fn open_file(path: &std::path::Path, password: Password) -> Result<FileStorage, KvError> {
+    FileStorage::open(path, password, &test_kdf())
+}
+
+/// all backends, each one on a fresh store
+fn backends() -> Vec<(StorageBackend, Box<dyn Storage>)> {
+    let dir = testdir!();
+    [
+        StorageBackend::EncryptedSled,
+        StorageBackend::Memory,
+        StorageBackend::File,
+    ]
+    .iter()
+    .map(|backend| {
+        let root = dir.join(backend.to_string());
+        let storage = backend
+            .open(
+                root.to_str().unwrap(),
+                get_test_password().into(),
+                &test_kdf(),
+            )
+            .unwrap();
+        (backend.clone(), storage)
+    })
+    .collect()
+}
+
+#[test]
+fn insert_get_remove() {
+    for (backend, storage) in backends() {
+        assert_eq!(storage.get("key").unwrap(), None, "{}", backend);
+        assert!(!storage.contains_key("key").unwrap(), "{}", backend);
+
+        storage.insert("key", b"value".to_vec()).unwrap();
+        assert_eq!(
+            storage.get("key").unwrap(),
+            Some(b"value".to_vec()),
+            "{}",
+            backend
+        );
+        assert!(storage.contains_key("key").unwrap(), "{}", backend);
+
+        // overwrite
+        storage.insert("key", b"new value".to_vec()).unwrap();
+        assert_eq!(
+            storage.get("key").unwrap(),
+            Some(b"new value".to_vec()),
+            "{}",
+            backend
+        );
+
+        assert_eq!(
+            storage.remove("key").unwrap(),
+            Some(b"new value".to_vec()),
+            "{}",
+            backend
+        );
+        assert_eq!(storage.remove("key").unwrap(), None, "{}", backend);
+        assert!(!storage.contains_key("key").unwrap(), "{}", backend);
+    }
+}
+
+#[test]
+fn archive() {
+    for (backend, storage) in backends() {
+        storage.insert("key", b"value".to_vec()).unwrap();
+        storage
+            .insert("other key", b"other value".to_vec())
+            .unwrap();
+
+        assert!(storage
+            .archive("key", "key/1", b"archived".to_vec())
+            .unwrap());
+        assert!(!storage.contains_key("key").unwrap(), "{}", backend);
+        assert_eq!(
+            storage.get_archived("key/1").unwrap(),
+            Some(b"archived".to_vec()),
+            "{}",
+            backend
+        );
+
+        // archived records are not live
+        assert_eq!(
+            storage.get_all().unwrap(),
+            vec![("other key".to_string(), b"other value".to_vec())],
+            "{}",
+            backend
+        );
+
+        // missing keys are not archived
+        assert!(!storage
+            .archive("key", "key/2", b"archived".to_vec())
+            .unwrap());
+        assert_eq!(storage.get_archived("key/2").unwrap(), None, "{}", backend);
+    }
+}
+
+#[test]
+fn get_all() {
+    for (backend, storage) in backends() {
+        assert!(storage.get_all().unwrap().is_empty(), "{}", backend);
+
+        storage.insert("a", b"1".to_vec()).unwrap();
+        storage.insert("b", b"2".to_vec()).unwrap();
+        storage.insert("c", b"3".to_vec()).unwrap();
+        storage.remove("b").unwrap();
+
+        let mut records = storage.get_all().unwrap();
+        records.sort();
+        assert_eq!(
+            records,
+            vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("c".to_string(), b"3".to_vec())
+            ],
+            "{}",
+            backend
+        );
+    }
+}
+
+#[test]
+fn apply_batch() {
+    for (backend, storage) in backends() {
+        storage.insert("removed", b"value".to_vec()).unwrap();
+        storage.insert("overwritten", b"value".to_vec()).unwrap();
+
+        storage
+            .apply_batch(vec![
+                BatchOp::Insert {
+                    key: "new".to_string(),
+                    value: b"new value".to_vec(),
+                },
+                BatchOp::Insert {
+                    key: "overwritten".to_string(),
+                    value: b"new value".to_vec(),
+                },
+                BatchOp::Remove {
+                    key: "removed".to_string(),
+                },
+            ])
+            .unwrap();
+
+        let mut records = storage.get_all().unwrap();
+        records.sort();
+        assert_eq!(
+            records,
+            vec![
+                ("new".to_string(), b"new value".to_vec()),
+                ("overwritten".to_string(), b"new value".to_vec())
+            ],
+            "{}",
+            backend
+        );
+    }
+}
+
+#[test]
+fn range() {
+    for (backend, storage) in backends() {
+        for key in ["c", "a/2", "b", "a/1"].iter() {
+            storage.insert(key, key.as_bytes().to_vec()).unwrap();
+        }
+
+        let keys = |range: (Bound<&str>, Bound<&str>)| -> Vec<String> {
+            storage
+                .range(range)
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+        assert_eq!(
+            keys((Bound::Unbounded, Bound::Unbounded)),
+            vec!["a/1", "a/2", "b", "c"],
+            "{}",
+            backend
+        );
+        assert_eq!(
+            keys((Bound::Included("a/2"), Bound::Excluded("c"))),
+            vec!["a/2", "b"],
+            "{}",
+            backend
+        );
+        assert!(keys((Bound::Excluded("c"), Bound::Unbounded)).is_empty());
+    }
+}
+
+#[test]
+fn parse_backend() {
+    for backend in [
+        StorageBackend::EncryptedSled,
+        StorageBackend::Memory,
+        StorageBackend::File,
+    ]
+    .iter()
+    {
+        assert_eq!(
+            &backend.to_string().parse::<StorageBackend>().unwrap(),
+            backend
+        );
+    }
+    assert!("rocksdb".parse::<StorageBackend>().is_err());
+}
+
+#[test]
+fn memory_is_ephemeral() {
+    let storage = MemoryStorage::new();
+    storage.insert("key", b"value".to_vec()).unwrap();
+    drop(storage);
+
+    assert!(MemoryStorage::new().get_all().unwrap().is_empty());
+}
+
+#[test]
+fn file_reopen() {
+    let path = testdir!().join("kv.log");
+    {
+        let storage = open_file(&path, get_test_password()).unwrap();
+        storage.insert("key", b"value".to_vec()).unwrap();
+        storage.insert("removed", b"value".to_vec()).unwrap();
+        storage.remove("removed").unwrap();
+        storage.insert("archived", b"value".to_vec()).unwrap();
+        storage
+            .archive("archived", "archived/1", b"record".to_vec())
+            .unwrap();
+    }
+
+    let storage = open_file(&path, get_test_password()).unwrap();
+    assert_eq!(
+        storage.get_all().unwrap(),
+        vec![("key".to_string(), b"value".to_vec())]
+    );
+    assert_eq!(
+        storage.get_archived("archived/1").unwrap(),
+        Some(b"record".to_vec())
+    );
+
+    // writes after reopening are kept too
+    storage.insert("new key", b"new value".to_vec()).unwrap();
+    drop(storage);
+    let storage = open_file(&path, get_test_password()).unwrap();
+    assert_eq!(storage.get_all().unwrap().len(), 2);
+}
+
+#[test]
+fn file_batch_reopen() {
+    let path = testdir!().join("kv.log");
+    {
+        let storage = open_file(&path, get_test_password()).unwrap();
+        storage.insert("removed", b"value".to_vec()).unwrap();
+        storage
+            .apply_batch(vec![
+                BatchOp::Insert {
+                    key: "key".to_string(),
+                    value: b"value".to_vec(),
+                },
+                BatchOp::Remove {
+                    key: "removed".to_string(),
+                },
+            ])
+            .unwrap();
+    }
+
+    let storage = open_file(&path, get_test_password()).unwrap();
+    assert_eq!(
+        storage.get_all().unwrap(),
+        vec![("key".to_string(), b"value".to_vec())]
+    );
+}
+
+#[test]
+fn file_wrong_password() {
+    let path = testdir!().join("kv.log");
+    drop(open_file(&path, get_test_password()).unwrap());
+
+    let res = open_file(&path, Password::from("wrong password"));
+    assert!(matches!(
+        res,
+        Err(KvError::InitErr(
+            crate::encrypted_sled::Error::WrongPassword
+        ))
+    ));
+}
+
+#[test]
+fn file_truncated_entry() {
+    let path = testdir!().join("kv.log");
+    {
+        let storage = open_file(&path, get_test_password()).unwrap();
+        storage.insert("key", b"value".to_vec()).unwrap();
+        storage.insert("lost", b"value".to_vec()).unwrap();
+    }
+
+    // simulate a crash in the middle of the last write
+    let len = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 5)
+        .unwrap();
+
+    let storage = open_file(&path, get_test_password()).unwrap();
+    assert_eq!(
+        storage.get_all().unwrap(),
+        vec![("key".to_string(), b"value".to_vec())]
+    );
+
+    // the partial entry was dropped, so new entries can be read back
+    storage.insert("key2", b"value2".to_vec()).unwrap();
+    drop(storage);
+    let storage = open_file(&path, get_test_password()).unwrap();
+    assert_eq!(storage.get_all().unwrap().len(), 2);
+}
+
+#[test]
+fn file_tampered_entry() {
+    let path = testdir!().join("kv.log");
+    {
+        let storage = open_file(&path, get_test_password()).unwrap();
+        storage.insert("key", b"value".to_vec()).unwrap();
+    }
+
+    // flip the last byte of the last entry
+    let mut bytes = std::fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&path, &bytes).unwrap();
+
+    assert!(matches!(
+        open_file(&path, get_test_password()),
+        Err(KvError::StorageErr(_))
+    ));
+}
+
+#[test]
+fn file_is_encrypted() {
+    let path = testdir!().join("kv.log");
+    {
+        let storage = open_file(&path, get_test_password()).unwrap();
+        storage
+            .insert("secret key", b"secret value".to_vec())
+            .unwrap();
+    }
+    // appending garbage that is not a complete entry is ignored as a partial write
+    OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(&[1, 0])
+        .unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|window| window == needle);
+    assert!(!contains(b"secret key"));
+    assert!(!contains(b"secret value"));
+
+    assert_eq!(
+        open_file(&path, get_test_password())
+            .unwrap()
+            .get("secret key")
+            .unwrap(),
+        Some(b"secret value".to_vec())
+    );
+}
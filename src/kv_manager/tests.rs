@@ -2,10 +2,17 @@
 
 use super::{
     error::InnerKvError::LogicalErr,
-    sled_bindings::{handle_exists, handle_get, handle_put, handle_reserve},
-    types::{KeyReservation, DEFAULT_RESERV},
+    sled_bindings::{
+        handle_archive, handle_batch, handle_delete, handle_exists, handle_get, handle_get_all,
+        handle_put, handle_range, handle_release, handle_reservations, handle_reserve,
+        handle_scan_prefix, handle_update,
+    },
+    types::{
+        is_reservation, ArchivedRecord, KeyReservation, ReservationRecord, WriteOp, DEFAULT_RESERV,
+    },
 };
 use crate::encrypted_sled;
+use std::ops::Bound;
 
 // testdir creates a test directory at $TMPDIR.
 // Mac: /var/folders/v4/x_j3jj7d6ql4gjdf7b7jvjhm0000gn/T/testdir-of-$(USER)
@@ -27,6 +34,31 @@ where
     encrypted_sled::Db::open(db_name, encrypted_sled::get_test_password())
 }
 
+/// Returns the names of all trees of the sled db `db_name` along with all their records,
+/// once the kv manager that used the db has released it
+pub(super) fn sled_contents(db_name: &str) -> Vec<(sled::IVec, Vec<(sled::IVec, sled::IVec)>)> {
+    // sled does not support to rapidly open/close databases, see tests/tofnd_party.rs
+    let mut db = None;
+    for _ in 0..50 {
+        match sled::open(db_name) {
+            Ok(opened) => {
+                db = Some(opened);
+                break;
+            }
+            Err(_) => std::thread::sleep(std::time::Duration::from_millis(100)),
+        }
+    }
+    let db = db.unwrap_or_else(|| panic!("could not open db [{}]", db_name));
+    db.tree_names()
+        .into_iter()
+        .map(|name| {
+            let tree = db.open_tree(&name).unwrap();
+            let records = tree.iter().collect::<Result<_, _>>().unwrap();
+            (name, records)
+        })
+        .collect()
+}
+
 #[test]
 fn reserve_success() {
     let kv_name = testdir!("reserve_success");
@@ -34,15 +66,17 @@ fn reserve_success() {
 
     let key: String = "key".to_string();
     assert_eq!(
-        handle_reserve(&kv, key.clone()).unwrap(),
+        handle_reserve(&kv, key.clone(), "session".to_string()).unwrap(),
         KeyReservation { key: key.clone() }
     );
 
-    // check if default value was stored
+    // check if a record of the reservation was stored
     // get bytes
-    let default_reserv = kv.get(&key).unwrap().unwrap();
-    // convert to value type
-    assert!(default_reserv == DEFAULT_RESERV);
+    let reservation = kv.get(&key).unwrap().unwrap();
+    // convert to reservation record
+    let record = ReservationRecord::from_bytes(&reservation).unwrap();
+    assert_eq!(record.session, "session");
+    assert!(record.created_at > 0);
 
     clean_up(kv_name.to_str().unwrap(), kv);
 }
@@ -53,9 +87,11 @@ fn reserve_failure() {
     let kv = open_with_test_password(&kv_name).unwrap();
 
     let key: String = "key".to_string();
-    handle_reserve(&kv, key.clone()).unwrap();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
     // try reserving twice
-    let err = handle_reserve(&kv, key).err().unwrap();
+    let err = handle_reserve(&kv, key, "session".to_string())
+        .err()
+        .unwrap();
     assert!(matches!(err, LogicalErr(_)));
     clean_up(kv_name.to_str().unwrap(), kv);
 }
@@ -66,7 +102,7 @@ fn put_success() {
     let kv = open_with_test_password(&kv_name).unwrap();
 
     let key: String = "key".to_string();
-    handle_reserve(&kv, key.clone()).unwrap();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
 
     let value: String = "value".to_string();
     assert!(handle_put(&kv, KeyReservation { key }, value).is_ok());
@@ -102,7 +138,7 @@ fn put_failure_put_twice() {
     let value = "value".to_string();
     let value2 = "value2".to_string();
 
-    handle_reserve(&kv, key.clone()).unwrap();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
     handle_put(&kv, KeyReservation { key: key.clone() }, value.clone()).unwrap();
 
     let err = handle_put(&kv, KeyReservation { key: key.clone() }, value2)
@@ -128,7 +164,7 @@ fn get_success() {
 
     let key: String = "key".to_string();
     let value = "value";
-    handle_reserve(&kv, key.clone()).unwrap();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
     handle_put(&kv, KeyReservation { key: key.clone() }, value).unwrap();
     let res = handle_get::<String>(&kv, key);
     assert!(res.is_ok());
@@ -150,6 +186,442 @@ fn get_failure() {
     clean_up(kv_name.to_str().unwrap(), kv);
 }
 
+#[test]
+fn get_all_success() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    let key: String = "key".to_string();
+    let value = "value";
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
+    handle_put(&kv, KeyReservation { key: key.clone() }, value).unwrap();
+
+    // reserved keys without a value should not be returned
+    handle_reserve(&kv, "reserved".to_string(), "session".to_string()).unwrap();
+
+    let res = handle_get_all::<String>(&kv).unwrap();
+    assert_eq!(res, vec![(key, value.to_string())]);
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn archive_success() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    let key: String = "key".to_string();
+    let value = "value";
+    let reason = "rotated".to_string();
+    let mut archive_keys = vec![];
+    // a key can be archived more than once, e.g. when a key uid is reused
+    for _ in 0..2 {
+        handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
+        handle_put(&kv, KeyReservation { key: key.clone() }, value).unwrap();
+
+        let archive_key = handle_archive(&kv, key.clone(), reason.clone(), |_: String| true)
+            .unwrap()
+            .unwrap();
+        archive_keys.push(archive_key);
+
+        // key is no longer available
+        assert!(!kv.contains_key(&key).unwrap());
+    }
+    assert_ne!(archive_keys[0], archive_keys[1]);
+
+    // archived records hold the original value
+    for archive_key in archive_keys {
+        let record = kv.get_archived(&archive_key).unwrap().unwrap();
+        let record: ArchivedRecord = deserialize(&record).unwrap();
+        assert_eq!(record.reason, reason);
+        let archived_value: String = deserialize(&record.value).unwrap();
+        assert_eq!(archived_value, value);
+    }
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn archive_failure() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    // a key that does not exist is not archived
+    let res = handle_archive(&kv, "key".to_string(), String::default(), |_: String| true);
+    assert_eq!(res.unwrap(), None);
+
+    // cannot archive a key that is only reserved
+    handle_reserve(&kv, "key".to_string(), "session".to_string()).unwrap();
+    let err = handle_archive(&kv, "key".to_string(), String::default(), |_: String| true)
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(kv.contains_key("key").unwrap());
+
+    // cannot archive a value that does not pass the check
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "key".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+    let err = handle_archive(&kv, "key".to_string(), String::default(), |_: String| false)
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(kv.contains_key("key").unwrap());
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn delete_success() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    let key: String = "key".to_string();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
+    handle_put(&kv, KeyReservation { key: key.clone() }, "value").unwrap();
+
+    assert!(handle_delete(&kv, key.clone(), |value: String| value == "value").unwrap());
+    assert!(!kv.contains_key(&key).unwrap());
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn delete_failure() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    // a key that does not exist is not deleted
+    assert!(!handle_delete(&kv, "key".to_string(), |_: String| true).unwrap());
+
+    // cannot delete a key that is only reserved
+    handle_reserve(&kv, "key".to_string(), "session".to_string()).unwrap();
+    let err = handle_delete(&kv, "key".to_string(), |_: String| true)
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(kv.contains_key("key").unwrap());
+
+    // cannot delete a value that does not pass the check
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "key".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+    let err = handle_delete(&kv, "key".to_string(), |_: String| false)
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(kv.contains_key("key").unwrap());
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn update_success() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    let key: String = "key".to_string();
+    handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
+    handle_put(&kv, KeyReservation { key: key.clone() }, "value").unwrap();
+
+    handle_update(&kv, key.clone(), "new value").unwrap();
+    let res: String = handle_get(&kv, key).unwrap();
+    assert_eq!(res, "new value");
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn update_failure() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    // cannot update a key that does not exist
+    let err = handle_update(&kv, "key".to_string(), "value")
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(!kv.contains_key("key").unwrap());
+
+    // cannot update a key that is only reserved
+    handle_reserve(&kv, "key".to_string(), "session".to_string()).unwrap();
+    let err = handle_update(&kv, "key".to_string(), "value")
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    assert!(is_reservation(&kv.get("key").unwrap().unwrap()));
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn batch_success() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    handle_reserve(&kv, "reserved".to_string(), "session".to_string()).unwrap();
+    handle_reserve(&kv, "updated".to_string(), "session".to_string()).unwrap();
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "updated".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+    handle_reserve(&kv, "deleted".to_string(), "session".to_string()).unwrap();
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "deleted".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+
+    let ops = vec![
+        WriteOp::Insert {
+            key: "inserted".to_string(),
+            value: "inserted value",
+        },
+        WriteOp::Put {
+            reservation: KeyReservation {
+                key: "reserved".to_string(),
+            },
+            value: "put value",
+        },
+        WriteOp::Update {
+            key: "updated".to_string(),
+            value: "updated value",
+        },
+        WriteOp::Delete {
+            key: "deleted".to_string(),
+        },
+    ];
+    handle_batch(&kv, ops).unwrap();
+
+    let mut res: Vec<(String, String)> = handle_get_all(&kv).unwrap();
+    res.sort();
+    assert_eq!(
+        res,
+        vec![
+            ("inserted".to_string(), "inserted value".to_string()),
+            ("reserved".to_string(), "put value".to_string()),
+            ("updated".to_string(), "updated value".to_string()),
+        ]
+    );
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn batch_failure() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    handle_reserve(&kv, "key".to_string(), "session".to_string()).unwrap();
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "key".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+
+    // a failing write aborts the whole batch
+    let failing_ops = vec![
+        vec![WriteOp::Insert {
+            key: "key".to_string(),
+            value: "new value",
+        }],
+        vec![WriteOp::Put {
+            reservation: KeyReservation {
+                key: "key".to_string(),
+            },
+            value: "new value",
+        }],
+        vec![WriteOp::Update {
+            key: "missing".to_string(),
+            value: "new value",
+        }],
+        vec![WriteOp::Delete {
+            key: "missing".to_string(),
+        }],
+        // keys can be written only once in a batch
+        vec![
+            WriteOp::Update {
+                key: "key".to_string(),
+                value: "new value",
+            },
+            WriteOp::Delete {
+                key: "key".to_string(),
+            },
+        ],
+    ];
+    for mut ops in failing_ops {
+        ops.insert(
+            0,
+            WriteOp::Insert {
+                key: "other key".to_string(),
+                value: "other value",
+            },
+        );
+        let err = handle_batch(&kv, ops).err().unwrap();
+        assert!(matches!(err, LogicalErr(_)));
+
+        assert!(!kv.contains_key("other key").unwrap());
+        let res: String = handle_get(&kv, "key".to_string()).unwrap();
+        assert_eq!(res, "value");
+    }
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn range_and_scan_prefix() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    for key in ["a/1", "a/2", "b/1", "c"].iter() {
+        handle_reserve(&kv, key.to_string(), "session".to_string()).unwrap();
+        handle_put(
+            &kv,
+            KeyReservation {
+                key: key.to_string(),
+            },
+            key.to_string(),
+        )
+        .unwrap();
+    }
+    // reserved keys are skipped
+    handle_reserve(&kv, "a/3".to_string(), "session".to_string()).unwrap();
+
+    let keys = |records: Vec<(String, String)>| -> Vec<String> {
+        records.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let res = handle_scan_prefix(&kv, "a/".to_string()).unwrap();
+    assert_eq!(keys(res), vec!["a/1", "a/2"]);
+    let res = handle_scan_prefix(&kv, "d".to_string()).unwrap();
+    assert!(keys(res).is_empty());
+
+    let res = handle_range(
+        &kv,
+        Bound::Excluded("a/1".to_string()),
+        Bound::Included("b/1".to_string()),
+    )
+    .unwrap();
+    assert_eq!(keys(res), vec!["a/2", "b/1"]);
+    let res = handle_range(&kv, Bound::Unbounded, Bound::Excluded("b".to_string())).unwrap();
+    assert_eq!(keys(res), vec!["a/1", "a/2"]);
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn reservations_and_release() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    handle_reserve(&kv, "b".to_string(), "session b".to_string()).unwrap();
+    handle_reserve(&kv, "a".to_string(), "session a".to_string()).unwrap();
+    let reservation = handle_reserve(&kv, "c".to_string(), "session c".to_string()).unwrap();
+    handle_put(&kv, reservation, "value").unwrap();
+
+    // keys that hold a value are not reservations
+    let reservations = handle_reservations(&kv).unwrap();
+    let keys: Vec<_> = reservations
+        .iter()
+        .map(|reservation| (reservation.key.as_str(), reservation.session.as_str()))
+        .collect();
+    assert_eq!(keys, vec![("a", "session a"), ("b", "session b")]);
+
+    // release a reservation
+    let released = handle_release(&kv, "a".to_string()).unwrap();
+    assert_eq!(released, reservations[0]);
+    assert!(!kv.contains_key("a").unwrap());
+
+    // keys that are not reserved cannot be released
+    let err = handle_release(&kv, "a".to_string()).err().unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    let err = handle_release(&kv, "c".to_string()).err().unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+    let res: String = handle_get(&kv, "c".to_string()).unwrap();
+    assert_eq!(res, "value");
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn legacy_reservation() {
+    let kv_name = testdir!();
+    let kv = open_with_test_password(&kv_name).unwrap();
+
+    // reservations of older versions don't record their session
+    kv.insert("key", DEFAULT_RESERV).unwrap();
+    let reservations = handle_reservations(&kv).unwrap();
+    assert_eq!(reservations.len(), 1);
+    assert_eq!(reservations[0].session, "unknown");
+    assert_eq!(reservations[0].created_at, 0);
+
+    // but can still be filled
+    handle_put(
+        &kv,
+        KeyReservation {
+            key: "key".to_string(),
+        },
+        "value",
+    )
+    .unwrap();
+    assert!(handle_reservations(&kv).unwrap().is_empty());
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
+#[test]
+fn crash_between_reserve_and_put() {
+    let kv_name = testdir!();
+    let key = "key".to_string();
+
+    // tofnd crashes after reserving a key, but before putting its value
+    let kv = open_with_test_password(&kv_name).unwrap();
+    handle_reserve(&kv, key.clone(), "keygen".to_string()).unwrap();
+    drop(kv);
+
+    // after a restart, the key cannot be reserved again
+    let kv = open_with_test_password(&kv_name).unwrap();
+    let err = handle_reserve(&kv, key.clone(), "retry".to_string())
+        .err()
+        .unwrap();
+    assert!(matches!(err, LogicalErr(_)));
+
+    // the orphaned reservation is found and released
+    let reservations = handle_reservations(&kv).unwrap();
+    assert_eq!(reservations.len(), 1);
+    assert_eq!(reservations[0].key, key);
+    assert_eq!(reservations[0].session, "keygen");
+    handle_release(&kv, key.clone()).unwrap();
+
+    // now the key can be reserved and filled again
+    let reservation = handle_reserve(&kv, key.clone(), "retry".to_string()).unwrap();
+    handle_put(&kv, reservation, "value").unwrap();
+    let res: String = handle_get(&kv, key).unwrap();
+    assert_eq!(res, "value");
+
+    clean_up(kv_name.to_str().unwrap(), kv);
+}
+
 #[test]
 fn test_exists() {
     let kv_name = testdir!();
@@ -163,7 +635,7 @@ fn test_exists() {
     assert!(!exists.unwrap()); // assert that the result is false
 
     // reserve key
-    let reservation = handle_reserve(&kv, key.clone()).unwrap();
+    let reservation = handle_reserve(&kv, key.clone(), "session".to_string()).unwrap();
 
     // exists should succeed
     let exists = handle_exists(&kv, &key);
@@ -1,6 +1,11 @@
 //! useful types and default paths for the kv_manager
 
-use std::fmt::Debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug},
+    ops::Bound,
+};
+use tofn::sdk::api::{deserialize, serialize};
 
 // default KV store names
 pub const DEFAULT_KV_NAME: &str = "kv";
@@ -9,9 +14,87 @@ pub const DEFAULT_KV_NAME: &str = "kv";
 /// the full name of the kv store is "DEFAULT_KV_PATH/kv_name"
 pub(super) const DEFAULT_KV_PATH: &str = "kvstore";
 
-/// default value for reserved key
+/// maximum number of commands that wait for the kv_manager before callers are blocked
+pub(super) const DEFAULT_KV_QUEUE_LEN: usize = 1024;
+
+/// value of reserved keys written by older versions of tofnd, which don't record the owner of a reservation
 pub(super) const DEFAULT_RESERV: &str = "";
 
+/// prefix of the value of a reserved key, followed by a serialized [ReservationRecord]
+pub(super) const RESERVATION_MAGIC: &[u8; 8] = b"TOFNDRSV";
+
+/// The value of a reserved key: the session that owns the reservation and the time it was created
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub(super) struct ReservationRecord {
+    pub(super) session: String,
+    pub(super) created_at: u64, // seconds since unix epoch
+}
+
+impl ReservationRecord {
+    /// Returns the stored value of the reservation
+    pub(super) fn to_bytes(&self) -> Option<Vec<u8>> {
+        let bytes = serialize(self).ok()?;
+        Some([&RESERVATION_MAGIC[..], &bytes].concat())
+    }
+
+    /// Returns the reservation stored in `bytes`, or `None` if `bytes` is not a reservation.
+    /// Reservations of older versions are returned with an unknown session and creation time.
+    pub(super) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes == DEFAULT_RESERV.as_bytes() {
+            return Some(Self {
+                session: UNKNOWN_SESSION.to_string(),
+                created_at: 0,
+            });
+        }
+        deserialize(bytes.strip_prefix(&RESERVATION_MAGIC[..])?)
+    }
+
+    /// Returns the [Reservation] of `key`
+    pub(super) fn into_reservation(self, key: String) -> Reservation {
+        Reservation {
+            key,
+            session: self.session,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// session of reservations written by older versions of tofnd
+const UNKNOWN_SESSION: &str = "unknown";
+
+/// Returns `true` if `bytes` is the value of a reserved key
+pub(super) fn is_reservation(bytes: &[u8]) -> bool {
+    ReservationRecord::from_bytes(bytes).is_some()
+}
+
+/// A key that is reserved but does not hold a value yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reservation {
+    pub key: String,
+    /// the session that reserved the key: the uid of a keygen or recover session,
+    /// or the tofnd process that ran a mnemonic command
+    pub session: String,
+    /// seconds since unix epoch; 0 if the reservation was created by an older version of tofnd
+    pub created_at: u64,
+}
+
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.created_at {
+            0 => write!(
+                f,
+                "key <{}> reserved by session [{}]",
+                self.key, self.session
+            ),
+            created_at => write!(
+                f,
+                "key <{}> reserved by session [{}] at {}",
+                self.key, self.session, created_at
+            ),
+        }
+    }
+}
+
 /// Returned from a successful `ReserveKey` command
 #[derive(Debug)] // disallow derive Clone, Copy
 pub struct KeyReservation {
@@ -24,6 +107,46 @@ impl PartialEq for KeyReservation {
     }
 }
 
+/// A value that was moved to the archive, along with the time and the reason of archival.
+/// `value` holds the serialized bytes of the value as they were stored in the kv store.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub(super) struct ArchivedRecord {
+    pub(super) value: Vec<u8>,
+    pub(super) archived_at: u64, // seconds since unix epoch
+    pub(super) reason: String,
+}
+
+/// A write of a batch that is applied atomically by [super::kv::Kv::batch].
+/// Each write is checked like its single-key counterpart.
+#[derive(Debug)]
+pub enum WriteOp<V> {
+    /// Sets the value of a key that does not exist yet
+    Insert { key: String, value: V },
+    /// Sets the value of a reserved key, like [super::kv::Kv::put]
+    Put {
+        reservation: KeyReservation,
+        value: V,
+    },
+    /// Overwrites the value of a key that holds a value, like [super::kv::Kv::update]
+    Update { key: String, value: V },
+    /// Permanently deletes the value of a key, like [super::kv::Kv::delete]
+    Delete { key: String },
+}
+
+impl<V> WriteOp<V> {
+    /// Returns the key that is written
+    pub(super) fn key(&self) -> &str {
+        match self {
+            Self::Insert { key, .. } | Self::Update { key, .. } | Self::Delete { key } => key,
+            Self::Put { reservation, .. } => &reservation.key,
+        }
+    }
+}
+
+/// Checks the value of a key before it is archived or deleted, e.g. that the key holds the expected kind of value.
+/// The value is kept if the check returns `false`.
+pub type ValueCheck<V> = fn(V) -> bool;
+
 // Provided by the requester and used by the manager task to send the command response back to the requester.
 type Responder<T> = tokio::sync::oneshot::Sender<super::error::InnerKvResult<T>>;
 
@@ -31,6 +154,7 @@ type Responder<T> = tokio::sync::oneshot::Sender<super::error::InnerKvResult<T>>
 pub(super) enum Command<V> {
     ReserveKey {
         key: String,
+        session: String,
         resp: Responder<KeyReservation>,
     },
     UnreserveKey {
@@ -49,4 +173,46 @@ pub(super) enum Command<V> {
         key: String, // TODO should be &str except lifetimes...
         resp: Responder<bool>,
     },
+    GetAll {
+        resp: Responder<Vec<(String, V)>>,
+    },
+    Archive {
+        key: String,
+        reason: String,
+        check: ValueCheck<V>,
+        resp: Responder<Option<String>>,
+    },
+    Delete {
+        key: String,
+        check: ValueCheck<V>,
+        resp: Responder<bool>,
+    },
+    Update {
+        key: String,
+        value: V,
+        resp: Responder<()>,
+    },
+    Batch {
+        ops: Vec<WriteOp<V>>,
+        resp: Responder<()>,
+    },
+    Range {
+        start: Bound<String>,
+        end: Bound<String>,
+        resp: Responder<Vec<(String, V)>>,
+    },
+    ScanPrefix {
+        prefix: String,
+        resp: Responder<Vec<(String, V)>>,
+    },
+    Reservations {
+        resp: Responder<Vec<Reservation>>,
+    },
+    Release {
+        key: String,
+        resp: Responder<Reservation>,
+    },
+    Flush {
+        resp: Responder<()>,
+    },
 }
@@ -2,14 +2,18 @@ use std::{convert::TryFrom, path::PathBuf};
 use tofn::sdk::api::{deserialize, serialize};
 
 use crate::{
-    encrypted_sled::Password,
+    encrypted_sled::{KdfParams, Kek},
     gg20::types::{Entropy, PartyInfo},
     mnemonic::FileIo,
+    multisig::types::MultisigKeyInfo,
 };
 
 use super::{
+    durability::Durability,
     error::{InnerKvError, KvResult},
     kv::Kv,
+    migration::{seal, unseal, ValueKind},
+    storage::{Storage, StorageBackend},
 };
 
 /// Kv manager for grpc services
@@ -20,12 +24,57 @@ pub struct KvManager {
 }
 
 impl KvManager {
-    pub fn new(root: &str, password: Password) -> KvResult<Self> {
+    #[cfg(test)]
+    pub fn new(root: &str, password: crate::encrypted_sled::Password) -> KvResult<Self> {
+        Self::with_backend(
+            root,
+            password.into(),
+            &KdfParams::default(),
+            &StorageBackend::default(),
+            Durability::default(),
+        )
+    }
+    /// Opens the `backend` storage of the kvstore under `root`, flushing writes according to `durability`.
+    /// If the kvstore does not exist, it is created with `kek` and `kdf`.
+    pub fn with_backend(
+        root: &str,
+        kek: Kek,
+        kdf: &KdfParams,
+        backend: &StorageBackend,
+        durability: Durability,
+    ) -> KvResult<Self> {
+        let storage = backend.open(root, kek, kdf)?;
+        Self::with_storage(root, storage, durability)
+    }
+    /// Upgrades the `backend` storage of the kvstore under `root` if it was written by an older tofnd;
+    /// see [StorageBackend::upgrade]. Must be called before a [KvManager] is created for `root`.
+    pub fn upgrade(root: &str, kek: &Kek, backend: &StorageBackend) -> KvResult<()> {
+        backend.upgrade(root, kek)
+    }
+    /// Uses `storage` for the kvstore, e.g. a custom [super::Storage] of an embedding application.
+    /// Files other than the kvstore, such as mnemonic exports, are written under `root`.
+    pub fn with_storage(
+        root: &str,
+        storage: Box<dyn Storage>,
+        durability: Durability,
+    ) -> KvResult<Self> {
         Ok(KvManager {
-            kv: Kv::<KvValue>::new(root, password)?,
+            kv: Kv::<KvValue>::with_storage(storage, durability)?,
             io: FileIo::new(PathBuf::from(root)),
         })
     }
+    /// Re-wraps the data key of the sled kvstore under `root` with `new_kek`, deriving a new password kek
+    /// with `new_kdf` if provided, and replaces the data key if `rotate_data_key` is set.
+    /// Must be called before a [KvManager] is created for `root`.
+    pub fn change_kek(
+        root: &str,
+        kek: Kek,
+        new_kek: Kek,
+        new_kdf: Option<&KdfParams>,
+        rotate_data_key: bool,
+    ) -> KvResult<()> {
+        super::kv::change_kek(root, kek, new_kek, new_kdf, rotate_data_key)
+    }
     pub fn kv(&self) -> &Kv<KvValue> {
         &self.kv
     }
@@ -34,37 +83,65 @@ impl KvManager {
     }
 }
 
-/// Value type stored in the kv-store
-type KvValue = Vec<u8>;
+/// Value type stored in the kv-store; a versioned envelope of a serialized value
+pub(super) type KvValue = Vec<u8>;
 
-/// Create PartyInfo from KvValue
+/// Returns `true` if `v` holds a `T`.
+/// Used as a [super::ValueCheck], so that only values of the expected kind are archived or deleted.
+pub fn holds<T: TryFrom<KvValue>>(v: KvValue) -> bool {
+    T::try_from(v).is_ok()
+}
+
+/// Create PartyInfo from KvValue, upgrading values of older versions
 impl TryFrom<KvValue> for PartyInfo {
     type Error = InnerKvError;
     fn try_from(v: KvValue) -> Result<Self, Self::Error> {
-        deserialize(&v).ok_or(InnerKvError::DeserializationErr)
+        let payload = unseal(ValueKind::PartyInfo, &v)?;
+        deserialize(&payload).ok_or(InnerKvError::DeserializationErr)
     }
 }
 
-/// Create KvValue from PartyInfo
+/// Create KvValue of the current version from PartyInfo
 impl TryFrom<PartyInfo> for KvValue {
     type Error = InnerKvError;
     fn try_from(v: PartyInfo) -> Result<Self, Self::Error> {
-        serialize(&v).map_err(|_| InnerKvError::SerializationErr)
+        let payload = serialize(&v).map_err(|_| InnerKvError::SerializationErr)?;
+        Ok(seal(ValueKind::PartyInfo, payload))
     }
 }
 
-/// Create Entropy from KvValue
+/// Create Entropy from KvValue, upgrading values of older versions
 impl TryFrom<KvValue> for Entropy {
     type Error = InnerKvError;
     fn try_from(v: KvValue) -> Result<Self, Self::Error> {
-        deserialize(&v).ok_or(InnerKvError::DeserializationErr)
+        let payload = unseal(ValueKind::Entropy, &v)?;
+        deserialize(&payload).ok_or(InnerKvError::DeserializationErr)
     }
 }
 
-/// Create KvValue from Entropy
+/// Create KvValue of the current version from Entropy
 impl TryFrom<Entropy> for KvValue {
     type Error = InnerKvError;
     fn try_from(v: Entropy) -> Result<Self, Self::Error> {
-        serialize(&v).map_err(|_| InnerKvError::SerializationErr)
+        let payload = serialize(&v).map_err(|_| InnerKvError::SerializationErr)?;
+        Ok(seal(ValueKind::Entropy, payload))
+    }
+}
+
+/// Create MultisigKeyInfo from KvValue, upgrading values of older versions
+impl TryFrom<KvValue> for MultisigKeyInfo {
+    type Error = InnerKvError;
+    fn try_from(v: KvValue) -> Result<Self, Self::Error> {
+        let payload = unseal(ValueKind::MultisigKeyInfo, &v)?;
+        deserialize(&payload).ok_or(InnerKvError::DeserializationErr)
+    }
+}
+
+/// Create KvValue of the current version from MultisigKeyInfo
+impl TryFrom<MultisigKeyInfo> for KvValue {
+    type Error = InnerKvError;
+    fn try_from(v: MultisigKeyInfo) -> Result<Self, Self::Error> {
+        let payload = serialize(&v).map_err(|_| InnerKvError::SerializationErr)?;
+        Ok(seal(ValueKind::MultisigKeyInfo, payload))
     }
 }
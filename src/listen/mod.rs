@@ -0,0 +1,266 @@
+//! Bind the gRPC server to a tcp or a unix domain socket address.
+//! A [ListenAddr] is parsed from `tcp://<host>:<port>` or `unix://<path>`.
+//! [Listener::into_incoming] returns a stream of [Connection]s that can be
+//! passed to [tonic::transport::server::Router::serve_with_incoming_shutdown].
+
+use std::{
+    fmt,
+    fs::{DirBuilder, Permissions},
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use rand::RngCore;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_stream::Stream;
+use tonic::transport::server::Connected;
+
+// logging
+use tracing::warn;
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+const TCP_SCHEME: &str = "tcp://";
+const UNIX_SCHEME: &str = "unix://";
+
+/// only the owner of the socket file can connect to tofnd
+const UNIX_SOCKET_MODE: u32 = 0o600;
+/// only the owner can access the directory in which a unix socket is bound
+const UNIX_SOCKET_DIR_MODE: u32 = 0o700;
+
+/// Address the gRPC server listens on
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> TofndResult<Self> {
+        if let Some(addr) = s.strip_prefix(TCP_SCHEME) {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow!("could not resolve tcp address [{}]", addr))?;
+            return Ok(ListenAddr::Tcp(addr));
+        }
+
+        if let Some(path) = s.strip_prefix(UNIX_SCHEME) {
+            if path.is_empty() {
+                return Err(anyhow!("missing unix socket path in [{}]", s));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        Err(anyhow!(
+            "invalid listen address [{}]: expected {}<host>:<port> or {}<path>",
+            s,
+            TCP_SCHEME,
+            UNIX_SCHEME
+        ))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}{}", TCP_SCHEME, addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+/// A bound tcp or unix domain socket listener.
+/// The socket file of a unix listener is removed when the listener is dropped.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> TofndResult<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = bind_private_unix(path)?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Returns the address the listener is bound to.
+    /// Useful to retrieve the actual port when binding a tcp listener to port 0.
+    pub fn local_addr(&self) -> TofndResult<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    pub fn into_incoming(self) -> Incoming {
+        Incoming { listener: self }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            if let Err(err) = std::fs::remove_file(path.as_path()) {
+                warn!("could not remove socket file {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// A socket file left behind by a previous process prevents binding; remove it.
+/// Refuse to remove anything that is not a socket.
+fn remove_stale_socket(path: &Path) -> TofndResult<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!(
+            "cannot listen on {}: file exists and is not a socket",
+            path.display()
+        ));
+    }
+
+    warn!("removing stale socket file {}", path.display());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Binds a unix socket at `path` that only the owner of the process can connect to.
+/// A socket file is created with the default permissions of the process, so the socket is bound inside
+/// a new directory next to `path` that only the owner can access. Once its permissions are restricted
+/// to [UNIX_SOCKET_MODE], the socket is moved to `path` and the directory is removed.
+fn bind_private_unix(path: &Path) -> TofndResult<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = parent.join(format!(".tofnd-{:016x}", rand::thread_rng().next_u64()));
+    let private_path = dir.join("sock");
+
+    // fails if the directory exists, so that it is always created with the mode below
+    DirBuilder::new().mode(UNIX_SOCKET_DIR_MODE).create(&dir)?;
+
+    let res = UnixListener::bind(&private_path)
+        .and_then(|listener| {
+            std::fs::set_permissions(&private_path, Permissions::from_mode(UNIX_SOCKET_MODE))?;
+            std::fs::rename(&private_path, path)?;
+            Ok(listener)
+        })
+        .map_err(|err| anyhow!("cannot listen on {}: {}", path.display(), err));
+
+    // the socket file is only left in the directory on failure
+    let _ = std::fs::remove_file(&private_path);
+    if let Err(err) = std::fs::remove_dir(&dir) {
+        warn!("could not remove directory {}: {}", dir.display(), err);
+    }
+    res
+}
+
+/// Stream of incoming [Connection]s accepted by a [Listener]
+pub struct Incoming {
+    listener: Listener,
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<Connection>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = match &self.get_mut().listener {
+            Listener::Tcp(listener) => match listener.poll_accept(cx) {
+                Poll::Ready(res) => res.map(|(stream, _)| Connection::Tcp(stream)),
+                Poll::Pending => return Poll::Pending,
+            },
+            Listener::Unix(listener, _) => match listener.poll_accept(cx) {
+                Poll::Ready(res) => res.map(|(stream, _)| Connection::Unix(stream)),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        Poll::Ready(Some(res))
+    }
+}
+
+/// A connection accepted over tcp or a unix domain socket
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connected for Connection {
+    /// remote address of tcp connections; unix domain socket peers have no address
+    type ConnectInfo = Option<SocketAddr>;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
+
+#[cfg(test)]
+pub use tests::{connect, test_unix_addr};
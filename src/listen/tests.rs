@@ -0,0 +1,125 @@
+use super::{ListenAddr, Listener};
+
+use std::{
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+
+/// Create a client channel to a server listening on `addr`
+pub async fn connect(addr: &ListenAddr) -> Channel {
+    match addr {
+        ListenAddr::Tcp(addr) => Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap(),
+        ListenAddr::Unix(path) => {
+            let path = path.clone();
+            // the uri is ignored by the connector, but it has to be valid
+            Endpoint::from_static("http://[::]:50051")
+                .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+                .await
+                .unwrap()
+        }
+    }
+}
+
+/// Get a unique unix socket address in the temp dir.
+/// Paths of test directories can exceed the maximum length of a socket path, so we don't use testdir here.
+pub fn test_unix_addr() -> ListenAddr {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "tofnd-{}-{}.sock",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    );
+    ListenAddr::Unix(std::env::temp_dir().join(name))
+}
+
+#[test]
+fn parse_listen_addr() {
+    assert_eq!(
+        "tcp://127.0.0.1:50051".parse::<ListenAddr>().unwrap(),
+        ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 50051)))
+    );
+    assert_eq!(
+        "tcp://[::1]:50051".parse::<ListenAddr>().unwrap(),
+        ListenAddr::Tcp("[::1]:50051".parse().unwrap())
+    );
+    assert_eq!(
+        "unix:///tmp/tofnd.sock".parse::<ListenAddr>().unwrap(),
+        ListenAddr::Unix(PathBuf::from("/tmp/tofnd.sock"))
+    );
+
+    // display and parse round trip
+    let addr = "unix:///tmp/tofnd.sock".parse::<ListenAddr>().unwrap();
+    assert_eq!(addr.to_string().parse::<ListenAddr>().unwrap(), addr);
+
+    assert!("127.0.0.1:50051".parse::<ListenAddr>().is_err());
+    assert!("tcp://127.0.0.1".parse::<ListenAddr>().is_err());
+    assert!("unix://".parse::<ListenAddr>().is_err());
+    assert!("http://127.0.0.1:50051".parse::<ListenAddr>().is_err());
+}
+
+#[tokio::test]
+async fn unix_socket_permissions_and_cleanup() {
+    let addr = test_unix_addr();
+    let path = match &addr {
+        ListenAddr::Unix(path) => path.clone(),
+        _ => unreachable!(),
+    };
+
+    let listener = Listener::bind(&addr).await.unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // socket file is removed when the listener is dropped
+    drop(listener);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn refuse_to_replace_regular_file() {
+    let addr = test_unix_addr();
+    let path = match &addr {
+        ListenAddr::Unix(path) => path.clone(),
+        _ => unreachable!(),
+    };
+
+    std::fs::write(&path, b"not a socket").unwrap();
+    assert!(Listener::bind(&addr).await.is_err());
+    assert!(path.exists());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn unix_socket_bound_in_private_dir() {
+    // a directory of our own, so that sockets of other tests don't show up in it
+    let dir = std::env::temp_dir().join(format!("tofnd-listen-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tofnd.sock");
+
+    let listener = Listener::bind(&ListenAddr::Unix(path.clone()))
+        .await
+        .unwrap();
+
+    // the private bind directory is gone and only the socket is left
+    let entries: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(entries, vec![path.clone()]);
+
+    // the socket still accepts connections after it was moved into place
+    UnixStream::connect(&path).await.unwrap();
+
+    drop(listener);
+    std::fs::remove_dir(&dir).unwrap();
+}
@@ -1,17 +1,18 @@
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
-use tokio_stream::wrappers::TcpListenerStream;
 
 mod encrypted_sled;
 mod gg20;
 mod kv_manager;
+mod listen;
 mod mnemonic;
 mod multisig;
+mod tls;
 
 // gather logs; need to set RUST_LOG=info
 use tracing::{info, span, Level};
 
 // error handling
+use anyhow::anyhow;
 pub type TofndResult<Success> = anyhow::Result<Success>;
 
 // protocol buffers via tonic: https://github.com/hyperium/tonic/blob/master/examples/helloworld-tutorial.md#writing-our-server
@@ -22,12 +23,15 @@ pub mod proto {
 mod config;
 use config::parse_args;
 
-use crate::kv_manager::KvManager;
+use crate::{
+    encrypted_sled::{Kek, Password, PasswordMethod},
+    kv_manager::{BackupCmd, Durability, KvManager},
+};
 
-fn set_up_logs() {
-    // enable only tofnd and tofn debug logs - disable serde, tonic, tokio, etc.
+fn set_up_logs(log_filter: &str) {
+    // by default, enable only tofnd and tofn debug logs - disable serde, tonic, tokio, etc.
     tracing_subscriber::fmt()
-        .with_env_filter("tofnd=debug,tofn=debug")
+        .with_env_filter(log_filter)
         .json()
         .with_ansi(atty::is(atty::Stream::Stdout))
         .without_time()
@@ -43,6 +47,20 @@ pub fn warn_for_malicious_build() {
     warn!("WARNING: THIS tofnd BINARY AS COMPILED IN 'MALICIOUS' MODE.  MALICIOUS BEHAVIOUR IS INTENTIONALLY INSERTED INTO SOME MESSAGES.  THIS BEHAVIOUR WILL CAUSE OTHER tofnd PROCESSES TO IDENTIFY THE CURRENT PROCESS AS MALICIOUS.");
 }
 
+/// Read a new password or passphrase, named `name` in prompts. Prompted secrets are typed
+/// twice because a mistyped secret would lock the user out of their data.
+fn read_new_secret(method: &PasswordMethod, name: &str) -> TofndResult<Password> {
+    let secret = method.execute_with_prompt(&format!("Please type your {}:", name))?;
+    if let PasswordMethod::Prompt = method {
+        let confirmation =
+            method.execute_with_prompt(&format!("Please type your {} again:", name))?;
+        if secret.as_ref() != confirmation.as_ref() {
+            return Err(anyhow!("{}s do not match", name));
+        }
+    }
+    Ok(secret)
+}
+
 fn warn_for_unsafe_execution() {
     use tracing::warn;
     warn!("WARNING: THIS tofnd BINARY IS NOT SAFE: SAFE PRIMES ARE NOT USED BECAUSE '--unsafe' FLAG IS ENABLED.  USE '--unsafe' FLAG ONLY FOR TESTING.");
@@ -54,10 +72,32 @@ fn warn_for_unsafe_execution() {
 async fn main() -> TofndResult<()> {
     let cfg = parse_args()?;
 
-    // immediately read an encryption password from stdin
-    let password = cfg.password_method.execute()?;
-
-    set_up_logs();
+    // immediately retrieve the kek, reading the password from the configured source if needed
+    let kek = cfg.kek.execute(&cfg.password_method)?;
+    let new_kek = match (&cfg.change_password, &cfg.change_kek) {
+        (Some(method), _) => Some(Kek::Password(read_new_secret(
+            method,
+            "new tofnd password",
+        )?)),
+        (None, Some(method)) => Some(method.execute(&cfg.password_method)?),
+        (None, None) => None,
+    };
+    let backup_passphrase = cfg
+        .backup_cmd
+        .as_ref()
+        .map(|cmd| match cmd {
+            BackupCmd::Backup { .. } => {
+                read_new_secret(cmd.passphrase_method(), "backup passphrase")
+            }
+            BackupCmd::Restore { .. } => cmd
+                .passphrase_method()
+                .execute_with_prompt("Please type your backup passphrase:")
+                .map_err(Into::into),
+        })
+        .transpose()?;
+
+    set_up_logs(&cfg.log_filter);
+    info!("effective config: {}", cfg);
 
     // print config warnings
     #[cfg(feature = "malicious")]
@@ -70,33 +110,128 @@ async fn main() -> TofndResult<()> {
     let main_span = span!(Level::INFO, "main");
     let _enter = main_span.enter();
 
-    let incoming = TcpListener::bind(addr(cfg.port)).await?;
-    info!(
-        "tofnd listen addr {:?}, use ctrl+c to shutdown",
-        incoming.local_addr()?
-    );
+    let kdf = cfg.kdf.clone().unwrap_or_default();
+
+    if let Some(new_kek) = new_kek {
+        let name = new_kek.name();
+        KvManager::change_kek(
+            &cfg.tofnd_path,
+            kek,
+            new_kek,
+            cfg.kdf.as_ref(),
+            cfg.rotate_data_key,
+        )?;
+        info!(
+            "Tofnd exited after changing the kek. Use the new {} kek from now on.",
+            name
+        );
+        return Ok(());
+    }
 
-    let cmd = cfg.mnemonic_cmd.clone();
+    if cfg.db_check {
+        let report = KvManager::check(&cfg.tofnd_path, kek)?;
+        report.log();
+        if !report.corrupt.is_empty() {
+            return Err(anyhow!(
+                "kv store check found {} corrupt records",
+                report.corrupt.len()
+            ));
+        }
+        info!("Tofnd exited after checking the kv store.");
+        return Ok(());
+    }
 
-    let kv_manager = KvManager::new(&cfg.tofnd_path, password)?
-        .handle_mnemonic(&cfg.mnemonic_cmd)
+    // one-off commands exit right after their writes, so they flush every write
+    if let (Some(backup_cmd), Some(passphrase)) = (&cfg.backup_cmd, backup_passphrase) {
+        KvManager::with_backend(
+            &cfg.tofnd_path,
+            kek,
+            &kdf,
+            &cfg.storage,
+            Durability::EveryWrite,
+        )?
+        .handle_backup_cmd(backup_cmd, passphrase)
         .await?;
+        info!("Tofnd exited after {}.", backup_cmd);
+        return Ok(());
+    }
 
-    let gg20_service = gg20::service::new_service(cfg, kv_manager.clone());
-    let multisig_service = multisig::service::new_service(kv_manager);
+    if let Some(reservation_cmd) = &cfg.reservation_cmd {
+        KvManager::with_backend(
+            &cfg.tofnd_path,
+            kek,
+            &kdf,
+            &cfg.storage,
+            Durability::EveryWrite,
+        )?
+        .handle_reservation_cmd(reservation_cmd)
+        .await?;
+        info!("Tofnd exited after {}.", reservation_cmd);
+        return Ok(());
+    }
+
+    if cfg.migrate_dry_run {
+        // opening the kv store does not upgrade it; see KvManager::upgrade
+        let planned = KvManager::with_backend(
+            &cfg.tofnd_path,
+            kek,
+            &kdf,
+            &cfg.storage,
+            Durability::EveryWrite,
+        )?
+        .plan_migrations()
+        .await?;
+        for migration in &planned {
+            info!("would migrate {}", migration);
+        }
+        info!(
+            "Tofnd exited after finding {} values to migrate. No value was changed.",
+            planned.len()
+        );
+        return Ok(());
+    }
+
+    let listener = listen::Listener::bind(&cfg.listen_addr).await?;
+    info!(
+        "tofnd listen addr {}, use ctrl+c to shutdown",
+        listener.local_addr()?
+    );
+
+    let cmd = cfg.mnemonic_cmd.clone();
+    let tls = cfg.tls.clone();
+
+    // only the daemon upgrades a kvstore of an older tofnd; one-off commands leave it untouched
+    KvManager::upgrade(&cfg.tofnd_path, &kek, &cfg.storage)?;
+    let kv_manager =
+        KvManager::with_backend(&cfg.tofnd_path, kek, &kdf, &cfg.storage, cfg.durability)?
+            .migrate()
+            .await?
+            .sweep_reservations(cfg.stale_reservations)
+            .await?
+            .handle_mnemonic(&cfg.mnemonic_cmd)
+            .await?;
+
+    let multisig_service =
+        multisig::service::new_service(kv_manager.clone(), cfg.legacy_multisig_keys);
+    let gg20_service = gg20::service::new_service(cfg, kv_manager)?;
 
     if cmd.exit_after_cmd() {
         info!("Tofnd exited after using command <{:?}>. Run `./tofnd -m existing` to execute gRPC daemon.", cmd);
         return Ok(());
     }
 
+    let gg20_admin_service = proto::gg20_admin_server::Gg20AdminServer::new(gg20_service.clone());
     let gg20_service = proto::gg20_server::Gg20Server::new(gg20_service);
+    let multisig_admin_service =
+        proto::multisig_admin_server::MultisigAdminServer::new(multisig_service.clone());
     let multisig_service = proto::multisig_server::MultisigServer::new(multisig_service);
 
-    tonic::transport::Server::builder()
+    tls::server_builder(tls.as_ref())?
         .add_service(gg20_service)
+        .add_service(gg20_admin_service)
         .add_service(multisig_service)
-        .serve_with_incoming_shutdown(TcpListenerStream::new(incoming), shutdown_signal())
+        .add_service(multisig_admin_service)
+        .serve_with_incoming_shutdown(listener.into_incoming(), shutdown_signal())
         .await?;
 
     Ok(())
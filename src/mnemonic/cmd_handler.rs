@@ -20,7 +20,7 @@ use std::convert::TryInto;
 use tracing::{error, info};
 
 // default key to store mnemonic
-const MNEMONIC_KEY: &str = "mnemonic";
+pub(crate) const MNEMONIC_KEY: &str = "mnemonic";
 
 #[derive(Clone, Debug)]
 pub enum Cmd {
@@ -101,8 +101,13 @@ impl KvManager {
     /// inserts entropy to the kv-store
     /// takes ownership of entropy to delegate zeroization.
     async fn handle_insert(&self, entropy: Entropy) -> InnerMnemonicResult<()> {
+        // mnemonic commands don't run in a session; the reservation records the tofnd process instead
+        let session = format!("tofnd process {}", std::process::id());
         // Don't use `map_err` to make it more readable.
-        let reservation = self.kv().reserve_key(MNEMONIC_KEY.to_owned()).await;
+        let reservation = self
+            .kv()
+            .reserve_key(MNEMONIC_KEY.to_owned(), &session)
+            .await;
         match reservation {
             // if we can reserve, try put
             Ok(reservation) => match self
@@ -110,8 +115,9 @@ impl KvManager {
                 .put(reservation, entropy.try_into().map_err(KvError::PutErr)?)
                 .await
             {
-                // if put is ok, write the phrase to a file
+                // if put is ok, make sure the entropy is on disk
                 Ok(()) => {
+                    self.kv().flush().await.map_err(KvErr)?;
                     info!("Mnemonic successfully added in kv store. Use the `-m export` command to retrieve it.");
                     Ok(())
                 }
@@ -12,4 +12,5 @@ mod file_io;
 mod results;
 
 pub use cmd_handler::Cmd;
+pub(crate) use cmd_handler::MNEMONIC_KEY;
 pub use file_io::FileIo;
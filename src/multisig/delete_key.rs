@@ -0,0 +1,73 @@
+//! This module handles the delete_key and archive_key gRPCs for multisig keys.
+//! Multisig records live in the same kv-store as gg20 keys, under [MultisigService::kv_key].
+
+use super::{service::MultisigService, types::MultisigKeyInfo};
+use crate::kv_manager::holds;
+
+// logging
+use tracing::info;
+
+// error handling
+use crate::{proto, TofndResult};
+use anyhow::anyhow;
+
+impl MultisigService {
+    pub(super) async fn handle_delete_key(
+        &self,
+        request: proto::DeleteKeyRequest,
+    ) -> TofndResult<proto::delete_key_response::Response> {
+        // deletion is irreversible; make sure the client means it
+        if request.confirm_key_uid != request.key_uid {
+            return Err(anyhow!(
+                "confirmation [{}] does not match key uid [{}]",
+                request.confirm_key_uid,
+                request.key_uid
+            ));
+        }
+
+        let kv_key = Self::kv_key(&request.key_uid);
+        if !self
+            .kv_manager
+            .kv()
+            .delete(&kv_key, holds::<MultisigKeyInfo>)
+            .await?
+        {
+            info!(
+                "[{}] did not find multisig key in kv store during delete",
+                request.key_uid
+            );
+            return Ok(proto::delete_key_response::Response::Absent);
+        }
+        info!("[{}] deleted multisig key", request.key_uid);
+
+        Ok(proto::delete_key_response::Response::Success)
+    }
+
+    pub(super) async fn handle_archive_key(
+        &self,
+        request: proto::ArchiveKeyRequest,
+    ) -> TofndResult<proto::archive_key_response::Response> {
+        let kv_key = Self::kv_key(&request.key_uid);
+        let archive_key = match self
+            .kv_manager
+            .kv()
+            .archive(&kv_key, request.reason, holds::<MultisigKeyInfo>)
+            .await?
+        {
+            Some(archive_key) => archive_key,
+            None => {
+                info!(
+                    "[{}] did not find multisig key in kv store during archive",
+                    request.key_uid
+                );
+                return Ok(proto::archive_key_response::Response::Absent);
+            }
+        };
+        info!(
+            "[{}] archived multisig key as {}",
+            request.key_uid, archive_key
+        );
+
+        Ok(proto::archive_key_response::Response::Success)
+    }
+}
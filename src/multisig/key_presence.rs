@@ -4,7 +4,7 @@
 use super::service::MultisigService;
 
 // logging
-use tracing::debug;
+use tracing::info;
 
 // error handling
 use crate::{proto, TofndResult};
@@ -17,13 +17,32 @@ impl MultisigService {
         // check if mnemonic is available
         let _ = self.kv_manager.seed().await?;
 
-        // key presence for multisig always returns `Present`.
-        // this is done in order to not break compatibility with axelar-core
-        // TODO: better handling for multisig key presence.
-        debug!(
-            "[{}] key presence check for multisig always return Present",
-            request.key_uid
-        );
-        Ok(proto::key_presence_response::Response::Present)
+        // check if requested key was generated
+        if self
+            .kv_manager
+            .kv()
+            .exists(&Self::kv_key(&request.key_uid))
+            .await?
+        {
+            info!(
+                "[{}] found multisig key in kv store during key presence check",
+                request.key_uid
+            );
+            Ok(proto::key_presence_response::Response::Present)
+        } else if self.legacy_keys {
+            // keys generated before multisig keygen stored records can't be told apart from
+            // keys that were never generated
+            info!(
+                "[{}] did not find multisig key in kv store during key presence check; reporting legacy key as present",
+                request.key_uid
+            );
+            Ok(proto::key_presence_response::Response::Present)
+        } else {
+            info!(
+                "[{}] did not find multisig key in kv store during key presence check",
+                request.key_uid
+            );
+            Ok(proto::key_presence_response::Response::Absent)
+        }
     }
 }
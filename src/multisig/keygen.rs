@@ -1,16 +1,64 @@
-use super::service::MultisigService;
+use super::{service::MultisigService, types::MultisigKeyInfo};
 use crate::{proto::KeygenRequest, TofndResult};
+use std::convert::TryInto;
 use tofn::ecdsa::keygen;
 
 use anyhow::anyhow;
 
+// logging
+use tracing::info;
+
 impl MultisigService {
     pub(super) async fn handle_keygen(&self, request: &KeygenRequest) -> TofndResult<Vec<u8>> {
         let secret_recovery_key = self.kv_manager.seed().await?;
 
         let key_pair = keygen(&secret_recovery_key, request.key_uid.as_bytes())
             .map_err(|_| anyhow!("Cannot generate keypair"))?;
+        let verifying_key = key_pair.encoded_verifying_key().to_vec();
+
+        // keys are derived deterministically from the seed, so a repeated keygen
+        // for the same key uid results in the key that is already stored
+        let kv_key = Self::kv_key(&request.key_uid);
+        if self.kv_manager.kv().exists(&kv_key).await? {
+            let key_info: MultisigKeyInfo = self.kv_manager.kv().get(&kv_key).await?.try_into()?;
+            if key_info.verifying_key != verifying_key {
+                return Err(anyhow!("stored verifying key does not match derived key"));
+            }
+            info!(
+                "[{}] multisig key with key id [{}] already exists since {}",
+                key_info.party_uid, key_info.key_uid, key_info.created_at
+            );
+            return Ok(verifying_key);
+        }
+
+        // persist the key so that key presence and sign can tell it was generated
+        self.store_key_info(&request.key_uid, &request.party_uid, verifying_key.clone())
+            .await?;
+
+        Ok(verifying_key)
+    }
 
-        Ok(key_pair.encoded_verifying_key().to_vec())
+    /// Stores the record of the multisig key `key_uid` and waits until it is on disk
+    pub(super) async fn store_key_info(
+        &self,
+        key_uid: &str,
+        party_uid: &str,
+        verifying_key: Vec<u8>,
+    ) -> TofndResult<()> {
+        let reservation = self
+            .kv_manager
+            .kv()
+            .reserve_key(Self::kv_key(key_uid), key_uid)
+            .await
+            .map_err(|err| anyhow!("failed to reserve key: {}", err))?;
+        let key_info =
+            MultisigKeyInfo::new(key_uid.to_string(), party_uid.to_string(), verifying_key)?;
+        self.kv_manager
+            .kv()
+            .put(reservation, key_info.try_into()?)
+            .await?;
+        // don't return the key before it is on disk
+        self.kv_manager.kv().flush().await?;
+        Ok(())
     }
 }
@@ -1,7 +1,11 @@
+mod delete_key;
 mod key_presence;
 mod keygen;
 pub mod service;
 mod sign;
+pub mod types;
+
+pub(crate) use types::MULTISIG_KEY_PREFIX;
 
 #[cfg(test)]
 mod tests;
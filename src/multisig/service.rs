@@ -1,6 +1,7 @@
 use tonic::Response;
 use tonic::Status;
 
+use super::types::MULTISIG_KEY_PREFIX;
 use crate::kv_manager::KvManager;
 use crate::proto;
 
@@ -10,11 +11,27 @@ use tracing::{error, info};
 #[derive(Clone)]
 pub struct MultisigService {
     pub(super) kv_manager: KvManager,
+    /// see [crate::config::Config::legacy_multisig_keys]
+    pub(super) legacy_keys: bool,
 }
 
-/// create a new Multisig gRPC server
-pub fn new_service(kv_manager: KvManager) -> impl proto::multisig_server::Multisig {
-    MultisigService { kv_manager }
+impl MultisigService {
+    /// get the kv-store key that holds the record of a multisig `key_uid`
+    pub(super) fn kv_key(key_uid: &str) -> String {
+        format!("{}{}", MULTISIG_KEY_PREFIX, key_uid)
+    }
+}
+
+/// create a new Multisig gRPC server; the service also serves the [proto::multisig_admin_server::MultisigAdmin] gRPCs.
+/// If `legacy_keys` is set, keys without a record are treated as keys that were generated before multisig keygen stored records.
+pub fn new_service(
+    kv_manager: KvManager,
+    legacy_keys: bool,
+) -> impl proto::multisig_server::Multisig + proto::multisig_admin_server::MultisigAdmin + Clone {
+    MultisigService {
+        kv_manager,
+        legacy_keys,
+    }
 }
 
 #[tonic::async_trait]
@@ -100,3 +117,50 @@ impl proto::multisig_server::Multisig for MultisigService {
         }))
     }
 }
+
+#[tonic::async_trait]
+impl proto::multisig_admin_server::MultisigAdmin for MultisigService {
+    async fn delete_key(
+        &self,
+        request: tonic::Request<proto::DeleteKeyRequest>,
+    ) -> Result<Response<proto::DeleteKeyResponse>, Status> {
+        let request = request.into_inner();
+
+        let response = match self.handle_delete_key(request).await {
+            Ok(res) => {
+                info!("Multisig key deletion completed successfully");
+                res
+            }
+            Err(err) => {
+                error!("Unable to complete multisig key deletion: {}", err);
+                proto::delete_key_response::Response::Fail
+            }
+        };
+
+        Ok(Response::new(proto::DeleteKeyResponse {
+            response: response as i32,
+        }))
+    }
+
+    async fn archive_key(
+        &self,
+        request: tonic::Request<proto::ArchiveKeyRequest>,
+    ) -> Result<Response<proto::ArchiveKeyResponse>, Status> {
+        let request = request.into_inner();
+
+        let response = match self.handle_archive_key(request).await {
+            Ok(res) => {
+                info!("Multisig key archival completed successfully");
+                res
+            }
+            Err(err) => {
+                error!("Unable to complete multisig key archival: {}", err);
+                proto::archive_key_response::Response::Fail
+            }
+        };
+
+        Ok(Response::new(proto::ArchiveKeyResponse {
+            response: response as i32,
+        }))
+    }
+}
@@ -1,17 +1,49 @@
-use super::service::MultisigService;
+use super::{service::MultisigService, types::MultisigKeyInfo};
 use crate::{proto::SignRequest, TofndResult};
 use std::convert::TryInto;
 
 use anyhow::anyhow;
 use tofn::ecdsa::{keygen, sign};
 
+// logging
+use tracing::info;
+
 impl MultisigService {
     pub(super) async fn handle_sign(&self, request: &SignRequest) -> TofndResult<Vec<u8>> {
+        // refuse to sign with keys that were never generated, unless legacy keys are allowed
+        let kv_key = Self::kv_key(&request.key_uid);
+        let key_info: Option<MultisigKeyInfo> = if self.kv_manager.kv().exists(&kv_key).await? {
+            Some(self.kv_manager.kv().get(&kv_key).await?.try_into()?)
+        } else if self.legacy_keys {
+            None
+        } else {
+            return Err(anyhow!("key [{}] was not found", request.key_uid));
+        };
+
         // re-generate secret key from seed, then sign
         let secret_recovery_key = self.kv_manager.seed().await?;
 
         let key_pair = keygen(&secret_recovery_key, request.key_uid.as_bytes())
             .map_err(|_| anyhow!("key re-generation failed"))?;
+        let verifying_key = key_pair.encoded_verifying_key().to_vec();
+        match key_info {
+            Some(key_info) => {
+                if verifying_key != key_info.verifying_key {
+                    return Err(anyhow!(
+                        "re-generated key does not match stored verifying key"
+                    ));
+                }
+            }
+            // the key was generated before multisig keygen stored records; store it now
+            None => {
+                info!(
+                    "[{}] storing record of legacy multisig key [{}]",
+                    request.party_uid, request.key_uid
+                );
+                self.store_key_info(&request.key_uid, &request.party_uid, verifying_key)
+                    .await?;
+            }
+        }
 
         let signature = sign(
             key_pair.signing_key(),
@@ -1,13 +1,17 @@
-use crate::{addr, encrypted_sled::get_test_password, kv_manager::KvManager};
+use crate::{
+    addr,
+    encrypted_sled::get_test_password,
+    kv_manager::KvManager,
+    listen::{self, ListenAddr, Listener},
+};
 use tokio::{
     self,
-    net::TcpListener,
     sync::oneshot::{channel, Sender},
+    time::{sleep, Duration},
 };
-use tokio_stream::wrappers::TcpListenerStream;
 use tonic::transport::Channel;
 
-use super::service::new_service;
+use super::service::{new_service, MultisigService};
 
 use testdir::testdir;
 use tracing::error;
@@ -16,13 +20,40 @@ use tracing_test::traced_test;
 use std::convert::TryInto;
 
 use crate::proto::{
-    key_presence_response::Response::Present, keygen_response::KeygenResponse,
-    multisig_client::MultisigClient, multisig_server::MultisigServer, sign_response::SignResponse,
-    KeyPresenceRequest, KeygenRequest, SignRequest,
+    archive_key_response, delete_key_response,
+    key_presence_response::Response::{Absent, Present},
+    keygen_response::KeygenResponse,
+    multisig_admin_client::MultisigAdminClient,
+    multisig_admin_server::MultisigAdminServer,
+    multisig_client::MultisigClient,
+    multisig_server::MultisigServer,
+    sign_response::SignResponse,
+    ArchiveKeyRequest, DeleteKeyRequest, KeyPresenceRequest, KeygenRequest, SignRequest,
 };
 
 // set up tests
 async fn spin_test_service_and_client() -> (MultisigClient<Channel>, Sender<()>) {
+    let (client, _, shutdown_sender) = spin_test_service_and_clients().await;
+    (client, shutdown_sender)
+}
+
+async fn spin_test_service_and_clients() -> (
+    MultisigClient<Channel>,
+    MultisigAdminClient<Channel>,
+    Sender<()>,
+) {
+    // use port 0 and let the OS decide
+    spin_test_service_and_client_at(ListenAddr::Tcp(addr(0)), false).await
+}
+
+async fn spin_test_service_and_client_at(
+    listen_addr: ListenAddr,
+    legacy_keys: bool,
+) -> (
+    MultisigClient<Channel>,
+    MultisigAdminClient<Channel>,
+    Sender<()>,
+) {
     // create root directory for service
     let root = testdir!();
 
@@ -34,36 +65,38 @@ async fn spin_test_service_and_client() -> (MultisigClient<Channel>, Sender<()>)
         .unwrap();
 
     // create service
-    let service = new_service(kv_manager);
+    let service = new_service(kv_manager, legacy_keys);
+    let admin_service = MultisigAdminServer::new(service.clone());
     let service = MultisigServer::new(service);
 
-    // create incoming tcp server for service
-    let incoming = TcpListener::bind(addr(0)).await.unwrap(); // use port 0 and let the OS decide
+    // create incoming server for service
+    let listener = Listener::bind(&listen_addr).await.unwrap();
 
     // create shutdown channels
     let (shutdown_sender, shutdown_receiver) = channel::<()>();
 
     // get server's address
-    let server_addr = incoming.local_addr().unwrap();
+    let server_addr = listener.local_addr().unwrap();
 
     // spin up multisig gRPC server with incoming shutdown
     tokio::spawn(async move {
         tonic::transport::Server::builder()
             .add_service(service)
-            .serve_with_incoming_shutdown(TcpListenerStream::new(incoming), async {
+            .add_service(admin_service)
+            .serve_with_incoming_shutdown(listener.into_incoming(), async {
                 shutdown_receiver.await.unwrap();
             })
             .await
             .unwrap();
     });
 
-    // create a client to multisig service
-    let client = MultisigClient::connect(format!("http://{}", server_addr))
-        .await
-        .unwrap();
+    // create clients to multisig services
+    let channel = listen::connect(&server_addr).await;
+    let client = MultisigClient::new(channel.clone());
+    let admin_client = MultisigAdminClient::new(channel);
 
-    // return the client and the shutdown channel for the service
-    (client, shutdown_sender)
+    // return the clients and the shutdown channel for the service
+    (client, admin_client, shutdown_sender)
 }
 
 // dummy ctor for KeygenResult
@@ -126,18 +159,76 @@ async fn test_multisig_keygen_sign() {
 
 #[traced_test]
 #[tokio::test]
-async fn test_multisig_only_sign() {
+async fn test_multisig_keygen_sign_unix_socket() {
+    let key = "multisig key";
+    let (mut client, _, shutdown_sender) =
+        spin_test_service_and_client_at(listen::test_unix_addr(), false).await;
+
+    let response = client
+        .keygen(KeygenRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        response.keygen_response.unwrap(),
+        KeygenResponse::PubKey(_)
+    ));
+
+    let response = client
+        .sign(SignRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        response.sign_response.unwrap(),
+        SignResponse::Signature(_)
+    ));
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_multisig_only_sign_fail() {
     let key = "multisig key";
     let (mut client, shutdown_sender) = spin_test_service_and_client().await;
 
+    // sign with a key that was never generated
     let request = SignRequest::new(key);
     let response = client.sign(request).await.unwrap().into_inner();
-    let _ = match response.sign_response.unwrap() {
-        SignResponse::Signature(signature) => signature,
-        SignResponse::Error(err) => {
-            panic!("Got error from sign: {}", err)
-        }
-    };
+    if let SignResponse::Error(err) = response.clone().sign_response.unwrap() {
+        error!("{}", err);
+    }
+    assert!(matches!(
+        response.sign_response.unwrap(),
+        SignResponse::Error(_)
+    ));
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_multisig_keygen_twice() {
+    let key = "multisig key";
+    let (mut client, shutdown_sender) = spin_test_service_and_client().await;
+
+    // a repeated keygen returns the same key
+    let first = client
+        .keygen(KeygenRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    let second = client
+        .keygen(KeygenRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        first.keygen_response.clone().unwrap(),
+        KeygenResponse::PubKey(_)
+    ));
+    assert_eq!(first, second);
 
     let _ = shutdown_sender.send(()).unwrap();
 }
@@ -179,6 +270,9 @@ async fn test_multisig_truncated_msg_fail() {
     let key = "key-uid";
     let (mut client, shutdown_sender) = spin_test_service_and_client().await;
 
+    let request = KeygenRequest::new(key);
+    let _ = client.keygen(request).await.unwrap();
+
     // attempt sign with truncated msg digest
     let mut request = SignRequest::new(key);
     request.msg_to_sign = vec![32; 31];
@@ -203,6 +297,17 @@ async fn test_key_presence() {
         key_uid: "key_uid".to_string(),
     };
 
+    // key is absent before keygen
+    let response = client
+        .key_presence(presence_request.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.response, Absent as i32);
+
+    let _ = client.keygen(KeygenRequest::new("key_uid")).await.unwrap();
+
+    // key is present after keygen
     let response = client
         .key_presence(presence_request)
         .await
@@ -212,3 +317,217 @@ async fn test_key_presence() {
 
     let _ = shutdown_sender.send(()).unwrap();
 }
+
+#[traced_test]
+#[tokio::test]
+async fn test_legacy_keys() {
+    let key = "legacy key";
+    let (mut client, _, shutdown_sender) =
+        spin_test_service_and_client_at(ListenAddr::Tcp(addr(0)), true).await;
+
+    // a key without a record is reported as present
+    let presence_request = KeyPresenceRequest {
+        key_uid: key.to_string(),
+    };
+    let response = client
+        .key_presence(presence_request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.response, Present as i32);
+
+    // and can be used for signing, which stores its record
+    let request = SignRequest::new(key);
+    let msg_digest = request.msg_to_sign.as_slice().try_into().unwrap();
+    let response = client.sign(request).await.unwrap().into_inner();
+    let signature = match response.sign_response.unwrap() {
+        SignResponse::Signature(signature) => signature,
+        SignResponse::Error(err) => panic!("Got error from sign: {}", err),
+    };
+
+    // the stored key is the key that signed
+    let response = client
+        .keygen(KeygenRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    let pub_key = match response.keygen_response.unwrap() {
+        KeygenResponse::PubKey(pub_key) => pub_key,
+        KeygenResponse::Error(err) => panic!("Got error from keygen: {}", err),
+    };
+    assert!(tofn::ecdsa::verify(&to_array(pub_key), &msg_digest, &signature).unwrap());
+
+    let response = client
+        .sign(SignRequest::new(key))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        response.sign_response.unwrap(),
+        SignResponse::Signature(_)
+    ));
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_delete_key() {
+    let (mut client, mut admin_client, shutdown_sender) = spin_test_service_and_clients().await;
+
+    // deletion without a matching confirmation fails
+    let request = DeleteKeyRequest {
+        key_uid: "key_uid".to_string(),
+        confirm_key_uid: "other_key_uid".to_string(),
+    };
+    let response = admin_client.delete_key(request).await.unwrap().into_inner();
+    assert_eq!(
+        response.response,
+        delete_key_response::Response::Fail as i32
+    );
+
+    // deletion of a key that is not stored returns absent
+    let request = DeleteKeyRequest {
+        key_uid: "key_uid".to_string(),
+        confirm_key_uid: "key_uid".to_string(),
+    };
+    let response = admin_client
+        .delete_key(request.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        response.response,
+        delete_key_response::Response::Absent as i32
+    );
+
+    // deletion of a generated key succeeds
+    let _ = client.keygen(KeygenRequest::new("key_uid")).await.unwrap();
+    let response = admin_client.delete_key(request).await.unwrap().into_inner();
+    assert_eq!(
+        response.response,
+        delete_key_response::Response::Success as i32
+    );
+
+    // deleted key can no longer be used for signing
+    let response = client
+        .sign(SignRequest::new("key_uid"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        response.sign_response.unwrap(),
+        SignResponse::Error(_)
+    ));
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_archive_key() {
+    let (mut client, mut admin_client, shutdown_sender) = spin_test_service_and_clients().await;
+
+    // archival of a key that is not stored returns absent
+    let request = ArchiveKeyRequest {
+        key_uid: "key_uid".to_string(),
+        reason: "test".to_string(),
+    };
+    let response = admin_client
+        .archive_key(request.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        response.response,
+        archive_key_response::Response::Absent as i32
+    );
+
+    // archival of a generated key succeeds
+    let _ = client.keygen(KeygenRequest::new("key_uid")).await.unwrap();
+    let response = admin_client
+        .archive_key(request)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        response.response,
+        archive_key_response::Response::Success as i32
+    );
+
+    // archived key is no longer present
+    let response = client
+        .key_presence(KeyPresenceRequest {
+            key_uid: "key_uid".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.response, Absent as i32);
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_archive_and_delete_stored_keys() {
+    let dir = testdir!();
+    let root = dir.to_str().unwrap();
+    let kv_manager = KvManager::new(root, get_test_password())
+        .unwrap()
+        .handle_mnemonic(&crate::mnemonic::Cmd::Create)
+        .await
+        .unwrap();
+    let service = MultisigService {
+        kv_manager,
+        legacy_keys: false,
+    };
+
+    // a key uid can be archived more than once, also within the same second
+    for _ in 0..2 {
+        service
+            .handle_keygen(&KeygenRequest::new("archived_key_uid"))
+            .await
+            .unwrap();
+        let response = service
+            .handle_archive_key(ArchiveKeyRequest {
+                key_uid: "archived_key_uid".to_string(),
+                reason: "test".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, archive_key_response::Response::Success);
+    }
+
+    service
+        .handle_keygen(&KeygenRequest::new("deleted_key_uid"))
+        .await
+        .unwrap();
+    let response = service
+        .handle_delete_key(DeleteKeyRequest {
+            key_uid: "deleted_key_uid".to_string(),
+            confirm_key_uid: "deleted_key_uid".to_string(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(response, delete_key_response::Response::Success);
+    drop(service);
+
+    // the store holds the mnemonic and both archived records of the key;
+    // sled does not support to rapidly open/close databases, see tests/tofnd_party.rs
+    let mut report = None;
+    for _ in 0..50 {
+        match KvManager::check(root, get_test_password().into()) {
+            Ok(res) => {
+                report = Some(res);
+                break;
+            }
+            Err(_) => sleep(Duration::from_millis(100)).await,
+        }
+    }
+    let report = report.expect("could not check kvstore");
+    assert_eq!(report.values, 1);
+    assert_eq!(report.archived, 2);
+    assert!(report.reservations.is_empty());
+    assert!(report.corrupt.is_empty());
+}
@@ -0,0 +1,34 @@
+//! Helper structs and implementations for [crate::multisig].
+
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::TofndResult;
+
+/// prefix of multisig records in the kv-store, to keep them apart from gg20 keys
+pub(crate) const MULTISIG_KEY_PREFIX: &str = "multisig/";
+
+/// `MultisigKeyInfo` record, stored in the kv-store after a successful multisig keygen
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MultisigKeyInfo {
+    pub(super) key_uid: String,
+    pub(super) party_uid: String,
+    pub(super) verifying_key: Vec<u8>,
+    pub(super) created_at: u64, // seconds since unix epoch
+}
+
+impl MultisigKeyInfo {
+    pub(super) fn new(
+        key_uid: String,
+        party_uid: String,
+        verifying_key: Vec<u8>,
+    ) -> TofndResult<Self> {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(Self {
+            key_uid,
+            party_uid,
+            verifying_key,
+            created_at,
+        })
+    }
+}
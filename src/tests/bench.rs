@@ -0,0 +1,204 @@
+//! Concurrent signs with the same key on the same parties.
+//! Signs share the kv store of each party, so storage operations must not stall the protocols
+//! that run at the same time. [concurrent_signs] checks that concurrent signs complete with the same
+//! signature at all parties. [bench_concurrent_signs] measures their throughput and latency against
+//! a single sign, e.g. before and after a change of the kv manager. Run with
+//!     cargo test --release bench_concurrent_signs -- --ignored --nocapture
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use testdir::testdir;
+use tokio::{sync::Barrier, time::sleep};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{transport::Channel, Request};
+
+use super::{
+    basic_keygen, clean_up, init_parties_from_test_case,
+    mock::{Deliverer, SenderReceiver},
+    TestCase, TofndParty, MSG_TO_SIGN, SLEEP_TIME,
+};
+use crate::proto::{
+    self, gg20_client::Gg20Client, message_out::sign_result::SignResultData::Signature,
+};
+
+const TEST_SIGN_COUNT: usize = 4;
+const BENCH_SIGN_COUNT: usize = 16;
+
+/// Results of a batch of concurrent signs
+struct SignBatch {
+    /// time from the start of the first sign to the result of the last one
+    elapsed: Duration,
+    /// time from the start of each sign of each party to its result
+    latencies: Vec<Duration>,
+    /// signature of each sign, as returned by each party
+    signatures: Vec<Vec<Vec<u8>>>,
+}
+
+/// Keygen of a key that all parties use for the signs
+async fn keygen_parties(dir: &std::path::Path) -> (Vec<TofndParty>, Vec<String>, &'static str) {
+    let test_case = TestCase::new(3, vec![1, 1, 1], 1, vec![0, 1, 2]);
+    let (parties, party_uids) = init_parties_from_test_case(&test_case, dir).await;
+
+    let key_uid = "bench-key";
+    let (parties, _, _, success) =
+        basic_keygen(&test_case, parties, party_uids.clone(), key_uid).await;
+    assert!(success, "keygen failed");
+    (parties, party_uids, key_uid)
+}
+
+// few workers, so that blocked workers stall the protocols
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn concurrent_signs() {
+    let dir = testdir!();
+    let (parties, party_uids, key_uid) = keygen_parties(&dir).await;
+
+    let batch = run_signs(&parties, &party_uids, key_uid, "test-sig", TEST_SIGN_COUNT).await;
+
+    // every party returned the same signature for each sign
+    assert_eq!(batch.signatures.len(), TEST_SIGN_COUNT);
+    for signatures in &batch.signatures {
+        assert_eq!(signatures.len(), parties.len());
+        assert!(signatures.iter().all(|s| *s == signatures[0]));
+    }
+    assert_eq!(batch.latencies.len(), TEST_SIGN_COUNT * parties.len());
+
+    clean_up(parties).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore]
+async fn bench_concurrent_signs() {
+    let dir = testdir!();
+    let (parties, party_uids, key_uid) = keygen_parties(&dir).await;
+
+    // a single sign, as the baseline of the concurrent ones
+    let single = run_signs(&parties, &party_uids, key_uid, "single-sig", 1).await;
+    let mut batch = run_signs(
+        &parties,
+        &party_uids,
+        key_uid,
+        "bench-sig",
+        BENCH_SIGN_COUNT,
+    )
+    .await;
+    batch.latencies.sort();
+
+    println!(
+        "single sign of {} parties: {:?}",
+        parties.len(),
+        single.elapsed
+    );
+    println!(
+        "{} concurrent signs of {} parties: total {:?}, {:.2} signs/s, median latency {:?}, max latency {:?}",
+        BENCH_SIGN_COUNT,
+        parties.len(),
+        batch.elapsed,
+        BENCH_SIGN_COUNT as f64 / batch.elapsed.as_secs_f64(),
+        batch.latencies[batch.latencies.len() / 2],
+        batch.latencies[batch.latencies.len() - 1],
+    );
+    // concurrent signs are not serialized behind each other, e.g. by the kv store
+    assert!(batch.elapsed < single.elapsed * BENCH_SIGN_COUNT as u32);
+
+    clean_up(parties).await;
+}
+
+/// Runs `sign_count` concurrent signs of all `parties` with the key `key_uid`
+async fn run_signs(
+    parties: &[TofndParty],
+    party_uids: &[String],
+    key_uid: &str,
+    sig_prefix: &str,
+    sign_count: usize,
+) -> SignBatch {
+    let barrier = Arc::new(Barrier::new(sign_count * parties.len() + 1));
+    let mut handles = Vec::with_capacity(sign_count);
+    for i in 0..sign_count {
+        let (delivery, channel_pairs) = Deliverer::with_party_ids(party_uids);
+        let mut sign_handles = Vec::with_capacity(parties.len());
+        for ((party, channels), party_uid) in parties.iter().zip(channel_pairs).zip(party_uids) {
+            let init = proto::SignInit {
+                new_sig_uid: format!("{}-{}", sig_prefix, i),
+                key_uid: key_uid.to_string(),
+                party_uids: party_uids.to_vec(),
+                message_to_sign: MSG_TO_SIGN.clone(),
+            };
+            sign_handles.push(tokio::spawn(sign(
+                party.client(),
+                init,
+                channels,
+                delivery.clone(),
+                party_uid.clone(),
+                barrier.clone(),
+            )));
+        }
+        handles.push(sign_handles);
+    }
+
+    // as in execute_sign, let all parties receive their SignInit before messages are delivered
+    sleep(Duration::from_secs(SLEEP_TIME)).await;
+    barrier.wait().await;
+    let start = Instant::now();
+
+    let mut latencies = Vec::with_capacity(sign_count * parties.len());
+    let mut signatures = Vec::with_capacity(sign_count);
+    for sign_handles in handles {
+        let mut sign_signatures = Vec::with_capacity(parties.len());
+        for handle in sign_handles {
+            let (latency, signature) = handle.await.unwrap();
+            latencies.push(latency);
+            sign_signatures.push(signature);
+        }
+        signatures.push(sign_signatures);
+    }
+
+    SignBatch {
+        elapsed: start.elapsed(),
+        latencies,
+        signatures,
+    }
+}
+
+/// Runs a sign of a party and returns the time from the start of the protocol to its result, and the signature
+async fn sign(
+    mut client: Gg20Client<Channel>,
+    init: proto::SignInit,
+    channels: SenderReceiver,
+    delivery: Deliverer,
+    my_uid: String,
+    start: Arc<Barrier>,
+) -> (Duration, Vec<u8>) {
+    let (sign_server_incoming, rx) = channels;
+    let mut sign_server_outgoing = client
+        .sign(Request::new(UnboundedReceiverStream::new(rx)))
+        .await
+        .unwrap()
+        .into_inner();
+
+    sign_server_incoming
+        .send(proto::MessageIn {
+            data: Some(proto::message_in::Data::SignInit(init)),
+        })
+        .unwrap();
+
+    start.wait().await;
+    let start = Instant::now();
+
+    loop {
+        let msg = sign_server_outgoing
+            .message()
+            .await
+            .unwrap()
+            .expect("sign stream closed before the result");
+        match msg.data.as_ref().expect("missing data") {
+            proto::message_out::Data::Traffic(_) => delivery.deliver(&msg, &my_uid),
+            proto::message_out::Data::SignResult(result) => match &result.sign_result_data {
+                Some(Signature(signature)) => return (start.elapsed(), signature.clone()),
+                _ => panic!("party [{}] sign failed", my_uid),
+            },
+            _ => panic!("party [{}] sign error: bad outgoing message type", my_uid),
+        }
+    }
+}
@@ -20,6 +20,9 @@ pub(super) trait Party: Sync + Send {
         keygen_output: proto::KeygenOutput,
     );
     async fn execute_key_presence(&mut self, key_uid: String) -> bool;
+    async fn execute_list_keys(&mut self) -> Vec<proto::KeyInfo>;
+    async fn execute_archive_key(&mut self, key_uid: String, reason: String) -> bool;
+    async fn execute_delete_key(&mut self, key_uid: String, confirm_key_uid: String) -> bool;
     async fn execute_sign(
         &mut self,
         init: proto::SignInit,
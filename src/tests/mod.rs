@@ -13,7 +13,9 @@ use testdir::testdir;
 use tokio::time::{sleep, Duration};
 use tonic::Code::InvalidArgument;
 
+mod bench;
 mod mock;
+mod session;
 mod tofnd_party;
 
 mod honest_test_cases;
@@ -24,6 +26,7 @@ use malicious::{MaliciousData, PartyMaliciousData};
 
 mod mnemonic;
 
+use crate::encrypted_sled;
 use crate::mnemonic::Cmd::{self, Create};
 use proto::message_out::CriminalList;
 use tracing::{info, warn};
@@ -46,6 +49,9 @@ lazy_static::lazy_static! {
     // TODO add test for messages smaller and larger than 32 bytes
 }
 const SLEEP_TIME: u64 = 1;
+// seconds that parties wait for the messages of a round. Timeout cases rely on it to
+// report stallers, so it has to be long enough for honest parties to finish each round.
+const ROUND_TIMEOUT: u64 = 20;
 const MAX_TRIES: u32 = 3;
 
 struct TestCase {
@@ -235,7 +241,8 @@ async fn delete_party_shares(mut party_db_path: PathBuf, key: &str) {
 
     let mut tries = 0;
     let db = loop {
-        match sled::open(&party_db_path) {
+        // keys are hashed at rest, so the share has to be removed through the encrypted db
+        match encrypted_sled::Db::open(&party_db_path, encrypted_sled::get_test_password()) {
             Ok(db) => break db,
             Err(err) => {
                 sleep(Duration::from_secs(SLEEP_TIME)).await;
@@ -402,6 +409,9 @@ async fn basic_keygen_and_sign(test_case: &TestCase, dir: &Path, restart: bool,
     // Check that the session is present in the kvstore
     let parties = execute_key_presence(parties, new_key_uid.into(), true).await;
 
+    // Check that the key is listed by all parties
+    let parties = execute_list_keys(parties, &keygen_init).await;
+
     // restart party if restart is enabled and return new parties' set
     let parties = match restart {
         true => {
@@ -458,6 +468,10 @@ async fn basic_keygen_and_sign(test_case: &TestCase, dir: &Path, restart: bool,
     let results = results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
     check_sign_results(results, expected_sign_faults);
 
+    // archive the key and check that it is no longer present in the kvstore
+    let parties = execute_archive_key(parties, new_key_uid.into()).await;
+    let parties = execute_key_presence(parties, new_key_uid.into(), false).await;
+
     clean_up(parties).await;
 }
 
@@ -704,11 +718,8 @@ async fn execute_keygen(
     // wake up one party
     notify.notify_one();
 
-    // if we are expecting a timeout, abort parties after a reasonable amount of time
-    if expect_timeout {
-        let unblocker = keygen_delivery.clone();
-        abort_parties(unblocker, 10);
-    }
+    // if we are expecting a timeout, parties report the staller when the round times out;
+    // no abort message is needed
 
     let mut parties = Vec::with_capacity(share_count); // async closures are unstable https://github.com/rust-lang/rust/issues/62290
     let mut results = vec![];
@@ -761,6 +772,74 @@ async fn execute_key_presence(
     parties
 }
 
+async fn execute_list_keys(
+    parties: Vec<TofndParty>,
+    keygen_init: &proto::KeygenInit,
+) -> Vec<TofndParty> {
+    let mut handles = Vec::new();
+
+    for mut party in parties {
+        let handle = tokio::spawn(async move {
+            let keys = party.execute_list_keys().await;
+            (party, keys)
+        });
+
+        handles.push(handle);
+    }
+
+    let mut parties = Vec::new();
+
+    for (i, handle) in handles.into_iter().enumerate() {
+        let (party, keys) = handle.await.unwrap();
+        assert_eq!(
+            keys.len(),
+            1,
+            "party {} expected to list exactly one key",
+            i
+        );
+
+        let key = &keys[0];
+        assert_eq!(key.key_uid, keygen_init.new_key_uid);
+        assert_eq!(key.threshold, keygen_init.threshold);
+        assert_eq!(key.party_uids, keygen_init.party_uids);
+        assert_eq!(key.my_party_index as usize, i);
+
+        parties.push(party);
+    }
+
+    parties
+}
+
+async fn execute_archive_key(parties: Vec<TofndParty>, key_uid: String) -> Vec<TofndParty> {
+    let mut handles = Vec::new();
+
+    for mut party in parties {
+        let key_uid = key_uid.clone();
+
+        let handle = tokio::spawn(async move {
+            let archived = party
+                .execute_archive_key(key_uid.clone(), "test archive".to_string())
+                .await;
+            // the key is gone, so a subsequent deletion finds nothing
+            let deleted = party.execute_delete_key(key_uid.clone(), key_uid).await;
+            (party, archived, deleted)
+        });
+
+        handles.push(handle);
+    }
+
+    let mut parties = Vec::new();
+
+    for handle in handles {
+        let (party, archived, deleted) = handle.await.unwrap();
+        assert!(archived, "key expected to be archived");
+        assert!(!deleted, "archived key expected to be absent");
+        parties.push(party);
+    }
+
+    parties
+}
+
 async fn execute_recover(
     mut parties: Vec<TofndParty>,
     recover_party_index: usize,
@@ -843,11 +922,8 @@ async fn execute_sign(
     sleep(Duration::from_secs(SLEEP_TIME)).await;
     notify.notify_one();
 
-    // if we are expecting a timeout, abort parties after a reasonable amount of time
-    if expect_timeout {
-        let unblocker = sign_delivery.clone();
-        abort_parties(unblocker, 10);
-    }
+    // if we are expecting a timeout, parties report the staller when the round times out;
+    // no abort message is needed
 
     let mut results = Vec::with_capacity(sign_join_handles.len());
     for (i, h) in sign_join_handles {
@@ -864,12 +940,3 @@ async fn execute_sign(
         results,
     )
 }
-
-fn abort_parties(unblocker: Deliverer, time: u64) {
-    // send an abort message if protocol is taking too much time
-    info!("I will send an abort message in {} seconds", time);
-    std::thread::spawn(move || {
-        unblocker.send_timeouts(time);
-    });
-    info!("Continuing for now");
-}
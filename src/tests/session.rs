@@ -0,0 +1,176 @@
+//! Sessions that end because their client disconnects or because they are cancelled.
+
+use testdir::testdir;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout, Duration},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::Request;
+use tracing_test::traced_test;
+
+use super::{
+    basic_keygen, clean_up, init_parties_from_test_case, tofnd_party::TofndParty, TestCase,
+};
+use crate::proto;
+
+const SESSIONS_END_TIMEOUT: Duration = Duration::from_secs(10);
+const SESSIONS_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait until no session of `party` is listed. A session is listed until it has ended and all of
+/// its tasks have stopped, so all tasks of the sessions have stopped once this returns.
+async fn wait_for_sessions_to_end(party: &TofndParty) {
+    let mut client = party.admin_client();
+    let sessions_ended = async {
+        loop {
+            let sessions = client
+                .list_sessions(Request::new(proto::ListSessionsRequest {}))
+                .await
+                .unwrap()
+                .into_inner()
+                .sessions;
+            if sessions.is_empty() {
+                return;
+            }
+            sleep(SESSIONS_POLL_INTERVAL).await;
+        }
+    };
+    timeout(SESSIONS_END_TIMEOUT, sessions_ended)
+        .await
+        .expect("the tasks of a session did not stop");
+}
+
+/// Start a keygen at the first party only, so that it waits for the messages of the others.
+/// Returns the keygen streams once the keygen has sent its first message.
+async fn start_lonely_keygen(
+    party: &TofndParty,
+    party_uids: &[String],
+    test_case: &TestCase,
+    key_uid: &str,
+) -> (
+    mpsc::UnboundedSender<proto::MessageIn>,
+    tonic::Streaming<proto::MessageOut>,
+) {
+    let (keygen_server_incoming, rx) = mpsc::unbounded_channel();
+    let mut keygen_server_outgoing = party
+        .client()
+        .keygen(Request::new(UnboundedReceiverStream::new(rx)))
+        .await
+        .unwrap()
+        .into_inner();
+    keygen_server_incoming
+        .send(proto::MessageIn {
+            data: Some(proto::message_in::Data::KeygenInit(proto::KeygenInit {
+                new_key_uid: key_uid.to_string(),
+                party_uids: party_uids.to_vec(),
+                party_share_counts: test_case.share_counts.clone(),
+                my_party_index: 0,
+                threshold: test_case.threshold as u32,
+            })),
+        })
+        .unwrap();
+
+    // wait until the keygen sends its first message
+    let msg = keygen_server_outgoing.message().await.unwrap().unwrap();
+    assert!(matches!(
+        msg.data,
+        Some(proto::message_out::Data::Traffic(_))
+    ));
+    (keygen_server_incoming, keygen_server_outgoing)
+}
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn disconnected_keygen_releases_key() {
+    let dir = testdir!();
+    let test_case = TestCase::new(3, vec![1, 1, 1], 1, vec![0, 1, 2]);
+    let (parties, party_uids) = init_parties_from_test_case(&test_case, &dir).await;
+    let key_uid = "disconnected-key";
+
+    let (keygen_server_incoming, keygen_server_outgoing) =
+        start_lonely_keygen(&parties[0], &party_uids, &test_case, key_uid).await;
+    drop(keygen_server_outgoing);
+    drop(keygen_server_incoming);
+    wait_for_sessions_to_end(&parties[0]).await;
+
+    // the aborted session released the key, so a keygen with the same key uid succeeds
+    let (parties, _, _, success) = basic_keygen(&test_case, parties, party_uids, key_uid).await;
+    assert!(
+        success,
+        "keygen with the key uid of a disconnected keygen failed"
+    );
+
+    clean_up(parties).await;
+}
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_keygen_releases_key() {
+    let dir = testdir!();
+    let test_case = TestCase::new(3, vec![1, 1, 1], 1, vec![0, 1, 2]);
+    let (parties, party_uids) = init_parties_from_test_case(&test_case, &dir).await;
+    let key_uid = "cancelled-key";
+
+    let (_keygen_server_incoming, mut keygen_server_outgoing) =
+        start_lonely_keygen(&parties[0], &party_uids, &test_case, key_uid).await;
+
+    // the keygen is listed while it waits for the messages of the first round
+    let sessions = parties[0]
+        .admin_client()
+        .list_sessions(Request::new(proto::ListSessionsRequest {}))
+        .await
+        .unwrap()
+        .into_inner()
+        .sessions;
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].session_uid, key_uid);
+    assert_eq!(sessions[0].kind, proto::session_info::Kind::Keygen as i32);
+    assert_eq!(
+        sessions[0].state,
+        proto::session_info::State::Running as i32
+    );
+    assert_eq!(sessions[0].round, 1);
+    assert_eq!(sessions[0].party_uids, party_uids);
+
+    let cancel = |session_uid: &str| {
+        let mut client = parties[0].admin_client();
+        let request = proto::CancelSessionRequest {
+            session_uid: session_uid.to_string(),
+        };
+        async move {
+            client
+                .cancel_session(Request::new(request))
+                .await
+                .unwrap()
+                .into_inner()
+                .response
+        }
+    };
+    assert_eq!(
+        cancel("absent-key").await,
+        proto::cancel_session_response::Response::Absent as i32
+    );
+    assert_eq!(
+        cancel(key_uid).await,
+        proto::cancel_session_response::Response::Success as i32
+    );
+
+    // the client of the cancelled keygen gets an error
+    loop {
+        match keygen_server_outgoing.message().await {
+            Ok(Some(_)) => continue,
+            Ok(None) => panic!("cancelled keygen ended without an error"),
+            Err(_) => break,
+        }
+    }
+    wait_for_sessions_to_end(&parties[0]).await;
+
+    // the cancelled keygen released the key, so a keygen with the same key uid succeeds
+    let (parties, _, _, success) = basic_keygen(&test_case, parties, party_uids, key_uid).await;
+    assert!(
+        success,
+        "keygen with the key uid of a cancelled keygen failed"
+    );
+
+    clean_up(parties).await;
+}
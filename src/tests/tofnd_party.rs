@@ -12,17 +12,18 @@ use crate::{
     encrypted_sled::{get_test_password, PasswordMethod},
     gg20,
     kv_manager::KvManager,
+    listen::{self, ListenAddr, Listener},
     mnemonic::Cmd,
     proto,
-    tests::SLEEP_TIME,
+    tests::{ROUND_TIMEOUT, SLEEP_TIME},
 };
 
 use proto::message_out::{KeygenResult, SignResult};
 use std::convert::TryFrom;
 use std::path::Path;
 use tokio::time::{sleep, Duration};
-use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
-use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tonic::Request;
 
 use tracing::{info, warn};
@@ -37,9 +38,10 @@ use gg20::service::malicious::Behaviours;
 pub(super) struct TofndParty {
     tofnd_path: String,
     client: proto::gg20_client::Gg20Client<tonic::transport::Channel>,
+    admin_client: proto::gg20_admin_client::Gg20AdminClient<tonic::transport::Channel>,
     server_handle: JoinHandle<()>,
     server_shutdown_sender: oneshot::Sender<()>,
-    server_port: u16,
+    server_addr: ListenAddr,
     #[cfg(feature = "malicious")]
     pub(super) malicious_data: PartyMaliciousData,
 }
@@ -53,17 +55,39 @@ impl TofndParty {
         // start server
         let (server_shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
 
-        let incoming = TcpListener::bind(addr(0)).await.unwrap(); // use port 0 and let the OS decide
-        let server_addr = incoming.local_addr().unwrap();
-        let server_port = server_addr.port();
-        info!("new party bound to port [{:?}]", server_port);
+        // alternate between tcp and unix domain sockets so that tests cover both transports
+        let listen_addr = match init_party.party_index % 2 {
+            0 => ListenAddr::Tcp(addr(0)), // use port 0 and let the OS decide
+            _ => listen::test_unix_addr(),
+        };
+        let listener = Listener::bind(&listen_addr).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        info!("new party bound to [{}]", server_addr);
 
         let cfg = Config {
             mnemonic_cmd,
-            port: server_port,
+            listen_addr: server_addr.clone(),
+            tls: None,
             safe_keygen: false,
             tofnd_path: tofnd_path.to_string(),
             password_method: PasswordMethod::NoPassword,
+            kdf: None,
+            kek: Default::default(),
+            storage: Default::default(),
+            durability: Default::default(),
+            change_password: None,
+            change_kek: None,
+            rotate_data_key: false,
+            backup_cmd: None,
+            migrate_dry_run: false,
+            reservation_cmd: None,
+            db_check: false,
+            stale_reservations: Default::default(),
+            round_timeout: Some(Duration::from_secs(ROUND_TIMEOUT)),
+            compute_threads: 2,
+            max_concurrent_keygens: None,
+            legacy_multisig_keys: false,
+            log_filter: String::new(),
             #[cfg(feature = "malicious")]
             behaviours: Behaviours {
                 keygen: init_party.malicious_data.keygen_behaviour.clone(),
@@ -93,14 +117,16 @@ impl TofndParty {
         };
         let kv_manager = kv_manager.handle_mnemonic(&cfg.mnemonic_cmd).await.unwrap();
 
-        let my_service = gg20::service::new_service(cfg.clone(), kv_manager);
+        let my_service = gg20::service::new_service(cfg.clone(), kv_manager).unwrap();
 
+        let admin_service = proto::gg20_admin_server::Gg20AdminServer::new(my_service.clone());
         let proto_service = proto::gg20_server::Gg20Server::new(my_service);
         // let (startup_sender, startup_receiver) = tokio::sync::oneshot::channel::<()>();
         let server_handle = tokio::spawn(async move {
             tonic::transport::Server::builder()
                 .add_service(proto_service)
-                .serve_with_incoming_shutdown(TcpListenerStream::new(incoming), async {
+                .add_service(admin_service)
+                .serve_with_incoming_shutdown(listener.into_incoming(), async {
                     shutdown_receiver.await.unwrap();
                 })
                 .await
@@ -117,21 +143,34 @@ impl TofndParty {
         // startup_receiver.await.unwrap();
         // println!("party [{}] server started!", init.party_uids[my_id_index]);
 
-        info!("new party [{}] connect to server...", server_port);
-        let client = proto::gg20_client::Gg20Client::connect(format!("http://{}", server_addr))
-            .await
-            .unwrap();
+        info!("new party [{}] connect to server...", server_addr);
+        let channel = listen::connect(&server_addr).await;
+        let client = proto::gg20_client::Gg20Client::new(channel.clone());
+        let admin_client = proto::gg20_admin_client::Gg20AdminClient::new(channel);
 
         TofndParty {
             tofnd_path: tofnd_path.to_owned(),
             client,
+            admin_client,
             server_handle,
             server_shutdown_sender,
-            server_port,
+            server_addr,
             #[cfg(feature = "malicious")]
             malicious_data: init_party.malicious_data,
         }
     }
+
+    /// Returns a new client of the party's gRPC server, e.g. to run several sessions concurrently
+    pub(super) fn client(&self) -> proto::gg20_client::Gg20Client<tonic::transport::Channel> {
+        self.client.clone()
+    }
+
+    /// Returns a new client of the party's admin gRPC server
+    pub(super) fn admin_client(
+        &self,
+    ) -> proto::gg20_admin_client::Gg20AdminClient<tonic::transport::Channel> {
+        self.admin_client.clone()
+    }
 }
 
 // r1 -> bcast
@@ -398,6 +437,64 @@ impl Party for TofndParty {
         }
     }
 
+    async fn execute_list_keys(&mut self) -> Vec<proto::KeyInfo> {
+        self.admin_client
+            .list_keys(Request::new(proto::ListKeysRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .keys
+    }
+
+    async fn execute_archive_key(&mut self, key_uid: String, reason: String) -> bool {
+        let response = self
+            .admin_client
+            .archive_key(Request::new(proto::ArchiveKeyRequest { key_uid, reason }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        match proto::archive_key_response::Response::from_i32(response.response) {
+            Some(proto::archive_key_response::Response::Success) => true,
+            Some(proto::archive_key_response::Response::Absent) => false,
+            Some(proto::archive_key_response::Response::Fail) => {
+                panic!("archive key request failed")
+            }
+            Some(proto::archive_key_response::Response::Unspecified) => {
+                panic!("Unspecified archive key response")
+            }
+            None => {
+                panic!("Invalid archive key response. Could not convert i32 to enum")
+            }
+        }
+    }
+
+    async fn execute_delete_key(&mut self, key_uid: String, confirm_key_uid: String) -> bool {
+        let response = self
+            .admin_client
+            .delete_key(Request::new(proto::DeleteKeyRequest {
+                key_uid,
+                confirm_key_uid,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        match proto::delete_key_response::Response::from_i32(response.response) {
+            Some(proto::delete_key_response::Response::Success) => true,
+            Some(proto::delete_key_response::Response::Absent) => false,
+            Some(proto::delete_key_response::Response::Fail) => {
+                panic!("delete key request failed")
+            }
+            Some(proto::delete_key_response::Response::Unspecified) => {
+                panic!("Unspecified delete key response")
+            }
+            None => {
+                panic!("Invalid delete key response. Could not convert i32 to enum")
+            }
+        }
+    }
+
     async fn execute_sign(
         &mut self,
         init: proto::SignInit,
@@ -502,7 +599,7 @@ impl Party for TofndParty {
     async fn shutdown(mut self) {
         self.server_shutdown_sender.send(()).unwrap(); // tell the server to shut down
         self.server_handle.await.unwrap(); // wait for server to shut down
-        info!("party [{}] shutdown success", self.server_port);
+        info!("party [{}] shutdown success", self.server_addr);
     }
 
     fn get_root(&self) -> std::path::PathBuf {
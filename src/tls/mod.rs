@@ -0,0 +1,127 @@
+//! Optional mutual TLS for the gRPC server.
+//! When a [TlsConfig] is provided, the server authenticates itself with a certificate and
+//! only accepts clients that present a certificate signed by the configured CA.
+//! Additionally, clients can be restricted to an allow-list of certificate subjects with [ClientAuth].
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use tonic::{
+    service::{interceptor::InterceptorLayer, Interceptor},
+    transport::{server::TlsConnectInfo, Certificate, Identity, Server, ServerTlsConfig},
+    Request, Status,
+};
+use x509_parser::parse_x509_certificate;
+use zeroize::Zeroize;
+
+// logging
+use tracing::{info, warn};
+
+// error handling
+use crate::TofndResult;
+use anyhow::anyhow;
+
+/// Paths of the PEM encoded files needed for mutual TLS, and the allowed client subjects
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: PathBuf,
+    /// subjects of client certificates that are allowed to connect, e.g. `CN=axelar-core`.
+    /// If empty, all clients with a certificate signed by `client_ca` are allowed.
+    pub allowed_subjects: Vec<String>,
+}
+
+impl TlsConfig {
+    /// read the server identity and the client CA bundle from disk
+    fn server_tls_config(&self) -> TofndResult<ServerTlsConfig> {
+        let cert = read(&self.cert)?;
+        let mut key = read(&self.key)?;
+        let client_ca = read(&self.client_ca)?;
+
+        let identity = Identity::from_pem(cert, &key);
+        key.zeroize();
+
+        Ok(ServerTlsConfig::new()
+            .identity(identity)
+            .client_ca_root(Certificate::from_pem(client_ca)))
+    }
+}
+
+fn read(path: &std::path::Path) -> TofndResult<Vec<u8>> {
+    std::fs::read(path).map_err(|err| anyhow!("could not read {}: {}", path.display(), err))
+}
+
+/// Create a gRPC server builder. If `tls` is provided, the server requires mutual TLS
+/// and checks client certificates against the allowed subjects.
+pub fn server_builder(
+    tls: Option<&TlsConfig>,
+) -> TofndResult<Server<InterceptorLayer<ClientAuth>>> {
+    let mut server = Server::builder();
+    let client_auth = match tls {
+        Some(tls) => {
+            server = server.tls_config(tls.server_tls_config()?)?;
+            info!(
+                "mutual TLS enabled, allowed client subjects: {:?}",
+                tls.allowed_subjects
+            );
+            ClientAuth::new(tls.allowed_subjects.clone())
+        }
+        None => ClientAuth::new(vec![]),
+    };
+    Ok(server.layer(tonic::service::interceptor(client_auth)))
+}
+
+/// Interceptor that rejects requests from clients whose certificate subject is not allowed
+#[derive(Clone)]
+pub struct ClientAuth {
+    allowed_subjects: Arc<Vec<String>>,
+}
+
+impl ClientAuth {
+    fn new(allowed_subjects: Vec<String>) -> Self {
+        Self {
+            allowed_subjects: Arc::new(allowed_subjects),
+        }
+    }
+}
+
+impl Interceptor for ClientAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        // no allow-list; TLS (if enabled) has already verified the client certificate
+        if self.allowed_subjects.is_empty() {
+            return Ok(request);
+        }
+
+        // connect info of incoming connections, see [crate::listen::Connection]
+        let certs = request
+            .extensions()
+            .get::<TlsConnectInfo<Option<SocketAddr>>>()
+            .and_then(|info| info.peer_certs())
+            .ok_or_else(|| Status::unauthenticated("missing client certificate"))?;
+        let cert = certs
+            .first()
+            .ok_or_else(|| Status::unauthenticated("missing client certificate"))?;
+
+        // tonic stores the DER encoding of peer certificates
+        let subject = match parse_x509_certificate(cert.get_ref()) {
+            Ok((_, cert)) => cert.subject().to_string(),
+            Err(err) => {
+                warn!("could not parse client certificate: {}", err);
+                return Err(Status::unauthenticated("invalid client certificate"));
+            }
+        };
+
+        if !self.allowed_subjects.contains(&subject) {
+            warn!("rejected client with certificate subject [{}]", subject);
+            return Err(Status::permission_denied(format!(
+                "client [{}] is not allowed",
+                subject
+            )));
+        }
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,211 @@
+use super::{server_builder, TlsConfig};
+use crate::{
+    addr,
+    encrypted_sled::get_test_password,
+    kv_manager::KvManager,
+    listen::{ListenAddr, Listener},
+    multisig,
+    proto::{
+        key_presence_response::Response::Absent, multisig_client::MultisigClient,
+        multisig_server::MultisigServer, KeyPresenceRequest,
+    },
+};
+
+use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+use std::path::Path;
+use testdir::testdir;
+use tokio::sync::oneshot;
+use tonic::{
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity},
+    Code,
+};
+use tracing_test::traced_test;
+
+const SERVER_DOMAIN: &str = "localhost";
+const ALLOWED_CLIENT: &str = "axelar-core";
+
+fn distinguished_name(common_name: &str) -> DistinguishedName {
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, common_name);
+    name
+}
+
+/// create a self-signed CA
+fn new_ca(common_name: &str) -> rcgen::Certificate {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = distinguished_name(common_name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    rcgen::Certificate::from_params(params).unwrap()
+}
+
+/// create a PEM encoded certificate signed by `ca` and its PEM encoded private key
+fn new_signed_cert(ca: &rcgen::Certificate, common_name: &str) -> (String, String) {
+    let mut params = CertificateParams::new(vec![SERVER_DOMAIN.to_string()]);
+    params.distinguished_name = distinguished_name(common_name);
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+    (
+        cert.serialize_pem_with_signer(ca).unwrap(),
+        cert.serialize_private_key_pem(),
+    )
+}
+
+// spin up a multisig server that requires mutual TLS
+async fn spin_tls_server(dir: &Path, ca: &rcgen::Certificate) -> (u16, oneshot::Sender<()>) {
+    let (cert, key) = new_signed_cert(ca, "tofnd");
+    let tls = TlsConfig {
+        cert: dir.join("server.pem"),
+        key: dir.join("server.key"),
+        client_ca: dir.join("ca.pem"),
+        allowed_subjects: vec![format!("CN={}", ALLOWED_CLIENT)],
+    };
+    std::fs::write(&tls.cert, cert).unwrap();
+    std::fs::write(&tls.key, key).unwrap();
+    std::fs::write(&tls.client_ca, ca.serialize_pem().unwrap()).unwrap();
+
+    let kv_manager = KvManager::new(dir.to_str().unwrap(), get_test_password())
+        .unwrap()
+        .handle_mnemonic(&crate::mnemonic::Cmd::Create)
+        .await
+        .unwrap();
+    let service = MultisigServer::new(multisig::service::new_service(kv_manager, false));
+
+    let listener = Listener::bind(&ListenAddr::Tcp(addr(0))).await.unwrap();
+    let port = match listener.local_addr().unwrap() {
+        ListenAddr::Tcp(addr) => addr.port(),
+        ListenAddr::Unix(_) => unreachable!(),
+    };
+
+    let server = server_builder(Some(&tls)).unwrap();
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        server
+            .add_service(service)
+            .serve_with_incoming_shutdown(listener.into_incoming(), async {
+                shutdown_receiver.await.unwrap();
+            })
+            .await
+            .unwrap();
+    });
+
+    (port, shutdown_sender)
+}
+
+async fn connect(
+    port: u16,
+    ca: &rcgen::Certificate,
+    identity: Option<(String, String)>,
+) -> Result<MultisigClient<Channel>, tonic::transport::Error> {
+    let mut tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(ca.serialize_pem().unwrap()))
+        .domain_name(SERVER_DOMAIN);
+    if let Some((cert, key)) = identity {
+        tls = tls.identity(Identity::from_pem(cert, key));
+    }
+
+    let channel = Endpoint::from_shared(format!("https://127.0.0.1:{}", port))
+        .unwrap()
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    Ok(MultisigClient::new(channel))
+}
+
+/// Returns the status code of a key presence request, or [Code::Unavailable] if we cannot connect
+async fn key_presence(
+    port: u16,
+    ca: &rcgen::Certificate,
+    identity: Option<(String, String)>,
+) -> Code {
+    let mut client = match connect(port, ca, identity).await {
+        Ok(client) => client,
+        Err(_) => return Code::Unavailable,
+    };
+
+    let request = KeyPresenceRequest {
+        key_uid: "key_uid".to_string(),
+    };
+    match client.key_presence(request).await {
+        Ok(response) => {
+            assert_eq!(response.into_inner().response, Absent as i32);
+            Code::Ok
+        }
+        Err(status) => status.code(),
+    }
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_allowed_client() {
+    let dir = testdir!();
+    let ca = new_ca("tofnd test ca");
+    let (port, shutdown_sender) = spin_tls_server(&dir, &ca).await;
+
+    let identity = new_signed_cert(&ca, ALLOWED_CLIENT);
+    assert_eq!(key_presence(port, &ca, Some(identity)).await, Code::Ok);
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_client_subject_not_allowed() {
+    let dir = testdir!();
+    let ca = new_ca("tofnd test ca");
+    let (port, shutdown_sender) = spin_tls_server(&dir, &ca).await;
+
+    let identity = new_signed_cert(&ca, "intruder");
+    assert_eq!(
+        key_presence(port, &ca, Some(identity)).await,
+        Code::PermissionDenied
+    );
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_client_without_certificate() {
+    let dir = testdir!();
+    let ca = new_ca("tofnd test ca");
+    let (port, shutdown_sender) = spin_tls_server(&dir, &ca).await;
+
+    assert_ne!(key_presence(port, &ca, None).await, Code::Ok);
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_client_certificate_from_unknown_ca() {
+    let dir = testdir!();
+    let ca = new_ca("tofnd test ca");
+    let (port, shutdown_sender) = spin_tls_server(&dir, &ca).await;
+
+    // the subject is allowed, but the certificate is not signed by the server's CA
+    let other_ca = new_ca("other ca");
+    let identity = new_signed_cert(&other_ca, ALLOWED_CLIENT);
+    assert_ne!(key_presence(port, &ca, Some(identity)).await, Code::Ok);
+
+    let _ = shutdown_sender.send(()).unwrap();
+}
+
+#[traced_test]
+#[tokio::test]
+async fn test_plaintext_client() {
+    let dir = testdir!();
+    let ca = new_ca("tofnd test ca");
+    let (port, shutdown_sender) = spin_tls_server(&dir, &ca).await;
+
+    let res = match MultisigClient::connect(format!("http://127.0.0.1:{}", port)).await {
+        Ok(mut client) => client
+            .key_presence(KeyPresenceRequest {
+                key_uid: "key_uid".to_string(),
+            })
+            .await
+            .is_ok(),
+        Err(_) => false,
+    };
+    assert!(!res);
+
+    let _ = shutdown_sender.send(()).unwrap();
+}